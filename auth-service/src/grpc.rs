@@ -1,3 +1,4 @@
+use std::future::Future;
 use std::net::SocketAddr;
 use tonic::{Request, Response, Status};
 use tracing::{error, info, instrument};
@@ -55,9 +56,11 @@ impl auth_service_server::AuthService for AuthServiceImpl {
     }
 }
 
+// Runs until `shutdown` resolves and the calls in flight are answered
 pub async fn start_grpc_server(
     addr: SocketAddr,
     redis_client: redis::Client,
+    shutdown: impl Future<Output = ()>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let svc = auth_service_server::AuthServiceServer::new(AuthServiceImpl { redis_client });
 
@@ -65,7 +68,7 @@ pub async fn start_grpc_server(
 
     tonic::transport::Server::builder()
         .add_service(svc)
-        .serve(addr)
+        .serve_with_shutdown(addr, shutdown)
         .await?;
 
     Ok(())
@@ -1,4 +1,5 @@
 use std::env;
+use std::time::Duration;
 
 use axum::{
     Router,
@@ -7,6 +8,7 @@ use axum::{
     routing::{get, post},
 };
 use sqlx::PgPool;
+use tokio::sync::watch;
 use tower_http::cors::CorsLayer;
 use tracing::{error, info, warn};
 
@@ -18,6 +20,9 @@ mod rate_limit;
 mod tenant;
 mod user_token;
 
+// Requests still running this long after a shutdown signal are dropped
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(20);
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv::dotenv().ok();
@@ -47,8 +52,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .parse()?;
     info!(grpc_addr = %grpc_addr, "starting gRPC server");
 
+    // flips once on SIGTERM or Ctrl-C, both servers then stop taking requests
+    let (shutdown_sender, shutdown) = watch::channel(false);
     tokio::spawn(async move {
-        if let Err(e) = grpc::start_grpc_server(grpc_addr, grpc_redis).await {
+        shutdown_signal().await;
+        let _ = shutdown_sender.send(true);
+    });
+
+    let grpc_shutdown = shutdown.clone();
+    let grpc_server = tokio::spawn(async move {
+        if let Err(e) = grpc::start_grpc_server(grpc_addr, grpc_redis, stopped(grpc_shutdown)).await
+        {
             error!(%e, "gRPC server failed");
         }
     });
@@ -92,7 +106,46 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3004").await.unwrap();
     info!("server listening on 0.0.0.0:3004");
-    axum::serve(listener, app).await.unwrap();
+    let http_shutdown = shutdown.clone();
+    let servers = async move {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(stopped(http_shutdown))
+            .await
+            .unwrap();
+        let _ = grpc_server.await;
+    };
+    let deadline = async move {
+        stopped(shutdown).await;
+        tokio::time::sleep(DRAIN_TIMEOUT).await;
+    };
+
+    tokio::select! {
+        _ = servers => info!("drained"),
+        _ = deadline => warn!("requests still running after {:?}, exiting", DRAIN_TIMEOUT),
+    }
 
     Ok(())
 }
+
+async fn stopped(mut shutdown: watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stopped| *stopped).await;
+}
+
+// Logs which of SIGTERM and Ctrl-C arrived first, SIGTERM only exists on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => terminate.recv().await,
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<Option<()>>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("received Ctrl-C, shutting down"),
+        _ = terminate => info!("received SIGTERM, shutting down"),
+    }
+}
@@ -1,25 +1,76 @@
+use std::collections::HashMap;
+use std::hash::Hash;
 use std::sync::Arc;
 
 use scylla::client::session::Session;
 use tonic::{Request, Response, Status};
 
+use crate::chat_service::AckDeliveriesRequest;
+use crate::chat_service::AckDeliveriesResponse;
+use crate::chat_service::DeleteMessageRequest;
+use crate::chat_service::EditMessageRequest;
+use crate::chat_service::FetchConversationPartnersRequest;
+use crate::chat_service::FetchConversationPartnersResponse;
+use crate::chat_service::FetchPendingDeliveriesRequest;
+use crate::chat_service::FetchPendingDeliveriesResponse;
+use crate::chat_service::FetchUserRoomsRequest;
+use crate::chat_service::FetchUserRoomsResponse;
+use crate::chat_service::GetConversationParticipantsRequest;
+use crate::chat_service::GetConversationParticipantsResponse;
 use crate::chat_service::GetPaginatedMessagesRequest;
 use crate::chat_service::GetPaginatedMessagesResponse;
 use crate::chat_service::GetPaginatedRoomMessagesRequest;
 use crate::chat_service::GetPaginatedRoomMessagesResponse;
+use crate::chat_service::GetReadCursorsRequest;
+use crate::chat_service::GetReadCursorsResponse;
 use crate::chat_service::GetSertConversationRequest;
 use crate::chat_service::GetSertConversationResponse;
+use crate::chat_service::MessageChangeRejection;
+use crate::chat_service::MessageChangeResponse;
+use crate::chat_service::PendingDelivery;
+use crate::chat_service::Reaction;
+use crate::chat_service::ReactionRejection as ProtoReactionRejection;
+use crate::chat_service::ReactionRequest;
+use crate::chat_service::ReactionResponse;
+use crate::chat_service::ReadCursor;
+use crate::chat_service::RoomMemberRequest;
+use crate::chat_service::RoomMemberResponse;
 use crate::chat_service::RoomMessage;
 use crate::chat_service::SyncMessagesRequest;
 use crate::chat_service::SyncMessagesResponse;
+use crate::chat_service::UpdateReadCursorRequest;
+use crate::chat_service::UpdateReadCursorResponse;
+use crate::chat_service::WriteDmBatchRequest;
+use crate::chat_service::WriteDmBatchResponse;
+use crate::chat_service::WriteRoomMessageBatchRequest;
+use crate::chat_service::WriteRoomMessageBatchResponse;
 use crate::chat_service::WriteRoomMessageRequest;
 use crate::chat_service::WriteRoomMessageResponse;
+use crate::queries::ChangeRejection;
+use crate::queries::MessageChange;
+use crate::queries::MessageKind;
+use crate::queries::ReactionChange;
+use crate::queries::ReactionRejection;
+use crate::queries::ack_deliveries;
+use crate::queries::add_reaction;
+use crate::queries::add_room_member;
+use crate::queries::delete_message;
+use crate::queries::edit_message;
+use crate::queries::fetch_conversation_participants;
+use crate::queries::fetch_conversation_partners;
+use crate::queries::fetch_member_rooms;
 use crate::queries::fetch_messages_after;
 use crate::queries::fetch_paginated_room_messages;
+use crate::queries::fetch_pending_deliveries;
+use crate::queries::fetch_read_cursors;
 use crate::queries::getsert_conversation_id;
-use crate::queries::write_direct_message;
-use crate::queries::write_room_message;
+use crate::queries::remove_reaction;
+use crate::queries::remove_room_member;
+use crate::queries::update_read_cursor;
+use crate::queries::write_direct_messages;
+use crate::queries::write_room_messages;
 use crate::utils::DbRoomMessageEx;
+use crate::utils::ReactionCount;
 use crate::{
     chat_service::{
         ConversationMessage, FetchConversationHistoryRequest, FetchConversationHistoryResponse,
@@ -108,55 +159,509 @@ impl ChatService for ChatServiceImpl {
         }
     }
 
-    async fn write_dm(
+    async fn get_conversation_participants(
         &self,
-        request: Request<WriteDmRequest>,
-    ) -> Result<Response<WriteDmResponse>, Status> {
+        request: Request<GetConversationParticipantsRequest>,
+    ) -> Result<Response<GetConversationParticipantsResponse>, Status> {
         let req = request.into_inner();
 
-        // 1. Input Validation
         if req.project_id.is_empty() || req.conversation_id.is_empty() {
-            return Ok(Response::new(WriteDmResponse {
+            return Ok(Response::new(GetConversationParticipantsResponse {
                 success: false,
                 error_message: "project_id and conversation_id are required".to_string(),
+                found: false,
+                user_id_1: String::new(),
+                user_id_2: String::new(),
+            }));
+        }
+
+        match fetch_conversation_participants(&self.session, &req.project_id, &req.conversation_id)
+            .await
+        {
+            Ok(Some((user_id_1, user_id_2))) => {
+                Ok(Response::new(GetConversationParticipantsResponse {
+                    success: true,
+                    error_message: String::new(),
+                    found: true,
+                    user_id_1,
+                    user_id_2,
+                }))
+            }
+            Ok(None) => Ok(Response::new(GetConversationParticipantsResponse {
+                success: true,
+                error_message: String::new(),
+                found: false,
+                user_id_1: String::new(),
+                user_id_2: String::new(),
+            })),
+            Err(e) => Ok(Response::new(GetConversationParticipantsResponse {
+                success: false,
+                error_message: e.to_string(),
+                found: false,
+                user_id_1: String::new(),
+                user_id_2: String::new(),
+            })),
+        }
+    }
+
+    async fn fetch_conversation_partners(
+        &self,
+        request: Request<FetchConversationPartnersRequest>,
+    ) -> Result<Response<FetchConversationPartnersResponse>, Status> {
+        let req = request.into_inner();
+
+        let tenant_id = match req.tenant_user_id {
+            Some(id) if !id.project_id.is_empty() && !id.user_id.is_empty() => id,
+            _ => {
+                return Ok(Response::new(FetchConversationPartnersResponse {
+                    success: false,
+                    error_message: "project_id and user_id are required".to_string(),
+                    user_ids: Vec::new(),
+                }));
+            }
+        };
+
+        match fetch_conversation_partners(&self.session, &tenant_id.project_id, &tenant_id.user_id)
+            .await
+        {
+            Ok(user_ids) => Ok(Response::new(FetchConversationPartnersResponse {
+                success: true,
+                error_message: String::new(),
+                user_ids,
+            })),
+            Err(e) => Ok(Response::new(FetchConversationPartnersResponse {
+                success: false,
+                error_message: e.to_string(),
+                user_ids: Vec::new(),
+            })),
+        }
+    }
+
+    async fn update_read_cursor(
+        &self,
+        request: Request<UpdateReadCursorRequest>,
+    ) -> Result<Response<UpdateReadCursorResponse>, Status> {
+        let req = request.into_inner();
+
+        if req.project_id.is_empty() || req.conversation_id.is_empty() || req.user_id.is_empty() {
+            return Ok(Response::new(UpdateReadCursorResponse {
+                success: false,
+                error_message: "project_id, conversation_id and user_id are required".to_string(),
+                advanced: false,
             }));
         }
 
-        // 2. Parse UUID
         let message_id = match Uuid::parse_str(&req.message_id) {
             Ok(uuid) => uuid,
             Err(_) => {
-                return Ok(Response::new(WriteDmResponse {
+                return Ok(Response::new(UpdateReadCursorResponse {
                     success: false,
                     error_message: "Invalid message_id UUID".to_string(),
+                    advanced: false,
                 }));
             }
         };
 
-        // 3. Construct the internal struct
-        let message = crate::queries::DirectMessage {
-            project_id: req.project_id,
-            conversation_id: req.conversation_id,
+        match update_read_cursor(
+            &self.session,
+            &req.project_id,
+            &req.conversation_id,
+            &req.user_id,
             message_id,
-            sender_id: req.sender_id,
-            recipient_id: req.receiver_id, // Map receiver_id (proto) to recipient_id (db)
-            message_text: req.message,
-            created_at: CqlTimestamp(req.timestamp),
+            CqlTimestamp(req.timestamp),
+        )
+        .await
+        {
+            Ok(advanced) => Ok(Response::new(UpdateReadCursorResponse {
+                success: true,
+                error_message: String::new(),
+                advanced,
+            })),
+            Err(e) => Ok(Response::new(UpdateReadCursorResponse {
+                success: false,
+                error_message: e.to_string(),
+                advanced: false,
+            })),
+        }
+    }
+
+    async fn get_read_cursors(
+        &self,
+        request: Request<GetReadCursorsRequest>,
+    ) -> Result<Response<GetReadCursorsResponse>, Status> {
+        let req = request.into_inner();
+
+        if req.project_id.is_empty() || req.conversation_id.is_empty() {
+            return Ok(Response::new(GetReadCursorsResponse {
+                success: false,
+                error_message: "project_id and conversation_id are required".to_string(),
+                cursors: Vec::new(),
+            }));
+        }
+
+        match fetch_read_cursors(&self.session, &req.project_id, &req.conversation_id).await {
+            Ok(rows) => Ok(Response::new(GetReadCursorsResponse {
+                success: true,
+                error_message: String::new(),
+                cursors: rows
+                    .into_iter()
+                    .map(|(user_id, message_id, updated_at)| ReadCursor {
+                        user_id,
+                        message_id: message_id.to_string(),
+                        updated_at: updated_at.0,
+                    })
+                    .collect(),
+            })),
+            Err(e) => Ok(Response::new(GetReadCursorsResponse {
+                success: false,
+                error_message: e.to_string(),
+                cursors: Vec::new(),
+            })),
+        }
+    }
+
+    async fn add_room_member(
+        &self,
+        request: Request<RoomMemberRequest>,
+    ) -> Result<Response<RoomMemberResponse>, Status> {
+        let req = request.into_inner();
+
+        if req.project_id.is_empty() || req.room_id.is_empty() || req.user_id.is_empty() {
+            return Ok(Response::new(RoomMemberResponse {
+                success: false,
+                error_message: "project_id, room_id and user_id are required".to_string(),
+            }));
+        }
+
+        let joined_at = CqlTimestamp(chrono::Utc::now().timestamp_millis());
+        match add_room_member(
+            &self.session,
+            &req.project_id,
+            &req.room_id,
+            &req.user_id,
+            joined_at,
+        )
+        .await
+        {
+            Ok(()) => Ok(Response::new(RoomMemberResponse {
+                success: true,
+                error_message: String::new(),
+            })),
+            Err(e) => Ok(Response::new(RoomMemberResponse {
+                success: false,
+                error_message: e.to_string(),
+            })),
+        }
+    }
+
+    async fn remove_room_member(
+        &self,
+        request: Request<RoomMemberRequest>,
+    ) -> Result<Response<RoomMemberResponse>, Status> {
+        let req = request.into_inner();
+
+        if req.project_id.is_empty() || req.room_id.is_empty() || req.user_id.is_empty() {
+            return Ok(Response::new(RoomMemberResponse {
+                success: false,
+                error_message: "project_id, room_id and user_id are required".to_string(),
+            }));
+        }
+
+        match remove_room_member(&self.session, &req.project_id, &req.room_id, &req.user_id).await {
+            Ok(()) => Ok(Response::new(RoomMemberResponse {
+                success: true,
+                error_message: String::new(),
+            })),
+            Err(e) => Ok(Response::new(RoomMemberResponse {
+                success: false,
+                error_message: e.to_string(),
+            })),
+        }
+    }
+
+    async fn fetch_user_rooms(
+        &self,
+        request: Request<FetchUserRoomsRequest>,
+    ) -> Result<Response<FetchUserRoomsResponse>, Status> {
+        let req = request.into_inner();
+
+        let tenant_id = match req.tenant_user_id {
+            Some(id) if !id.project_id.is_empty() && !id.user_id.is_empty() => id,
+            _ => {
+                return Ok(Response::new(FetchUserRoomsResponse {
+                    success: false,
+                    error_message: "project_id and user_id are required".to_string(),
+                    room_ids: Vec::new(),
+                }));
+            }
+        };
+
+        match fetch_member_rooms(&self.session, &tenant_id.project_id, &tenant_id.user_id).await {
+            Ok(room_ids) => Ok(Response::new(FetchUserRoomsResponse {
+                success: true,
+                error_message: String::new(),
+                room_ids,
+            })),
+            Err(e) => Ok(Response::new(FetchUserRoomsResponse {
+                success: false,
+                error_message: e.to_string(),
+                room_ids: Vec::new(),
+            })),
+        }
+    }
+
+    async fn fetch_pending_deliveries(
+        &self,
+        request: Request<FetchPendingDeliveriesRequest>,
+    ) -> Result<Response<FetchPendingDeliveriesResponse>, Status> {
+        const DEFAULT_LIMIT: i32 = 100;
+        const MAX_LIMIT: i32 = 1000;
+
+        let req = request.into_inner();
+
+        let tenant_id = match req.tenant_user_id {
+            Some(id) if !id.project_id.is_empty() && !id.user_id.is_empty() => id,
+            _ => {
+                return Ok(Response::new(FetchPendingDeliveriesResponse {
+                    success: false,
+                    error_message: "project_id and user_id are required".to_string(),
+                    deliveries: Vec::new(),
+                    has_more: false,
+                }));
+            }
+        };
+
+        let limit = if req.limit > 0 {
+            req.limit.min(MAX_LIMIT)
+        } else {
+            DEFAULT_LIMIT
+        };
+
+        let after_message_id = if req.after_message_id.is_empty() {
+            None
+        } else {
+            match Uuid::parse_str(&req.after_message_id) {
+                Ok(uuid) => Some(uuid),
+                Err(_) => {
+                    return Ok(Response::new(FetchPendingDeliveriesResponse {
+                        success: false,
+                        error_message: "Invalid after_message_id UUID".to_string(),
+                        deliveries: Vec::new(),
+                        has_more: false,
+                    }));
+                }
+            }
+        };
+
+        match fetch_pending_deliveries(
+            &self.session,
+            &tenant_id.project_id,
+            &tenant_id.user_id,
+            after_message_id,
+            limit,
+        )
+        .await
+        {
+            Ok((deliveries, has_more)) => Ok(Response::new(FetchPendingDeliveriesResponse {
+                success: true,
+                error_message: String::new(),
+                deliveries: deliveries
+                    .into_iter()
+                    .map(|delivery| PendingDelivery {
+                        message_id: delivery.message_id.to_string(),
+                        kind: delivery.kind,
+                        target_id: delivery.target_id,
+                        sender_id: delivery.sender_id,
+                        content: delivery.content,
+                        created_at: delivery.created_at.0,
+                    })
+                    .collect(),
+                has_more,
+            })),
+            Err(e) => Ok(Response::new(FetchPendingDeliveriesResponse {
+                success: false,
+                error_message: e.to_string(),
+                deliveries: Vec::new(),
+                has_more: false,
+            })),
+        }
+    }
+
+    async fn ack_deliveries(
+        &self,
+        request: Request<AckDeliveriesRequest>,
+    ) -> Result<Response<AckDeliveriesResponse>, Status> {
+        let req = request.into_inner();
+
+        let tenant_id = match req.tenant_user_id {
+            Some(id) if !id.project_id.is_empty() && !id.user_id.is_empty() => id,
+            _ => {
+                return Ok(Response::new(AckDeliveriesResponse {
+                    success: false,
+                    error_message: "project_id and user_id are required".to_string(),
+                }));
+            }
         };
 
-        // 4. Execute Batch Write
-        match write_direct_message(&self.session, message).await {
-            Ok(_) => Ok(Response::new(WriteDmResponse {
+        let message_ids: Result<Vec<Uuid>, _> = req
+            .message_ids
+            .iter()
+            .map(|id| Uuid::parse_str(id))
+            .collect();
+        let message_ids = match message_ids {
+            Ok(ids) => ids,
+            Err(_) => {
+                return Ok(Response::new(AckDeliveriesResponse {
+                    success: false,
+                    error_message: "Invalid message_id UUID".to_string(),
+                }));
+            }
+        };
+
+        if message_ids.is_empty() {
+            return Ok(Response::new(AckDeliveriesResponse {
+                success: true,
+                error_message: String::new(),
+            }));
+        }
+
+        match ack_deliveries(
+            &self.session,
+            &tenant_id.project_id,
+            &tenant_id.user_id,
+            &message_ids,
+        )
+        .await
+        {
+            Ok(()) => Ok(Response::new(AckDeliveriesResponse {
                 success: true,
                 error_message: String::new(),
             })),
-            Err(e) => Ok(Response::new(WriteDmResponse {
+            Err(e) => Ok(Response::new(AckDeliveriesResponse {
                 success: false,
                 error_message: e.to_string(),
             })),
         }
     }
 
+    async fn edit_message(
+        &self,
+        request: Request<EditMessageRequest>,
+    ) -> Result<Response<MessageChangeResponse>, Status> {
+        let req = request.into_inner();
+
+        let change = match message_change(
+            req.project_id,
+            &req.kind,
+            req.target_id,
+            &req.message_id,
+            req.user_id,
+            req.timestamp,
+            req.window_ms,
+        ) {
+            Ok(change) => change,
+            Err(e) => return Ok(Response::new(change_failed(e))),
+        };
+
+        let result = edit_message(&self.session, &change, &req.content).await;
+        Ok(Response::new(change_response(result)))
+    }
+
+    async fn delete_message(
+        &self,
+        request: Request<DeleteMessageRequest>,
+    ) -> Result<Response<MessageChangeResponse>, Status> {
+        let req = request.into_inner();
+
+        let change = match message_change(
+            req.project_id,
+            &req.kind,
+            req.target_id,
+            &req.message_id,
+            req.user_id,
+            req.timestamp,
+            req.window_ms,
+        ) {
+            Ok(change) => change,
+            Err(e) => return Ok(Response::new(change_failed(e))),
+        };
+
+        let result = delete_message(&self.session, &change).await;
+        Ok(Response::new(change_response(result)))
+    }
+
+    async fn add_reaction(
+        &self,
+        request: Request<ReactionRequest>,
+    ) -> Result<Response<ReactionResponse>, Status> {
+        let change = match reaction_change(request.into_inner()) {
+            Ok(change) => change,
+            Err(e) => return Ok(Response::new(reaction_failed(e))),
+        };
+
+        let result = add_reaction(&self.session, &change).await;
+        Ok(Response::new(reaction_response(result)))
+    }
+
+    async fn remove_reaction(
+        &self,
+        request: Request<ReactionRequest>,
+    ) -> Result<Response<ReactionResponse>, Status> {
+        let change = match reaction_change(request.into_inner()) {
+            Ok(change) => change,
+            Err(e) => return Ok(Response::new(reaction_failed(e))),
+        };
+
+        let result = remove_reaction(&self.session, &change).await.map(Ok);
+        Ok(Response::new(reaction_response(result)))
+    }
+
+    async fn write_dm(
+        &self,
+        request: Request<WriteDmRequest>,
+    ) -> Result<Response<WriteDmResponse>, Status> {
+        let mut results = persist_dms(&self.session, vec![request.into_inner()]).await;
+        Ok(Response::new(results.remove(0)))
+    }
+
+    async fn write_dm_batch(
+        &self,
+        request: Request<WriteDmBatchRequest>,
+    ) -> Result<Response<WriteDmBatchResponse>, Status> {
+        // conversations are written concurrently, each with a batch per
+        // partition it touches
+        let messages = request.into_inner().messages;
+        let count = messages.len();
+        let writes: Vec<_> = group_in_order(messages, |req| {
+            (req.project_id.clone(), req.conversation_id.clone())
+        })
+        .into_iter()
+        .map(|(indices, conversation)| {
+            let session = self.session.clone();
+            let write = tokio::spawn(async move { persist_dms(&session, conversation).await });
+            (indices, write)
+        })
+        .collect();
+
+        let mut results = vec![WriteDmResponse::default(); count];
+        for (indices, write) in writes {
+            match write.await {
+                Ok(written) => {
+                    for (index, result) in indices.into_iter().zip(written) {
+                        results[index] = result;
+                    }
+                }
+                Err(e) => {
+                    for index in indices {
+                        results[index].error_message = e.to_string();
+                    }
+                }
+            }
+        }
+
+        Ok(Response::new(WriteDmBatchResponse { results }))
+    }
+
     // async fn write_dm(
     //     &self,
     //     request: Request<WriteDmRequest>,
@@ -357,6 +862,9 @@ impl ChatService for ChatServiceImpl {
                         recipient_id: m.recipient_id,
                         message_text: m.message_text,
                         created_at: m.created_at.0,
+                        edited_at: m.edited_at.map_or(0, |at| at.0),
+                        deleted_at: m.deleted_at.map_or(0, |at| at.0),
+                        reactions: reactions(m.reactions),
                     })
                     .collect();
 
@@ -385,48 +893,45 @@ impl ChatService for ChatServiceImpl {
         &self,
         request: Request<WriteRoomMessageRequest>,
     ) -> Result<Response<WriteRoomMessageResponse>, Status> {
-        let req = request.into_inner();
+        let mut results = persist_room_messages(&self.session, vec![request.into_inner()]).await;
+        Ok(Response::new(results.remove(0)))
+    }
 
-        // 1. Validate Inputs
-        if req.project_id.is_empty() || req.room_id.is_empty() {
-            return Ok(Response::new(WriteRoomMessageResponse {
-                success: false,
-                error_message: "project_id and room_id are required".to_string(),
-            }));
-        }
+    async fn write_room_message_batch(
+        &self,
+        request: Request<WriteRoomMessageBatchRequest>,
+    ) -> Result<Response<WriteRoomMessageBatchResponse>, Status> {
+        // rooms are written concurrently, like conversations above
+        let messages = request.into_inner().messages;
+        let count = messages.len();
+        let writes: Vec<_> = group_in_order(messages, |req| {
+            (req.project_id.clone(), req.room_id.clone())
+        })
+        .into_iter()
+        .map(|(indices, room)| {
+            let session = self.session.clone();
+            let write = tokio::spawn(async move { persist_room_messages(&session, room).await });
+            (indices, write)
+        })
+        .collect();
 
-        // 2. Parse UUID
-        let message_id = match Uuid::parse_str(&req.message_id) {
-            Ok(uuid) => uuid,
-            Err(_) => {
-                return Ok(Response::new(WriteRoomMessageResponse {
-                    success: false,
-                    error_message: "Invalid message_id UUID".to_string(),
-                }));
+        let mut results = vec![WriteRoomMessageResponse::default(); count];
+        for (indices, write) in writes {
+            match write.await {
+                Ok(written) => {
+                    for (index, result) in indices.into_iter().zip(written) {
+                        results[index] = result;
+                    }
+                }
+                Err(e) => {
+                    for index in indices {
+                        results[index].error_message = e.to_string();
+                    }
+                }
             }
-        };
-
-        // 3. Construct DB Struct
-        let message = DbRoomMessageEx {
-            project_id: req.project_id,
-            room_id: req.room_id,
-            message_id,
-            sender_id: req.sender_id,
-            content: req.content,
-            created_at: CqlTimestamp(req.timestamp),
-        };
-
-        // 4. Execute Batch
-        match write_room_message(&self.session, message).await {
-            Ok(_) => Ok(Response::new(WriteRoomMessageResponse {
-                success: true,
-                error_message: String::new(),
-            })),
-            Err(e) => Ok(Response::new(WriteRoomMessageResponse {
-                success: false,
-                error_message: e.to_string(),
-            })),
         }
+
+        Ok(Response::new(WriteRoomMessageBatchResponse { results }))
     }
 
     async fn get_paginated_room_messages(
@@ -477,6 +982,9 @@ impl ChatService for ChatServiceImpl {
                         sender_id: m.sender_id,
                         content: m.content,
                         created_at: m.created_at.0,
+                        edited_at: m.edited_at.map_or(0, |at| at.0),
+                        deleted_at: m.deleted_at.map_or(0, |at| at.0),
+                        reactions: reactions(m.reactions),
                     })
                     .collect();
 
@@ -548,6 +1056,9 @@ impl ChatService for ChatServiceImpl {
                         recipient_id: m.recipient_id,
                         message_text: m.message_text,
                         created_at: m.created_at.0,
+                        edited_at: m.edited_at.map_or(0, |at| at.0),
+                        deleted_at: m.deleted_at.map_or(0, |at| at.0),
+                        reactions: reactions(m.reactions),
                     })
                     .collect();
 
@@ -569,3 +1080,248 @@ impl ChatService for ChatServiceImpl {
         }
     }
 }
+
+// Splits `items` by key, keeping their order and their index in `items`
+fn group_in_order<T, K: Hash + Eq>(
+    items: Vec<T>,
+    key: impl Fn(&T) -> K,
+) -> Vec<(Vec<usize>, Vec<T>)> {
+    let mut groups: Vec<(Vec<usize>, Vec<T>)> = Vec::new();
+    let mut positions = HashMap::new();
+    for (index, item) in items.into_iter().enumerate() {
+        let position = *positions.entry(key(&item)).or_insert_with(|| {
+            groups.push((Vec::new(), Vec::new()));
+            groups.len() - 1
+        });
+        groups[position].0.push(index);
+        groups[position].1.push(item);
+    }
+    groups
+}
+
+// Checks what EditMessage and DeleteMessage have in common
+fn message_change(
+    project_id: String,
+    kind: &str,
+    target_id: String,
+    message_id: &str,
+    user_id: String,
+    timestamp: i64,
+    window_ms: i64,
+) -> Result<MessageChange, String> {
+    if project_id.is_empty() || target_id.is_empty() || user_id.is_empty() {
+        return Err("project_id, target_id and user_id are required".to_string());
+    }
+    let kind = MessageKind::parse(kind).ok_or("kind has to be \"dm\" or \"room\"")?;
+    let message_id = Uuid::parse_str(message_id).map_err(|_| "Invalid message_id UUID")?;
+
+    Ok(MessageChange {
+        project_id,
+        kind,
+        target_id,
+        message_id,
+        user_id,
+        changed_at: CqlTimestamp(timestamp),
+        window_ms: (window_ms > 0).then_some(window_ms),
+    })
+}
+
+fn change_failed(error_message: String) -> MessageChangeResponse {
+    MessageChangeResponse {
+        success: false,
+        error_message,
+        rejection: MessageChangeRejection::NotRejected as i32,
+    }
+}
+
+fn change_response(
+    result: Result<Result<(), ChangeRejection>, Box<dyn std::error::Error + Send + Sync>>,
+) -> MessageChangeResponse {
+    match result {
+        Ok(Ok(())) => MessageChangeResponse {
+            success: true,
+            error_message: String::new(),
+            rejection: MessageChangeRejection::NotRejected as i32,
+        },
+        Ok(Err(rejection)) => {
+            let rejection = match rejection {
+                ChangeRejection::NotFound => MessageChangeRejection::NotFound,
+                ChangeRejection::NotSender => MessageChangeRejection::NotSender,
+                ChangeRejection::WindowExpired => MessageChangeRejection::WindowExpired,
+                ChangeRejection::Deleted => MessageChangeRejection::Deleted,
+            };
+            MessageChangeResponse {
+                success: false,
+                error_message: String::new(),
+                rejection: rejection as i32,
+            }
+        }
+        Err(e) => change_failed(e.to_string()),
+    }
+}
+
+fn reaction_change(req: ReactionRequest) -> Result<ReactionChange, String> {
+    if req.project_id.is_empty() || req.target_id.is_empty() || req.user_id.is_empty() {
+        return Err("project_id, target_id and user_id are required".to_string());
+    }
+    if req.reaction.is_empty() {
+        return Err("reaction is required".to_string());
+    }
+    let kind = MessageKind::parse(&req.kind).ok_or("kind has to be \"dm\" or \"room\"")?;
+    let message_id = Uuid::parse_str(&req.message_id).map_err(|_| "Invalid message_id UUID")?;
+
+    Ok(ReactionChange {
+        project_id: req.project_id,
+        kind,
+        target_id: req.target_id,
+        message_id,
+        user_id: req.user_id,
+        reaction: req.reaction,
+        reacted_at: CqlTimestamp(req.timestamp),
+        max_reactions: (req.max_reactions > 0).then_some(req.max_reactions as usize),
+    })
+}
+
+fn reactions(counts: Vec<ReactionCount>) -> Vec<Reaction> {
+    counts
+        .into_iter()
+        .map(|c| Reaction {
+            reaction: c.reaction,
+            count: c.user_ids.len() as i32,
+            user_ids: c.user_ids,
+        })
+        .collect()
+}
+
+fn reaction_failed(error_message: String) -> ReactionResponse {
+    ReactionResponse {
+        success: false,
+        error_message,
+        rejection: ProtoReactionRejection::NotRejected as i32,
+        reactions: Vec::new(),
+    }
+}
+
+fn reaction_response(
+    result: Result<
+        Result<Vec<ReactionCount>, ReactionRejection>,
+        Box<dyn std::error::Error + Send + Sync>,
+    >,
+) -> ReactionResponse {
+    match result {
+        Ok(Ok(counts)) => ReactionResponse {
+            success: true,
+            error_message: String::new(),
+            rejection: ProtoReactionRejection::NotRejected as i32,
+            reactions: reactions(counts),
+        },
+        Ok(Err(rejection)) => {
+            let rejection = match rejection {
+                ReactionRejection::NotFound => ProtoReactionRejection::NotFound,
+                ReactionRejection::LimitReached => ProtoReactionRejection::LimitReached,
+            };
+            ReactionResponse {
+                success: false,
+                error_message: String::new(),
+                rejection: rejection as i32,
+                reactions: Vec::new(),
+            }
+        }
+        Err(e) => reaction_failed(e.to_string()),
+    }
+}
+
+fn direct_message(req: WriteDmRequest) -> Result<crate::queries::DirectMessage, String> {
+    if req.project_id.is_empty() || req.conversation_id.is_empty() {
+        return Err("project_id and conversation_id are required".to_string());
+    }
+    let message_id = Uuid::parse_str(&req.message_id).map_err(|_| "Invalid message_id UUID")?;
+
+    Ok(crate::queries::DirectMessage {
+        project_id: req.project_id,
+        conversation_id: req.conversation_id,
+        message_id,
+        sender_id: req.sender_id,
+        recipient_id: req.receiver_id, // Map receiver_id (proto) to recipient_id (db)
+        message_text: req.message,
+        created_at: CqlTimestamp(req.timestamp),
+    })
+}
+
+fn room_message(req: WriteRoomMessageRequest) -> Result<DbRoomMessageEx, String> {
+    if req.project_id.is_empty() || req.room_id.is_empty() {
+        return Err("project_id and room_id are required".to_string());
+    }
+    let message_id = Uuid::parse_str(&req.message_id).map_err(|_| "Invalid message_id UUID")?;
+
+    Ok(DbRoomMessageEx {
+        project_id: req.project_id,
+        room_id: req.room_id,
+        message_id,
+        sender_id: req.sender_id,
+        content: req.content,
+        created_at: CqlTimestamp(req.timestamp),
+    })
+}
+
+// Writes the messages of one conversation together, the valid ones all
+// succeed or all fail
+async fn persist_dms(session: &Arc<Session>, reqs: Vec<WriteDmRequest>) -> Vec<WriteDmResponse> {
+    let (results, messages) = validated(reqs, direct_message);
+    let written = write_direct_messages(session, messages)
+        .await
+        .map_err(|e| e.to_string());
+    results
+        .into_iter()
+        .map(|result| write_result(result.and(written.clone())))
+        .map(|(success, error_message)| WriteDmResponse {
+            success,
+            error_message,
+        })
+        .collect()
+}
+
+// As persist_dms, for the messages of one room
+async fn persist_room_messages(
+    session: &Arc<Session>,
+    reqs: Vec<WriteRoomMessageRequest>,
+) -> Vec<WriteRoomMessageResponse> {
+    let (results, messages) = validated(reqs, room_message);
+    let written = write_room_messages(session, messages)
+        .await
+        .map_err(|e| e.to_string());
+    results
+        .into_iter()
+        .map(|result| write_result(result.and(written.clone())))
+        .map(|(success, error_message)| WriteRoomMessageResponse {
+            success,
+            error_message,
+        })
+        .collect()
+}
+
+// The messages that passed `parse`, and per request whether it did
+fn validated<R, M>(
+    reqs: Vec<R>,
+    parse: impl Fn(R) -> Result<M, String>,
+) -> (Vec<Result<(), String>>, Vec<M>) {
+    let mut results = Vec::with_capacity(reqs.len());
+    let mut messages = Vec::with_capacity(reqs.len());
+    for req in reqs {
+        match parse(req) {
+            Ok(message) => {
+                messages.push(message);
+                results.push(Ok(()));
+            }
+            Err(e) => results.push(Err(e)),
+        }
+    }
+    (results, messages)
+}
+
+fn write_result(result: Result<(), String>) -> (bool, String) {
+    match result {
+        Ok(()) => (true, String::new()),
+        Err(e) => (false, e),
+    }
+}
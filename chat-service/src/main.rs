@@ -12,6 +12,7 @@ use scylla::statement::prepared::PreparedStatement;
 use std::env;
 use std::error::Error;
 use std::sync::Arc;
+use std::time::Duration;
 use tonic::transport::Server;
 use tonic_health::server::health_reporter;
 mod chat_services;
@@ -26,6 +27,12 @@ pub mod chat_service {
 mod migrations;
 mod utils;
 
+// How long peroxo's health checks get to take a stopping instance out of
+// rotation before it stops taking calls
+const HEALTH_GRACE: Duration = Duration::from_secs(5);
+// Calls still running this long after that are dropped
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(20);
+
 pub struct Queries {
     pub q_fetch_after_message_id: PreparedStatement,
 }
@@ -34,7 +41,7 @@ pub async fn prepare_queries(
     session: &Session,
 ) -> Result<Arc<Queries>, Box<dyn std::error::Error>> {
     let query_text = r#"
-        SELECT conversation_id, message_id, sender_id, recipient_id, message_text, created_at 
+        SELECT conversation_id, message_id, sender_id, recipient_id, message_text, created_at, edited_at, deleted_at 
         FROM affinity.direct_messages 
         WHERE project_id = ? AND conversation_id = ? AND message_id > ?
     "#;
@@ -109,12 +116,51 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     println!("ChatService gRPC server listening on {}", addr);
 
+    let (draining_sender, draining) = tokio::sync::oneshot::channel();
+    let shutdown = async move {
+        shutdown_signal().await;
+        println!("Shutting down, reporting not serving");
+        health_reporter
+            .set_not_serving::<ChatServiceServer<ChatServiceImpl>>()
+            .await;
+        tokio::time::sleep(HEALTH_GRACE).await;
+        let _ = draining_sender.send(());
+    };
+
     // Start the gRPC server
-    Server::builder()
+    let server = Server::builder()
         .add_service(health_service)
         .add_service(service)
-        .serve(addr)
-        .await?;
+        .serve_with_shutdown(addr, shutdown);
+    tokio::pin!(server);
+
+    // once draining no new calls are taken, the ones in flight get until the
+    // drain timeout
+    tokio::select! {
+        result = &mut server => result?,
+        Ok(()) = draining => {
+            match tokio::time::timeout(DRAIN_TIMEOUT, &mut server).await {
+                Ok(result) => result?,
+                Err(_) => eprintln!("Calls still running after {:?}, exiting", DRAIN_TIMEOUT),
+            }
+        }
+    }
 
     Ok(())
 }
+
+// The orchestrator stops the pod with SIGTERM, Ctrl-C is for local runs
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    if let Ok(mut terminate) =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+    {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        return;
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+}
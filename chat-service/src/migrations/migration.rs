@@ -71,8 +71,16 @@ impl DatabaseMigrations {
         self.create_direct_messages_table().await?;
         self.create_user_conversations_table().await?;
         self.create_dm_lookup_table().await?;
+        self.create_dm_lookup_conversation_index().await?;
         self.create_room_messages_table().await?;
         self.create_project_rooms_table().await?;
+        self.create_read_cursors_table().await?;
+        self.create_room_members_table().await?;
+        self.create_member_rooms_table().await?;
+        self.create_pending_deliveries_table().await?;
+        self.add_message_change_columns().await?;
+        self.create_message_edits_table().await?;
+        self.create_message_reactions_table().await?;
 
         Ok(())
     }
@@ -105,6 +113,8 @@ impl DatabaseMigrations {
                 recipient_id text,
                 message_text text,
                 created_at timestamp,
+                edited_at timestamp,
+                deleted_at timestamp,
                 PRIMARY KEY ((project_id, conversation_id), message_id)
             ) WITH CLUSTERING ORDER BY (message_id ASC)
         "#;
@@ -155,6 +165,20 @@ impl DatabaseMigrations {
         Ok(())
     }
 
+    async fn create_dm_lookup_conversation_index(&self) -> Result<(), Box<dyn Error>> {
+        // Lets conversation membership be resolved from a conversation_id alone
+        let query = r#"
+            CREATE INDEX IF NOT EXISTS dm_lookup_conversation_id_idx
+            ON dm_lookup (conversation_id)
+        "#;
+
+        println!("Creating index 'dm_lookup_conversation_id_idx'...");
+        let prepared = self.session.prepare(query).await?;
+        self.session.execute_unpaged(&prepared, &[]).await?;
+        println!("Index 'dm_lookup_conversation_id_idx' created successfully");
+        Ok(())
+    }
+
     async fn create_room_messages_table(&self) -> Result<(), Box<dyn Error>> {
         // Partition key: (project_id, room_id)
         // Clustering key: message_id (timeuuid)
@@ -166,6 +190,8 @@ impl DatabaseMigrations {
                 sender_id text,
                 content text,
                 created_at timestamp,
+                edited_at timestamp,
+                deleted_at timestamp,
                 PRIMARY KEY ((project_id, room_id), message_id)
             ) WITH CLUSTERING ORDER BY (message_id ASC)
         "#;
@@ -195,6 +221,182 @@ impl DatabaseMigrations {
         println!("Table 'project_rooms' created successfully");
         Ok(())
     }
+
+    async fn create_read_cursors_table(&self) -> Result<(), Box<dyn Error>> {
+        // Partition key: (project_id, conversation_id)
+        // Clustering key: user_id
+        let query = r#"
+            CREATE TABLE IF NOT EXISTS read_cursors (
+                project_id text,
+                conversation_id text,
+                user_id text,
+                message_id timeuuid,
+                updated_at timestamp,
+                PRIMARY KEY ((project_id, conversation_id), user_id)
+            )
+        "#;
+
+        println!("Creating table 'read_cursors'...");
+        let prepared = self.session.prepare(query).await?;
+        self.session.execute_unpaged(&prepared, &[]).await?;
+        println!("Table 'read_cursors' created successfully");
+        Ok(())
+    }
+
+    async fn create_room_members_table(&self) -> Result<(), Box<dyn Error>> {
+        // Partition key: (project_id, room_id)
+        // Clustering key: user_id
+        let query = r#"
+            CREATE TABLE IF NOT EXISTS room_members (
+                project_id text,
+                room_id text,
+                user_id text,
+                joined_at timestamp,
+                PRIMARY KEY ((project_id, room_id), user_id)
+            )
+        "#;
+
+        println!("Creating table 'room_members'...");
+        let prepared = self.session.prepare(query).await?;
+        self.session.execute_unpaged(&prepared, &[]).await?;
+        println!("Table 'room_members' created successfully");
+        Ok(())
+    }
+
+    async fn create_member_rooms_table(&self) -> Result<(), Box<dyn Error>> {
+        // Partition key: (project_id, user_id)
+        // Clustering key: room_id
+        let query = r#"
+            CREATE TABLE IF NOT EXISTS member_rooms (
+                project_id text,
+                user_id text,
+                room_id text,
+                joined_at timestamp,
+                PRIMARY KEY ((project_id, user_id), room_id)
+            )
+        "#;
+
+        println!("Creating table 'member_rooms'...");
+        let prepared = self.session.prepare(query).await?;
+        self.session.execute_unpaged(&prepared, &[]).await?;
+        println!("Table 'member_rooms' created successfully");
+        Ok(())
+    }
+
+    async fn create_pending_deliveries_table(&self) -> Result<(), Box<dyn Error>> {
+        // Partition key: (project_id, user_id)
+        // Clustering key: message_id (timeuuid)
+        // Rows are deleted once the client acks, the TTL (30 days) only
+        // bounds what an abandoned account can accumulate.
+        let query = r#"
+            CREATE TABLE IF NOT EXISTS pending_deliveries (
+                project_id text,
+                user_id text,
+                message_id timeuuid,
+                kind text,
+                target_id text,
+                sender_id text,
+                content text,
+                created_at timestamp,
+                PRIMARY KEY ((project_id, user_id), message_id)
+            ) WITH CLUSTERING ORDER BY (message_id ASC)
+            AND default_time_to_live = 2592000
+        "#;
+
+        println!("Creating table 'pending_deliveries'...");
+        let prepared = self.session.prepare(query).await?;
+        self.session.execute_unpaged(&prepared, &[]).await?;
+        println!("Table 'pending_deliveries' created successfully");
+        Ok(())
+    }
+
+    // Tables created before messages could be edited or deleted
+    async fn add_message_change_columns(&self) -> Result<(), Box<dyn Error>> {
+        for table in ["direct_messages", "room_messages"] {
+            for column in ["edited_at", "deleted_at"] {
+                self.add_column(table, column, "timestamp").await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn add_column(
+        &self,
+        table: &str,
+        column: &str,
+        column_type: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        // ALTER TABLE has no IF NOT EXISTS
+        let query = r#"
+            SELECT column_name FROM system_schema.columns
+            WHERE keyspace_name = 'affinity' AND table_name = ? AND column_name = ?
+        "#;
+        let exists = self
+            .session
+            .query_unpaged(query, (table, column))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<(String,)>()?
+            .is_some();
+        if exists {
+            return Ok(());
+        }
+
+        println!("Adding column '{}' to table '{}'...", column, table);
+        let query = format!("ALTER TABLE {} ADD {} {}", table, column, column_type);
+        self.session.query_unpaged(query, &[]).await?;
+        println!("Column '{}' added to table '{}'", column, table);
+        Ok(())
+    }
+
+    async fn create_message_edits_table(&self) -> Result<(), Box<dyn Error>> {
+        // Partition key: (project_id, message_id)
+        // Clustering key: edited_at
+        // The text each edit replaced, removed when the message is deleted
+        let query = r#"
+            CREATE TABLE IF NOT EXISTS message_edits (
+                project_id text,
+                message_id timeuuid,
+                edited_at timestamp,
+                kind text,
+                target_id text,
+                editor_id text,
+                previous_text text,
+                PRIMARY KEY ((project_id, message_id), edited_at)
+            ) WITH CLUSTERING ORDER BY (edited_at ASC)
+        "#;
+
+        println!("Creating table 'message_edits'...");
+        let prepared = self.session.prepare(query).await?;
+        self.session.execute_unpaged(&prepared, &[]).await?;
+        println!("Table 'message_edits' created successfully");
+        Ok(())
+    }
+
+    async fn create_message_reactions_table(&self) -> Result<(), Box<dyn Error>> {
+        // Partition key: (project_id, kind, target_id)
+        // Clustering key: message_id, reaction, user_id
+        // One partition per conversation or room, so a page of history gets
+        // its reactions with one query
+        let query = r#"
+            CREATE TABLE IF NOT EXISTS message_reactions (
+                project_id text,
+                kind text,
+                target_id text,
+                message_id timeuuid,
+                reaction text,
+                user_id text,
+                reacted_at timestamp,
+                PRIMARY KEY ((project_id, kind, target_id), message_id, reaction, user_id)
+            )
+        "#;
+
+        println!("Creating table 'message_reactions'...");
+        let prepared = self.session.prepare(query).await?;
+        self.session.execute_unpaged(&prepared, &[]).await?;
+        println!("Table 'message_reactions' created successfully");
+        Ok(())
+    }
 }
 
 pub async fn run_database_migrations(node: &str) -> Result<Arc<Session>, Box<dyn Error>> {
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+use std::pin::Pin;
 use std::sync::Arc;
 
 use scylla::{
@@ -8,11 +10,12 @@ use scylla::{
     },
     value::{CqlTimestamp, CqlTimeuuid},
 };
+use tokio::task::JoinSet;
 use uuid::Uuid;
 
 use crate::{
     Queries,
-    utils::{DbMessage, DbRoomMessage, DbRoomMessageEx},
+    utils::{DbMessage, DbRoomMessage, DbRoomMessageEx, ReactionCount},
 };
 
 pub struct DirectMessage {
@@ -72,6 +75,29 @@ pub async fn fetch_conversation_history(
     Ok(messages)
 }
 
+// conversation_id, message_id, sender_id, recipient_id, message_text,
+// created_at, edited_at, deleted_at
+type DbMessageRow = (
+    String,
+    Uuid,
+    String,
+    String,
+    String,
+    CqlTimestamp,
+    Option<CqlTimestamp>,
+    Option<CqlTimestamp>,
+);
+
+// A delete racing an edit of the same message can leave the edit's text on
+// the tombstone, it is never handed out
+fn tombstoned(text: String, deleted_at: Option<CqlTimestamp>) -> String {
+    if deleted_at.is_some() {
+        String::new()
+    } else {
+        text
+    }
+}
+
 pub async fn fetch_paginated_messages(
     session: &Session,
     project_id: &str,
@@ -81,7 +107,7 @@ pub async fn fetch_paginated_messages(
     // Query 1: Initial fetch (no cursor)
     // Filter by project_id AND conversation_id
     let query_without_cursor = r#"
-        SELECT conversation_id, message_id, sender_id, recipient_id, message_text, created_at 
+        SELECT conversation_id, message_id, sender_id, recipient_id, message_text, created_at, edited_at, deleted_at 
         FROM direct_messages 
         WHERE project_id = ? AND conversation_id = ? 
         ORDER BY message_id DESC 
@@ -91,7 +117,7 @@ pub async fn fetch_paginated_messages(
     // Query 2: Pagination fetch (with cursor)
     // Filter by project_id AND conversation_id AND message_id < cursor
     let query_with_cursor = r#"
-        SELECT conversation_id, message_id, sender_id, recipient_id, message_text, created_at 
+        SELECT conversation_id, message_id, sender_id, recipient_id, message_text, created_at, edited_at, deleted_at 
         FROM direct_messages 
         WHERE project_id = ? AND conversation_id = ? AND message_id < ? 
         ORDER BY message_id DESC 
@@ -113,21 +139,39 @@ pub async fn fetch_paginated_messages(
 
     let rows_result = result.into_rows_result()?;
 
-    let typed_rows = rows_result.rows::<(String, Uuid, String, String, String, CqlTimestamp)>()?;
+    let typed_rows = rows_result.rows::<DbMessageRow>()?;
 
     let mut messages = Vec::new();
     for row_result in typed_rows {
-        let (conv_id, msg_id, sender_id, recipient_id, msg_text, created_at) = row_result?;
+        let (conv_id, msg_id, sender_id, recipient_id, msg_text, created_at, edited_at, deleted_at) =
+            row_result?;
         messages.push(DbMessage {
             conversation_id: conv_id,
             message_id: msg_id,
             sender_id,
             recipient_id,
-            message_text: msg_text,
+            message_text: tombstoned(msg_text, deleted_at),
             created_at,
+            edited_at,
+            deleted_at,
+            reactions: Vec::new(),
         });
     }
 
+    let message_ids: Vec<Uuid> = messages.iter().map(|m| m.message_id).collect();
+    let mut reactions = fetch_reactions(
+        session,
+        project_id,
+        MessageKind::Direct,
+        conversation_id,
+        &message_ids,
+    )
+    .await
+    .map_err(|e| e as Box<dyn std::error::Error>)?;
+    for message in &mut messages {
+        message.reactions = reactions.remove(&message.message_id).unwrap_or_default();
+    }
+
     Ok(messages)
 }
 
@@ -140,7 +184,7 @@ pub async fn fetch_paginated_room_messages(
     // Query 1: Initial fetch (no cursor)
     // Filter by project_id AND room_id
     let query_without_cursor = r#"
-        SELECT room_id, message_id, sender_id, content, created_at 
+        SELECT room_id, message_id, sender_id, content, created_at, edited_at, deleted_at 
         FROM room_messages 
         WHERE project_id = ? AND room_id = ? 
         ORDER BY message_id DESC 
@@ -149,7 +193,7 @@ pub async fn fetch_paginated_room_messages(
 
     // Query 2: Pagination fetch (with cursor)
     let query_with_cursor = r#"
-        SELECT room_id, message_id, sender_id, content, created_at 
+        SELECT room_id, message_id, sender_id, content, created_at, edited_at, deleted_at 
         FROM room_messages 
         WHERE project_id = ? AND room_id = ? AND message_id < ? 
         ORDER BY message_id DESC 
@@ -171,20 +215,45 @@ pub async fn fetch_paginated_room_messages(
 
     let rows_result = result.into_rows_result()?;
 
-    let typed_rows = rows_result.rows::<(String, Uuid, String, String, CqlTimestamp)>()?;
+    let typed_rows = rows_result.rows::<(
+        String,
+        Uuid,
+        String,
+        String,
+        CqlTimestamp,
+        Option<CqlTimestamp>,
+        Option<CqlTimestamp>,
+    )>()?;
 
     let mut messages = Vec::new();
     for row_result in typed_rows {
-        let (rm_id, msg_id, sender_id, content, created_at) = row_result?;
+        let (rm_id, msg_id, sender_id, content, created_at, edited_at, deleted_at) = row_result?;
         messages.push(DbRoomMessage {
             room_id: rm_id,
             message_id: msg_id,
             sender_id,
-            content,
+            content: tombstoned(content, deleted_at),
             created_at,
+            edited_at,
+            deleted_at,
+            reactions: Vec::new(),
         });
     }
 
+    let message_ids: Vec<Uuid> = messages.iter().map(|m| m.message_id).collect();
+    let mut reactions = fetch_reactions(
+        session,
+        project_id,
+        MessageKind::Room,
+        room_id,
+        &message_ids,
+    )
+    .await
+    .map_err(|e| e as Box<dyn std::error::Error>)?;
+    for message in &mut messages {
+        message.reactions = reactions.remove(&message.message_id).unwrap_or_default();
+    }
+
     Ok(messages)
 }
 
@@ -206,125 +275,945 @@ pub async fn fetch_messages_after(
         .await?;
 
     let rows_result = res.into_rows_result()?;
-    let typed_rows = rows_result.rows::<(String, Uuid, String, String, String, CqlTimestamp)>()?;
+    let typed_rows = rows_result.rows::<DbMessageRow>()?;
 
     let mut messages = Vec::new();
     for row_result in typed_rows {
-        let (conv_id, msg_id, sender_id, recipient_id, msg_text, created_at) = row_result?;
+        let (conv_id, msg_id, sender_id, recipient_id, msg_text, created_at, edited_at, deleted_at) =
+            row_result?;
         messages.push(DbMessage {
             conversation_id: conv_id,
             message_id: msg_id,
             sender_id,
             recipient_id,
-            message_text: msg_text,
+            message_text: tombstoned(msg_text, deleted_at),
             created_at,
+            edited_at,
+            deleted_at,
+            reactions: Vec::new(),
         });
     }
+
+    let message_ids: Vec<Uuid> = messages.iter().map(|m| m.message_id).collect();
+    let mut reactions = fetch_reactions(
+        session,
+        project_id,
+        MessageKind::Direct,
+        conversation_id,
+        &message_ids,
+    )
+    .await
+    .map_err(|e| e as Box<dyn std::error::Error>)?;
+    for message in &mut messages {
+        message.reactions = reactions.remove(&message.message_id).unwrap_or_default();
+    }
     Ok(messages)
 }
 
-pub async fn write_direct_message(
+// Partitions are written in parallel, at most this many at once
+const WRITE_CONCURRENCY: usize = 16;
+
+type WriteError = Box<dyn std::error::Error + Send + Sync>;
+type Write = Pin<Box<dyn Future<Output = Result<(), WriteError>> + Send>>;
+
+// Runs `writes` with at most WRITE_CONCURRENCY in flight and fails with the
+// first error, the writes still running are dropped then. Every write has to
+// be safe to repeat, the caller retries the lot.
+async fn write_concurrently<F>(writes: impl IntoIterator<Item = F>) -> Result<(), WriteError>
+where
+    F: Future<Output = Result<(), WriteError>> + Send + 'static,
+{
+    let mut running = JoinSet::new();
+    for write in writes {
+        if running.len() >= WRITE_CONCURRENCY
+            && let Some(result) = running.join_next().await
+        {
+            result??;
+        }
+        running.spawn(write);
+    }
+    while let Some(result) = running.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+// Writes messages of one conversation with a batch per partition instead of
+// a logged batch per message. The messages go first, then the recipients'
+// pending deliveries and both users' conversation lists side by side. Every
+// statement is an idempotent insert, a failed call is simply made again.
+pub async fn write_direct_messages(
+    session: &Arc<Session>,
+    messages: Vec<DirectMessage>,
+) -> Result<(), WriteError> {
+    let Some(first) = messages.first() else {
+        return Ok(());
+    };
+    let project_id = first.project_id.clone();
+    let conversation_id = first.conversation_id.clone();
+
+    // PK: ((project_id, conversation_id), message_id)
+    let mut batch = Batch::new(BatchType::Unlogged);
+    let mut batch_values = Vec::with_capacity(messages.len());
+    for message in &messages {
+        batch.append_statement(
+            "INSERT INTO affinity.direct_messages \
+            (project_id, conversation_id, message_id, sender_id, recipient_id, message_text, created_at) \
+            VALUES (?, ?, ?, ?, ?, ?, ?)",
+        );
+        batch_values.push((
+            &message.project_id,
+            &message.conversation_id,
+            CqlTimeuuid::from(message.message_id),
+            &message.sender_id,
+            &message.recipient_id,
+            &message.message_text,
+            message.created_at,
+        ));
+    }
+    batch.set_consistency(Consistency::One);
+    session.batch(&batch, batch_values).await?;
+
+    let mut last_message: HashMap<String, CqlTimestamp> = HashMap::new();
+    let mut pending: HashMap<String, Vec<DirectMessage>> = HashMap::new();
+    for message in messages {
+        for user_id in [&message.sender_id, &message.recipient_id] {
+            let last = last_message
+                .entry(user_id.clone())
+                .or_insert(message.created_at);
+            last.0 = last.0.max(message.created_at.0);
+        }
+        pending
+            .entry(message.recipient_id.clone())
+            .or_default()
+            .push(message);
+    }
+
+    let mut writes: Vec<Write> = Vec::new();
+    for (user_id, last_message) in last_message {
+        let session = session.clone();
+        let (project_id, conversation_id) = (project_id.clone(), conversation_id.clone());
+        writes.push(Box::pin(async move {
+            // PK: ((project_id, user_id), conversation_id)
+            session
+                .query_unpaged(
+                    "INSERT INTO affinity.user_conversations \
+                    (project_id, user_id, conversation_id, last_message) VALUES (?, ?, ?, ?)",
+                    (project_id, user_id, conversation_id, last_message),
+                )
+                .await?;
+            Ok(())
+        }));
+    }
+    for (recipient_id, messages) in pending {
+        let session = session.clone();
+        writes.push(Box::pin(async move {
+            // Queued for the recipient until their client acks it
+            // PK: ((project_id, user_id), message_id)
+            // Written at the message's own timestamp, so an ack that reaches
+            // Scylla before this batch still shadows the row.
+            let mut batch = Batch::new(BatchType::Unlogged);
+            let mut batch_values = Vec::with_capacity(messages.len());
+            for message in &messages {
+                batch.append_statement(
+                    "INSERT INTO affinity.pending_deliveries \
+                    (project_id, user_id, message_id, kind, target_id, sender_id, content, created_at) \
+                    VALUES (?, ?, ?, 'dm', ?, ?, ?, ?) USING TIMESTAMP ?",
+                );
+                batch_values.push((
+                    &message.project_id,
+                    &recipient_id,
+                    CqlTimeuuid::from(message.message_id),
+                    &message.conversation_id,
+                    &message.sender_id,
+                    &message.message_text,
+                    message.created_at,
+                    message.created_at.0 * 1000,
+                ));
+            }
+            batch.set_consistency(Consistency::One);
+            session.batch(&batch, batch_values).await?;
+            Ok(())
+        }));
+    }
+
+    write_concurrently(writes).await
+}
+
+// Writes messages of one room, one batch for the room's partition and the
+// room's last activity, then queues them for the members
+pub async fn write_room_messages(
+    session: &Arc<Session>,
+    messages: Vec<DbRoomMessageEx>,
+) -> Result<(), WriteError> {
+    let Some(last_activity) = messages.iter().map(|message| message.created_at.0).max() else {
+        return Ok(());
+    };
+    let (project_id, room_id) = (&messages[0].project_id, &messages[0].room_id);
+
+    // PK: ((project_id, room_id), message_id)
+    let mut batch = Batch::new(BatchType::Unlogged);
+    let mut batch_values = Vec::with_capacity(messages.len());
+    for message in &messages {
+        batch.append_statement(
+            "INSERT INTO affinity.room_messages \
+            (project_id, room_id, message_id, sender_id, content, created_at) \
+            VALUES (?, ?, ?, ?, ?, ?)",
+        );
+        batch_values.push((
+            &message.project_id,
+            &message.room_id,
+            message.message_id,
+            &message.sender_id,
+            &message.content,
+            message.created_at,
+        ));
+    }
+    batch.set_consistency(Consistency::One);
+    session.batch(&batch, batch_values).await?;
+
+    // PK: ((project_id), room_id)
+    session
+        .query_unpaged(
+            "UPDATE affinity.project_rooms SET last_activity = ? \
+            WHERE project_id = ? AND room_id = ?",
+            (CqlTimestamp(last_activity), project_id, room_id),
+        )
+        .await?;
+
+    queue_room_messages(session, &messages).await
+}
+
+// Fans room messages out to the pending deliveries of every member but
+// their sender, one single-partition batch per member
+async fn queue_room_messages(
+    session: &Arc<Session>,
+    messages: &[DbRoomMessageEx],
+) -> Result<(), WriteError> {
+    let Some(first) = messages.first() else {
+        return Ok(());
+    };
+    let members = fetch_room_members(session, &first.project_id, &first.room_id).await?;
+
+    let writes = room_deliveries(members, messages)
+        .into_iter()
+        .map(|(member, messages)| {
+            let session = session.clone();
+            async move {
+                // PK: ((project_id, user_id), message_id)
+                let mut batch = Batch::new(BatchType::Unlogged);
+                let mut batch_values = Vec::with_capacity(messages.len());
+                for message in &messages {
+                    batch.append_statement(
+                        "INSERT INTO affinity.pending_deliveries \
+                        (project_id, user_id, message_id, kind, target_id, sender_id, content, created_at) \
+                        VALUES (?, ?, ?, 'room', ?, ?, ?, ?) USING TIMESTAMP ?",
+                    );
+                    batch_values.push((
+                        &message.project_id,
+                        &member,
+                        CqlTimeuuid::from(message.message_id),
+                        &message.room_id,
+                        &message.sender_id,
+                        &message.content,
+                        message.created_at,
+                        message.created_at.0 * 1000,
+                    ));
+                }
+                batch.set_consistency(Consistency::One);
+                session.batch(&batch, batch_values).await?;
+                Ok(())
+            }
+        });
+
+    write_concurrently(writes).await
+}
+
+// The messages each member gets, in order, leaving out their own
+fn room_deliveries(
+    members: Vec<String>,
+    messages: &[DbRoomMessageEx],
+) -> Vec<(String, Vec<DbRoomMessageEx>)> {
+    members
+        .into_iter()
+        .filter_map(|member| {
+            let messages: Vec<_> = messages
+                .iter()
+                .filter(|message| message.sender_id != member)
+                .cloned()
+                .collect();
+            (!messages.is_empty()).then_some((member, messages))
+        })
+        .collect()
+}
+
+pub async fn fetch_room_members(
+    session: &Session,
+    project_id: &str,
+    room_id: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let query = "SELECT user_id FROM affinity.room_members WHERE project_id = ? AND room_id = ?";
+
+    let result = session.query_unpaged(query, (project_id, room_id)).await?;
+
+    let rows_result = result.into_rows_result()?;
+    let typed_rows = rows_result.rows::<(String,)>()?;
+
+    let mut members = Vec::new();
+    for row_result in typed_rows {
+        members.push(row_result?.0);
+    }
+
+    Ok(members)
+}
+
+pub async fn fetch_member_rooms(
     session: &Session,
-    message: DirectMessage,
+    project_id: &str,
+    user_id: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let query = "SELECT room_id FROM affinity.member_rooms WHERE project_id = ? AND user_id = ?";
+
+    let result = session.query_unpaged(query, (project_id, user_id)).await?;
+
+    let rows_result = result.into_rows_result()?;
+    let typed_rows = rows_result.rows::<(String,)>()?;
+
+    let mut rooms = Vec::new();
+    for row_result in typed_rows {
+        rooms.push(row_result?.0);
+    }
+
+    Ok(rooms)
+}
+
+pub async fn add_room_member(
+    session: &Session,
+    project_id: &str,
+    room_id: &str,
+    user_id: &str,
+    joined_at: CqlTimestamp,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut batch = Batch::new(BatchType::Logged);
 
-    // 1. Insert into direct_messages
-    // PK: ((project_id, conversation_id), message_id)
+    // PK: ((project_id, room_id), user_id)
     batch.append_statement(
-        "INSERT INTO affinity.direct_messages \
-        (project_id, conversation_id, message_id, sender_id, recipient_id, message_text, created_at) \
-        VALUES (?, ?, ?, ?, ?, ?, ?)"
+        "INSERT INTO affinity.room_members (project_id, room_id, user_id, joined_at) \
+        VALUES (?, ?, ?, ?)",
+    );
+    // PK: ((project_id, user_id), room_id)
+    batch.append_statement(
+        "INSERT INTO affinity.member_rooms (project_id, user_id, room_id, joined_at) \
+        VALUES (?, ?, ?, ?)",
     );
 
-    // 2. Update Sender's conversation list
-    // PK: ((project_id, user_id), conversation_id)
+    batch.set_consistency(Consistency::One);
+
+    let batch_values = (
+        (project_id, room_id, user_id, joined_at),
+        (project_id, user_id, room_id, joined_at),
+    );
+
+    session.batch(&batch, batch_values).await?;
+
+    Ok(())
+}
+
+pub async fn remove_room_member(
+    session: &Session,
+    project_id: &str,
+    room_id: &str,
+    user_id: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut batch = Batch::new(BatchType::Logged);
+
     batch.append_statement(
-        "INSERT INTO affinity.user_conversations (project_id, user_id, conversation_id, last_message) \
-        VALUES (?, ?, ?, ?)"
+        "DELETE FROM affinity.room_members WHERE project_id = ? AND room_id = ? AND user_id = ?",
+    );
+    batch.append_statement(
+        "DELETE FROM affinity.member_rooms WHERE project_id = ? AND user_id = ? AND room_id = ?",
+    );
+
+    batch.set_consistency(Consistency::One);
+
+    let batch_values = (
+        (project_id, room_id, user_id),
+        (project_id, user_id, room_id),
+    );
+
+    session.batch(&batch, batch_values).await?;
+
+    Ok(())
+}
+
+pub struct PendingDelivery {
+    pub message_id: Uuid,
+    pub kind: String,
+    pub target_id: String,
+    pub sender_id: String,
+    pub content: String,
+    pub created_at: CqlTimestamp,
+}
+
+// Oldest first, with the text the message has now. Deleted messages are
+// acked on the way and a page continues past them.
+pub async fn fetch_pending_deliveries(
+    session: &Session,
+    project_id: &str,
+    user_id: &str,
+    mut after_message_id: Option<Uuid>,
+    limit: i32,
+) -> Result<(Vec<PendingDelivery>, bool), Box<dyn std::error::Error + Send + Sync>> {
+    loop {
+        let (page, has_more) =
+            fetch_pending_page(session, project_id, user_id, after_message_id, limit).await?;
+        let last = page.last().map(|delivery| delivery.message_id);
+
+        let mut texts = HashMap::new();
+        let mut targets: HashMap<(&str, &str), Vec<Uuid>> = HashMap::new();
+        for delivery in &page {
+            targets
+                .entry((&delivery.kind, &delivery.target_id))
+                .or_default()
+                .push(delivery.message_id);
+        }
+        for ((kind, target_id), message_ids) in targets {
+            if let Some(kind) = MessageKind::parse(kind) {
+                texts.extend(
+                    current_texts(session, project_id, kind, target_id, &message_ids).await?,
+                );
+            }
+        }
+
+        let (deliveries, deleted) = with_current_texts(page, &texts);
+        if !deleted.is_empty() {
+            ack_deliveries(session, project_id, user_id, &deleted).await?;
+        }
+        if !deliveries.is_empty() || !has_more {
+            return Ok((deliveries, has_more));
+        }
+        after_message_id = last;
+    }
+}
+
+// The text each of `message_ids` has now, None for the deleted ones
+async fn current_texts(
+    session: &Session,
+    project_id: &str,
+    kind: MessageKind,
+    target_id: &str,
+    message_ids: &[Uuid],
+) -> Result<HashMap<Uuid, Option<String>>, Box<dyn std::error::Error + Send + Sync>> {
+    let (table, key, text) = kind.columns();
+    let query = format!(
+        "SELECT message_id, {text}, deleted_at FROM affinity.{table} \
+        WHERE project_id = ? AND {key} = ? AND message_id IN ?"
     );
 
-    // 3. Update Recipient's conversation list
-    // PK: ((project_id, user_id), conversation_id)
+    let mut texts = HashMap::new();
+    for chunk in message_ids.chunks(50) {
+        let chunk: Vec<CqlTimeuuid> = chunk.iter().copied().map(CqlTimeuuid::from).collect();
+        let rows_result = session
+            .query_unpaged(query.as_str(), (project_id, target_id, chunk))
+            .await?
+            .into_rows_result()?;
+        for row in rows_result.rows::<(Uuid, Option<String>, Option<CqlTimestamp>)>()? {
+            let (message_id, text, deleted_at) = row?;
+            texts.insert(
+                message_id,
+                deleted_at.is_none().then(|| text.unwrap_or_default()),
+            );
+        }
+    }
+
+    Ok(texts)
+}
+
+// Swaps in the current text of every delivery and takes out the deleted
+// ones, returned by id. A message not found keeps the text it was queued with.
+fn with_current_texts(
+    deliveries: Vec<PendingDelivery>,
+    texts: &HashMap<Uuid, Option<String>>,
+) -> (Vec<PendingDelivery>, Vec<Uuid>) {
+    let mut current = Vec::with_capacity(deliveries.len());
+    let mut deleted = Vec::new();
+    for mut delivery in deliveries {
+        match texts.get(&delivery.message_id) {
+            Some(Some(text)) => {
+                delivery.content = text.clone();
+                current.push(delivery);
+            }
+            Some(None) => deleted.push(delivery.message_id),
+            None => current.push(delivery),
+        }
+    }
+    (current, deleted)
+}
+
+// Oldest first. Returns one row more than it hands back to report `has_more`.
+async fn fetch_pending_page(
+    session: &Session,
+    project_id: &str,
+    user_id: &str,
+    after_message_id: Option<Uuid>,
+    limit: i32,
+) -> Result<(Vec<PendingDelivery>, bool), Box<dyn std::error::Error + Send + Sync>> {
+    let result = match after_message_id {
+        Some(after) => {
+            let query = "SELECT message_id, kind, target_id, sender_id, content, created_at \
+                FROM affinity.pending_deliveries \
+                WHERE project_id = ? AND user_id = ? AND message_id > ? LIMIT ?";
+            session
+                .query_unpaged(
+                    query,
+                    (project_id, user_id, CqlTimeuuid::from(after), limit + 1),
+                )
+                .await?
+        }
+        None => {
+            let query = "SELECT message_id, kind, target_id, sender_id, content, created_at \
+                FROM affinity.pending_deliveries WHERE project_id = ? AND user_id = ? LIMIT ?";
+            session
+                .query_unpaged(query, (project_id, user_id, limit + 1))
+                .await?
+        }
+    };
+
+    let rows_result = result.into_rows_result()?;
+    let typed_rows = rows_result.rows::<(Uuid, String, String, String, String, CqlTimestamp)>()?;
+
+    let mut deliveries = Vec::new();
+    for row_result in typed_rows {
+        let (message_id, kind, target_id, sender_id, content, created_at) = row_result?;
+        deliveries.push(PendingDelivery {
+            message_id,
+            kind,
+            target_id,
+            sender_id,
+            content,
+            created_at,
+        });
+    }
+
+    let has_more = deliveries.len() > limit as usize;
+    deliveries.truncate(limit as usize);
+
+    Ok((deliveries, has_more))
+}
+
+pub async fn ack_deliveries(
+    session: &Session,
+    project_id: &str,
+    user_id: &str,
+    message_ids: &[Uuid],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let query = "DELETE FROM affinity.pending_deliveries \
+        WHERE project_id = ? AND user_id = ? AND message_id IN ?";
+
+    let message_ids: Vec<CqlTimeuuid> =
+        message_ids.iter().copied().map(CqlTimeuuid::from).collect();
+
+    session
+        .query_unpaged(query, (project_id, user_id, message_ids))
+        .await?;
+
+    Ok(())
+}
+
+// Where a changed message is stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageKind {
+    Direct,
+    Room,
+}
+
+impl MessageKind {
+    // "dm" or "room", as in pending_deliveries
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "dm" => Some(MessageKind::Direct),
+            "room" => Some(MessageKind::Room),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageKind::Direct => "dm",
+            MessageKind::Room => "room",
+        }
+    }
+
+    // The table, its key next to project_id, and its text column
+    fn columns(self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            MessageKind::Direct => ("direct_messages", "conversation_id", "message_text"),
+            MessageKind::Room => ("room_messages", "room_id", "content"),
+        }
+    }
+}
+
+pub struct MessageChange {
+    pub project_id: String,
+    pub kind: MessageKind,
+    // conversation_id for a dm, room_id for a room
+    pub target_id: String,
+    pub message_id: Uuid,
+    // has to be the sender of the message
+    pub user_id: String,
+    pub changed_at: CqlTimestamp,
+    // how long after it was sent the message may be changed, None for ever
+    pub window_ms: Option<i64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChangeRejection {
+    NotFound,
+    NotSender,
+    WindowExpired,
+    Deleted,
+}
+
+// The current text of the message, if `change` may be made to it
+async fn check_change(
+    session: &Session,
+    change: &MessageChange,
+) -> Result<Result<String, ChangeRejection>, Box<dyn std::error::Error + Send + Sync>> {
+    let (table, key, text) = change.kind.columns();
+    let query = format!(
+        "SELECT sender_id, {text}, created_at, deleted_at FROM affinity.{table} \
+        WHERE project_id = ? AND {key} = ? AND message_id = ?"
+    );
+
+    let row = session
+        .query_unpaged(
+            query,
+            (
+                &change.project_id,
+                &change.target_id,
+                CqlTimeuuid::from(change.message_id),
+            ),
+        )
+        .await?
+        .into_rows_result()?
+        .maybe_first_row::<(String, String, CqlTimestamp, Option<CqlTimestamp>)>()?;
+
+    let Some((sender_id, current_text, created_at, deleted_at)) = row else {
+        return Ok(Err(ChangeRejection::NotFound));
+    };
+
+    Ok(change_allowed(change, &sender_id, created_at, deleted_at).map(|()| current_text))
+}
+
+fn change_allowed(
+    change: &MessageChange,
+    sender_id: &str,
+    created_at: CqlTimestamp,
+    deleted_at: Option<CqlTimestamp>,
+) -> Result<(), ChangeRejection> {
+    if sender_id != change.user_id {
+        return Err(ChangeRejection::NotSender);
+    }
+    if deleted_at.is_some() {
+        return Err(ChangeRejection::Deleted);
+    }
+    if let Some(window_ms) = change.window_ms
+        && change.changed_at.0 - created_at.0 > window_ms
+    {
+        return Err(ChangeRejection::WindowExpired);
+    }
+    Ok(())
+}
+
+// Replaces the text and keeps the one it replaced in message_edits. Copies
+// still pending aren't touched, replays read the text from the message row.
+// This is a read-then-write, see `tombstoned` for a delete racing it.
+pub async fn edit_message(
+    session: &Session,
+    change: &MessageChange,
+    content: &str,
+) -> Result<Result<(), ChangeRejection>, Box<dyn std::error::Error + Send + Sync>> {
+    let previous_text = match check_change(session, change).await? {
+        Ok(previous_text) => previous_text,
+        Err(rejection) => return Ok(Err(rejection)),
+    };
+
+    let (table, key, text) = change.kind.columns();
+    let message_id = CqlTimeuuid::from(change.message_id);
+    let mut batch = Batch::new(BatchType::Logged);
+
+    // PK: ((project_id, conversation_id | room_id), message_id)
     batch.append_statement(
-        "INSERT INTO affinity.user_conversations (project_id, user_id, conversation_id, last_message) \
-        VALUES (?, ?, ?, ?)"
+        format!(
+            "UPDATE affinity.{table} SET {text} = ?, edited_at = ? \
+            WHERE project_id = ? AND {key} = ? AND message_id = ?"
+        )
+        .as_str(),
+    );
+    // PK: ((project_id, message_id), edited_at)
+    batch.append_statement(
+        "INSERT INTO affinity.message_edits \
+        (project_id, message_id, edited_at, kind, target_id, editor_id, previous_text) \
+        VALUES (?, ?, ?, ?, ?, ?, ?)",
     );
 
-    // Consistency::One is fine for dev; consider Quorum for prod
     batch.set_consistency(Consistency::One);
 
     let batch_values = (
-        // Statement 1: direct_messages
         (
-            &message.project_id,
-            &message.conversation_id,
-            CqlTimeuuid::from(message.message_id),
-            &message.sender_id,
-            &message.recipient_id,
-            &message.message_text,
-            message.created_at,
-        ),
-        // Statement 2: user_conversations (Sender)
-        (
-            &message.project_id,
-            &message.sender_id,
-            &message.conversation_id,
-            message.created_at,
+            content,
+            change.changed_at,
+            &change.project_id,
+            &change.target_id,
+            message_id,
         ),
-        // Statement 3: user_conversations (Recipient)
         (
-            &message.project_id,
-            &message.recipient_id,
-            &message.conversation_id,
-            message.created_at,
+            &change.project_id,
+            message_id,
+            change.changed_at,
+            change.kind.as_str(),
+            &change.target_id,
+            &change.user_id,
+            &previous_text,
         ),
     );
 
     session.batch(&batch, batch_values).await?;
 
-    Ok(())
+    Ok(Ok(()))
 }
 
-pub async fn write_room_message(
+// Clears the text and the edit history, the row stays as a tombstone so
+// history still shows a message was there. Copies still pending are dropped
+// when they are next replayed.
+pub async fn delete_message(
     session: &Session,
-    message: DbRoomMessageEx,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    change: &MessageChange,
+) -> Result<Result<(), ChangeRejection>, Box<dyn std::error::Error + Send + Sync>> {
+    if let Err(rejection) = check_change(session, change).await? {
+        return Ok(Err(rejection));
+    }
+
+    let (table, key, text) = change.kind.columns();
+    let message_id = CqlTimeuuid::from(change.message_id);
     let mut batch = Batch::new(BatchType::Logged);
 
-    // 1. Insert into room_messages
-    // PK: ((project_id, room_id), message_id)
     batch.append_statement(
-        "INSERT INTO affinity.room_messages \
-        (project_id, room_id, message_id, sender_id, content, created_at) \
-        VALUES (?, ?, ?, ?, ?, ?)",
+        format!(
+            "UPDATE affinity.{table} SET {text} = '', deleted_at = ? \
+            WHERE project_id = ? AND {key} = ? AND message_id = ?"
+        )
+        .as_str(),
+    );
+    batch.append_statement(
+        "DELETE FROM affinity.message_edits WHERE project_id = ? AND message_id = ?",
     );
-
-    // 2. Update project_rooms (last_activity)
-    // PK: ((project_id), room_id)
     batch.append_statement(
-        "UPDATE affinity.project_rooms SET last_activity = ? WHERE project_id = ? AND room_id = ?",
+        "DELETE FROM affinity.message_reactions \
+        WHERE project_id = ? AND kind = ? AND target_id = ? AND message_id = ?",
     );
 
     batch.set_consistency(Consistency::One);
 
     let batch_values = (
-        // Statement 1: room_messages
         (
-            &message.project_id,
-            &message.room_id,
-            message.message_id,
-            &message.sender_id,
-            &message.content,
-            message.created_at,
+            change.changed_at,
+            &change.project_id,
+            &change.target_id,
+            message_id,
+        ),
+        (&change.project_id, message_id),
+        (
+            &change.project_id,
+            change.kind.as_str(),
+            &change.target_id,
+            message_id,
         ),
-        // Statement 2: project_rooms
-        (message.created_at, &message.project_id, &message.room_id),
     );
 
     session.batch(&batch, batch_values).await?;
 
-    Ok(())
+    Ok(Ok(()))
+}
+
+// Who reacted with what to each of `message_ids`. Reactions come in
+// alphabetical order, the users of one by id.
+pub async fn fetch_reactions(
+    session: &Session,
+    project_id: &str,
+    kind: MessageKind,
+    target_id: &str,
+    message_ids: &[Uuid],
+) -> Result<HashMap<Uuid, Vec<ReactionCount>>, Box<dyn std::error::Error + Send + Sync>> {
+    let query = "SELECT message_id, reaction, user_id FROM affinity.message_reactions \
+        WHERE project_id = ? AND kind = ? AND target_id = ? AND message_id IN ?";
+
+    let mut reactions: HashMap<Uuid, Vec<ReactionCount>> = HashMap::new();
+    // a sync can return any number of messages, IN lists are kept to a page
+    for chunk in message_ids.chunks(50) {
+        let chunk: Vec<CqlTimeuuid> = chunk.iter().copied().map(CqlTimeuuid::from).collect();
+        let result = session
+            .query_unpaged(query, (project_id, kind.as_str(), target_id, chunk))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+
+        for row in rows_result.rows::<(Uuid, String, String)>()? {
+            let (message_id, reaction, user_id) = row?;
+            push_reaction(reactions.entry(message_id).or_default(), reaction, user_id);
+        }
+    }
+
+    Ok(reactions)
+}
+
+// Rows come clustered by reaction, so a user either joins the last one or
+// starts the next
+fn push_reaction(reactions: &mut Vec<ReactionCount>, reaction: String, user_id: String) {
+    match reactions.last_mut() {
+        Some(last) if last.reaction == reaction => last.user_ids.push(user_id),
+        _ => reactions.push(ReactionCount {
+            reaction,
+            user_ids: vec![user_id],
+        }),
+    }
+}
+
+// Whether the message has no room for `reaction`. Joining one it already
+// has never counts against the limit.
+fn over_reaction_limit(
+    reactions: &[ReactionCount],
+    reaction: &str,
+    max_reactions: Option<usize>,
+) -> bool {
+    let Some(max_reactions) = max_reactions else {
+        return false;
+    };
+    let distinct = reactions.len() + usize::from(!reactions.iter().any(|r| r.reaction == reaction));
+    distinct > max_reactions
+}
+
+pub struct ReactionChange {
+    pub project_id: String,
+    pub kind: MessageKind,
+    // conversation_id for a dm, room_id for a room
+    pub target_id: String,
+    pub message_id: Uuid,
+    pub user_id: String,
+    pub reaction: String,
+    pub reacted_at: CqlTimestamp,
+    // distinct reactions the message may have, None for no limit
+    pub max_reactions: Option<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReactionRejection {
+    NotFound,
+    LimitReached,
+}
+
+// The reactions of the message `change` is about
+async fn message_reactions(
+    session: &Session,
+    change: &ReactionChange,
+) -> Result<Vec<ReactionCount>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut reactions = fetch_reactions(
+        session,
+        &change.project_id,
+        change.kind,
+        &change.target_id,
+        &[change.message_id],
+    )
+    .await?;
+
+    Ok(reactions.remove(&change.message_id).unwrap_or_default())
+}
+
+// Adds the reaction and answers with all of the message's. The limit is
+// checked before the write and again after it, a new reaction that lost a
+// race and went past the limit is taken back. Readers may see the message
+// over the limit for that moment, and two racing users may both be turned
+// away.
+pub async fn add_reaction(
+    session: &Session,
+    change: &ReactionChange,
+) -> Result<Result<Vec<ReactionCount>, ReactionRejection>, Box<dyn std::error::Error + Send + Sync>>
+{
+    let (table, key, _) = change.kind.columns();
+    let query = format!(
+        "SELECT deleted_at FROM affinity.{table} \
+        WHERE project_id = ? AND {key} = ? AND message_id = ?"
+    );
+    let message_id = CqlTimeuuid::from(change.message_id);
+
+    let row = session
+        .query_unpaged(query, (&change.project_id, &change.target_id, message_id))
+        .await?
+        .into_rows_result()?
+        .maybe_first_row::<(Option<CqlTimestamp>,)>()?;
+    // deleted messages keep no reactions
+    if !matches!(row, Some((None,))) {
+        return Ok(Err(ReactionRejection::NotFound));
+    }
+
+    let reactions = message_reactions(session, change).await?;
+    if over_reaction_limit(&reactions, &change.reaction, change.max_reactions) {
+        return Ok(Err(ReactionRejection::LimitReached));
+    }
+    let is_new = !reactions.iter().any(|r| r.reaction == change.reaction);
+
+    // PK: ((project_id, kind, target_id), message_id, reaction, user_id)
+    session
+        .query_unpaged(
+            "INSERT INTO affinity.message_reactions \
+            (project_id, kind, target_id, message_id, reaction, user_id, reacted_at) \
+            VALUES (?, ?, ?, ?, ?, ?, ?)",
+            (
+                &change.project_id,
+                change.kind.as_str(),
+                &change.target_id,
+                message_id,
+                &change.reaction,
+                &change.user_id,
+                change.reacted_at,
+            ),
+        )
+        .await?;
+
+    let reactions = message_reactions(session, change).await?;
+    if is_new
+        && let Some(max_reactions) = change.max_reactions
+        && reactions.len() > max_reactions
+    {
+        remove_reaction(session, change).await?;
+        return Ok(Err(ReactionRejection::LimitReached));
+    }
+
+    Ok(Ok(reactions))
+}
+
+// Removes the reaction, if there was one, and answers with the message's
+// remaining ones
+pub async fn remove_reaction(
+    session: &Session,
+    change: &ReactionChange,
+) -> Result<Vec<ReactionCount>, Box<dyn std::error::Error + Send + Sync>> {
+    session
+        .query_unpaged(
+            "DELETE FROM affinity.message_reactions \
+            WHERE project_id = ? AND kind = ? AND target_id = ? \
+            AND message_id = ? AND reaction = ? AND user_id = ?",
+            (
+                &change.project_id,
+                change.kind.as_str(),
+                &change.target_id,
+                CqlTimeuuid::from(change.message_id),
+                &change.reaction,
+                &change.user_id,
+            ),
+        )
+        .await?;
+
+    message_reactions(session, change).await
 }
 
 pub async fn getsert_conversation_id(
@@ -375,6 +1264,134 @@ pub async fn getsert_conversation_id(
     Ok((new_conversation_id, true))
 }
 
+pub async fn fetch_conversation_participants(
+    session: &Session,
+    project_id: &str,
+    conversation_id: &str,
+) -> Result<Option<(String, String)>, Box<dyn std::error::Error + Send + Sync>> {
+    // Served by dm_lookup_conversation_id_idx; the project is checked on the
+    // returned rows so a conversation_id never resolves across tenants.
+    let query = r#"
+        SELECT project_id, user_id_1, user_id_2
+        FROM affinity.dm_lookup
+        WHERE conversation_id = ?
+    "#;
+
+    let result = session.query_unpaged(query, (conversation_id,)).await?;
+
+    let rows_result = result.into_rows_result()?;
+    let typed_rows = rows_result.rows::<(String, String, String)>()?;
+
+    for row_result in typed_rows {
+        let (row_project_id, user_id_1, user_id_2) = row_result?;
+        if row_project_id == project_id {
+            return Ok(Some((user_id_1, user_id_2)));
+        }
+    }
+
+    Ok(None)
+}
+
+pub async fn fetch_conversation_partners(
+    session: &Session,
+    project_id: &str,
+    user_id: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let conversations = fetch_user_conversations(session, project_id, user_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut partners = Vec::with_capacity(conversations.len());
+    for (conversation_id, _) in conversations {
+        if let Some((user_id_1, user_id_2)) =
+            fetch_conversation_participants(session, project_id, &conversation_id).await?
+        {
+            if user_id_1 == user_id {
+                partners.push(user_id_2);
+            } else if user_id_2 == user_id {
+                partners.push(user_id_1);
+            }
+        }
+    }
+
+    Ok(partners)
+}
+
+pub async fn fetch_read_cursors(
+    session: &Session,
+    project_id: &str,
+    conversation_id: &str,
+) -> Result<Vec<(String, Uuid, CqlTimestamp)>, Box<dyn std::error::Error + Send + Sync>> {
+    let query = "SELECT user_id, message_id, updated_at FROM affinity.read_cursors WHERE project_id = ? AND conversation_id = ?";
+
+    let result = session
+        .query_unpaged(query, (project_id, conversation_id))
+        .await?;
+
+    let rows_result = result.into_rows_result()?;
+    let typed_rows = rows_result.rows::<(String, Uuid, CqlTimestamp)>()?;
+
+    let mut cursors = Vec::new();
+    for row_result in typed_rows {
+        cursors.push(row_result?);
+    }
+
+    Ok(cursors)
+}
+
+// Returns whether the cursor moved. This is a read-then-write, two devices of
+// the same user racing can at worst leave the cursor on the older of the two
+// until the next read.
+pub async fn update_read_cursor(
+    session: &Session,
+    project_id: &str,
+    conversation_id: &str,
+    user_id: &str,
+    message_id: Uuid,
+    updated_at: CqlTimestamp,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let select = "SELECT message_id FROM affinity.read_cursors WHERE project_id = ? AND conversation_id = ? AND user_id = ?";
+
+    let result = session
+        .query_unpaged(select, (project_id, conversation_id, user_id))
+        .await?;
+    let current = result
+        .into_rows_result()?
+        .maybe_first_row::<(Uuid,)>()?
+        .map(|(id,)| id);
+
+    if let Some(current) = current
+        && timeuuid_order(current) >= timeuuid_order(message_id)
+    {
+        return Ok(false);
+    }
+
+    let insert = "INSERT INTO affinity.read_cursors (project_id, conversation_id, user_id, message_id, updated_at) VALUES (?, ?, ?, ?, ?)";
+    session
+        .query_unpaged(
+            insert,
+            (
+                project_id,
+                conversation_id,
+                user_id,
+                CqlTimeuuid::from(message_id),
+                updated_at,
+            ),
+        )
+        .await?;
+
+    Ok(true)
+}
+
+// Orders timeuuids the way Scylla does, by timestamp first
+fn timeuuid_order(id: Uuid) -> (u64, u32, Uuid) {
+    let (secs, nanos) = id
+        .get_timestamp()
+        .map(|ts| ts.to_unix())
+        .unwrap_or_default();
+    (secs, nanos, id)
+}
+
 // pub fn create_dm(
 //     sender_id: i32,
 //     recipient_id: i32,
@@ -432,3 +1449,167 @@ pub fn create_room_message(
         created_at,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reactions(rows: &[(&str, &str)]) -> Vec<ReactionCount> {
+        let mut reactions = Vec::new();
+        for (reaction, user_id) in rows {
+            push_reaction(&mut reactions, reaction.to_string(), user_id.to_string());
+        }
+        reactions
+    }
+
+    #[test]
+    fn groups_rows_by_reaction() {
+        let grouped = reactions(&[("👍", "alice"), ("👍", "bob"), ("🎉", "alice")]);
+        assert_eq!(
+            grouped,
+            vec![
+                ReactionCount {
+                    reaction: "👍".to_string(),
+                    user_ids: vec!["alice".to_string(), "bob".to_string()],
+                },
+                ReactionCount {
+                    reaction: "🎉".to_string(),
+                    user_ids: vec!["alice".to_string()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn limit_only_turns_away_new_reactions() {
+        let full = reactions(&[("👍", "alice"), ("🎉", "bob")]);
+
+        assert!(over_reaction_limit(&full, "🔥", Some(2)));
+        // joining a reaction the message already has is always fine
+        assert!(!over_reaction_limit(&full, "👍", Some(2)));
+        assert!(!over_reaction_limit(&full, "🔥", Some(3)));
+        assert!(!over_reaction_limit(&full, "🔥", None));
+    }
+
+    fn change(changed_at: i64, window_ms: Option<i64>) -> MessageChange {
+        MessageChange {
+            project_id: "p".to_string(),
+            kind: MessageKind::Direct,
+            target_id: "c".to_string(),
+            message_id: Uuid::new_v4(),
+            user_id: "alice".to_string(),
+            changed_at: CqlTimestamp(changed_at),
+            window_ms,
+        }
+    }
+
+    #[test]
+    fn changes_are_limited_to_the_sender_and_the_window() {
+        let sent = CqlTimestamp(1_000);
+
+        assert_eq!(
+            change_allowed(&change(61_000, Some(60_000)), "alice", sent, None),
+            Ok(())
+        );
+        assert_eq!(
+            change_allowed(&change(61_001, Some(60_000)), "alice", sent, None),
+            Err(ChangeRejection::WindowExpired)
+        );
+        assert_eq!(
+            change_allowed(&change(i64::MAX, None), "alice", sent, None),
+            Ok(())
+        );
+        assert_eq!(
+            change_allowed(&change(2_000, None), "bob", sent, None),
+            Err(ChangeRejection::NotSender)
+        );
+        assert_eq!(
+            change_allowed(
+                &change(2_000, None),
+                "alice",
+                sent,
+                Some(CqlTimestamp(1_500))
+            ),
+            Err(ChangeRejection::Deleted)
+        );
+    }
+
+    #[test]
+    fn tombstones_never_hand_out_text() {
+        assert_eq!(tombstoned("hi".to_string(), None), "hi");
+        // an edit that raced the delete
+        assert_eq!(tombstoned("edited".to_string(), Some(CqlTimestamp(1))), "");
+    }
+
+    fn delivery(message_id: Uuid, content: &str) -> PendingDelivery {
+        PendingDelivery {
+            message_id,
+            kind: "dm".to_string(),
+            target_id: "c".to_string(),
+            sender_id: "alice".to_string(),
+            content: content.to_string(),
+            created_at: CqlTimestamp(1),
+        }
+    }
+
+    #[test]
+    fn replays_carry_the_current_text() {
+        let (edited, deleted, unknown) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let texts = HashMap::from([(edited, Some("edited".to_string())), (deleted, None)]);
+
+        let (current, dropped) = with_current_texts(
+            vec![
+                delivery(edited, "original"),
+                delivery(deleted, "gone"),
+                delivery(unknown, "queued"),
+            ],
+            &texts,
+        );
+
+        let current: Vec<_> = current
+            .iter()
+            .map(|delivery| (delivery.message_id, delivery.content.as_str()))
+            .collect();
+        assert_eq!(current, vec![(edited, "edited"), (unknown, "queued")]);
+        assert_eq!(dropped, vec![deleted]);
+    }
+
+    fn room_message(sender_id: &str) -> DbRoomMessageEx {
+        DbRoomMessageEx {
+            project_id: "p".to_string(),
+            room_id: "lobby".to_string(),
+            message_id: Uuid::new_v4(),
+            sender_id: sender_id.to_string(),
+            content: "hi".to_string(),
+            created_at: CqlTimestamp(1),
+        }
+    }
+
+    #[test]
+    fn room_messages_are_queued_for_every_member_but_their_sender() {
+        let messages = [
+            room_message("alice"),
+            room_message("bob"),
+            room_message("alice"),
+        ];
+        let members = ["alice", "bob", "carol"].map(String::from).to_vec();
+
+        let queued: Vec<_> = room_deliveries(members, &messages)
+            .into_iter()
+            .map(|(member, messages)| {
+                let ids: Vec<_> = messages.iter().map(|message| message.message_id).collect();
+                (member, ids)
+            })
+            .collect();
+
+        let ids: Vec<_> = messages.iter().map(|message| message.message_id).collect();
+        assert_eq!(
+            queued,
+            vec![
+                ("alice".to_string(), vec![ids[1]]),
+                ("bob".to_string(), vec![ids[0], ids[2]]),
+                ("carol".to_string(), ids.clone()),
+            ]
+        );
+    }
+}
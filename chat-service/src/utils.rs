@@ -18,6 +18,9 @@ pub struct DbMessage {
     pub recipient_id: String,
     pub message_text: String,
     pub created_at: CqlTimestamp,
+    pub edited_at: Option<CqlTimestamp>,
+    pub deleted_at: Option<CqlTimestamp>,
+    pub reactions: Vec<ReactionCount>,
 }
 
 pub struct DbRoomMessage {
@@ -26,8 +29,19 @@ pub struct DbRoomMessage {
     pub sender_id: String,
     pub content: String,
     pub created_at: CqlTimestamp,
+    pub edited_at: Option<CqlTimestamp>,
+    pub deleted_at: Option<CqlTimestamp>,
+    pub reactions: Vec<ReactionCount>,
 }
 
+// Everyone who reacted to a message with `reaction`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReactionCount {
+    pub reaction: String,
+    pub user_ids: Vec<String>,
+}
+
+#[derive(Clone)]
 pub struct DbRoomMessageEx {
     pub project_id: String,
     pub room_id: String,
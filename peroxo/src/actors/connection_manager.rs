@@ -1,25 +1,56 @@
 use crate::{
-    actors::{message_router::RouterMessage, user_session::session::UserSession},
+    actors::{message_router::RouterHandle, user_session::session::UserSession},
+    auth::{Authenticator, Credentials},
+    backpressure::BackpressureConfig,
+    heartbeat::HeartbeatConfig,
     metrics::Metrics,
-    tenant::TenantUserId,
+    shutdown::Drain,
 };
 use axum::extract::ws::WebSocket;
-use tokio::sync::mpsc;
 use tracing::{error, info};
 
 pub struct ConnectionManager {
-    router_sender: mpsc::UnboundedSender<RouterMessage>,
+    router: RouterHandle,
+    backpressure: BackpressureConfig,
+    heartbeat: HeartbeatConfig,
+    drain: Drain,
+    authenticator: Authenticator,
 }
 
 impl ConnectionManager {
-    pub fn new(router_sender: mpsc::UnboundedSender<RouterMessage>) -> Self {
-        Self { router_sender }
+    pub fn new(
+        router: RouterHandle,
+        backpressure: BackpressureConfig,
+        heartbeat: HeartbeatConfig,
+        drain: Drain,
+        authenticator: Authenticator,
+    ) -> Self {
+        Self {
+            router,
+            backpressure,
+            heartbeat,
+            drain,
+            authenticator,
+        }
     }
 
-    pub async fn handle_connection(&self, socket: WebSocket, tenant_user_id: TenantUserId) {
+    pub async fn handle_connection(&self, socket: WebSocket, credentials: Credentials) {
+        let tenant_user_id = credentials.tenant_user_id.clone();
         info!("New connection attempt for user: {}", tenant_user_id);
+        // open until the session is over, shutdown waits for it
+        let _session = self.drain.session();
 
-        match UserSession::new(tenant_user_id.clone(), socket, self.router_sender.clone()).await {
+        match UserSession::new(
+            credentials,
+            socket,
+            &self.router,
+            &self.backpressure,
+            &self.heartbeat,
+            &self.drain,
+            &self.authenticator,
+        )
+        .await
+        {
             Ok(session) => {
                 info!("User session created for: {}", tenant_user_id);
                 Metrics::websocket_connected();
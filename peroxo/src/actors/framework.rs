@@ -0,0 +1,269 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::{error, info};
+
+// How long `ask` waits for an answer unless told otherwise
+pub const ASK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Delay before a crashed actor is started again, doubled for every crash in
+// a row up to the maximum
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(5);
+// An incarnation that ran this long resets the delay
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+// A task that owns its state and handles one message of its mailbox at a
+// time. Its address is all the rest of the code gets to see.
+pub trait Actor: Send + 'static {
+    type Message: Send + 'static;
+
+    fn handle(&mut self, message: Self::Message) -> impl Future<Output = ()> + Send;
+
+    // Messages the actor queued for itself, handled before the mailbox
+    fn next_pending(&mut self) -> Option<Self::Message> {
+        None
+    }
+
+    // `tick` runs on this interval between messages when set
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    fn tick(&mut self) {}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AskError {
+    // the actor is gone
+    Closed,
+    // the actor took the request but dropped it without an answer
+    Dropped,
+    Timeout,
+}
+
+impl fmt::Display for AskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AskError::Closed => write!(f, "Actor is not running"),
+            AskError::Dropped => write!(f, "Actor dropped the request"),
+            AskError::Timeout => write!(f, "Actor did not answer in time"),
+        }
+    }
+}
+
+impl std::error::Error for AskError {}
+
+// Where messages for an actor of type `M` go. Stays valid when the actor
+// behind it is restarted by its supervisor.
+pub struct Addr<M> {
+    sender: mpsc::Sender<M>,
+}
+
+impl<M> Clone for Addr<M> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<M> fmt::Debug for Addr<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Addr").finish_non_exhaustive()
+    }
+}
+
+impl<M: Send + 'static> Addr<M> {
+    // Waits for room in the mailbox
+    pub async fn send(&self, message: M) -> Result<(), mpsc::error::SendError<M>> {
+        self.sender.send(message).await
+    }
+
+    pub fn try_send(&self, message: M) -> Result<(), mpsc::error::TrySendError<M>> {
+        self.sender.try_send(message)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    // Sends the message built around a reply channel and waits at most
+    // `timeout` for the reply, mailbox time included
+    pub async fn ask<R>(
+        &self,
+        message: impl FnOnce(oneshot::Sender<R>) -> M,
+        timeout: Duration,
+    ) -> Result<R, AskError> {
+        let (respond_to, response) = oneshot::channel();
+        let deadline = Instant::now() + timeout;
+        match tokio::time::timeout_at(deadline, self.sender.send(message(respond_to))).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => return Err(AskError::Closed),
+            Err(_) => return Err(AskError::Timeout),
+        }
+        match tokio::time::timeout_at(deadline, response).await {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err(AskError::Dropped),
+            Err(_) => Err(AskError::Timeout),
+        }
+    }
+}
+
+// The receiving end of an address. It outlives the actor task reading it,
+// so a restarted actor picks up the messages queued while it was down.
+type Mailbox<M> = Arc<Mutex<mpsc::Receiver<M>>>;
+
+// Runs `actor` until every address of it is dropped
+pub fn spawn<A: Actor>(actor: A, mailbox_size: usize) -> Addr<A::Message> {
+    let (sender, receiver) = mpsc::channel(mailbox_size);
+    tokio::spawn(run(actor, Arc::new(Mutex::new(receiver))));
+    Addr { sender }
+}
+
+// Like `spawn`, but an actor that panics is replaced by a fresh one from
+// `factory`, reading the same mailbox. The new one starts from scratch, it
+// is up to `on_restart` to have its state rebuilt.
+pub fn supervise<A, F, R>(
+    name: impl Into<String>,
+    mailbox_size: usize,
+    mut factory: F,
+    on_restart: R,
+) -> Addr<A::Message>
+where
+    A: Actor,
+    F: FnMut(&Addr<A::Message>) -> A + Send + 'static,
+    R: Fn() + Send + 'static,
+{
+    let name = name.into();
+    let (sender, receiver) = mpsc::channel(mailbox_size);
+    let mailbox: Mailbox<A::Message> = Arc::new(Mutex::new(receiver));
+    // only a weak handle is kept, so the actor still stops once the
+    // addresses handed out are all dropped
+    let weak = sender.downgrade();
+    let addr = Addr { sender };
+
+    let mut actor = factory(&addr);
+    tokio::spawn(async move {
+        let mut delay = RESTART_DELAY;
+        loop {
+            let started = Instant::now();
+            match tokio::spawn(run(actor, mailbox.clone())).await {
+                Ok(()) => break,
+                Err(e) if e.is_panic() => {
+                    error!("Actor {} crashed, restarting it in {:?}", name, delay);
+                }
+                Err(_) => break,
+            }
+
+            if started.elapsed() >= STABLE_AFTER {
+                delay = RESTART_DELAY;
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RESTART_DELAY);
+
+            let Some(sender) = weak.upgrade() else {
+                break;
+            };
+            actor = factory(&Addr { sender });
+            info!("Actor {} restarted", name);
+            on_restart();
+        }
+    });
+
+    addr
+}
+
+async fn run<A: Actor>(mut actor: A, mailbox: Mailbox<A::Message>) {
+    let mut mailbox = mailbox.lock().await;
+    let mut ticks = actor.tick_interval().map(tokio::time::interval);
+
+    loop {
+        if let Some(message) = actor.next_pending() {
+            actor.handle(message).await;
+            continue;
+        }
+
+        let message = match &mut ticks {
+            Some(ticks) => tokio::select! {
+                message = mailbox.recv() => message,
+                _ = ticks.tick() => {
+                    actor.tick();
+                    continue;
+                }
+            },
+            None => mailbox.recv().await,
+        };
+        match message {
+            Some(message) => actor.handle(message).await,
+            None => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    enum CounterMessage {
+        Add(u32),
+        Get(oneshot::Sender<u32>),
+        Crash,
+        Ignore(#[allow(dead_code)] oneshot::Sender<u32>),
+    }
+
+    struct Counter {
+        total: u32,
+    }
+
+    impl Actor for Counter {
+        type Message = CounterMessage;
+
+        async fn handle(&mut self, message: CounterMessage) {
+            match message {
+                CounterMessage::Add(n) => self.total += n,
+                CounterMessage::Get(respond_to) => {
+                    let _ = respond_to.send(self.total);
+                }
+                CounterMessage::Crash => panic!("crash requested"),
+                CounterMessage::Ignore(_) => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn restarts_a_crashed_actor_behind_the_same_address() {
+        let (restarted, mut restarts) = mpsc::unbounded_channel();
+        let addr = supervise(
+            "counter",
+            8,
+            |_: &Addr<CounterMessage>| Counter { total: 0 },
+            move || {
+                let _ = restarted.send(());
+            },
+        );
+
+        addr.send(CounterMessage::Add(2)).await.unwrap();
+        assert_eq!(addr.ask(CounterMessage::Get, ASK_TIMEOUT).await, Ok(2));
+
+        addr.send(CounterMessage::Crash).await.unwrap();
+        restarts.recv().await.unwrap();
+        // the state went down with the crash, the address did not
+        addr.send(CounterMessage::Add(1)).await.unwrap();
+        assert_eq!(addr.ask(CounterMessage::Get, ASK_TIMEOUT).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn ask_fails_when_the_reply_is_dropped() {
+        let addr = spawn(Counter { total: 0 }, 8);
+        assert_eq!(
+            addr.ask(CounterMessage::Ignore, Duration::from_secs(1))
+                .await,
+            Err(AskError::Dropped)
+        );
+    }
+}
@@ -1,6 +1,13 @@
+pub mod directory;
 pub mod handlers;
+pub mod limits;
+#[cfg(feature = "persistence")]
+pub mod membership;
 pub mod messages;
+pub mod presence;
 pub mod router;
+#[cfg(all(test, feature = "persistence", not(feature = "mongo_db")))]
+mod tests;
 
 pub use messages::*;
 pub use router::*;
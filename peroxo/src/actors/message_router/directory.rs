@@ -0,0 +1,257 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, RwLock};
+
+use super::presence::MAX_PRESENCE_SUBSCRIPTIONS;
+use crate::actors::user_session::outbox::Outbox;
+use crate::actors::user_session::session::ConnectionId;
+use crate::chat::{PresenceStatus, UserPresence};
+use crate::cluster::NodeId;
+use crate::tenant::TenantUserId;
+
+// Lock stripes, so a burst of connects doesn't stall every lookup
+const STRIPES: usize = 16;
+
+pub fn hash_index(key: &impl Hash, count: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % count as u64) as usize
+}
+
+#[derive(Default)]
+struct Stripe {
+    // every connected device of a user, keyed by its connection id
+    local: HashMap<TenantUserId, HashMap<ConnectionId, Outbox>>,
+    // other nodes the user is connected to, as they announced it
+    remote: HashMap<TenantUserId, HashSet<NodeId>>,
+}
+
+// Who is connected where, shared by every router shard and session so a
+// lookup never waits on an actor. Locks are only held for the lookup itself.
+#[derive(Clone)]
+pub struct UserDirectory {
+    stripes: Arc<[RwLock<Stripe>]>,
+}
+
+impl Default for UserDirectory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UserDirectory {
+    pub fn new() -> Self {
+        Self {
+            stripes: (0..STRIPES).map(|_| RwLock::default()).collect(),
+        }
+    }
+
+    fn stripe(&self, user: &TenantUserId) -> &RwLock<Stripe> {
+        &self.stripes[hash_index(user, self.stripes.len())]
+    }
+
+    // Returns how many devices the user has connected now
+    pub fn add_connection(
+        &self,
+        user: &TenantUserId,
+        connection_id: ConnectionId,
+        outbox: Outbox,
+    ) -> usize {
+        let mut stripe = self.stripe(user).write().unwrap();
+        let connections = stripe.local.entry(user.clone()).or_default();
+        connections.insert(connection_id, outbox);
+        connections.len()
+    }
+
+    // Returns how many devices are left, None if the connection was unknown
+    pub fn remove_connection(
+        &self,
+        user: &TenantUserId,
+        connection_id: ConnectionId,
+    ) -> Option<usize> {
+        let mut stripe = self.stripe(user).write().unwrap();
+        let connections = stripe.local.get_mut(user)?;
+        connections.remove(&connection_id)?;
+        let remaining = connections.len();
+        if remaining == 0 {
+            stripe.local.remove(user);
+        }
+        Some(remaining)
+    }
+
+    pub fn connections(&self, user: &TenantUserId) -> Vec<(ConnectionId, Outbox)> {
+        self.stripe(user)
+            .read()
+            .unwrap()
+            .local
+            .get(user)
+            .map(|connections| {
+                connections
+                    .iter()
+                    .map(|(connection_id, outbox)| (*connection_id, outbox.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn outbox(&self, user: &TenantUserId, connection_id: ConnectionId) -> Option<Outbox> {
+        self.stripe(user)
+            .read()
+            .unwrap()
+            .local
+            .get(user)
+            .and_then(|connections| connections.get(&connection_id))
+            .cloned()
+    }
+
+    // Connected to this node
+    pub fn is_local(&self, user: &TenantUserId) -> bool {
+        self.stripe(user).read().unwrap().local.contains_key(user)
+    }
+
+    // Connected to another node of the cluster
+    pub fn is_remote(&self, user: &TenantUserId) -> bool {
+        self.stripe(user).read().unwrap().remote.contains_key(user)
+    }
+
+    // Connected to this node or any other
+    pub fn is_online(&self, user: &TenantUserId) -> bool {
+        let stripe = self.stripe(user).read().unwrap();
+        stripe.local.contains_key(user) || stripe.remote.contains_key(user)
+    }
+
+    pub fn set_remote(&self, user: &TenantUserId, node: NodeId, status: &PresenceStatus) {
+        let mut stripe = self.stripe(user).write().unwrap();
+        match status {
+            PresenceStatus::Online => {
+                stripe.remote.entry(user.clone()).or_default().insert(node);
+            }
+            PresenceStatus::Offline => {
+                if let Some(nodes) = stripe.remote.get_mut(user) {
+                    nodes.remove(&node);
+                    if nodes.is_empty() {
+                        stripe.remote.remove(user);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn local_users(&self) -> Vec<TenantUserId> {
+        self.stripes
+            .iter()
+            .flat_map(|stripe| {
+                stripe
+                    .read()
+                    .unwrap()
+                    .local
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    // Online users of a single project, wherever they are connected
+    pub fn online_users(&self, project_id: &str) -> Vec<TenantUserId> {
+        self.stripes
+            .iter()
+            .flat_map(|stripe| {
+                let stripe = stripe.read().unwrap();
+                stripe
+                    .local
+                    .keys()
+                    .chain(
+                        stripe
+                            .remote
+                            .keys()
+                            .filter(|user| !stripe.local.contains_key(*user)),
+                    )
+                    .filter(|user| user.project_id == project_id)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub fn presence_of(
+        &self,
+        requester: &TenantUserId,
+        targets: Vec<TenantUserId>,
+    ) -> Vec<UserPresence> {
+        targets
+            .into_iter()
+            .filter(|target| requester.same_tenant(target))
+            .take(MAX_PRESENCE_SUBSCRIPTIONS)
+            .map(|target| {
+                let status = if self.is_online(&target) {
+                    PresenceStatus::Online
+                } else {
+                    PresenceStatus::Offline
+                };
+                UserPresence {
+                    user_id: target.user_id,
+                    status,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backpressure::BackpressureConfig;
+
+    fn user(project_id: &str, user_id: &str) -> TenantUserId {
+        TenantUserId::new(project_id.to_string(), user_id.to_string())
+    }
+
+    fn outbox() -> Outbox {
+        Outbox::new(&BackpressureConfig::default()).0
+    }
+
+    #[test]
+    fn counts_the_devices_of_a_user() {
+        let directory = UserDirectory::new();
+        let alice = user("p", "alice");
+        let (phone, laptop) = (ConnectionId::new_v4(), ConnectionId::new_v4());
+
+        assert_eq!(directory.add_connection(&alice, phone, outbox()), 1);
+        assert_eq!(directory.add_connection(&alice, laptop, outbox()), 2);
+        // the same device registering again is still one device
+        assert_eq!(directory.add_connection(&alice, laptop, outbox()), 2);
+        assert_eq!(directory.connections(&alice).len(), 2);
+
+        assert_eq!(directory.remove_connection(&alice, phone), Some(1));
+        assert_eq!(directory.remove_connection(&alice, phone), None);
+        assert!(directory.is_local(&alice));
+        assert!(directory.outbox(&alice, laptop).is_some());
+
+        assert_eq!(directory.remove_connection(&alice, laptop), Some(0));
+        assert!(!directory.is_online(&alice));
+        assert!(directory.local_users().is_empty());
+    }
+
+    #[test]
+    fn online_users_are_listed_per_project() {
+        let directory = UserDirectory::new();
+        directory.add_connection(&user("p", "alice"), ConnectionId::new_v4(), outbox());
+        directory.add_connection(&user("q", "eve"), ConnectionId::new_v4(), outbox());
+        // connected here and on another node, listed once
+        directory.add_connection(&user("p", "bob"), ConnectionId::new_v4(), outbox());
+        directory.set_remote(
+            &user("p", "bob"),
+            "node-2".to_string(),
+            &PresenceStatus::Online,
+        );
+
+        let mut online: Vec<_> = directory
+            .online_users("p")
+            .into_iter()
+            .map(|user| user.user_id)
+            .collect();
+        online.sort();
+        assert_eq!(online, vec!["alice", "bob"]);
+    }
+}
@@ -1,75 +1,525 @@
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info};
 
+#[cfg(feature = "persistence")]
+use super::membership::MembershipCheck;
+use super::messages::RouterMessage;
+use super::presence::MAX_PRESENCE_SUBSCRIPTIONS;
 use super::router::MessageRouter;
-#[cfg(any(feature = "mongo_db", feature = "persistence"))]
-use crate::actors::room_actor::RoomActor;
-use crate::actors::room_actor::RoomMessage;
-use crate::chat::{ChatMessage, MessageAckResponse, MessageStatus};
+use crate::actors::framework::{self, ASK_TIMEOUT, Addr};
+use crate::actors::room_actor::{RoomActor, RoomMessage};
+use crate::actors::user_session::outbox::{Outbox, OutboxError};
+use crate::actors::user_session::session::ConnectionId;
+use crate::chat::{
+    ChatMessage, ErrorCode, MessageAckResponse, MessageStatus, PresenceStatus, TypingTarget,
+};
+use crate::cluster::{ClusterEvent, DeliveryReceipt, NodeId};
+#[cfg(feature = "persistence")]
+use crate::{
+    chat::{MessageTarget, PaginatedRoomMessagesResponse},
+    edits::ChangeError,
+    reactions::MAX_REACTION_BYTES,
+};
 
 #[cfg(any(feature = "mongo_db", feature = "persistence"))]
 use crate::chat::PaginatedMessagesResponse;
 
-use crate::tenant::TenantUserId;
+use crate::metrics::Metrics;
+use crate::tenant::{TenantRoomId, TenantUserId};
+
+// Kept below the session chat queue so a page fits without overflowing it
+#[cfg(feature = "persistence")]
+const PENDING_REPLAY_BATCH: i32 = 50;
 
 impl MessageRouter {
     pub async fn handle_register_user(
         &mut self,
         tenant_user_id: TenantUserId,
-        sender: mpsc::Sender<ChatMessage>,
+        connection_id: ConnectionId,
+        outbox: Outbox,
         respond_to: oneshot::Sender<Result<(), String>>,
     ) {
-        if self.users.contains_key(&tenant_user_id) {
-            let _ = respond_to.send(Err("User already online".to_string()));
-            return;
+        let active = self
+            .directory
+            .add_connection(&tenant_user_id, connection_id, outbox.clone());
+        let first_connection = active == 1;
+
+        debug!(
+            "User {} registered connection {} ({} active)",
+            tenant_user_id, connection_id, active
+        );
+
+        if first_connection {
+            if !self.directory.is_remote(&tenant_user_id) {
+                self.broadcast_presence(&tenant_user_id, PresenceStatus::Online);
+            }
+            self.announce(&tenant_user_id, PresenceStatus::Online);
         }
-        // clone?????
-        self.users.insert(tenant_user_id.clone(), sender);
-        self.online_users.push(tenant_user_id.clone());
 
-        debug!("User {} registered successfully", tenant_user_id);
+        // a device that connects later still receives the user's rooms
+        if let Some(rooms) = self.user_rooms.get(&tenant_user_id) {
+            for room_id in rooms {
+                if let Some(room_sender) = self.rooms.get(room_id) {
+                    Self::send_to_room(
+                        room_sender,
+                        RoomMessage::AddMember {
+                            tenant_user_id: tenant_user_id.clone(),
+                            connections: vec![(connection_id, outbox.clone())],
+                            respond_to: None,
+                        },
+                    );
+                }
+            }
+        }
 
         let _ = respond_to.send(Ok(()));
+
+        #[cfg(feature = "persistence")]
+        self.replay_pending(tenant_user_id, connection_id, None, first_connection);
     }
-    // must be a better way
-    pub async fn handle_unregister_user(&mut self, tenant_user_id: TenantUserId) {
-        if self.users.remove(&tenant_user_id).is_some() {
-            self.online_users.retain(|u| u != &tenant_user_id);
+
+    pub async fn handle_unregister_user(
+        &mut self,
+        tenant_user_id: TenantUserId,
+        connection_id: ConnectionId,
+    ) {
+        let Some(remaining) = self
+            .directory
+            .remove_connection(&tenant_user_id, connection_id)
+        else {
+            return;
+        };
+        let last_connection = remaining == 0;
+
+        #[cfg(feature = "persistence")]
+        self.replay_cursors.remove(&connection_id);
+
+        if let Some(rooms) = self.user_rooms.get(&tenant_user_id) {
+            for room_id in rooms {
+                if let Some(room_sender) = self.rooms.get(room_id) {
+                    Self::send_to_room(
+                        room_sender,
+                        RoomMessage::RemoveMember {
+                            tenant_user_id: tenant_user_id.clone(),
+                            connection_id: Some(connection_id),
+                        },
+                    );
+                }
+            }
+        }
+
+        if last_connection {
+            self.user_rooms.remove(&tenant_user_id);
+            self.presence.remove_subscriber(&tenant_user_id);
+            if !self.directory.is_remote(&tenant_user_id) {
+                self.broadcast_presence(&tenant_user_id, PresenceStatus::Offline);
+            }
+            self.announce(&tenant_user_id, PresenceStatus::Offline);
+            debug!("User {} went offline", tenant_user_id);
         }
     }
 
-    pub async fn handle_direct_message(
+    fn announce(&self, user: &TenantUserId, status: PresenceStatus) {
+        let Some(announcements) = &self.announcements else {
+            return;
+        };
+        if let Err(mpsc::error::TrySendError::Full(announcement)) =
+            announcements.try_send((user.clone(), status))
+        {
+            let announcements = announcements.clone();
+            tokio::spawn(async move {
+                let _ = announcements.send(announcement).await;
+            });
+        }
+    }
+
+    fn broadcast_presence(&self, user: &TenantUserId, status: PresenceStatus) {
+        let message = ChatMessage::Presence {
+            user: user.clone(),
+            status,
+        };
+        for watcher in self.presence.watchers_of(user) {
+            self.deliver_to_user(&watcher, message.clone(), None);
+        }
+    }
+
+    pub fn handle_subscribe_presence(
+        &mut self,
+        subscriber: TenantUserId,
+        connection_id: ConnectionId,
+        targets: Vec<TenantUserId>,
+        conversation_partners: bool,
+        request_id: Option<String>,
+    ) {
+        // a subscription only lives as long as the subscriber is online
+        if !self.directory.is_local(&subscriber) {
+            return;
+        }
+
+        // users of other projects are left out like the subscriber itself,
+        // without counting against the limit
+        let targets: Vec<_> = targets
+            .into_iter()
+            .filter(|target| *target != subscriber && subscriber.same_tenant(target))
+            .collect();
+        let requested = targets.len();
+        let accepted = self.presence.subscribe(&subscriber, targets);
+        if accepted.len() < requested {
+            self.deliver_to_connection(
+                &subscriber,
+                connection_id,
+                ChatMessage::Error {
+                    code: ErrorCode::LimitExceeded,
+                    message: format!(
+                        "Presence subscriptions are limited to {} users",
+                        MAX_PRESENCE_SUBSCRIPTIONS
+                    ),
+                    request_id: request_id.clone(),
+                },
+            );
+        }
+        if !accepted.is_empty() {
+            let users = self.directory.presence_of(&subscriber, accepted);
+            self.deliver_to_connection(
+                &subscriber,
+                connection_id,
+                ChatMessage::PresenceSnapshot {
+                    users,
+                    request_id: request_id.clone(),
+                },
+            );
+        }
+
+        #[cfg(feature = "persistence")]
+        if conversation_partners && let Some(persistence) = self.persistence.clone() {
+            let self_sender = self.self_sender.clone();
+            tokio::spawn(async move {
+                match persistence
+                    .handle_fetch_conversation_partners(subscriber.clone())
+                    .await
+                {
+                    Ok(partners) => {
+                        let targets = partners
+                            .into_iter()
+                            .map(|user_id| subscriber.peer(user_id))
+                            .collect();
+                        let _ = self_sender
+                            .send(RouterMessage::SubscribePresence {
+                                subscriber,
+                                connection_id,
+                                targets,
+                                conversation_partners: false,
+                                // the partners' snapshot answers the same request
+                                request_id,
+                            })
+                            .await;
+                    }
+                    Err(e) => {
+                        error!(
+                            "Failed to fetch conversation partners of {}: {}",
+                            subscriber, e
+                        );
+                    }
+                }
+            });
+        }
+        #[cfg(not(feature = "persistence"))]
+        let _ = (conversation_partners, request_id);
+    }
+
+    // Fans a message out to every device of `user`, skipping `except`.
+    // Returns how many devices accepted it.
+    pub fn deliver_to_user(
+        &self,
+        user: &TenantUserId,
+        message: ChatMessage,
+        except: Option<ConnectionId>,
+    ) -> usize {
+        let mut delivered = 0;
+        for (connection_id, outbox) in self.directory.connections(user) {
+            if Some(connection_id) == except {
+                continue;
+            }
+            match outbox.push(message.clone()) {
+                Ok(()) => {
+                    delivered += 1;
+                }
+                Err(OutboxError::Full) => {
+                    debug!(
+                        "Connection {} of {} message queue is full, dropping message",
+                        connection_id, user
+                    );
+                }
+                Err(OutboxError::Closed) => {
+                    debug!("Connection {} of {} channel is closed", connection_id, user);
+                }
+            }
+        }
+        delivered
+    }
+
+    pub fn deliver_to_connection(
+        &self,
+        user: &TenantUserId,
+        connection_id: ConnectionId,
+        message: ChatMessage,
+    ) -> bool {
+        let Some(outbox) = self.directory.outbox(user, connection_id) else {
+            return false;
+        };
+        match outbox.push(message) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!(
+                    "Failed to deliver to connection {} of {}: {:?}",
+                    connection_id, user, e
+                );
+                false
+            }
+        }
+    }
+
+    // Sends `message` to the devices of `user` connected to other nodes
+    fn forward_to_user(
         &self,
+        user: &TenantUserId,
+        message: ChatMessage,
+        receipt: Option<Box<DeliveryReceipt>>,
+    ) {
+        let Some(cluster) = self.cluster.clone() else {
+            return;
+        };
+        let user = user.clone();
+
+        tokio::spawn(async move {
+            let nodes = match cluster.locate_user(&user).await {
+                Ok(nodes) => nodes,
+                Err(e) => {
+                    error!("Failed to locate {}: {}", user, e);
+                    return;
+                }
+            };
+            for node in nodes.iter().filter(|node| *node != cluster.node_id()) {
+                let event = ClusterEvent::Deliver {
+                    user: user.clone(),
+                    connection_id: None,
+                    message: message.clone(),
+                    receipt: receipt.clone(),
+                };
+                if let Err(e) = cluster.send_to_node(node, event).await {
+                    error!("Failed to forward to {} on node {}: {}", user, node, e);
+                }
+            }
+        });
+    }
+
+    // deliver_to_user, plus the user's devices on other nodes
+    pub fn deliver_everywhere(
+        &self,
+        user: &TenantUserId,
+        message: ChatMessage,
+        except: Option<ConnectionId>,
+    ) {
+        self.deliver_to_user(user, message.clone(), except);
+        self.forward_to_user(user, message, None);
+    }
+
+    pub fn handle_cluster_event(&mut self, origin: NodeId, event: ClusterEvent) {
+        match event {
+            ClusterEvent::Deliver {
+                user,
+                connection_id,
+                message,
+                receipt,
+            } => {
+                let delivered = match connection_id {
+                    Some(connection_id) => {
+                        self.deliver_to_connection(&user, connection_id, message)
+                    }
+                    None => self.deliver_to_user(&user, message, None) > 0,
+                };
+                if delivered
+                    && let Some(receipt) = receipt
+                    && let Some(cluster) = self.cluster.clone()
+                {
+                    tokio::spawn(async move {
+                        let event = ClusterEvent::Deliver {
+                            user: receipt.user,
+                            connection_id: Some(receipt.connection_id),
+                            message: receipt.ack,
+                            receipt: None,
+                        };
+                        let _ = cluster.send_to_node(&receipt.node, event).await;
+                    });
+                }
+            }
+            ClusterEvent::Room {
+                room_id,
+                message,
+                skip,
+            } => {
+                if let Some(room_sender) = self.rooms.get(&room_id) {
+                    Self::send_to_room(room_sender, RoomMessage::Relay { message, skip });
+                }
+            }
+            ClusterEvent::Presence { user, status } => {
+                let was_online = self.directory.is_online(&user);
+                self.directory.set_remote(&user, origin, &status);
+                if self.directory.is_online(&user) != was_online {
+                    self.broadcast_presence(&user, status);
+                }
+            }
+            ClusterEvent::NodeStarted => {
+                let Some(cluster) = self.cluster.clone() else {
+                    return;
+                };
+                // only one shard gets this, the directory covers all of them
+                let users = self.directory.local_users();
+                tokio::spawn(async move {
+                    for user in users {
+                        let event = ClusterEvent::Presence {
+                            user,
+                            status: PresenceStatus::Online,
+                        };
+                        if cluster.send_to_node(&origin, event).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    // The router never waits on its own mailbox, a full one hands the
+    // message to a task instead
+    pub fn requeue(&self, message: RouterMessage) {
+        if let Err(mpsc::error::TrySendError::Full(message)) = self.self_sender.try_send(message) {
+            let self_sender = self.self_sender.clone();
+            tokio::spawn(async move {
+                let _ = self_sender.send(message).await;
+            });
+        }
+    }
+
+    // Hands a message to a room without blocking the router. When the room
+    // mailbox is full chat messages are failed back to the sender, typing
+    // updates are dropped and membership changes wait in a task.
+    // Returns false if the room is gone.
+    pub fn send_to_room(room_sender: &Addr<RoomMessage>, message: RoomMessage) -> bool {
+        let message = match room_sender.try_send(message) {
+            Ok(()) => return true,
+            Err(mpsc::error::TrySendError::Closed(_)) => return false,
+            Err(mpsc::error::TrySendError::Full(message)) => message,
+        };
+
+        match message {
+            RoomMessage::SendMessage {
+                message_id,
+                respond_to,
+                ..
+            } => {
+                Metrics::message_dropped("room", "chat", 1);
+                if let Some(responder) = respond_to {
+                    let _ = responder.send(MessageAckResponse {
+                        message_id,
+                        timestamp: chrono::Utc::now().timestamp_millis(),
+                        status: MessageStatus::Failed("Room is overloaded".to_string()),
+                    });
+                }
+            }
+            RoomMessage::Typing { .. } => {
+                Metrics::message_dropped("room", "ephemeral", 1);
+            }
+            message => {
+                let room_sender = room_sender.clone();
+                tokio::spawn(async move {
+                    let _ = room_sender.send(message).await;
+                });
+            }
+        }
+        true
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn handle_direct_message(
+        &mut self,
         conversation_id: String,
         from: TenantUserId,
+        connection_id: ConnectionId,
         to: TenantUserId,
         content: String,
         message_id: uuid::Uuid,
+        client_message_id: uuid::Uuid,
+        request_id: Option<String>,
         #[cfg(any(feature = "mongo_db", feature = "persistence"))] respond_to: Option<
             oneshot::Sender<MessageAckResponse>,
         >,
     ) {
+        // the one check between projects, whoever queued the message
+        if !from.same_tenant(&to) {
+            error!(
+                "Rejected cross-tenant direct message from {} to {}",
+                from, to
+            );
+            #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+            if let Some(responder) = respond_to {
+                let _ = responder.send(MessageAckResponse {
+                    message_id,
+                    timestamp: chrono::Utc::now().timestamp_millis(),
+                    status: MessageStatus::Failed("Cross-tenant recipient".to_string()),
+                });
+            }
+            return;
+        }
+
+        #[cfg(feature = "persistence")]
+        {
+            let check = self.conversations.check(&from, &conversation_id, Some(&to));
+            if !matches!(check, MembershipCheck::Allowed) {
+                let project_id = from.project_id.clone();
+                let pending_conversation_id = conversation_id.clone();
+                self.reject_or_resolve(
+                    check,
+                    project_id,
+                    pending_conversation_id,
+                    RouterMessage::SendDirectMessage {
+                        conversation_id,
+                        from,
+                        connection_id,
+                        to,
+                        content,
+                        message_id,
+                        client_message_id,
+                        request_id,
+                        respond_to,
+                    },
+                );
+                return;
+            }
+        }
+
         #[cfg(any(feature = "mongo_db", feature = "persistence"))]
         {
             if let Some(persistence) = &self.persistence {
-                let persistence = persistence.clone();
                 let from_clone = from.clone();
                 let to_clone = to.clone();
                 let content_clone = content.clone();
                 let timestamp = chrono::Utc::now().timestamp_millis();
 
+                let conversation_id = conversation_id.clone();
+
                 if let Some(responder) = respond_to {
+                    // queued here, so the conversation keeps the order of the mailbox
+                    let write = persistence.handle_persist_direct_message(
+                        conversation_id,
+                        from_clone,
+                        to_clone,
+                        content_clone,
+                        message_id,
+                        timestamp,
+                    );
                     tokio::spawn(async move {
-                        let result = persistence
-                            .handle_persist_direct_message(
-                                conversation_id,
-                                from_clone,
-                                to_clone,
-                                content_clone,
-                                message_id,
-                                timestamp,
-                            )
-                            .await;
+                        let result = write.await;
 
                         crate::metrics::Metrics::websocket_message_persisted();
 
@@ -94,44 +544,76 @@ impl MessageRouter {
             }
         }
 
-        if let Some(recipient_sender) = self.users.get(&to) {
-            let to_clone = to;
-            let message = ChatMessage::DirectMessage {
-                from,
-                content,
-                server_message_id: message_id,
-                timestamp: chrono::Utc::now().timestamp_millis(),
-            };
+        let message = ChatMessage::DirectMessage {
+            conversation_id,
+            from: from.clone(),
+            to: to.clone(),
+            content,
+            server_message_id: message_id,
+            timestamp: chrono::Utc::now().timestamp_millis(),
+        };
 
-            match recipient_sender.try_send(message) {
-                Ok(()) => {
-                    debug!("Message sent successfully to {}", to_clone);
-                }
-                Err(mpsc::error::TrySendError::Full(_)) => {
-                    debug!(
-                        "Recipient {} message queue is full, dropping message",
-                        to_clone
-                    );
-                }
-                Err(mpsc::error::TrySendError::Closed(_)) => {
-                    debug!("Recipient {} channel is closed", to_clone);
-                }
-            }
+        let ack = ChatMessage::MessageAck {
+            client_message_id,
+            message_id,
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            status: MessageStatus::Delivered,
+            request_id,
+        };
+        let mut receipt = None;
+        if self.deliver_to_user(&to, message.clone(), None) > 0 {
+            debug!("Message sent successfully to {}", to);
+            // at least one of the recipient's sessions accepted it
+            self.deliver_to_connection(&from, connection_id, ack);
+        } else if let Some(cluster) = &self.cluster {
+            // acked by whichever node the recipient is connected to
+            receipt = Some(Box::new(DeliveryReceipt {
+                node: cluster.node_id().to_string(),
+                user: from.clone(),
+                connection_id,
+                ack,
+            }));
         } else {
             debug!("User {} not found or offline", to);
         }
+        self.forward_to_user(&to, message.clone(), receipt);
+
+        // keep the sender's other devices in sync
+        self.deliver_everywhere(&from, message, Some(connection_id));
     }
 
     #[cfg(any(feature = "mongo_db", feature = "persistence"))]
     pub async fn handle_get_paginated_chat_history(
-        &self,
-        project_id: String,
+        &mut self,
+        requester: TenantUserId,
         message_id: Option<uuid::Uuid>,
         conversation_id: String,
         respond_to: oneshot::Sender<Result<PaginatedMessagesResponse, String>>,
     ) {
+        #[cfg(feature = "persistence")]
+        {
+            let check = self.conversations.check(&requester, &conversation_id, None);
+            if !matches!(check, MembershipCheck::Allowed) {
+                let project_id = requester.project_id.clone();
+                let pending_conversation_id = conversation_id.clone();
+                self.reject_or_resolve(
+                    check,
+                    project_id,
+                    pending_conversation_id,
+                    RouterMessage::GetPaginatedMessages {
+                        requester,
+                        message_id,
+                        conversation_id,
+                        respond_to,
+                    },
+                );
+                return;
+            }
+        }
+
         if let Some(persistence) = &self.persistence {
             let persistence = persistence.clone();
+            let project_id = requester.project_id;
 
             tokio::spawn(async move {
                 let result = persistence
@@ -145,26 +627,57 @@ impl MessageRouter {
     pub async fn handle_join_room(
         &mut self,
         tenant_user_id: TenantUserId,
-        room_id: String,
-        sender: mpsc::Sender<ChatMessage>,
+        room_id: TenantRoomId,
+        respond_to: oneshot::Sender<Result<(), String>>,
+    ) {
+        #[cfg(feature = "persistence")]
+        let respond_to = {
+            let (joined_to, joined) = oneshot::channel();
+            let persistence = self.persistence.clone();
+            let (room_id, tenant_user_id) = (room_id.clone(), tenant_user_id.clone());
+            tokio::spawn(async move {
+                let result = joined
+                    .await
+                    .unwrap_or_else(|_| Err("Room response timeout".to_string()));
+                let confirmed = result.is_ok();
+                let _ = respond_to.send(result);
+
+                // remembered across reconnects, room messages are queued for members
+                if confirmed && let Some(persistence) = persistence {
+                    let _ = persistence
+                        .handle_room_membership(room_id, tenant_user_id, true)
+                        .await;
+                }
+            });
+            joined_to
+        };
+
+        self.join_room(tenant_user_id, room_id, respond_to);
+    }
+
+    // Adds every connected device of the user to the live room. The room is
+    // only kept for the user once the room actor confirms.
+    fn join_room(
+        &mut self,
+        tenant_user_id: TenantUserId,
+        room_id: TenantRoomId,
         respond_to: oneshot::Sender<Result<(), String>>,
     ) {
+        if !room_id.same_tenant(&tenant_user_id) {
+            let _ = respond_to.send(Err("Room belongs to another project".to_string()));
+            return;
+        }
+
+        let connections = self.directory.connections(&tenant_user_id);
+        if connections.is_empty() {
+            let _ = respond_to.send(Err("User is not online".to_string()));
+            return;
+        }
+
         let room_sender = if let Some(sender) = self.rooms.get(&room_id) {
             sender.clone()
         } else {
-            #[cfg(any(feature = "mongo_db", feature = "persistence"))]
-            let (room_actor, room_sender) = RoomActor::new(
-                room_id.clone(),
-                self.persistence.as_ref().unwrap().clone(),
-            );
-
-            #[cfg(not(any(feature = "mongo_db", feature = "persistence")))]
-            let (room_actor, room_sender) = {
-                use crate::actors::room_actor::RoomActor;
-                RoomActor::new(room_id.clone())
-            };
-
-            tokio::spawn(room_actor.run());
+            let room_sender = self.spawn_room(room_id.clone());
             self.rooms.insert(room_id.clone(), room_sender.clone());
             info!("Created new room actor for room {}", room_id);
             room_sender
@@ -172,43 +685,185 @@ impl MessageRouter {
 
         let (room_respond_to, room_response) = oneshot::channel();
         let room_msg = RoomMessage::AddMember {
-            tenant_user_id,
-            sender,
-            respond_to: room_respond_to,
+            tenant_user_id: tenant_user_id.clone(),
+            connections,
+            respond_to: Some(room_respond_to),
         };
 
-        if room_sender.send(room_msg).is_err() {
+        if !Self::send_to_room(&room_sender, room_msg) {
             let _ = respond_to.send(Err("Failed to communicate with room".to_string()));
             return;
         }
 
+        // kept meanwhile, so a room restarted before answering is refilled
+        // with the user too
+        self.user_rooms
+            .entry(tenant_user_id.clone())
+            .or_default()
+            .insert(room_id.clone());
+
+        let router = self.self_sender.clone();
         tokio::spawn(async move {
-            match room_response.await {
-                Ok(result) => {
-                    let _ = respond_to.send(result);
-                }
-                Err(_) => {
-                    let _ = respond_to.send(Err("Room response timeout".to_string()));
-                }
+            let result = match tokio::time::timeout(ASK_TIMEOUT, room_response).await {
+                Ok(Ok(result)) => result,
+                _ => Err("Room response timeout".to_string()),
+            };
+            if result.is_err() {
+                let _ = router
+                    .send(RouterMessage::RoomJoinFailed {
+                        tenant_user_id,
+                        room_id,
+                    })
+                    .await;
             }
+            let _ = respond_to.send(result);
         });
     }
 
-    pub async fn handle_leave_room(&mut self, tenant_user_id: TenantUserId, room_id: String) {
+    pub fn handle_room_join_failed(&mut self, tenant_user_id: TenantUserId, room_id: TenantRoomId) {
+        if let Some(rooms) = self.user_rooms.get_mut(&tenant_user_id) {
+            rooms.remove(&room_id);
+        }
+        // in case the room added the user after giving up on it
+        if let Some(room_sender) = self.rooms.get(&room_id) {
+            Self::send_to_room(
+                room_sender,
+                RoomMessage::RemoveMember {
+                    tenant_user_id,
+                    connection_id: None,
+                },
+            );
+        }
+    }
+
+    // A room actor that crashes is replaced by an empty one, which the
+    // router then fills with the members it knows about
+    fn spawn_room(&self, room_id: TenantRoomId) -> Addr<RoomMessage> {
+        let factory = {
+            let room_id = room_id.clone();
+            let cluster = self.cluster.clone();
+            #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+            let persistence = self.persistence.as_ref().unwrap().clone();
+            move |_: &Addr<RoomMessage>| {
+                RoomActor::new(
+                    room_id.clone(),
+                    cluster.clone(),
+                    #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+                    persistence.clone(),
+                )
+            }
+        };
+        let on_restart = {
+            let router = self.self_sender.clone();
+            let room_id = room_id.clone();
+            move || {
+                let router = router.clone();
+                let room_id = room_id.clone();
+                tokio::spawn(async move {
+                    let _ = router.send(RouterMessage::RoomRestarted { room_id }).await;
+                });
+            }
+        };
+
+        framework::supervise(
+            format!("room {}", room_id),
+            self.backpressure.room_mailbox,
+            factory,
+            on_restart,
+        )
+    }
+
+    pub fn handle_room_restarted(&self, room_id: TenantRoomId) {
+        let Some(room_sender) = self.rooms.get(&room_id) else {
+            return;
+        };
+
+        for (tenant_user_id, rooms) in &self.user_rooms {
+            if !rooms.contains(&room_id) {
+                continue;
+            }
+            let connections = self.directory.connections(tenant_user_id);
+            if connections.is_empty() {
+                continue;
+            }
+            Self::send_to_room(
+                room_sender,
+                RoomMessage::AddMember {
+                    tenant_user_id: tenant_user_id.clone(),
+                    connections,
+                    respond_to: None,
+                },
+            );
+        }
+    }
+
+    pub async fn handle_leave_room(&mut self, tenant_user_id: TenantUserId, room_id: TenantRoomId) {
+        if !room_id.same_tenant(&tenant_user_id) {
+            return;
+        }
+
+        if let Some(rooms) = self.user_rooms.get_mut(&tenant_user_id) {
+            rooms.remove(&room_id);
+        }
+
+        #[cfg(feature = "persistence")]
+        if let Some(persistence) = self.persistence.clone() {
+            let room_id = room_id.clone();
+            let tenant_user_id = tenant_user_id.clone();
+            tokio::spawn(async move {
+                let _ = persistence
+                    .handle_room_membership(room_id, tenant_user_id, false)
+                    .await;
+            });
+        }
+
         if let Some(room_sender) = self.rooms.get(&room_id) {
-            let room_msg = RoomMessage::RemoveMember { tenant_user_id };
-            let _ = room_sender.send(room_msg);
+            let room_msg = RoomMessage::RemoveMember {
+                tenant_user_id,
+                connection_id: None,
+            };
+            Self::send_to_room(room_sender, room_msg);
         }
     }
 
     pub async fn handle_room_message(
         &self,
-        room_id: String,
+        room_id: TenantRoomId,
         from: TenantUserId,
         content: String,
         message_id: uuid::Uuid,
         respond_to: Option<oneshot::Sender<MessageAckResponse>>,
     ) {
+        if !room_id.same_tenant(&from) {
+            error!(
+                "Rejected cross-tenant message from {} to room {}",
+                from, room_id
+            );
+            if let Some(responder) = respond_to {
+                let _ = responder.send(MessageAckResponse {
+                    message_id,
+                    timestamp: chrono::Utc::now().timestamp_millis(),
+                    status: MessageStatus::Failed("Room belongs to another project".to_string()),
+                });
+            }
+            return;
+        }
+
+        if !self.is_room_member(&from, &room_id) {
+            debug!(
+                "Rejected message from {} to {}, not a member",
+                from, room_id
+            );
+            if let Some(responder) = respond_to {
+                let _ = responder.send(MessageAckResponse {
+                    message_id,
+                    timestamp: chrono::Utc::now().timestamp_millis(),
+                    status: MessageStatus::Failed("Not a member of the room".to_string()),
+                });
+            }
+            return;
+        }
+
         if let Some(room_sender) = self.rooms.get(&room_id) {
             let room_msg = RoomMessage::SendMessage {
                 from,
@@ -216,7 +871,7 @@ impl MessageRouter {
                 message_id,
                 respond_to,
             };
-            if room_sender.send(room_msg).is_err() {
+            if !Self::send_to_room(room_sender, room_msg) {
                 error!("Failed to send message to room {}", room_id);
             }
         } else {
@@ -231,9 +886,55 @@ impl MessageRouter {
         }
     }
 
+    pub fn handle_typing(&mut self, from: TenantUserId, target: TypingTarget, typing: bool) {
+        match &target {
+            TypingTarget::Conversation {
+                conversation_id,
+                to,
+            } => {
+                let to = from.peer(to.clone());
+
+                #[cfg(feature = "persistence")]
+                {
+                    let check = self.conversations.check(&from, conversation_id, Some(&to));
+                    if !matches!(check, MembershipCheck::Allowed) {
+                        let project_id = from.project_id.clone();
+                        let conversation_id = conversation_id.clone();
+                        self.reject_or_resolve(
+                            check,
+                            project_id,
+                            conversation_id,
+                            RouterMessage::Typing {
+                                from,
+                                target,
+                                typing,
+                            },
+                        );
+                        return;
+                    }
+                }
+                #[cfg(not(feature = "persistence"))]
+                let _ = conversation_id;
+
+                let message = ChatMessage::UserTyping {
+                    from,
+                    target,
+                    typing,
+                };
+                self.deliver_everywhere(&to, message, None);
+            }
+            TypingTarget::Room { room_id } => {
+                let room_id = from.room(room_id.clone());
+                if let Some(room_sender) = self.rooms.get(&room_id) {
+                    Self::send_to_room(room_sender, RoomMessage::Typing { from, typing });
+                }
+            }
+        }
+    }
+
     pub async fn handle_get_room_members(
         &self,
-        room_id: String,
+        room_id: TenantRoomId,
         respond_to: oneshot::Sender<Option<Vec<TenantUserId>>>,
     ) {
         if let Some(room_sender) = self.rooms.get(&room_id) {
@@ -242,17 +943,17 @@ impl MessageRouter {
                 respond_to: room_respond_to,
             };
 
-            if room_sender.send(room_msg).is_err() {
+            if !Self::send_to_room(room_sender, room_msg) {
                 let _ = respond_to.send(None);
                 return;
             }
 
             tokio::spawn(async move {
-                match room_response.await {
-                    Ok(members) => {
+                match tokio::time::timeout(ASK_TIMEOUT, room_response).await {
+                    Ok(Ok(members)) => {
                         let _ = respond_to.send(Some(members));
                     }
-                    Err(_) => {
+                    _ => {
                         let _ = respond_to.send(None);
                     }
                 }
@@ -264,14 +965,33 @@ impl MessageRouter {
 
     #[cfg(feature = "persistence")]
     pub async fn handle_sync_messages(
-        &self,
-        project_id: String,
+        &mut self,
+        requester: TenantUserId,
         conversation_id: String,
         message_id: uuid::Uuid,
         respond_to: oneshot::Sender<Result<Vec<crate::chat::ResponseDirectMessage>, String>>,
     ) {
+        let check = self.conversations.check(&requester, &conversation_id, None);
+        if !matches!(check, MembershipCheck::Allowed) {
+            let project_id = requester.project_id.clone();
+            let pending_conversation_id = conversation_id.clone();
+            self.reject_or_resolve(
+                check,
+                project_id,
+                pending_conversation_id,
+                RouterMessage::SyncMessages {
+                    requester,
+                    conversation_id,
+                    message_id,
+                    respond_to,
+                },
+            );
+            return;
+        }
+
         if let Some(persistence) = &self.persistence {
             let persistence = persistence.clone();
+            let project_id = requester.project_id;
 
             tokio::spawn(async move {
                 let result = persistence
@@ -283,4 +1003,637 @@ impl MessageRouter {
             let _ = respond_to.send(Err("Persistence not available".to_string()));
         }
     }
+
+    #[cfg(feature = "persistence")]
+    pub fn handle_mark_read(
+        &mut self,
+        reader: TenantUserId,
+        connection_id: ConnectionId,
+        conversation_id: String,
+        up_to_message_id: uuid::Uuid,
+    ) {
+        let check = self.conversations.check(&reader, &conversation_id, None);
+        if !matches!(check, MembershipCheck::Allowed) {
+            let project_id = reader.project_id.clone();
+            let pending_conversation_id = conversation_id.clone();
+            self.reject_or_resolve(
+                check,
+                project_id,
+                pending_conversation_id,
+                RouterMessage::MarkRead {
+                    reader,
+                    connection_id,
+                    conversation_id,
+                    up_to_message_id,
+                },
+            );
+            return;
+        }
+
+        let timestamp = chrono::Utc::now().timestamp_millis();
+
+        if let Some(persistence) = self.persistence.clone() {
+            let reader = reader.clone();
+            let conversation_id = conversation_id.clone();
+            tokio::spawn(async move {
+                if let Err(e) = persistence
+                    .handle_update_read_cursor(reader, conversation_id, up_to_message_id, timestamp)
+                    .await
+                {
+                    error!("Failed to store read cursor: {}", e);
+                }
+            });
+        }
+
+        let receipt = ChatMessage::ReadReceipt {
+            conversation_id: conversation_id.clone(),
+            reader: reader.clone(),
+            up_to_message_id,
+            timestamp,
+        };
+        if let Some(peer) = self.conversations.peer_of(&reader, &conversation_id) {
+            self.deliver_everywhere(&peer, receipt.clone(), None);
+        }
+        self.deliver_everywhere(&reader, receipt, Some(connection_id));
+    }
+
+    #[cfg(feature = "persistence")]
+    pub fn handle_get_room_history(
+        &self,
+        requester: TenantUserId,
+        room_id: String,
+        message_id: Option<uuid::Uuid>,
+        respond_to: oneshot::Sender<Result<PaginatedRoomMessagesResponse, String>>,
+    ) {
+        let room_id = requester.room(room_id);
+        if !self.is_room_member(&requester, &room_id) {
+            let _ = respond_to.send(Err("Not a member of the room".to_string()));
+            return;
+        }
+
+        let Some(persistence) = self.persistence.clone() else {
+            let _ = respond_to.send(Err("Persistence not available".to_string()));
+            return;
+        };
+        tokio::spawn(async move {
+            let result = persistence
+                .handle_get_paginated_room_messages(room_id, message_id)
+                .await;
+            let _ = respond_to.send(result);
+        });
+    }
+
+    // Whether the user joined the room, on any of their devices
+    pub fn is_room_member(&self, tenant_user_id: &TenantUserId, room_id: &TenantRoomId) -> bool {
+        self.user_rooms
+            .get(tenant_user_id)
+            .is_some_and(|rooms| rooms.contains(room_id))
+    }
+
+    #[cfg(feature = "persistence")]
+    pub fn handle_get_read_cursors(
+        &mut self,
+        requester: TenantUserId,
+        conversation_id: String,
+        respond_to: oneshot::Sender<Result<Vec<crate::chat::ReadCursor>, String>>,
+    ) {
+        let check = self.conversations.check(&requester, &conversation_id, None);
+        if !matches!(check, MembershipCheck::Allowed) {
+            let project_id = requester.project_id.clone();
+            let pending_conversation_id = conversation_id.clone();
+            self.reject_or_resolve(
+                check,
+                project_id,
+                pending_conversation_id,
+                RouterMessage::GetReadCursors {
+                    requester,
+                    conversation_id,
+                    respond_to,
+                },
+            );
+            return;
+        }
+
+        if let Some(persistence) = self.persistence.clone() {
+            tokio::spawn(async move {
+                let result = persistence
+                    .handle_get_read_cursors(requester.project_id, conversation_id)
+                    .await;
+                let _ = respond_to.send(result);
+            });
+        } else {
+            let _ = respond_to.send(Err("Persistence not available".to_string()));
+        }
+    }
+
+    #[cfg(feature = "persistence")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn handle_change_message(
+        &mut self,
+        editor: TenantUserId,
+        connection_id: ConnectionId,
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+        content: Option<String>,
+        request_id: Option<String>,
+        respond_to: oneshot::Sender<Result<ChatMessage, ChangeError>>,
+    ) {
+        // only members of the room or the two users of the conversation,
+        // chat-service refuses anyone but the sender on top of that
+        if let MessageTarget::Room { room_id } = &target
+            && !self.is_room_member(&editor, &editor.room(room_id.clone()))
+        {
+            let _ = respond_to.send(Err(ChangeError::new(
+                ErrorCode::Forbidden,
+                "Not a member of the room",
+            )));
+            return;
+        }
+        if let MessageTarget::Conversation { conversation_id } = &target {
+            let check = self.conversations.check(&editor, conversation_id, None);
+            if !matches!(check, MembershipCheck::Allowed) {
+                let project_id = editor.project_id.clone();
+                let pending_conversation_id = conversation_id.clone();
+                self.reject_or_resolve(
+                    check,
+                    project_id,
+                    pending_conversation_id,
+                    RouterMessage::ChangeMessage {
+                        editor,
+                        connection_id,
+                        target,
+                        message_id,
+                        content,
+                        request_id,
+                        respond_to,
+                    },
+                );
+                return;
+            }
+        }
+
+        let Some(persistence) = self.persistence.clone() else {
+            let _ = respond_to.send(Err(ChangeError::failed("Persistence not available")));
+            return;
+        };
+        let window = self.edits.window_for(&editor.project_id);
+        let self_sender = self.self_sender.clone();
+        let timestamp = chrono::Utc::now().timestamp_millis();
+
+        tokio::spawn(async move {
+            let result = match &content {
+                Some(content) => {
+                    persistence
+                        .handle_edit_message(
+                            editor.clone(),
+                            target.clone(),
+                            message_id,
+                            content.clone(),
+                            timestamp,
+                            window,
+                        )
+                        .await
+                }
+                None => {
+                    persistence
+                        .handle_delete_message(
+                            editor.clone(),
+                            target.clone(),
+                            message_id,
+                            timestamp,
+                            window,
+                        )
+                        .await
+                }
+            };
+            if let Err(e) = result {
+                let _ = respond_to.send(Err(e));
+                return;
+            }
+
+            let change = |request_id| match content.clone() {
+                Some(content) => ChatMessage::MessageEdited {
+                    target: target.clone(),
+                    message_id,
+                    from: editor.clone(),
+                    content,
+                    edited_at: timestamp,
+                    request_id,
+                },
+                None => ChatMessage::MessageDeleted {
+                    target: target.clone(),
+                    message_id,
+                    from: editor.clone(),
+                    deleted_at: timestamp,
+                    request_id,
+                },
+            };
+            let _ = respond_to.send(Ok(change(request_id)));
+            let _ = self_sender
+                .send(RouterMessage::MessageChanged {
+                    from: editor.clone(),
+                    connection_id,
+                    change: change(None),
+                })
+                .await;
+        });
+    }
+
+    #[cfg(feature = "persistence")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn handle_react(
+        &mut self,
+        from: TenantUserId,
+        connection_id: ConnectionId,
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+        reaction: String,
+        added: bool,
+        request_id: Option<String>,
+        respond_to: oneshot::Sender<Result<ChatMessage, ChangeError>>,
+    ) {
+        if reaction.is_empty() || reaction.len() > MAX_REACTION_BYTES {
+            let _ = respond_to.send(Err(ChangeError::new(
+                ErrorCode::InvalidMessage,
+                format!("Reaction has to be 1 to {} bytes", MAX_REACTION_BYTES),
+            )));
+            return;
+        }
+
+        // only members of the room or the two users of the conversation
+        if let MessageTarget::Room { room_id } = &target
+            && !self.is_room_member(&from, &from.room(room_id.clone()))
+        {
+            let _ = respond_to.send(Err(ChangeError::new(
+                ErrorCode::Forbidden,
+                "Not a member of the room",
+            )));
+            return;
+        }
+        if let MessageTarget::Conversation { conversation_id } = &target {
+            let check = self.conversations.check(&from, conversation_id, None);
+            if !matches!(check, MembershipCheck::Allowed) {
+                let project_id = from.project_id.clone();
+                let pending_conversation_id = conversation_id.clone();
+                self.reject_or_resolve(
+                    check,
+                    project_id,
+                    pending_conversation_id,
+                    RouterMessage::React {
+                        from,
+                        connection_id,
+                        target,
+                        message_id,
+                        reaction,
+                        added,
+                        request_id,
+                        respond_to,
+                    },
+                );
+                return;
+            }
+        }
+
+        let Some(persistence) = self.persistence.clone() else {
+            let _ = respond_to.send(Err(ChangeError::failed("Persistence not available")));
+            return;
+        };
+        let max_reactions = self.reactions.max_reactions_for(&from.project_id);
+        let self_sender = self.self_sender.clone();
+        let timestamp = chrono::Utc::now().timestamp_millis();
+
+        tokio::spawn(async move {
+            let reactions = match persistence
+                .handle_reaction(
+                    from.clone(),
+                    target.clone(),
+                    message_id,
+                    reaction.clone(),
+                    added,
+                    timestamp,
+                    max_reactions,
+                )
+                .await
+            {
+                Ok(reactions) => reactions,
+                Err(e) => {
+                    let _ = respond_to.send(Err(e));
+                    return;
+                }
+            };
+
+            let change = |request_id| {
+                let (target, from, reaction, reactions) = (
+                    target.clone(),
+                    from.clone(),
+                    reaction.clone(),
+                    reactions.clone(),
+                );
+                if added {
+                    ChatMessage::ReactionAdded {
+                        target,
+                        message_id,
+                        from,
+                        reaction,
+                        reactions,
+                        request_id,
+                    }
+                } else {
+                    ChatMessage::ReactionRemoved {
+                        target,
+                        message_id,
+                        from,
+                        reaction,
+                        reactions,
+                        request_id,
+                    }
+                }
+            };
+            let _ = respond_to.send(Ok(change(request_id)));
+            let _ = self_sender
+                .send(RouterMessage::MessageChanged {
+                    from: from.clone(),
+                    connection_id,
+                    change: change(None),
+                })
+                .await;
+        });
+    }
+
+    #[cfg(feature = "persistence")]
+    pub fn handle_message_changed(
+        &self,
+        from: TenantUserId,
+        connection_id: ConnectionId,
+        change: ChatMessage,
+    ) {
+        let target = match &change {
+            ChatMessage::MessageEdited { target, .. }
+            | ChatMessage::MessageDeleted { target, .. }
+            | ChatMessage::ReactionAdded { target, .. }
+            | ChatMessage::ReactionRemoved { target, .. } => target.clone(),
+            _ => return,
+        };
+
+        match target {
+            MessageTarget::Conversation { conversation_id } => {
+                if let Some(peer) = self.conversations.peer_of(&from, &conversation_id) {
+                    self.deliver_everywhere(&peer, change.clone(), None);
+                }
+            }
+            MessageTarget::Room { room_id } => {
+                // the sender's devices are reached below, members or not
+                let room_id = from.room(room_id);
+                if let Some(room_sender) = self.rooms.get(&room_id) {
+                    let relay = RoomMessage::Relay {
+                        message: change.clone(),
+                        skip: Some(from.clone()),
+                    };
+                    Self::send_to_room(room_sender, relay);
+                }
+                if let Some(cluster) = self.cluster.clone() {
+                    let event = ClusterEvent::Room {
+                        room_id,
+                        message: change.clone(),
+                        skip: Some(from.clone()),
+                    };
+                    tokio::spawn(async move {
+                        if let Err(e) = cluster.broadcast(event).await {
+                            error!("Failed to publish message change: {}", e);
+                        }
+                    });
+                }
+            }
+        }
+
+        self.deliver_everywhere(&from, change, Some(connection_id));
+    }
+
+    pub fn handle_ack_delivery(
+        &mut self,
+        tenant_user_id: TenantUserId,
+        connection_id: ConnectionId,
+        message_ids: Vec<uuid::Uuid>,
+    ) {
+        #[cfg(feature = "persistence")]
+        {
+            let next_page = self
+                .replay_cursors
+                .get(&connection_id)
+                .filter(|cursor| message_ids.contains(cursor))
+                .copied();
+            if let Some(cursor) = next_page {
+                self.replay_cursors.remove(&connection_id);
+                self.replay_pending(tenant_user_id.clone(), connection_id, Some(cursor), false);
+            }
+
+            if let Some(persistence) = self.persistence.clone() {
+                tokio::spawn(async move {
+                    if let Err(e) = persistence
+                        .handle_ack_deliveries(tenant_user_id, message_ids)
+                        .await
+                    {
+                        error!("Failed to ack deliveries: {}", e);
+                    }
+                });
+            }
+        }
+        #[cfg(not(feature = "persistence"))]
+        let _ = (tenant_user_id, connection_id, message_ids);
+    }
+
+    // Fetches what is still waiting for the user after `after`, and their
+    // persisted rooms when `restore_rooms` is set
+    #[cfg(feature = "persistence")]
+    fn replay_pending(
+        &self,
+        tenant_user_id: TenantUserId,
+        connection_id: ConnectionId,
+        after: Option<uuid::Uuid>,
+        restore_rooms: bool,
+    ) {
+        let Some(persistence) = self.persistence.clone() else {
+            return;
+        };
+        let self_sender = self.self_sender.clone();
+
+        tokio::spawn(async move {
+            let rooms = if restore_rooms {
+                persistence
+                    .handle_fetch_user_rooms(tenant_user_id.clone())
+                    .await
+                    .unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+            // left pending on failure, the next connection retries
+            let (deliveries, has_more) = persistence
+                .handle_fetch_pending_deliveries(
+                    tenant_user_id.clone(),
+                    after,
+                    PENDING_REPLAY_BATCH,
+                )
+                .await
+                .unwrap_or_default();
+
+            let _ = self_sender
+                .send(RouterMessage::PendingFetched {
+                    tenant_user_id,
+                    connection_id,
+                    rooms,
+                    deliveries,
+                    has_more,
+                })
+                .await;
+        });
+    }
+
+    #[cfg(feature = "persistence")]
+    pub fn handle_pending_fetched(
+        &mut self,
+        tenant_user_id: TenantUserId,
+        connection_id: ConnectionId,
+        rooms: Vec<TenantRoomId>,
+        deliveries: Vec<ChatMessage>,
+        mut has_more: bool,
+    ) {
+        for room_id in rooms {
+            let (respond_to, _) = oneshot::channel();
+            self.join_room(tenant_user_id.clone(), room_id, respond_to);
+        }
+
+        let mut last_replayed = None;
+        for message in deliveries {
+            let message_id = message.delivery_id();
+            if !self.deliver_to_connection(&tenant_user_id, connection_id, message) {
+                // the rest is picked up with the next page
+                has_more = true;
+                break;
+            }
+            last_replayed = message_id;
+        }
+
+        if has_more && let Some(cursor) = last_replayed {
+            self.replay_cursors.insert(connection_id, cursor);
+        }
+    }
+
+    // Either fails `pending` right away or parks it until chat-service tells
+    // us who the participants of the conversation are.
+    #[cfg(feature = "persistence")]
+    fn reject_or_resolve(
+        &mut self,
+        check: MembershipCheck,
+        project_id: String,
+        conversation_id: String,
+        pending: RouterMessage,
+    ) {
+        match check {
+            MembershipCheck::Allowed => {
+                self.requeue(pending);
+            }
+            MembershipCheck::Denied(reason) => {
+                debug!(
+                    "Rejected request for conversation {}: {}",
+                    conversation_id, reason
+                );
+                Self::reject_pending(pending, reason);
+            }
+            MembershipCheck::Unknown => {
+                let key = (project_id.clone(), conversation_id.clone());
+                // already being fetched, wait behind the requests before it
+                if let Some(waiting) = self.resolving.get_mut(&key) {
+                    waiting.push(pending);
+                    return;
+                }
+                let Some(persistence) = self.persistence.clone() else {
+                    Self::reject_pending(pending, "Persistence not available".to_string());
+                    return;
+                };
+                self.resolving.insert(key, vec![pending]);
+                let self_sender = self.self_sender.clone();
+
+                tokio::spawn(async move {
+                    let participants = persistence
+                        .handle_get_conversation_participants(
+                            project_id.clone(),
+                            conversation_id.clone(),
+                        )
+                        .await;
+                    let _ = self_sender
+                        .send(RouterMessage::ConversationResolved {
+                            project_id,
+                            conversation_id,
+                            participants,
+                        })
+                        .await;
+                });
+            }
+        }
+    }
+
+    #[cfg(feature = "persistence")]
+    pub fn handle_conversation_resolved(
+        &mut self,
+        project_id: String,
+        conversation_id: String,
+        participants: Result<Option<crate::chat::ConversationParticipants>, String>,
+    ) {
+        let waiting = self
+            .resolving
+            .remove(&(project_id.clone(), conversation_id.clone()))
+            .unwrap_or_default();
+        match participants {
+            Ok(Some(participants)) => {
+                self.conversations
+                    .insert(project_id, conversation_id, participants);
+                // the cache is warm now, so these go through the normal check
+                self.ready.extend(waiting);
+            }
+            Ok(None) => {
+                for pending in waiting {
+                    Self::reject_pending(pending, "Conversation not found".to_string());
+                }
+            }
+            Err(e) => {
+                for pending in waiting {
+                    Self::reject_pending(pending, e.clone());
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "persistence")]
+    fn reject_pending(pending: RouterMessage, reason: String) {
+        match pending {
+            RouterMessage::SendDirectMessage {
+                message_id,
+                respond_to: Some(responder),
+                ..
+            } => {
+                let _ = responder.send(MessageAckResponse {
+                    message_id,
+                    timestamp: chrono::Utc::now().timestamp_millis(),
+                    status: MessageStatus::Failed(reason),
+                });
+            }
+            RouterMessage::GetPaginatedMessages { respond_to, .. } => {
+                let _ = respond_to.send(Err(reason));
+            }
+            RouterMessage::SyncMessages { respond_to, .. } => {
+                let _ = respond_to.send(Err(reason));
+            }
+            RouterMessage::GetReadCursors { respond_to, .. } => {
+                let _ = respond_to.send(Err(reason));
+            }
+            RouterMessage::GetRoomHistory { respond_to, .. } => {
+                let _ = respond_to.send(Err(reason));
+            }
+            RouterMessage::ChangeMessage { respond_to, .. }
+            | RouterMessage::React { respond_to, .. } => {
+                let _ = respond_to.send(Err(ChangeError::failed(reason)));
+            }
+            _ => {}
+        }
+    }
 }
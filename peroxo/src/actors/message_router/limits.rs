@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::directory::hash_index;
+use crate::rate_limit::{Buckets, RateClass, RateLimitConfig};
+
+// Lock stripes, projects on different stripes never wait on each other
+const STRIPES: usize = 16;
+
+// Aggregate limits of every project, taken from by all of its sessions on
+// this node whichever router shard they're on
+#[derive(Clone)]
+pub struct TenantLimiter {
+    config: Arc<RateLimitConfig>,
+    stripes: Arc<[Mutex<HashMap<String, Buckets>>]>,
+}
+
+impl TenantLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            stripes: (0..STRIPES).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    // Takes a token of the project, or says how long until the next one
+    pub fn take(&self, project_id: &str, class: RateClass, now: Instant) -> Result<(), Duration> {
+        let mut stripe = self.stripes[hash_index(&project_id, self.stripes.len())]
+            .lock()
+            .unwrap();
+        if !stripe.contains_key(project_id) {
+            let buckets = Buckets::new(&self.config.for_project(project_id).tenant, now);
+            stripe.insert(project_id.to_string(), buckets);
+        }
+        let buckets = stripe.get_mut(project_id).unwrap();
+        buckets.take(class, now)
+    }
+}
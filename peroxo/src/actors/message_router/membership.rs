@@ -0,0 +1,170 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::chat::ConversationParticipants;
+use crate::tenant::TenantUserId;
+
+// Conversations never change participants, so entries only leave the cache
+// to keep it bounded, oldest first.
+const MAX_CACHED_CONVERSATIONS: usize = 10_000;
+
+pub enum MembershipCheck {
+    Allowed,
+    Denied(String),
+    // Not cached yet, participants have to be fetched from chat-service
+    Unknown,
+}
+
+#[derive(Default)]
+pub struct MembershipCache {
+    // keyed by (project_id, conversation_id)
+    conversations: HashMap<(String, String), ConversationParticipants>,
+    // keys in the order they were cached
+    order: VecDeque<(String, String)>,
+}
+
+impl MembershipCache {
+    pub fn insert(
+        &mut self,
+        project_id: String,
+        conversation_id: String,
+        participants: ConversationParticipants,
+    ) {
+        let key = (project_id, conversation_id);
+        if let Some(cached) = self.conversations.get_mut(&key) {
+            *cached = participants;
+            return;
+        }
+
+        if self.conversations.len() >= MAX_CACHED_CONVERSATIONS
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.conversations.remove(&oldest);
+        }
+        self.order.push_back(key.clone());
+        self.conversations.insert(key, participants);
+    }
+
+    // `recipient` is checked to be the other participant when given.
+    pub fn check(
+        &self,
+        requester: &TenantUserId,
+        conversation_id: &str,
+        recipient: Option<&TenantUserId>,
+    ) -> MembershipCheck {
+        let key = (requester.project_id.clone(), conversation_id.to_string());
+        let Some(participants) = self.conversations.get(&key) else {
+            return MembershipCheck::Unknown;
+        };
+
+        let Some(peer) = participants.peer_of(&requester.user_id) else {
+            return MembershipCheck::Denied("Not a participant of this conversation".to_string());
+        };
+
+        match recipient {
+            Some(to) if !requester.same_tenant(to) || to.user_id != peer => {
+                MembershipCheck::Denied(
+                    "Recipient is not the other participant of this conversation".to_string(),
+                )
+            }
+            _ => MembershipCheck::Allowed,
+        }
+    }
+
+    // The other participant, if `requester` is one of them
+    pub fn peer_of(&self, requester: &TenantUserId, conversation_id: &str) -> Option<TenantUserId> {
+        let key = (requester.project_id.clone(), conversation_id.to_string());
+        self.conversations
+            .get(&key)
+            .and_then(|participants| participants.peer_of(&requester.user_id))
+            .map(|peer| requester.peer(peer.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn participants(user_id_1: &str, user_id_2: &str) -> ConversationParticipants {
+        ConversationParticipants {
+            user_id_1: user_id_1.to_string(),
+            user_id_2: user_id_2.to_string(),
+        }
+    }
+
+    fn user(project_id: &str, user_id: &str) -> TenantUserId {
+        TenantUserId::new(project_id.to_string(), user_id.to_string())
+    }
+
+    #[test]
+    fn checks_participants_and_recipients() {
+        let mut cache = MembershipCache::default();
+        cache.insert(
+            "p".to_string(),
+            "c".to_string(),
+            participants("alice", "bob"),
+        );
+        let (alice, bob) = (user("p", "alice"), user("p", "bob"));
+
+        assert!(matches!(
+            cache.check(&alice, "c", Some(&bob)),
+            MembershipCheck::Allowed
+        ));
+        assert!(matches!(
+            cache.check(&bob, "c", None),
+            MembershipCheck::Allowed
+        ));
+        assert!(matches!(
+            cache.check(&user("p", "mallory"), "c", None),
+            MembershipCheck::Denied(_)
+        ));
+        assert!(matches!(
+            cache.check(&alice, "c", Some(&user("p", "mallory"))),
+            MembershipCheck::Denied(_)
+        ));
+        // the other project's bob is someone else
+        assert!(matches!(
+            cache.check(&alice, "c", Some(&user("q", "bob"))),
+            MembershipCheck::Denied(_)
+        ));
+        assert!(matches!(
+            cache.check(&alice, "other", None),
+            MembershipCheck::Unknown
+        ));
+        // conversation ids are only unique within a project
+        assert!(matches!(
+            cache.check(&user("q", "alice"), "c", None),
+            MembershipCheck::Unknown
+        ));
+    }
+
+    #[test]
+    fn evicts_the_oldest_conversation() {
+        let mut cache = MembershipCache::default();
+        for i in 0..MAX_CACHED_CONVERSATIONS {
+            cache.insert("p".to_string(), i.to_string(), participants("alice", "bob"));
+        }
+        // caching one again doesn't make it newer or take a second slot
+        cache.insert(
+            "p".to_string(),
+            "0".to_string(),
+            participants("alice", "bob"),
+        );
+        cache.insert(
+            "p".to_string(),
+            "new".to_string(),
+            participants("alice", "bob"),
+        );
+
+        let alice = user("p", "alice");
+        assert!(matches!(
+            cache.check(&alice, "0", None),
+            MembershipCheck::Unknown
+        ));
+        for conversation_id in ["1", "new"] {
+            assert!(matches!(
+                cache.check(&alice, conversation_id, None),
+                MembershipCheck::Allowed
+            ));
+        }
+    }
+}
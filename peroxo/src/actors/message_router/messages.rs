@@ -1,67 +1,199 @@
 #[cfg(any(feature = "mongo_db", feature = "persistence"))]
 use crate::chat::PaginatedMessagesResponse;
 use crate::{
-    chat::{ChatMessage, MessageAckResponse},
-    tenant::TenantUserId,
+    actors::user_session::{outbox::Outbox, session::ConnectionId},
+    chat::{ChatMessage, MessageAckResponse, TypingTarget},
+    cluster::{ClusterEvent, NodeId},
+    tenant::{TenantRoomId, TenantUserId},
+};
+#[cfg(feature = "persistence")]
+use crate::{
+    chat::{MessageTarget, PaginatedRoomMessagesResponse},
+    edits::ChangeError,
 };
 
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::oneshot;
 
 #[derive(Debug)]
 pub enum RouterMessage {
     RegisterUser {
         tenant_user_id: TenantUserId,
-        sender: tokio::sync::mpsc::Sender<ChatMessage>,
+        connection_id: ConnectionId,
+        outbox: Outbox,
         respond_to: oneshot::Sender<Result<(), String>>,
     },
+    // An event from another node of the cluster
+    Cluster {
+        origin: NodeId,
+        event: ClusterEvent,
+    },
+    // Removes a single device, the user stays online while others remain
     UnregisterUser {
         tenant_user_id: TenantUserId,
+        connection_id: ConnectionId,
     },
     SendDirectMessage {
         conversation_id: String,
         from: TenantUserId,
+        // the device the message was sent from, it is not echoed back there
+        connection_id: ConnectionId,
         to: TenantUserId,
         content: String,
         message_id: uuid::Uuid,
+        // echoed in the Delivered ack sent back to `connection_id`
+        client_message_id: uuid::Uuid,
+        request_id: Option<String>,
         respond_to: Option<oneshot::Sender<MessageAckResponse>>,
     },
-    GetOnlineUsers {
-        respond_to: oneshot::Sender<Vec<TenantUserId>>,
+    // The snapshot of the subscribed users goes back to `connection_id`,
+    // tagged with `request_id`
+    SubscribePresence {
+        subscriber: TenantUserId,
+        connection_id: ConnectionId,
+        targets: Vec<TenantUserId>,
+        conversation_partners: bool,
+        request_id: Option<String>,
+    },
+    UnsubscribePresence {
+        subscriber: TenantUserId,
+        targets: Vec<TenantUserId>,
     },
     #[cfg(any(feature = "mongo_db", feature = "persistence"))]
     GetPaginatedMessages {
-        project_id: String,
+        requester: TenantUserId,
         message_id: Option<uuid::Uuid>,
         conversation_id: String,
         respond_to: oneshot::Sender<Result<PaginatedMessagesResponse, String>>,
     },
+    // Joins every connected device of the user
     JoinRoom {
         tenant_user_id: TenantUserId,
-        room_id: String,
-        sender: mpsc::Sender<ChatMessage>,
+        room_id: TenantRoomId,
         respond_to: oneshot::Sender<Result<(), String>>,
     },
     LeaveRoom {
         tenant_user_id: TenantUserId,
-        room_id: String,
+        room_id: TenantRoomId,
     },
     SendRoomMessage {
-        room_id: String,
+        room_id: TenantRoomId,
         from: TenantUserId,
         content: String,
         message_id: uuid::Uuid,
         respond_to: Option<oneshot::Sender<MessageAckResponse>>,
     },
+    // Relayed to the DM peer or the room, never persisted
+    Typing {
+        from: TenantUserId,
+        target: TypingTarget,
+        typing: bool,
+    },
     GetRoomMembers {
-        room_id: String,
+        room_id: TenantRoomId,
         respond_to: oneshot::Sender<Option<Vec<TenantUserId>>>,
     },
 
     #[cfg(feature = "persistence")]
     SyncMessages {
-        project_id: String,
+        requester: TenantUserId,
         conversation_id: String,
         message_id: uuid::Uuid,
         respond_to: oneshot::Sender<Result<Vec<crate::chat::ResponseDirectMessage>, String>>,
     },
+
+    #[cfg(feature = "persistence")]
+    MarkRead {
+        reader: TenantUserId,
+        // the device the read happened on, it is not told about it again
+        connection_id: ConnectionId,
+        conversation_id: String,
+        up_to_message_id: uuid::Uuid,
+    },
+    #[cfg(feature = "persistence")]
+    GetReadCursors {
+        requester: TenantUserId,
+        conversation_id: String,
+        respond_to: oneshot::Sender<Result<Vec<crate::chat::ReadCursor>, String>>,
+    },
+
+    // A page of the history of a room `requester` joined
+    #[cfg(feature = "persistence")]
+    GetRoomHistory {
+        requester: TenantUserId,
+        room_id: String,
+        message_id: Option<uuid::Uuid>,
+        respond_to: oneshot::Sender<Result<PaginatedRoomMessagesResponse, String>>,
+    },
+
+    // Edits a message of `editor`, or deletes it when `content` is None.
+    // The change goes back tagged with `request_id`, everyone else in the
+    // conversation or room and the editor's other devices get it as well.
+    #[cfg(feature = "persistence")]
+    ChangeMessage {
+        editor: TenantUserId,
+        connection_id: ConnectionId,
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+        content: Option<String>,
+        request_id: Option<String>,
+        respond_to: oneshot::Sender<Result<ChatMessage, ChangeError>>,
+    },
+    // Adds or removes the reaction of `from`, answered and sent on like
+    // ChangeMessage
+    #[cfg(feature = "persistence")]
+    React {
+        from: TenantUserId,
+        connection_id: ConnectionId,
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+        reaction: String,
+        added: bool,
+        request_id: Option<String>,
+        respond_to: oneshot::Sender<Result<ChatMessage, ChangeError>>,
+    },
+    // Internal: a change chat-service stored, to be sent to everyone but the
+    // device it came from
+    #[cfg(feature = "persistence")]
+    MessageChanged {
+        from: TenantUserId,
+        connection_id: ConnectionId,
+        change: ChatMessage,
+    },
+
+    AckDelivery {
+        tenant_user_id: TenantUserId,
+        connection_id: ConnectionId,
+        message_ids: Vec<uuid::Uuid>,
+    },
+    // Internal: a page of pending deliveries for a new connection, `rooms`
+    // are the persisted rooms to rejoin when it was the user's first one.
+    #[cfg(feature = "persistence")]
+    PendingFetched {
+        tenant_user_id: TenantUserId,
+        connection_id: ConnectionId,
+        rooms: Vec<TenantRoomId>,
+        deliveries: Vec<ChatMessage>,
+        has_more: bool,
+    },
+
+    // Internal: a room actor crashed and was restarted without members,
+    // the router adds the ones it knows about again.
+    RoomRestarted {
+        room_id: TenantRoomId,
+    },
+
+    // Internal: a room didn't confirm a join, the user is forgotten there
+    RoomJoinFailed {
+        tenant_user_id: TenantUserId,
+        room_id: TenantRoomId,
+    },
+
+    // Internal: participants fetched for a conversation that was not cached,
+    // the requests waiting on them are parked in the router.
+    #[cfg(feature = "persistence")]
+    ConversationResolved {
+        project_id: String,
+        conversation_id: String,
+        participants: Result<Option<crate::chat::ConversationParticipants>, String>,
+    },
 }
@@ -0,0 +1,138 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::tenant::TenantUserId;
+
+// Upper bound on how many users a single user can watch.
+pub const MAX_PRESENCE_SUBSCRIPTIONS: usize = 1_000;
+
+// Subscriptions belong to the user, not a device, so presence is pushed to
+// every connected device. They are dropped once the subscriber goes offline.
+#[derive(Default)]
+pub struct PresenceSubscriptions {
+    // target -> users watching it
+    watchers: HashMap<TenantUserId, HashSet<TenantUserId>>,
+    // subscriber -> users it watches
+    watching: HashMap<TenantUserId, HashSet<TenantUserId>>,
+}
+
+impl PresenceSubscriptions {
+    // Returns the targets the subscriber now watches out of `targets`,
+    // anything past the limit is left out.
+    pub fn subscribe(
+        &mut self,
+        subscriber: &TenantUserId,
+        targets: Vec<TenantUserId>,
+    ) -> Vec<TenantUserId> {
+        let watching = self.watching.entry(subscriber.clone()).or_default();
+        let mut accepted = Vec::with_capacity(targets.len());
+
+        for target in targets {
+            if target == *subscriber || !subscriber.same_tenant(&target) {
+                continue;
+            }
+            if !watching.contains(&target) {
+                if watching.len() >= MAX_PRESENCE_SUBSCRIPTIONS {
+                    continue;
+                }
+                watching.insert(target.clone());
+                self.watchers
+                    .entry(target.clone())
+                    .or_default()
+                    .insert(subscriber.clone());
+            }
+            accepted.push(target);
+        }
+
+        if watching.is_empty() {
+            self.watching.remove(subscriber);
+        }
+        accepted
+    }
+
+    pub fn unsubscribe(&mut self, subscriber: &TenantUserId, targets: &[TenantUserId]) {
+        let Some(watching) = self.watching.get_mut(subscriber) else {
+            return;
+        };
+        for target in targets {
+            if watching.remove(target) {
+                remove_watcher(&mut self.watchers, target, subscriber);
+            }
+        }
+        if watching.is_empty() {
+            self.watching.remove(subscriber);
+        }
+    }
+
+    pub fn remove_subscriber(&mut self, subscriber: &TenantUserId) {
+        let Some(watching) = self.watching.remove(subscriber) else {
+            return;
+        };
+        for target in &watching {
+            remove_watcher(&mut self.watchers, target, subscriber);
+        }
+    }
+
+    pub fn watchers_of(&self, target: &TenantUserId) -> Vec<TenantUserId> {
+        self.watchers
+            .get(target)
+            .map(|watchers| watchers.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+fn remove_watcher(
+    watchers: &mut HashMap<TenantUserId, HashSet<TenantUserId>>,
+    target: &TenantUserId,
+    subscriber: &TenantUserId,
+) {
+    if let Some(target_watchers) = watchers.get_mut(target) {
+        target_watchers.remove(subscriber);
+        if target_watchers.is_empty() {
+            watchers.remove(target);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(user_id: &str) -> TenantUserId {
+        TenantUserId::new("p".to_string(), user_id.to_string())
+    }
+
+    #[test]
+    fn watchers_follow_subscriptions() {
+        let mut subscriptions = PresenceSubscriptions::default();
+        let (alice, bob, carol) = (user("alice"), user("bob"), user("carol"));
+
+        let accepted = subscriptions.subscribe(&alice, vec![bob.clone(), alice.clone()]);
+        assert_eq!(accepted, vec![bob.clone()]);
+        subscriptions.subscribe(&carol, vec![bob.clone()]);
+        let mut watchers = subscriptions.watchers_of(&bob);
+        watchers.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        assert_eq!(watchers, vec![alice.clone(), carol.clone()]);
+
+        subscriptions.unsubscribe(&alice, std::slice::from_ref(&bob));
+        assert_eq!(subscriptions.watchers_of(&bob), vec![carol.clone()]);
+        subscriptions.remove_subscriber(&carol);
+        assert!(subscriptions.watchers_of(&bob).is_empty());
+    }
+
+    #[test]
+    fn subscriptions_stop_at_the_limit() {
+        let mut subscriptions = PresenceSubscriptions::default();
+        let alice = user("alice");
+        let targets: Vec<_> = (0..=MAX_PRESENCE_SUBSCRIPTIONS)
+            .map(|i| user(&i.to_string()))
+            .collect();
+
+        let accepted = subscriptions.subscribe(&alice, targets.clone());
+        assert_eq!(accepted.len(), MAX_PRESENCE_SUBSCRIPTIONS);
+        // users already watched still count as accepted
+        assert_eq!(
+            subscriptions.subscribe(&alice, targets).len(),
+            MAX_PRESENCE_SUBSCRIPTIONS
+        );
+    }
+}
@@ -1,135 +1,464 @@
+use super::directory::{UserDirectory, hash_index};
+use super::limits::TenantLimiter;
+#[cfg(feature = "persistence")]
+use super::membership::MembershipCache;
 use super::messages::RouterMessage;
+use super::presence::PresenceSubscriptions;
+use crate::actors::framework::{self, Actor, Addr};
 #[cfg(any(feature = "mongo_db", feature = "persistence"))]
 use crate::actors::persistance_actor::PersistenceService;
 use crate::actors::room_actor::RoomMessage;
-use crate::chat::ChatMessage;
-use crate::tenant::TenantUserId;
-use std::collections::HashMap;
+use crate::actors::user_session::session::ConnectionId;
+use crate::backpressure::BackpressureConfig;
+use crate::chat::PresenceStatus;
+use crate::cluster::{self, CLUSTER_CHANNEL_SIZE, ClusterBus};
+#[cfg(feature = "persistence")]
+use crate::edits::EditConfig;
+#[cfg(feature = "persistence")]
+use crate::reactions::ReactionConfig;
+use crate::rate_limit::RateLimitConfig;
+use crate::tenant::{TenantRoomId, TenantUserId};
+#[cfg(feature = "persistence")]
+use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tracing::info;
 
+// Hands every project to one of the router shards, all of a project's users,
+// rooms and conversations live on the same one
+#[derive(Clone)]
+pub struct RouterHandle {
+    shards: Arc<[Addr<RouterMessage>]>,
+    // bumped every time a shard is restarted after a crash
+    restarts: Arc<[watch::Sender<u64>]>,
+    pub directory: UserDirectory,
+    pub rate_limits: TenantLimiter,
+}
+
+impl RouterHandle {
+    pub fn shard(&self, project_id: &str) -> &Addr<RouterMessage> {
+        &self.shards[hash_index(&project_id, self.shards.len())]
+    }
+
+    pub fn first_shard(&self) -> &Addr<RouterMessage> {
+        &self.shards[0]
+    }
+
+    // Changes when the shard of `project_id` lost its state, its sessions
+    // register again so it is rebuilt
+    pub fn restarts(&self, project_id: &str) -> watch::Receiver<u64> {
+        self.restarts[hash_index(&project_id, self.restarts.len())].subscribe()
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+}
+
 pub struct MessageRouter {
-    pub receiver: mpsc::UnboundedReceiver<RouterMessage>,
-    pub users: HashMap<TenantUserId, mpsc::Sender<ChatMessage>>,
-    pub online_users: Vec<TenantUserId>,
+    // connections of every user on this node, shared with the other shards
+    pub directory: UserDirectory,
+    // rooms joined by online users, so devices that connect later are added too
+    pub user_rooms: HashMap<TenantUserId, HashSet<TenantRoomId>>,
     #[cfg(any(feature = "mongo_db", feature = "persistence"))]
     pub persistence: Option<Arc<PersistenceService>>,
-    pub rooms: HashMap<String, mpsc::UnboundedSender<RoomMessage>>,
+    pub rooms: HashMap<TenantRoomId, Addr<RoomMessage>>,
+    #[cfg(feature = "persistence")]
+    pub conversations: MembershipCache,
+    // requests parked while the participants of their conversation are
+    // fetched, keyed by (project_id, conversation_id), in arrival order
+    #[cfg(feature = "persistence")]
+    pub resolving: HashMap<(String, String), Vec<RouterMessage>>,
+    // parked requests released by a resolved conversation, handled before
+    // the mailbox so nothing sent after them can overtake them
+    #[cfg(feature = "persistence")]
+    pub ready: VecDeque<RouterMessage>,
+    // last replayed message per connection when more pending ones are
+    // waiting, the next page is fetched once the client acks it
+    #[cfg(feature = "persistence")]
+    pub replay_cursors: HashMap<ConnectionId, uuid::Uuid>,
+    // how long senders may edit and delete their messages, per project
+    #[cfg(feature = "persistence")]
+    pub edits: Arc<EditConfig>,
+    // how many distinct reactions a message may have, per project
+    #[cfg(feature = "persistence")]
+    pub reactions: Arc<ReactionConfig>,
+    pub presence: PresenceSubscriptions,
+    // Used to re-queue requests once their conversation has been resolved
+    pub self_sender: Addr<RouterMessage>,
+    pub backpressure: BackpressureConfig,
+    pub cluster: Option<Arc<dyn ClusterBus>>,
+    // first connections and last disconnections of local users, in order
+    pub announcements: Option<mpsc::Sender<(TenantUserId, PresenceStatus)>>,
 }
 
 impl MessageRouter {
+    // Starts `shard_count` supervised routers sharing one user directory
+    pub fn spawn_shards(
+        shard_count: usize,
+        backpressure: BackpressureConfig,
+        rate_limits: RateLimitConfig,
+        #[cfg(feature = "persistence")] edits: EditConfig,
+        #[cfg(feature = "persistence")] reactions: ReactionConfig,
+        cluster: Option<Arc<dyn ClusterBus>>,
+        #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+        persistence: Arc<PersistenceService>,
+    ) -> RouterHandle {
+        let directory = UserDirectory::new();
+        #[cfg(feature = "persistence")]
+        let edits = Arc::new(edits);
+        #[cfg(feature = "persistence")]
+        let reactions = Arc::new(reactions);
+        let (shards, restarts) = (0..shard_count.max(1))
+            .map(|shard| {
+                let restarts = watch::Sender::new(0);
+                let factory = {
+                    let directory = directory.clone();
+                    let backpressure = backpressure.clone();
+                    let cluster = cluster.clone();
+                    #[cfg(feature = "persistence")]
+                    let edits = edits.clone();
+                    #[cfg(feature = "persistence")]
+                    let reactions = reactions.clone();
+                    #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+                    let persistence = persistence.clone();
+                    move |addr: &Addr<RouterMessage>| {
+                        Self::new(
+                            addr.clone(),
+                            directory.clone(),
+                            backpressure.clone(),
+                            #[cfg(feature = "persistence")]
+                            edits.clone(),
+                            #[cfg(feature = "persistence")]
+                            reactions.clone(),
+                            cluster.clone(),
+                            #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+                            persistence.clone(),
+                        )
+                    }
+                };
+                let on_restart = {
+                    let restarts = restarts.clone();
+                    move || restarts.send_modify(|count| *count += 1)
+                };
+                let addr = framework::supervise(
+                    format!("router shard {}", shard),
+                    backpressure.router_mailbox,
+                    factory,
+                    on_restart,
+                );
+                (addr, restarts)
+            })
+            .unzip::<_, _, Vec<_>, Vec<_>>();
+        info!("Started {} message router shards", shard_count.max(1));
+
+        RouterHandle {
+            shards: shards.into(),
+            restarts: restarts.into(),
+            directory,
+            rate_limits: TenantLimiter::new(rate_limits),
+        }
+    }
+
     pub fn new(
+        self_sender: Addr<RouterMessage>,
+        directory: UserDirectory,
+        backpressure: BackpressureConfig,
+        #[cfg(feature = "persistence")] edits: Arc<EditConfig>,
+        #[cfg(feature = "persistence")] reactions: Arc<ReactionConfig>,
+        cluster: Option<Arc<dyn ClusterBus>>,
         #[cfg(any(feature = "mongo_db", feature = "persistence"))]
         persistence: Arc<PersistenceService>,
-    ) -> (Self, mpsc::UnboundedSender<RouterMessage>) {
-        let (sender, receiver) = mpsc::unbounded_channel();
+    ) -> Self {
+        let announcements = cluster.clone().map(|cluster| {
+            let (announcements, receiver) = mpsc::channel(CLUSTER_CHANNEL_SIZE);
+            tokio::spawn(cluster::announce_presence(cluster, receiver));
+            announcements
+        });
 
-        let router = Self {
-            receiver,
-            users: HashMap::new(),
-            online_users: Vec::new(),
+        Self {
+            directory,
+            user_rooms: HashMap::new(),
             #[cfg(any(feature = "mongo_db", feature = "persistence"))]
             persistence: Some(persistence),
             rooms: HashMap::new(),
-        };
-
-        (router, sender)
+            #[cfg(feature = "persistence")]
+            conversations: MembershipCache::default(),
+            #[cfg(feature = "persistence")]
+            resolving: HashMap::new(),
+            #[cfg(feature = "persistence")]
+            ready: VecDeque::new(),
+            #[cfg(feature = "persistence")]
+            replay_cursors: HashMap::new(),
+            #[cfg(feature = "persistence")]
+            edits,
+            #[cfg(feature = "persistence")]
+            reactions,
+            presence: PresenceSubscriptions::default(),
+            self_sender,
+            backpressure,
+            cluster,
+            announcements,
+        }
     }
+}
 
-    pub async fn run(mut self) {
-        info!("Message router started");
+impl Actor for MessageRouter {
+    type Message = RouterMessage;
 
-        while let Some(message) = self.receiver.recv().await {
-            match message {
-                RouterMessage::RegisterUser {
-                    tenant_user_id,
-                    sender,
-                    respond_to,
-                } => {
-                    self.handle_register_user(tenant_user_id, sender, respond_to)
-                        .await;
-                }
-                RouterMessage::UnregisterUser { tenant_user_id } => {
-                    self.handle_unregister_user(tenant_user_id).await;
-                }
-                RouterMessage::SendDirectMessage {
+    async fn handle(&mut self, message: RouterMessage) {
+        match message {
+            RouterMessage::RegisterUser {
+                tenant_user_id,
+                connection_id,
+                outbox,
+                respond_to,
+            } => {
+                self.handle_register_user(tenant_user_id, connection_id, outbox, respond_to)
+                    .await;
+            }
+            RouterMessage::Cluster { origin, event } => {
+                self.handle_cluster_event(origin, event);
+            }
+            RouterMessage::UnregisterUser {
+                tenant_user_id,
+                connection_id,
+            } => {
+                self.handle_unregister_user(tenant_user_id, connection_id)
+                    .await;
+            }
+            RouterMessage::SendDirectMessage {
+                conversation_id,
+                from,
+                connection_id,
+                to,
+                content,
+                message_id,
+                client_message_id,
+                request_id,
+                #[allow(unused_variables)]
+                respond_to,
+            } => {
+                self.handle_direct_message(
                     conversation_id,
                     from,
+                    connection_id,
                     to,
                     content,
                     message_id,
-                    #[allow(unused_variables)]
+                    client_message_id,
+                    request_id,
+                    #[cfg(any(feature = "mongo_db", feature = "persistence"))]
                     respond_to,
-                } => {
-                    self.handle_direct_message(
-                        conversation_id,
-                        from,
-                        to,
-                        content,
-                        message_id,
-                        #[cfg(any(feature = "mongo_db", feature = "persistence"))]
-                        respond_to,
-                    )
-                    .await;
-                }
-                RouterMessage::GetOnlineUsers { respond_to } => {
-                    let _ = respond_to.send(self.online_users.clone());
-                }
-                #[cfg(any(feature = "mongo_db", feature = "persistence"))]
-                RouterMessage::GetPaginatedMessages {
-                    project_id,
+                )
+                .await;
+            }
+            RouterMessage::SubscribePresence {
+                subscriber,
+                connection_id,
+                targets,
+                conversation_partners,
+                request_id,
+            } => {
+                self.handle_subscribe_presence(
+                    subscriber,
+                    connection_id,
+                    targets,
+                    conversation_partners,
+                    request_id,
+                );
+            }
+            RouterMessage::UnsubscribePresence {
+                subscriber,
+                targets,
+            } => {
+                self.presence.unsubscribe(&subscriber, &targets);
+            }
+            #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+            RouterMessage::GetPaginatedMessages {
+                requester,
+                message_id,
+                conversation_id,
+                respond_to,
+            } => {
+                self.handle_get_paginated_chat_history(
+                    requester,
                     message_id,
                     conversation_id,
                     respond_to,
-                } => {
-                    self.handle_get_paginated_chat_history(project_id,message_id, conversation_id, respond_to)
-                        .await;
-                }
-                RouterMessage::JoinRoom {
-                    tenant_user_id,
-                    room_id,
-                    sender,
-                    respond_to,
-                } => {
-                    self.handle_join_room(tenant_user_id, room_id, sender, respond_to)
-                        .await;
-                }
-                RouterMessage::LeaveRoom {
-                    tenant_user_id,
-                    room_id,
-                } => {
-                    self.handle_leave_room(tenant_user_id, room_id).await;
-                }
-                RouterMessage::SendRoomMessage {
-                    room_id,
-                    from,
-                    content,
+                )
+                .await;
+            }
+            RouterMessage::JoinRoom {
+                tenant_user_id,
+                room_id,
+                respond_to,
+            } => {
+                self.handle_join_room(tenant_user_id, room_id, respond_to)
+                    .await;
+            }
+            RouterMessage::LeaveRoom {
+                tenant_user_id,
+                room_id,
+            } => {
+                self.handle_leave_room(tenant_user_id, room_id).await;
+            }
+            RouterMessage::SendRoomMessage {
+                room_id,
+                from,
+                content,
+                message_id,
+                respond_to,
+            } => {
+                self.handle_room_message(room_id, from, content, message_id, respond_to)
+                    .await;
+            }
+            RouterMessage::Typing {
+                from,
+                target,
+                typing,
+            } => {
+                self.handle_typing(from, target, typing);
+            }
+            RouterMessage::RoomRestarted { room_id } => {
+                self.handle_room_restarted(room_id);
+            }
+            RouterMessage::RoomJoinFailed {
+                tenant_user_id,
+                room_id,
+            } => {
+                self.handle_room_join_failed(tenant_user_id, room_id);
+            }
+            RouterMessage::GetRoomMembers {
+                room_id,
+                respond_to,
+            } => {
+                self.handle_get_room_members(room_id, respond_to).await;
+            }
+            #[cfg(feature = "persistence")]
+            RouterMessage::SyncMessages {
+                requester,
+                conversation_id,
+                message_id,
+                respond_to,
+            } => {
+                self.handle_sync_messages(requester, conversation_id, message_id, respond_to)
+                    .await;
+            }
+            #[cfg(feature = "persistence")]
+            RouterMessage::MarkRead {
+                reader,
+                connection_id,
+                conversation_id,
+                up_to_message_id,
+            } => {
+                self.handle_mark_read(reader, connection_id, conversation_id, up_to_message_id);
+            }
+            #[cfg(feature = "persistence")]
+            RouterMessage::GetReadCursors {
+                requester,
+                conversation_id,
+                respond_to,
+            } => {
+                self.handle_get_read_cursors(requester, conversation_id, respond_to);
+            }
+            #[cfg(feature = "persistence")]
+            RouterMessage::GetRoomHistory {
+                requester,
+                room_id,
+                message_id,
+                respond_to,
+            } => {
+                self.handle_get_room_history(requester, room_id, message_id, respond_to);
+            }
+            #[cfg(feature = "persistence")]
+            RouterMessage::ChangeMessage {
+                editor,
+                connection_id,
+                target,
+                message_id,
+                content,
+                request_id,
+                respond_to,
+            } => {
+                self.handle_change_message(
+                    editor,
+                    connection_id,
+                    target,
                     message_id,
+                    content,
+                    request_id,
                     respond_to,
-                } => {
-                    self.handle_room_message(room_id, from, content, message_id, respond_to)
-                        .await;
-                }
-                RouterMessage::GetRoomMembers {
-                    room_id,
-                    respond_to,
-                } => {
-                    self.handle_get_room_members(room_id, respond_to).await;
-                }
-                #[cfg(feature = "persistence")]
-                RouterMessage::SyncMessages {
-                    project_id,
-                    conversation_id,
+                );
+            }
+            #[cfg(feature = "persistence")]
+            RouterMessage::React {
+                from,
+                connection_id,
+                target,
+                message_id,
+                reaction,
+                added,
+                request_id,
+                respond_to,
+            } => {
+                self.handle_react(
+                    from,
+                    connection_id,
+                    target,
                     message_id,
+                    reaction,
+                    added,
+                    request_id,
                     respond_to,
-                } => {
-                    self.handle_sync_messages(project_id,conversation_id, message_id, respond_to)
-                        .await;
-                }
+                );
+            }
+            #[cfg(feature = "persistence")]
+            RouterMessage::MessageChanged {
+                from,
+                connection_id,
+                change,
+            } => {
+                self.handle_message_changed(from, connection_id, change);
+            }
+            RouterMessage::AckDelivery {
+                tenant_user_id,
+                connection_id,
+                message_ids,
+            } => {
+                self.handle_ack_delivery(tenant_user_id, connection_id, message_ids);
+            }
+            #[cfg(feature = "persistence")]
+            RouterMessage::PendingFetched {
+                tenant_user_id,
+                connection_id,
+                rooms,
+                deliveries,
+                has_more,
+            } => {
+                self.handle_pending_fetched(
+                    tenant_user_id,
+                    connection_id,
+                    rooms,
+                    deliveries,
+                    has_more,
+                );
+            }
+            #[cfg(feature = "persistence")]
+            RouterMessage::ConversationResolved {
+                project_id,
+                conversation_id,
+                participants,
+            } => {
+                self.handle_conversation_resolved(project_id, conversation_id, participants);
             }
         }
+    }
 
-        info!("Message router stopped");
+    // parked requests released by a resolved conversation go first
+    #[cfg(feature = "persistence")]
+    fn next_pending(&mut self) -> Option<RouterMessage> {
+        self.ready.pop_front()
     }
 }
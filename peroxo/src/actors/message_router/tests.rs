@@ -0,0 +1,978 @@
+use tokio::sync::{mpsc, oneshot};
+
+use super::directory::hash_index;
+use super::{RouterHandle, RouterMessage};
+use crate::PendingDelivery;
+use crate::actors::user_session::handlers::handle_message_change;
+use crate::actors::user_session::outbox::OutboxReceiver;
+use crate::actors::user_session::session::ConnectionId;
+use crate::chat::{
+    ChatMessage, ErrorCode, MessageAckResponse, MessageStatus, MessageTarget, PresenceStatus,
+    Reaction, TypingTarget,
+};
+use crate::edits::ChangeError;
+use crate::reactions::ReactionConfig;
+use crate::tenant::TenantUserId;
+use crate::testing::{FakeChatService, assert_silent, connect, disconnect, next_frame, user};
+
+fn conversation(conversation_id: &str) -> MessageTarget {
+    MessageTarget::Conversation {
+        conversation_id: conversation_id.to_string(),
+    }
+}
+
+async fn react(
+    router: &RouterHandle,
+    from: &TenantUserId,
+    connection_id: ConnectionId,
+    target: MessageTarget,
+    message_id: uuid::Uuid,
+    reaction: &str,
+    added: bool,
+) -> Result<ChatMessage, ChangeError> {
+    let (respond_to, response) = oneshot::channel();
+    router
+        .shard(&from.project_id)
+        .send(RouterMessage::React {
+            from: from.clone(),
+            connection_id,
+            target,
+            message_id,
+            reaction: reaction.to_string(),
+            added,
+            request_id: None,
+            respond_to,
+        })
+        .await
+        .unwrap();
+    response.await.unwrap()
+}
+
+// Edits the message, or deletes it without `content`, the way a session
+// does. Returns the frame the editing device gets back.
+async fn change(
+    router: &RouterHandle,
+    editor: &TenantUserId,
+    connection_id: ConnectionId,
+    target: MessageTarget,
+    message_id: uuid::Uuid,
+    content: Option<&str>,
+) -> ChatMessage {
+    let (ack_sender, mut answers) = mpsc::channel(1);
+    handle_message_change(
+        editor.clone(),
+        connection_id,
+        target,
+        message_id,
+        content.map(str::to_string),
+        Some("r".to_string()),
+        router.shard(&editor.project_id),
+        &ack_sender,
+    )
+    .await;
+    answers.recv().await.unwrap()
+}
+
+fn error_code(frame: ChatMessage) -> ErrorCode {
+    match frame {
+        ChatMessage::Error {
+            code, request_id, ..
+        } => {
+            assert_eq!(request_id.as_deref(), Some("r"));
+            code
+        }
+        frame => panic!("not an error: {:?}", frame),
+    }
+}
+
+async fn join(router: &RouterHandle, user: &TenantUserId, room_id: &str) -> Result<(), String> {
+    let (respond_to, response) = oneshot::channel();
+    router
+        .shard(&user.project_id)
+        .send(RouterMessage::JoinRoom {
+            tenant_user_id: user.clone(),
+            room_id: user.room(room_id.to_string()),
+            respond_to,
+        })
+        .await
+        .unwrap();
+    response.await.unwrap()
+}
+
+async fn send_to_room(
+    router: &RouterHandle,
+    from: &TenantUserId,
+    room_id: &str,
+) -> MessageAckResponse {
+    let (respond_to, response) = oneshot::channel();
+    router
+        .shard(&from.project_id)
+        .send(RouterMessage::SendRoomMessage {
+            room_id: from.room(room_id.to_string()),
+            from: from.clone(),
+            content: "hi".to_string(),
+            message_id: uuid::Uuid::new_v4(),
+            respond_to: Some(respond_to),
+        })
+        .await
+        .unwrap();
+    response.await.unwrap()
+}
+
+// Queues a message in conversation "c", the ack arrives on the receiver
+async fn send_dm(
+    router: &RouterHandle,
+    from: &TenantUserId,
+    connection_id: ConnectionId,
+    to: &TenantUserId,
+    content: &str,
+) -> oneshot::Receiver<MessageAckResponse> {
+    let (respond_to, response) = oneshot::channel();
+    router
+        .shard(&from.project_id)
+        .send(RouterMessage::SendDirectMessage {
+            conversation_id: "c".to_string(),
+            from: from.clone(),
+            connection_id,
+            to: to.clone(),
+            content: content.to_string(),
+            message_id: uuid::Uuid::new_v4(),
+            client_message_id: uuid::Uuid::new_v4(),
+            request_id: None,
+            respond_to: Some(respond_to),
+        })
+        .await
+        .unwrap();
+    response
+}
+
+// `count` direct messages from alice waiting for bob, oldest first
+fn seed_pending(fake: &FakeChatService, count: u128) -> Vec<uuid::Uuid> {
+    let ids: Vec<_> = (1..=count).map(uuid::Uuid::from_u128).collect();
+    let deliveries = ids
+        .iter()
+        .map(|id| PendingDelivery {
+            message_id: id.to_string(),
+            kind: "dm".to_string(),
+            target_id: "c".to_string(),
+            sender_id: "alice".to_string(),
+            content: "hi".to_string(),
+            created_at: 1,
+        })
+        .collect();
+    fake.state()
+        .pending
+        .insert(("p".to_string(), "bob".to_string()), deliveries);
+    ids
+}
+
+async fn replayed(frames: &mut OutboxReceiver, count: usize) -> Vec<uuid::Uuid> {
+    let mut ids = Vec::new();
+    for _ in 0..count {
+        ids.push(next_frame(frames).await.delivery_id().unwrap());
+    }
+    ids
+}
+
+async fn ack(
+    router: &RouterHandle,
+    user: &TenantUserId,
+    connection_id: ConnectionId,
+    ids: &[uuid::Uuid],
+) {
+    router
+        .shard(&user.project_id)
+        .send(RouterMessage::AckDelivery {
+            tenant_user_id: user.clone(),
+            connection_id,
+            message_ids: ids.to_vec(),
+        })
+        .await
+        .unwrap();
+}
+
+fn reactions_of(frame: &ChatMessage) -> Vec<Reaction> {
+    match frame {
+        ChatMessage::ReactionAdded { reactions, .. }
+        | ChatMessage::ReactionRemoved { reactions, .. } => reactions.clone(),
+        frame => panic!("not a reaction: {:?}", frame),
+    }
+}
+
+#[tokio::test]
+async fn reactions_reach_the_peer_and_the_reactors_other_devices() {
+    let fake = FakeChatService::default().with_conversation("p", "c", "alice", "bob");
+    let router = fake.router().await;
+    let (alice, bob) = (user("p", "alice"), user("p", "bob"));
+    let (phone, mut phone_frames) = connect(&router, &alice).await;
+    let (_, mut laptop_frames) = connect(&router, &alice).await;
+    let (_, mut bob_frames) = connect(&router, &bob).await;
+    let message_id = uuid::Uuid::new_v4();
+
+    let added = react(
+        &router,
+        &alice,
+        phone,
+        conversation("c"),
+        message_id,
+        "👍",
+        true,
+    )
+    .await
+    .unwrap();
+    assert!(matches!(added, ChatMessage::ReactionAdded { .. }));
+    assert_eq!(reactions_of(&added)[0].count, 1);
+    assert!(matches!(
+        next_frame(&mut bob_frames).await,
+        ChatMessage::ReactionAdded { .. }
+    ));
+    assert!(matches!(
+        next_frame(&mut laptop_frames).await,
+        ChatMessage::ReactionAdded { .. }
+    ));
+
+    let removed = react(
+        &router,
+        &alice,
+        phone,
+        conversation("c"),
+        message_id,
+        "👍",
+        false,
+    )
+    .await
+    .unwrap();
+    assert!(matches!(removed, ChatMessage::ReactionRemoved { .. }));
+    assert!(reactions_of(&removed).is_empty());
+    let frame = next_frame(&mut bob_frames).await;
+    assert!(matches!(frame, ChatMessage::ReactionRemoved { .. }));
+    assert!(reactions_of(&frame).is_empty());
+    // the device that reacted only gets the answer
+    assert_silent(&mut phone_frames).await;
+}
+
+#[tokio::test]
+async fn only_new_reactions_count_against_the_limit() {
+    let fake = FakeChatService::default().with_conversation("p", "c", "alice", "bob");
+    let router = fake
+        .router_with(ReactionConfig::default().with_max_reactions(Some(1)))
+        .await;
+    let (alice, bob) = (user("p", "alice"), user("p", "bob"));
+    let (alice_device, _alice_frames) = connect(&router, &alice).await;
+    let (bob_device, _bob_frames) = connect(&router, &bob).await;
+    let message_id = uuid::Uuid::new_v4();
+
+    react(
+        &router,
+        &alice,
+        alice_device,
+        conversation("c"),
+        message_id,
+        "👍",
+        true,
+    )
+    .await
+    .unwrap();
+    let error = react(
+        &router,
+        &alice,
+        alice_device,
+        conversation("c"),
+        message_id,
+        "🎉",
+        true,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(error.code, ErrorCode::LimitExceeded);
+
+    let joined = react(
+        &router,
+        &bob,
+        bob_device,
+        conversation("c"),
+        message_id,
+        "👍",
+        true,
+    )
+    .await
+    .unwrap();
+    assert_eq!(reactions_of(&joined)[0].user_ids, vec!["alice", "bob"]);
+}
+
+#[tokio::test]
+async fn reactions_are_refused_before_reaching_chat_service() {
+    let fake = FakeChatService::default().with_conversation("p", "c", "alice", "bob");
+    let router = fake.router().await;
+    let (alice, mallory) = (user("p", "alice"), user("p", "mallory"));
+    let (alice_device, _alice_frames) = connect(&router, &alice).await;
+    let (mallory_device, _mallory_frames) = connect(&router, &mallory).await;
+    let message_id = uuid::Uuid::new_v4();
+
+    let error = react(
+        &router,
+        &alice,
+        alice_device,
+        conversation("c"),
+        message_id,
+        "",
+        true,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(error.code, ErrorCode::InvalidMessage);
+
+    let room = MessageTarget::Room {
+        room_id: "lobby".to_string(),
+    };
+    let error = react(&router, &alice, alice_device, room, message_id, "👍", true)
+        .await
+        .unwrap_err();
+    assert_eq!(error.code, ErrorCode::Forbidden);
+
+    // mallory isn't one of the conversation's two users
+    assert!(
+        react(
+            &router,
+            &mallory,
+            mallory_device,
+            conversation("c"),
+            message_id,
+            "👍",
+            true
+        )
+        .await
+        .is_err()
+    );
+    assert_eq!(fake.calls("AddReaction"), 0);
+}
+
+#[tokio::test]
+async fn edits_and_deletes_reach_the_peer_and_the_editors_other_devices() {
+    let message_id = uuid::Uuid::new_v4();
+    let fake = FakeChatService::default()
+        .with_conversation("p", "c", "alice", "bob")
+        .with_message(message_id, "alice", chrono::Utc::now().timestamp_millis());
+    let router = fake.router().await;
+    let (alice, bob) = (user("p", "alice"), user("p", "bob"));
+    let (phone, mut phone_frames) = connect(&router, &alice).await;
+    let (_, mut laptop_frames) = connect(&router, &alice).await;
+    let (_, mut bob_frames) = connect(&router, &bob).await;
+
+    let edited = change(
+        &router,
+        &alice,
+        phone,
+        conversation("c"),
+        message_id,
+        Some("hello"),
+    )
+    .await;
+    assert!(matches!(
+        edited,
+        ChatMessage::MessageEdited { ref content, ref request_id, .. }
+            if content == "hello" && request_id.as_deref() == Some("r")
+    ));
+    for frames in [&mut bob_frames, &mut laptop_frames] {
+        assert!(matches!(
+            next_frame(frames).await,
+            ChatMessage::MessageEdited { ref content, request_id: None, .. } if content == "hello"
+        ));
+    }
+    assert_eq!(
+        fake.state().messages[&message_id.to_string()].content,
+        "hello"
+    );
+
+    let deleted = change(&router, &alice, phone, conversation("c"), message_id, None).await;
+    assert!(matches!(deleted, ChatMessage::MessageDeleted { .. }));
+    for frames in [&mut bob_frames, &mut laptop_frames] {
+        assert!(matches!(
+            next_frame(frames).await,
+            ChatMessage::MessageDeleted { message_id: id, .. } if id == message_id
+        ));
+    }
+    // the device that changed it only gets the answer
+    assert_silent(&mut phone_frames).await;
+}
+
+#[tokio::test]
+async fn room_edits_reach_the_members_and_the_editors_other_devices() {
+    let message_id = uuid::Uuid::new_v4();
+    let fake = FakeChatService::default().with_message(
+        message_id,
+        "alice",
+        chrono::Utc::now().timestamp_millis(),
+    );
+    let router = fake.router().await;
+    let (alice, bob, carol) = (user("p", "alice"), user("p", "bob"), user("p", "carol"));
+    let (phone, mut phone_frames) = connect(&router, &alice).await;
+    let (_, mut laptop_frames) = connect(&router, &alice).await;
+    let (_, mut bob_frames) = connect(&router, &bob).await;
+    let (_, mut carol_frames) = connect(&router, &carol).await;
+    join(&router, &alice, "lobby").await.unwrap();
+    join(&router, &bob, "lobby").await.unwrap();
+    let lobby = MessageTarget::Room {
+        room_id: "lobby".to_string(),
+    };
+
+    let edited = change(
+        &router,
+        &alice,
+        phone,
+        lobby.clone(),
+        message_id,
+        Some("hello"),
+    )
+    .await;
+    assert!(matches!(edited, ChatMessage::MessageEdited { .. }));
+    for frames in [&mut bob_frames, &mut laptop_frames] {
+        assert!(matches!(
+            next_frame(frames).await,
+            ChatMessage::MessageEdited { target: MessageTarget::Room { ref room_id }, .. }
+                if room_id == "lobby"
+        ));
+    }
+
+    let deleted = change(&router, &alice, phone, lobby, message_id, None).await;
+    assert!(matches!(deleted, ChatMessage::MessageDeleted { .. }));
+    for frames in [&mut bob_frames, &mut laptop_frames] {
+        assert!(matches!(
+            next_frame(frames).await,
+            ChatMessage::MessageDeleted { .. }
+        ));
+    }
+    assert_silent(&mut phone_frames).await;
+    // carol isn't in the room
+    assert_silent(&mut carol_frames).await;
+}
+
+#[tokio::test]
+async fn refused_changes_come_back_as_error_frames() {
+    let (fresh, old, unknown) = (
+        uuid::Uuid::new_v4(),
+        uuid::Uuid::new_v4(),
+        uuid::Uuid::new_v4(),
+    );
+    let fake = FakeChatService::default()
+        .with_conversation("p", "c", "alice", "bob")
+        .with_message(fresh, "alice", chrono::Utc::now().timestamp_millis())
+        .with_message(old, "alice", 0);
+    let router = fake.router().await;
+    let (alice, bob) = (user("p", "alice"), user("p", "bob"));
+    let (alice_device, mut alice_frames) = connect(&router, &alice).await;
+    let (bob_device, mut bob_frames) = connect(&router, &bob).await;
+
+    let frame = change(
+        &router,
+        &bob,
+        bob_device,
+        conversation("c"),
+        fresh,
+        Some("x"),
+    )
+    .await;
+    assert_eq!(error_code(frame), ErrorCode::Forbidden);
+    let frame = change(&router, &alice, alice_device, conversation("c"), old, None).await;
+    assert_eq!(error_code(frame), ErrorCode::EditWindowExpired);
+    let frame = change(
+        &router,
+        &alice,
+        alice_device,
+        conversation("c"),
+        unknown,
+        None,
+    )
+    .await;
+    assert_eq!(error_code(frame), ErrorCode::NotFound);
+    assert_eq!(fake.calls("EditMessage") + fake.calls("DeleteMessage"), 3);
+
+    // alice isn't in the room, chat-service isn't asked
+    let lobby = MessageTarget::Room {
+        room_id: "lobby".to_string(),
+    };
+    let frame = change(&router, &alice, alice_device, lobby, fresh, Some("x")).await;
+    assert_eq!(error_code(frame), ErrorCode::Forbidden);
+    assert_eq!(fake.calls("EditMessage"), 1);
+    assert!(!fake.state().messages[&fresh.to_string()].deleted);
+    assert_silent(&mut alice_frames).await;
+    assert_silent(&mut bob_frames).await;
+}
+
+#[tokio::test]
+async fn room_messages_need_membership_and_stay_in_their_project() {
+    let fake = FakeChatService::default();
+    let router = fake.router().await;
+    let (alice, bob, eve) = (user("p", "alice"), user("p", "bob"), user("q", "eve"));
+    let (_, mut alice_frames) = connect(&router, &alice).await;
+    let (_, mut bob_frames) = connect(&router, &bob).await;
+    let (_, mut eve_frames) = connect(&router, &eve).await;
+    join(&router, &alice, "lobby").await.unwrap();
+    // same name, another project's room
+    join(&router, &eve, "lobby").await.unwrap();
+
+    let ack = send_to_room(&router, &alice, "lobby").await;
+    assert!(matches!(ack.status, MessageStatus::Persisted));
+    assert!(matches!(
+        next_frame(&mut alice_frames).await,
+        ChatMessage::RoomMessage { .. }
+    ));
+    assert_silent(&mut eve_frames).await;
+
+    let ack = send_to_room(&router, &bob, "lobby").await;
+    assert!(matches!(ack.status, MessageStatus::Failed(_)));
+    assert_silent(&mut alice_frames).await;
+    assert_silent(&mut bob_frames).await;
+}
+
+#[tokio::test]
+async fn pending_deliveries_are_replayed_a_page_at_a_time() {
+    let fake = FakeChatService::default();
+    let ids = seed_pending(&fake, 60);
+    let router = fake.router().await;
+    let bob = user("p", "bob");
+    let (device, mut frames) = connect(&router, &bob).await;
+
+    let first_page = replayed(&mut frames, 50).await;
+    assert_eq!(first_page, ids[..50]);
+    // the next page waits for the client to catch up
+    assert_silent(&mut frames).await;
+
+    ack(&router, &bob, device, &first_page).await;
+    assert_eq!(replayed(&mut frames, 10).await, ids[50..]);
+    assert_silent(&mut frames).await;
+}
+
+#[tokio::test]
+async fn unacked_deliveries_are_replayed_again() {
+    let fake = FakeChatService::default();
+    let ids = seed_pending(&fake, 3);
+    let router = fake.router().await;
+    let bob = user("p", "bob");
+    let (device, mut frames) = connect(&router, &bob).await;
+    assert_eq!(replayed(&mut frames, 3).await, ids);
+
+    // the connection drops before the client acked anything
+    disconnect(&router, &bob, device).await;
+    let (device, mut frames) = connect(&router, &bob).await;
+    assert_eq!(replayed(&mut frames, 3).await, ids);
+
+    ack(&router, &bob, device, &ids).await;
+    // acks are written in the background
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let (_, mut frames) = connect(&router, &bob).await;
+    assert_silent(&mut frames).await;
+}
+
+#[tokio::test]
+async fn direct_messages_stay_in_their_project() {
+    // the same conversation id and users exist in both projects
+    let fake = FakeChatService::default()
+        .with_conversation("p", "c", "alice", "bob")
+        .with_conversation("q", "c", "alice", "bob");
+    let router = fake.router().await;
+    let (alice, bob) = (user("p", "alice"), user("q", "bob"));
+    let (device, _alice_frames) = connect(&router, &alice).await;
+    let (_, mut bob_frames) = connect(&router, &bob).await;
+
+    let ack = send_dm(&router, &alice, device, &bob, "hi").await;
+    assert!(matches!(
+        ack.await.unwrap().status,
+        MessageStatus::Failed(_)
+    ));
+    assert_silent(&mut bob_frames).await;
+    assert_eq!(fake.calls("WriteDMBatch"), 0);
+}
+
+#[tokio::test]
+async fn presence_is_only_shared_within_a_project() {
+    let fake = FakeChatService::default();
+    let router = fake.router().await;
+    let alice = user("p", "alice");
+    let (device, mut alice_frames) = connect(&router, &alice).await;
+
+    router
+        .shard(&alice.project_id)
+        .send(RouterMessage::SubscribePresence {
+            subscriber: alice.clone(),
+            connection_id: device,
+            targets: vec![user("q", "bob"), user("p", "carol")],
+            conversation_partners: false,
+            request_id: None,
+        })
+        .await
+        .unwrap();
+    match next_frame(&mut alice_frames).await {
+        ChatMessage::PresenceSnapshot { users, .. } => {
+            let user_ids: Vec<_> = users.iter().map(|user| user.user_id.as_str()).collect();
+            assert_eq!(user_ids, vec!["carol"]);
+        }
+        frame => panic!("not a presence snapshot: {:?}", frame),
+    }
+
+    let _bob = connect(&router, &user("q", "bob")).await;
+    assert_silent(&mut alice_frames).await;
+}
+
+#[tokio::test]
+async fn rooms_of_another_project_cannot_be_joined() {
+    let fake = FakeChatService::default();
+    let router = fake.router().await;
+    let (alice, eve) = (user("p", "alice"), user("q", "eve"));
+    let (_, mut alice_frames) = connect(&router, &alice).await;
+    let (_, mut eve_frames) = connect(&router, &eve).await;
+    join(&router, &eve, "lobby").await.unwrap();
+
+    let (respond_to, response) = oneshot::channel();
+    router
+        .shard(&alice.project_id)
+        .send(RouterMessage::JoinRoom {
+            tenant_user_id: alice.clone(),
+            room_id: eve.room("lobby".to_string()),
+            respond_to,
+        })
+        .await
+        .unwrap();
+    assert!(response.await.unwrap().is_err());
+    assert_eq!(fake.calls("AddRoomMember"), 1);
+
+    send_to_room(&router, &eve, "lobby").await;
+    assert!(matches!(
+        next_frame(&mut eve_frames).await,
+        ChatMessage::RoomMessage { .. }
+    ));
+    assert_silent(&mut alice_frames).await;
+}
+
+#[tokio::test]
+async fn messages_wait_in_order_for_their_conversation() {
+    let fake = FakeChatService::default().with_conversation("p", "c", "alice", "bob");
+    let router = fake.router().await;
+    let (alice, bob) = (user("p", "alice"), user("p", "bob"));
+    let (device, _alice_frames) = connect(&router, &alice).await;
+    let (_, mut bob_frames) = connect(&router, &bob).await;
+
+    // both are queued before the participants are known
+    let first = send_dm(&router, &alice, device, &bob, "first").await;
+    let second = send_dm(&router, &alice, device, &bob, "second").await;
+    for ack in [first, second] {
+        assert!(matches!(
+            ack.await.unwrap().status,
+            MessageStatus::Persisted
+        ));
+    }
+    for expected in ["first", "second"] {
+        match next_frame(&mut bob_frames).await {
+            ChatMessage::DirectMessage { content, .. } => assert_eq!(content, expected),
+            frame => panic!("not a direct message: {:?}", frame),
+        }
+    }
+    assert_eq!(fake.calls("GetConversationParticipants"), 1);
+
+    // mallory isn't one of the two, and "d" doesn't exist
+    let mallory = user("p", "mallory");
+    let (device, _mallory_frames) = connect(&router, &mallory).await;
+    let ack = send_dm(&router, &mallory, device, &bob, "hi").await;
+    assert!(matches!(
+        ack.await.unwrap().status,
+        MessageStatus::Failed(_)
+    ));
+    let (respond_to, response) = oneshot::channel();
+    router
+        .shard(&alice.project_id)
+        .send(RouterMessage::GetPaginatedMessages {
+            requester: alice.clone(),
+            conversation_id: "d".to_string(),
+            message_id: None,
+            respond_to,
+        })
+        .await
+        .unwrap();
+    assert!(response.await.unwrap().is_err());
+    assert_silent(&mut bob_frames).await;
+}
+
+#[tokio::test]
+async fn direct_messages_reach_every_device_but_the_sending_one() {
+    let fake = FakeChatService::default().with_conversation("p", "c", "alice", "bob");
+    let router = fake.router().await;
+    let (alice, bob) = (user("p", "alice"), user("p", "bob"));
+    let (phone, mut phone_frames) = connect(&router, &alice).await;
+    let (_, mut laptop_frames) = connect(&router, &alice).await;
+    let (bob_phone, mut bob_phone_frames) = connect(&router, &bob).await;
+    let (_, mut bob_laptop_frames) = connect(&router, &bob).await;
+
+    let ack = send_dm(&router, &alice, phone, &bob, "hi")
+        .await
+        .await
+        .unwrap();
+    assert!(matches!(ack.status, MessageStatus::Persisted));
+    for frames in [
+        &mut laptop_frames,
+        &mut bob_phone_frames,
+        &mut bob_laptop_frames,
+    ] {
+        assert!(matches!(
+            next_frame(frames).await,
+            ChatMessage::DirectMessage { .. }
+        ));
+    }
+    // the sending device only hears that it was delivered
+    assert!(matches!(
+        next_frame(&mut phone_frames).await,
+        ChatMessage::MessageAck {
+            status: MessageStatus::Delivered,
+            ..
+        }
+    ));
+    assert_silent(&mut phone_frames).await;
+
+    // only that device goes away
+    disconnect(&router, &bob, bob_phone).await;
+    send_dm(&router, &alice, phone, &bob, "still there?")
+        .await
+        .await
+        .unwrap();
+    assert!(matches!(
+        next_frame(&mut bob_laptop_frames).await,
+        ChatMessage::DirectMessage { .. }
+    ));
+    assert_silent(&mut bob_phone_frames).await;
+    assert_eq!(fake.calls("WriteDMBatch"), 2);
+}
+
+async fn subscribe(
+    router: &RouterHandle,
+    subscriber: &TenantUserId,
+    connection_id: ConnectionId,
+    user_id: &str,
+) {
+    router
+        .shard(&subscriber.project_id)
+        .send(RouterMessage::SubscribePresence {
+            subscriber: subscriber.clone(),
+            connection_id,
+            targets: vec![subscriber.peer(user_id.to_string())],
+            conversation_partners: false,
+            request_id: None,
+        })
+        .await
+        .unwrap();
+}
+
+fn presence_of(frame: ChatMessage) -> (String, PresenceStatus) {
+    match frame {
+        ChatMessage::Presence { user, status } => (user.user_id, status),
+        frame => panic!("not a presence update: {:?}", frame),
+    }
+}
+
+#[tokio::test]
+async fn presence_changes_reach_every_device_of_a_watcher() {
+    let fake = FakeChatService::default();
+    let router = fake.router().await;
+    let (alice, bob) = (user("p", "alice"), user("p", "bob"));
+    let (phone, mut phone_frames) = connect(&router, &alice).await;
+    let (_, mut laptop_frames) = connect(&router, &alice).await;
+
+    subscribe(&router, &alice, phone, "bob").await;
+    assert!(matches!(
+        next_frame(&mut phone_frames).await,
+        ChatMessage::PresenceSnapshot { .. }
+    ));
+
+    let (bob_phone, _bob_phone_frames) = connect(&router, &bob).await;
+    for frames in [&mut phone_frames, &mut laptop_frames] {
+        assert!(matches!(
+            presence_of(next_frame(frames).await),
+            (user_id, PresenceStatus::Online) if user_id == "bob"
+        ));
+    }
+
+    // bob is only offline once their last device is gone
+    let (bob_laptop, _bob_laptop_frames) = connect(&router, &bob).await;
+    disconnect(&router, &bob, bob_phone).await;
+    assert_silent(&mut phone_frames).await;
+    disconnect(&router, &bob, bob_laptop).await;
+    for frames in [&mut phone_frames, &mut laptop_frames] {
+        assert!(matches!(
+            presence_of(next_frame(frames).await),
+            (user_id, PresenceStatus::Offline) if user_id == "bob"
+        ));
+    }
+}
+
+#[tokio::test]
+async fn typing_is_relayed_to_the_peer_without_being_stored() {
+    let fake = FakeChatService::default().with_conversation("p", "c", "alice", "bob");
+    let router = fake.router().await;
+    let (alice, bob) = (user("p", "alice"), user("p", "bob"));
+    let (_, mut alice_frames) = connect(&router, &alice).await;
+    let (_, mut bob_frames) = connect(&router, &bob).await;
+
+    router
+        .shard(&alice.project_id)
+        .send(RouterMessage::Typing {
+            from: alice.clone(),
+            target: TypingTarget::Conversation {
+                conversation_id: "c".to_string(),
+                to: "bob".to_string(),
+            },
+            typing: true,
+        })
+        .await
+        .unwrap();
+    assert!(matches!(
+        next_frame(&mut bob_frames).await,
+        ChatMessage::UserTyping { typing: true, .. }
+    ));
+    assert_silent(&mut alice_frames).await;
+    assert_eq!(fake.calls("WriteDMBatch"), 0);
+}
+
+async fn type_in_room(router: &RouterHandle, from: &TenantUserId, typing: bool) {
+    router
+        .shard(&from.project_id)
+        .send(RouterMessage::Typing {
+            from: from.clone(),
+            target: TypingTarget::Room {
+                room_id: "lobby".to_string(),
+            },
+            typing,
+        })
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn only_members_and_who_was_typing_reach_a_room() {
+    let fake = FakeChatService::default();
+    let router = fake.router().await;
+    let (alice, bob, mallory) = (user("p", "alice"), user("p", "bob"), user("p", "mallory"));
+    let (_, mut alice_frames) = connect(&router, &alice).await;
+    let (_, _bob_frames) = connect(&router, &bob).await;
+    let (_, _mallory_frames) = connect(&router, &mallory).await;
+    join(&router, &alice, "lobby").await.unwrap();
+    join(&router, &bob, "lobby").await.unwrap();
+
+    // mallory isn't in the room, starting or stopping
+    type_in_room(&router, &mallory, true).await;
+    type_in_room(&router, &mallory, false).await;
+    assert_silent(&mut alice_frames).await;
+
+    type_in_room(&router, &bob, true).await;
+    assert!(matches!(
+        next_frame(&mut alice_frames).await,
+        ChatMessage::UserTyping { ref from, typing: true, .. } if *from == bob
+    ));
+    router
+        .shard(&bob.project_id)
+        .send(RouterMessage::LeaveRoom {
+            tenant_user_id: bob.clone(),
+            room_id: bob.room("lobby".to_string()),
+        })
+        .await
+        .unwrap();
+    // bob left while typing, a stop still clears it
+    type_in_room(&router, &bob, false).await;
+    assert!(matches!(
+        next_frame(&mut alice_frames).await,
+        ChatMessage::UserTyping { ref from, typing: false, .. } if *from == bob
+    ));
+    type_in_room(&router, &bob, false).await;
+    assert_silent(&mut alice_frames).await;
+}
+
+#[tokio::test]
+async fn read_receipts_reach_the_peer_and_the_readers_other_devices() {
+    let fake = FakeChatService::default().with_conversation("p", "c", "alice", "bob");
+    let router = fake.router().await;
+    let (alice, bob) = (user("p", "alice"), user("p", "bob"));
+    let (_, mut alice_frames) = connect(&router, &alice).await;
+    let (phone, mut phone_frames) = connect(&router, &bob).await;
+    let (_, mut laptop_frames) = connect(&router, &bob).await;
+    let up_to_message_id = uuid::Uuid::new_v4();
+
+    router
+        .shard(&bob.project_id)
+        .send(RouterMessage::MarkRead {
+            reader: bob.clone(),
+            connection_id: phone,
+            conversation_id: "c".to_string(),
+            up_to_message_id,
+        })
+        .await
+        .unwrap();
+    for frames in [&mut alice_frames, &mut laptop_frames] {
+        match next_frame(frames).await {
+            ChatMessage::ReadReceipt {
+                reader,
+                up_to_message_id: read,
+                ..
+            } => assert_eq!((reader, read), (bob.clone(), up_to_message_id)),
+            frame => panic!("not a read receipt: {:?}", frame),
+        }
+    }
+    assert_silent(&mut phone_frames).await;
+
+    let (respond_to, response) = oneshot::channel();
+    router
+        .shard(&alice.project_id)
+        .send(RouterMessage::GetReadCursors {
+            requester: alice.clone(),
+            conversation_id: "c".to_string(),
+            respond_to,
+        })
+        .await
+        .unwrap();
+    let cursors = response.await.unwrap().unwrap();
+    assert_eq!(cursors.len(), 1);
+    assert_eq!(
+        (cursors[0].user_id.as_str(), cursors[0].message_id),
+        ("bob", up_to_message_id)
+    );
+}
+
+#[tokio::test]
+async fn projects_on_different_shards_share_the_directory() {
+    let shard_count = 4;
+    // "p" and the first project that hashes to another shard
+    let other = (0..)
+        .map(|i| format!("q{}", i))
+        .find(|project_id| {
+            hash_index(project_id, shard_count) != hash_index(&"p".to_string(), shard_count)
+        })
+        .unwrap();
+    let fake = FakeChatService::default()
+        .with_conversation("p", "c", "alice", "bob")
+        .with_conversation(&other, "c", "alice", "bob");
+    let router = fake.sharded_router(shard_count).await;
+
+    for project_id in ["p", other.as_str()] {
+        let (alice, bob) = (user(project_id, "alice"), user(project_id, "bob"));
+        let (device, _alice_frames) = connect(&router, &alice).await;
+        let (_, mut bob_frames) = connect(&router, &bob).await;
+
+        let ack = send_dm(&router, &alice, device, &bob, "hi").await;
+        assert!(matches!(
+            ack.await.unwrap().status,
+            MessageStatus::Persisted
+        ));
+        assert!(matches!(
+            next_frame(&mut bob_frames).await,
+            ChatMessage::DirectMessage { .. }
+        ));
+    }
+
+    // every shard sees every user, lookups stay within the project
+    for project_id in ["p", other.as_str()] {
+        let mut online: Vec<_> = router
+            .directory
+            .online_users(project_id)
+            .into_iter()
+            .map(|user| user.user_id)
+            .collect();
+        online.sort();
+        assert_eq!(online, vec!["alice", "bob"]);
+    }
+}
@@ -1,4 +1,5 @@
 pub mod connection_manager;
+pub mod framework;
 pub mod message_router;
 #[cfg(any(feature = "mongo_db", feature = "persistence"))]
 pub mod persistance_actor;
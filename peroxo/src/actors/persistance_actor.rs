@@ -1,4 +1,12 @@
 pub mod actor;
+#[cfg(any(feature = "mongo_db", feature = "persistence"))]
+pub mod batcher;
 pub mod handlers;
+#[cfg(feature = "persistence")]
+pub mod outbox;
 
 pub use actor::*;
+#[cfg(any(feature = "mongo_db", feature = "persistence"))]
+pub use batcher::WriteBatchConfig;
+#[cfg(feature = "persistence")]
+pub use outbox::DurableOutbox;
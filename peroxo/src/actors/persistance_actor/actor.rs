@@ -1,33 +1,101 @@
 #[cfg(feature = "persistence")]
-use tonic::transport::Channel;
+use std::sync::Arc;
 
+#[cfg(feature = "persistence")]
+use crate::circuit_breaker::GuardedChannel;
+
+#[cfg(any(feature = "mongo_db", feature = "persistence"))]
+use super::batcher::{WriteBatchConfig, WriteBatcher};
+#[cfg(feature = "persistence")]
+use super::outbox::{self, DurableOutbox};
 #[cfg(feature = "persistence")]
 use crate::chat_service_client::ChatServiceClient;
 #[cfg(feature = "mongo_db")]
 use crate::mongo_db::config::MongoDbConfig;
+#[cfg(feature = "persistence")]
+use crate::{WriteDmRequest, WriteRoomMessageRequest};
 
 pub struct PersistenceService {
     #[cfg(feature = "persistence")]
-    pub chat_service_client: ChatServiceClient<Channel>,
+    pub chat_service_client: ChatServiceClient<GuardedChannel>,
+    // message writes go out in batches instead of one call each, ordered
+    // per conversation and room
+    #[cfg(feature = "persistence")]
+    pub dm_writes: WriteBatcher<WriteDmRequest>,
+    #[cfg(feature = "persistence")]
+    pub room_writes: WriteBatcher<WriteRoomMessageRequest>,
+    // writes chat-service couldn't take wait here to be replayed
+    #[cfg(feature = "persistence")]
+    pub outbox: Option<Arc<DurableOutbox>>,
     #[cfg(feature = "mongo_db")]
     pub mango_db_client: mongodb::Client,
     #[cfg(feature = "mongo_db")]
     pub mongo_config: MongoDbConfig,
+    #[cfg(feature = "mongo_db")]
+    pub mongo_writes: WriteBatcher<crate::mongo_db::models::DirectMessage>,
 }
 
 impl PersistenceService {
     pub fn new(
-        #[cfg(feature = "persistence")] chat_service_client: ChatServiceClient<Channel>,
+        #[cfg(feature = "persistence")] chat_service_client: ChatServiceClient<GuardedChannel>,
+        #[cfg(any(feature = "mongo_db", feature = "persistence"))] write_batch: WriteBatchConfig,
+        #[cfg(feature = "persistence")] outbox: Option<Arc<DurableOutbox>>,
         #[cfg(feature = "mongo_db")] mango_db_client: mongodb::Client,
         #[cfg(feature = "mongo_db")] mongo_config: MongoDbConfig,
     ) -> Self {
+        #[cfg(feature = "persistence")]
+        let dm_writes = {
+            let client = chat_service_client.clone();
+            let outbox = outbox.clone();
+            WriteBatcher::spawn(write_batch.clone(), move |messages| {
+                Self::flush_dm_batch(client.clone(), outbox.clone(), messages)
+            })
+        };
+        #[cfg(feature = "persistence")]
+        let room_writes = {
+            let client = chat_service_client.clone();
+            let outbox = outbox.clone();
+            WriteBatcher::spawn(write_batch.clone(), move |messages| {
+                Self::flush_room_message_batch(client.clone(), outbox.clone(), messages)
+            })
+        };
+        #[cfg(feature = "persistence")]
+        if let Some(outbox) = &outbox {
+            tokio::spawn(outbox::replay(outbox.clone(), chat_service_client.clone()));
+        }
+        #[cfg(feature = "mongo_db")]
+        let mongo_writes = {
+            let client = mango_db_client.clone();
+            let database_name = mongo_config.database_name.clone();
+            WriteBatcher::spawn(write_batch, move |messages| {
+                Self::flush_mongo_dm_batch(client.clone(), database_name.clone(), messages)
+            })
+        };
+
         Self {
             #[cfg(feature = "persistence")]
             chat_service_client,
+            #[cfg(feature = "persistence")]
+            dm_writes,
+            #[cfg(feature = "persistence")]
+            room_writes,
+            #[cfg(feature = "persistence")]
+            outbox,
             #[cfg(feature = "mongo_db")]
             mango_db_client,
             #[cfg(feature = "mongo_db")]
             mongo_config,
+            #[cfg(feature = "mongo_db")]
+            mongo_writes,
         }
     }
+
+    // Resolves once the message writes queued so far went out, written or
+    // spooled to the outbox
+    pub async fn flush(&self) {
+        #[cfg(feature = "persistence")]
+        futures::join!(self.dm_writes.flush(), self.room_writes.flush());
+        #[cfg(feature = "mongo_db")]
+        self.mongo_writes.flush().await;
+    }
 }
@@ -0,0 +1,288 @@
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+use crate::actors::message_router::directory::hash_index;
+
+// Writes waiting in a lane before new ones are failed
+const LANE_QUEUE_SIZE: usize = 4096;
+
+#[derive(Clone, Debug)]
+pub struct WriteBatchConfig {
+    // A batch is sent as soon as it holds this many writes
+    pub max_size: usize,
+    // or once its first write has waited this long
+    pub max_delay: Duration,
+    // Writes with the same key share a lane and are written one batch after
+    // the other, in the order they were queued. Lanes run in parallel.
+    pub lanes: usize,
+}
+
+impl Default for WriteBatchConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 50,
+            max_delay: Duration::from_millis(10),
+            lanes: 16,
+        }
+    }
+}
+
+impl WriteBatchConfig {
+    pub fn with_max_size(mut self, size: usize) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    pub fn with_max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    pub fn with_lanes(mut self, lanes: usize) -> Self {
+        self.lanes = lanes;
+        self
+    }
+}
+
+enum LaneItem<R> {
+    Write(R, oneshot::Sender<Result<(), String>>),
+    // answered once the writes queued before it have been written
+    Barrier(oneshot::Sender<()>),
+}
+
+// Groups writes into calls of `flush`, which answers with a result per
+// write in the order it got them
+pub struct WriteBatcher<R> {
+    lanes: Arc<[mpsc::Sender<LaneItem<R>>]>,
+}
+
+impl<R: Send + 'static> WriteBatcher<R> {
+    pub fn spawn<F, Fut>(config: WriteBatchConfig, flush: F) -> Self
+    where
+        F: Fn(Vec<R>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Vec<Result<(), String>>> + Send + 'static,
+    {
+        let flush = Arc::new(flush);
+        let lanes = (0..config.lanes.max(1))
+            .map(|_| {
+                let (sender, receiver) = mpsc::channel(LANE_QUEUE_SIZE);
+                tokio::spawn(run(config.clone(), receiver, flush.clone()));
+                sender
+            })
+            .collect();
+        Self { lanes }
+    }
+
+    // Queues `request` right away, behind the earlier writes with the same
+    // key, and resolves once the batch holding it has been written
+    pub fn write(&self, key: &impl Hash, request: R) -> BoxFuture<'static, Result<(), String>> {
+        let (respond_to, response) = oneshot::channel();
+        let lane = &self.lanes[hash_index(key, self.lanes.len())];
+        let queued = lane
+            .try_send(LaneItem::Write(request, respond_to))
+            .map_err(|e| match e {
+                mpsc::error::TrySendError::Full(_) => "Persistence is overloaded".to_string(),
+                mpsc::error::TrySendError::Closed(_) => "Write batcher stopped".to_string(),
+            });
+
+        async move {
+            queued?;
+            response
+                .await
+                .map_err(|_| "Batched write was dropped".to_string())?
+        }
+        .boxed()
+    }
+
+    // Resolves once every write queued so far has been written
+    pub async fn flush(&self) {
+        let barriers = self.lanes.iter().map(|lane| async move {
+            let (respond_to, response) = oneshot::channel();
+            if lane.send(LaneItem::Barrier(respond_to)).await.is_ok() {
+                let _ = response.await;
+            }
+        });
+        futures::future::join_all(barriers).await;
+    }
+}
+
+// One lane, a batch is only sent once the one before it has been written
+async fn run<R, F, Fut>(
+    config: WriteBatchConfig,
+    mut receiver: mpsc::Receiver<LaneItem<R>>,
+    flush: Arc<F>,
+) where
+    F: Fn(Vec<R>) -> Fut,
+    Fut: Future<Output = Vec<Result<(), String>>>,
+{
+    while let Some(first) = receiver.recv().await {
+        let first = match first {
+            LaneItem::Write(request, respond_to) => (request, respond_to),
+            LaneItem::Barrier(respond_to) => {
+                let _ = respond_to.send(());
+                continue;
+            }
+        };
+        let deadline = Instant::now() + config.max_delay;
+        let mut batch = vec![first];
+        // a barrier ends the batch early, it is answered once it is written
+        let mut barrier = None;
+        while batch.len() < config.max_size {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(LaneItem::Write(request, respond_to))) => batch.push((request, respond_to)),
+                Ok(Some(LaneItem::Barrier(respond_to))) => {
+                    barrier = Some(respond_to);
+                    break;
+                }
+                _ => break,
+            }
+        }
+
+        let (requests, responders): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+        let mut results = flush(requests).await.into_iter();
+        for responder in responders {
+            let result = results
+                .next()
+                .unwrap_or_else(|| Err("No result for batched write".to_string()));
+            let _ = responder.send(result);
+        }
+        if let Some(barrier) = barrier {
+            let _ = barrier.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn answers_each_write_of_a_batch() {
+        let config = WriteBatchConfig::default()
+            .with_max_size(3)
+            .with_max_delay(Duration::from_secs(60));
+        let batcher = WriteBatcher::spawn(config, |writes: Vec<u32>| {
+            let results = writes
+                .into_iter()
+                .map(|n| {
+                    if n % 2 == 0 {
+                        Ok(())
+                    } else {
+                        Err(n.to_string())
+                    }
+                })
+                .collect();
+            async move { results }
+        });
+
+        // a full batch goes out without waiting for the delay
+        let writes: Vec<_> = (0..3).map(|n| batcher.write(&"room", n)).collect();
+        let mut results = Vec::new();
+        for write in writes {
+            results.push(write.await);
+        }
+
+        assert_eq!(results, vec![Ok(()), Err("1".to_string()), Ok(())]);
+    }
+
+    #[tokio::test]
+    async fn writes_a_key_in_order_across_batches() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let recorded = batches.clone();
+        let config = WriteBatchConfig::default()
+            .with_max_size(2)
+            .with_max_delay(Duration::from_millis(5));
+        let batcher = WriteBatcher::spawn(config, move |writes: Vec<u32>| {
+            let results = writes.iter().map(|_| Ok(())).collect();
+            recorded.lock().unwrap().push(writes);
+            async move { results }
+        });
+
+        let writes: Vec<_> = (0..5).map(|n| batcher.write(&"conversation", n)).collect();
+        for write in writes {
+            assert_eq!(write.await, Ok(()));
+        }
+
+        assert_eq!(
+            *batches.lock().unwrap(),
+            vec![vec![0, 1], vec![2, 3], vec![4]]
+        );
+    }
+
+    #[tokio::test]
+    async fn flush_waits_for_queued_writes() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let recorded = written.clone();
+        let config = WriteBatchConfig::default()
+            .with_max_delay(Duration::from_millis(20))
+            .with_lanes(2);
+        let batcher = WriteBatcher::spawn(config, move |writes: Vec<u32>| {
+            let results = writes.iter().map(|_| Ok(())).collect();
+            recorded.lock().unwrap().extend(writes);
+            async move { results }
+        });
+
+        // nobody waits on the writes themselves
+        for n in 0..4 {
+            drop(batcher.write(&n, n));
+        }
+        batcher.flush().await;
+
+        let mut written = written.lock().unwrap().clone();
+        written.sort();
+        assert_eq!(written, vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn a_slow_lane_holds_up_only_its_own_keys() {
+        let lanes = 2;
+        let slow = 0u32;
+        let fast = (1..)
+            .find(|n| hash_index(n, lanes) != hash_index(&slow, lanes))
+            .unwrap();
+        let release = Arc::new(tokio::sync::Notify::new());
+        let released = release.clone();
+        let config = WriteBatchConfig::default()
+            .with_max_size(1)
+            .with_lanes(lanes);
+        let batcher = WriteBatcher::spawn(config, move |writes: Vec<u32>| {
+            let results = writes.iter().map(|_| Ok(())).collect();
+            let blocked = writes.contains(&slow).then(|| released.clone());
+            async move {
+                if let Some(released) = blocked {
+                    released.notified().await;
+                }
+                results
+            }
+        });
+
+        let slow_write = batcher.write(&slow, slow);
+        let mut queued_behind = batcher.write(&slow, slow);
+        let fast_write = batcher.write(&fast, fast);
+        tokio::time::timeout(Duration::from_secs(1), fast_write)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // the second write of the slow key waits for the first
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), &mut queued_behind)
+                .await
+                .is_err()
+        );
+
+        release.notify_one();
+        assert_eq!(slow_write.await, Ok(()));
+        release.notify_one();
+        assert_eq!(queued_behind.await, Ok(()));
+    }
+}
@@ -1,15 +1,42 @@
+use futures::FutureExt;
+use futures::future::BoxFuture;
 use tracing::{debug, error};
 
 use super::actor::PersistenceService;
 
+#[cfg(feature = "persistence")]
+use std::sync::Arc;
+#[cfg(feature = "persistence")]
+use std::time::Duration;
+
+#[cfg(feature = "persistence")]
+use crate::circuit_breaker::{GuardedChannel, is_circuit_open, is_unavailable};
+#[cfg(feature = "persistence")]
+use tracing::warn;
+
+#[cfg(feature = "persistence")]
+use super::outbox::{DurableOutbox, SpooledWrite, WriteFailure};
+
+#[cfg(feature = "persistence")]
+use crate::{
+    chat::{ErrorCode, MessageTarget},
+    edits::ChangeError,
+};
+
 #[cfg(feature = "persistence")]
 use crate::{
-    WriteDmRequest, WriteDmResponse, WriteRoomMessageRequest, WriteRoomMessageResponse, chat::{PaginatedMessagesResponse, ResponseDirectMessage}, tenant::TenantUserId
+    WriteDmBatchRequest, WriteDmBatchResponse, WriteDmRequest, WriteRoomMessageBatchRequest,
+    WriteRoomMessageBatchResponse, WriteRoomMessageRequest,
+    chat::{PaginatedMessagesResponse, ResponseDirectMessage},
+    chat_service_client::ChatServiceClient,
+    tenant::{TenantRoomId, TenantUserId},
 };
 
 impl PersistenceService {
+    // Queues the message behind the earlier ones of its conversation, the
+    // returned future resolves once it has been written
     #[allow(unreachable_code, unused_variables)]
-    pub async fn handle_persist_direct_message(
+    pub fn handle_persist_direct_message(
         &self,
         conversation_id: String,
         tenant_sender_id: TenantUserId,
@@ -17,10 +44,9 @@ impl PersistenceService {
         message_content: String,
         message_id: uuid::Uuid,
         timestamp: i64,
-    ) -> Result<(), String> {
+    ) -> BoxFuture<'static, Result<(), String>> {
         #[cfg(feature = "mongo_db")]
         {
-            use crate::metrics::Metrics;
             use crate::mongo_db::models::DirectMessageId;
 
             let sender_id = tenant_sender_id.user_id.clone();
@@ -42,31 +68,26 @@ impl PersistenceService {
                 created_at: mongodb::bson::DateTime::from_millis(timestamp),
             };
 
-            let start = std::time::Instant::now();
-
-            if let Err(e) = self
-                .insert_message_in_mongo(&self.mango_db_client, message)
-                .await
-            {
-                error!("Failed to persist message to MongoDB: {}", e);
-                return Err(format!("MongoDB persistence failed: {}", e));
-            }
-
-            Metrics::observe_db_query("mongo_db_write", start.elapsed());
-            debug!(
-                "Successfully persisted message to MongoDB from {} to {}",
-                sender_id, receiver_id
+            let key = (
+                tenant_sender_id.project_id.clone(),
+                message.id.conversation_id.clone(),
             );
-            return Ok(());
+            let write = self.mongo_writes.write(&key, message);
+            return async move {
+                write.await?;
+                debug!(
+                    "Successfully persisted message to MongoDB from {} to {}",
+                    sender_id, receiver_id
+                );
+                Ok(())
+            }
+            .boxed();
         }
 
         #[cfg(feature = "persistence")]
         {
             use crate::WriteDmRequest;
 
-            
-
-            let start = std::time::Instant::now();
             let request = WriteDmRequest {
                 project_id: tenant_sender_id.project_id.clone(),
                 conversation_id: conversation_id.clone(),
@@ -77,44 +98,62 @@ impl PersistenceService {
                 timestamp,
             };
 
-            match self.write_dm_with_retry(request, 3).await
-            {
-                Ok(response) => {
-                    let write_dm_response = response.into_inner();
-                    if write_dm_response.success {
-                        use crate::metrics::Metrics;
-
+            let key = (tenant_sender_id.project_id.clone(), conversation_id);
+            let write = self.dm_writes.write(&key, request);
+            async move {
+                match write.await {
+                    Ok(()) => {
                         debug!(
                             "Successfully persisted message from {} to {}",
                             tenant_sender_id, tenant_receiver_id
                         );
-                        Metrics::observe_db_query("grpc_write_dm", start.elapsed());
                         Ok(())
-                    } else {
-                        error!(
-                            "Failed to persist message: {}",
-                            write_dm_response.error_message
-                        );
-                        Err(write_dm_response.error_message)
+                    }
+                    Err(e) => {
+                        error!("Failed to persist message: {}", e);
+                        Err(e)
                     }
                 }
+            }
+            .boxed()
+        }
+    }
+
+    // Writes one batch of direct messages to MongoDB, one after the other
+    #[cfg(feature = "mongo_db")]
+    pub async fn flush_mongo_dm_batch(
+        client: mongodb::Client,
+        database_name: String,
+        messages: Vec<crate::mongo_db::models::DirectMessage>,
+    ) -> Vec<Result<(), String>> {
+        use crate::metrics::Metrics;
+
+        let mut results = Vec::with_capacity(messages.len());
+        for message in messages {
+            let start = std::time::Instant::now();
+            match Self::insert_message_in_mongo(&client, &database_name, message).await {
+                Ok(()) => {
+                    Metrics::observe_db_query("mongo_db_write", start.elapsed());
+                    results.push(Ok(()));
+                }
                 Err(e) => {
-                    error!("gRPC call failed: {}", e);
-                    Err(format!("gRPC call failed: {}", e))
+                    error!("Failed to persist message to MongoDB: {}", e);
+                    results.push(Err(format!("MongoDB persistence failed: {}", e)));
                 }
             }
         }
+        results
     }
 
     #[cfg(feature = "mongo_db")]
     pub async fn insert_message_in_mongo(
-        &self,
         client: &mongodb::Client,
+        database_name: &str,
         message: crate::mongo_db::models::DirectMessage,
     ) -> Result<(), mongodb::error::Error> {
         use mongodb::bson::doc;
 
-        let db = client.database(&self.mongo_config.database_name);
+        let db = client.database(database_name);
         let messages_col =
             db.collection::<crate::mongo_db::models::DirectMessage>("direct_messages");
         let conv_col =
@@ -151,21 +190,117 @@ impl PersistenceService {
         Ok(())
     }
 
+    // Writes one batch of direct messages, with a result per message. What
+    // chat-service couldn't be asked to take is spooled to the outbox when
+    // there is one.
     #[cfg(feature = "persistence")]
-    pub async fn write_dm_with_retry(
-        &self,
-        request: WriteDmRequest,
+    pub async fn flush_dm_batch(
+        client: ChatServiceClient<GuardedChannel>,
+        outbox: Option<Arc<DurableOutbox>>,
+        messages: Vec<WriteDmRequest>,
+    ) -> Vec<Result<(), String>> {
+        use crate::metrics::Metrics;
+
+        let count = messages.len();
+        let start = std::time::Instant::now();
+        let request = WriteDmBatchRequest { messages };
+        let results = match Self::write_dm_batch_with_retry(client, &request, 3).await {
+            Ok(response) => {
+                Metrics::observe_db_query("grpc_write_dm_batch", start.elapsed());
+                response
+                    .into_inner()
+                    .results
+                    .into_iter()
+                    .map(|result| {
+                        if result.success {
+                            Ok(())
+                        } else {
+                            Err(WriteFailure::Rejected(result.error_message))
+                        }
+                    })
+                    .collect()
+            }
+            Err(e) => {
+                error!("gRPC call failed: {}", e);
+                let message = format!("gRPC call failed: {}", e);
+                let failure = if is_unavailable(&e) {
+                    WriteFailure::Unavailable(message)
+                } else {
+                    WriteFailure::Rejected(message)
+                };
+                vec![Err(failure); count]
+            }
+        };
+
+        let writes = request.messages.into_iter().map(SpooledWrite::Dm).collect();
+        Self::spool_failed(outbox, writes, results).await
+    }
+
+    // Spooled writes count as persisted, they survive a restart and are
+    // replayed once chat-service is back. Writes it refused fail as they are.
+    #[cfg(feature = "persistence")]
+    async fn spool_failed(
+        outbox: Option<Arc<DurableOutbox>>,
+        writes: Vec<SpooledWrite>,
+        mut results: Vec<Result<(), WriteFailure>>,
+    ) -> Vec<Result<(), String>> {
+        results.resize_with(writes.len(), || {
+            Err(WriteFailure::Rejected(
+                "No result for batched write".to_string(),
+            ))
+        });
+
+        let unavailable: Vec<usize> = (0..writes.len())
+            .filter(|i| matches!(results[*i], Err(WriteFailure::Unavailable(_))))
+            .collect();
+        if let Some(outbox) = outbox
+            && !unavailable.is_empty()
+        {
+            let spooled: Vec<_> = unavailable.iter().map(|i| writes[*i].clone()).collect();
+            let appended = tokio::task::spawn_blocking(move || {
+                outbox.append(&spooled).map_err(|e| e.to_string())
+            })
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|appended| appended);
+
+            match appended {
+                Ok(()) => {
+                    warn!("Spooled {} writes to the outbox", unavailable.len());
+                    for i in unavailable {
+                        results[i] = Ok(());
+                    }
+                }
+                Err(e) => error!("Failed to spool writes to the outbox: {}", e),
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|result| {
+                result.map_err(|failure| match failure {
+                    WriteFailure::Unavailable(e) | WriteFailure::Rejected(e) => e,
+                })
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "persistence")]
+    pub async fn write_dm_batch_with_retry(
+        mut client: ChatServiceClient<GuardedChannel>,
+        request: &WriteDmBatchRequest,
         max_retries: u32,
-    ) -> Result<tonic::Response<WriteDmResponse>, tonic::Status> {
-        let mut client = self.chat_service_client.clone();
+    ) -> Result<tonic::Response<WriteDmBatchResponse>, tonic::Status> {
         let mut attempts = 0;
         let mut last_error = None;
 
         while attempts <= max_retries {
             use tonic::Request;
 
-            match client.write_dm(Request::new(request.clone())).await {
+            match client.write_dm_batch(Request::new(request.clone())).await {
                 Ok(response) => return Ok(response),
+                // chat-service is known to be down, retrying won't help
+                Err(e) if is_circuit_open(&e) => return Err(e),
                 Err(e) => {
                     attempts += 1;
                     last_error = Some(e);
@@ -235,6 +370,9 @@ impl PersistenceService {
                                 recipient_id: msg.recipient_id,
                                 message_text: msg.message_text,
                                 created_at: msg.created_at,
+                                edited_at: (msg.edited_at > 0).then_some(msg.edited_at),
+                                deleted_at: (msg.deleted_at > 0).then_some(msg.deleted_at),
+                                reactions: reactions(msg.reactions),
                             })
                             .collect::<Vec<ResponseDirectMessage>>();
 
@@ -268,6 +406,66 @@ impl PersistenceService {
         }
     }
 
+    #[cfg(feature = "persistence")]
+    pub async fn handle_get_paginated_room_messages(
+        &self,
+        room_id: TenantRoomId,
+        message_id: Option<uuid::Uuid>,
+    ) -> Result<crate::chat::PaginatedRoomMessagesResponse, String> {
+        use tonic::Request;
+
+        use crate::GetPaginatedRoomMessagesRequest;
+        use crate::chat::{PaginatedRoomMessagesResponse, ResponseRoomMessage};
+
+        let mut client = self.chat_service_client.clone();
+        let request = Request::new(GetPaginatedRoomMessagesRequest {
+            project_id: room_id.project_id,
+            room_id: room_id.room_id,
+            cursor_message_id: message_id.map(|id| id.to_string()).unwrap_or_default(),
+        });
+
+        match client.get_paginated_room_messages(request).await {
+            Ok(response) => {
+                let response = response.into_inner();
+                if !response.success {
+                    error!(
+                        "Failed to fetch paginated room messages: {}",
+                        response.error_message
+                    );
+                    return Err(response.error_message);
+                }
+
+                let messages: Vec<ResponseRoomMessage> = response
+                    .messages
+                    .into_iter()
+                    .filter_map(|msg| {
+                        Some(ResponseRoomMessage {
+                            message_id: uuid::Uuid::parse_str(&msg.message_id).ok()?,
+                            room_id: msg.room_id,
+                            sender_id: msg.sender_id,
+                            content: msg.content,
+                            created_at: msg.created_at,
+                            edited_at: (msg.edited_at > 0).then_some(msg.edited_at),
+                            deleted_at: (msg.deleted_at > 0).then_some(msg.deleted_at),
+                            reactions: reactions(msg.reactions),
+                        })
+                    })
+                    .collect();
+                let next_cursor = uuid::Uuid::parse_str(&response.next_cursor).ok();
+
+                Ok(PaginatedRoomMessagesResponse {
+                    has_more: next_cursor.is_some(),
+                    messages,
+                    next_cursor,
+                })
+            }
+            Err(e) => {
+                error!("gRPC call failed: {}", e);
+                Err(format!("gRPC call failed: {}", e))
+            }
+        }
+    }
+
     #[cfg(feature = "mongo_db")]
     async fn fetch_paginated_messages_from_mongo(
         &self,
@@ -311,6 +509,9 @@ impl PersistenceService {
                 recipient_id: doc.recipient_id.to_string(),
                 message_text: doc.message_text,
                 created_at: doc.created_at.timestamp_millis(),
+                edited_at: None,
+                deleted_at: None,
+                reactions: Vec::new(),
             });
         }
 
@@ -329,68 +530,115 @@ impl PersistenceService {
         })
     }
 
+    // Queues the message behind the earlier ones of its room, the returned
+    // future resolves once it has been written
     #[cfg(feature = "persistence")]
-    pub async fn handle_persist_room_message(
+    pub fn handle_persist_room_message(
         &self,
-        room_id: String,
+        room_id: TenantRoomId,
         sender_id: TenantUserId,
         message_content: String,
         message_id: uuid::Uuid,
         timestamp: i64,
-    ) -> Result<(), String> {
+    ) -> BoxFuture<'static, Result<(), String>> {
         use crate::WriteRoomMessageRequest;
 
-
         let request = WriteRoomMessageRequest {
-            project_id: sender_id.project_id.clone(),
-            room_id: room_id.clone(),
+            project_id: room_id.project_id.clone(),
+            room_id: room_id.room_id.clone(),
             sender_id: sender_id.user_id.clone(),
             content: message_content,
             message_id: message_id.to_string(),
             timestamp,
         };
 
-        match self.write_room_message_with_retry(request, 3).await
-        {
-            Ok(response) => {
-                let write_room_response = response.into_inner();
-                if write_room_response.success {
+        let write = self.room_writes.write(&room_id, request);
+        async move {
+            match write.await {
+                Ok(()) => {
                     debug!(
                         "Successfully persisted room message from {} in room {}",
                         sender_id, room_id
                     );
                     Ok(())
-                } else {
-                    error!(
-                        "Failed to persist room message: {}",
-                        write_room_response.error_message
-                    );
-                    Err(write_room_response.error_message)
+                }
+                Err(e) => {
+                    error!("Failed to persist room message: {}", e);
+                    Err(e)
                 }
             }
+        }
+        .boxed()
+    }
+
+    // Writes one batch of room messages, with a result per message, spooled
+    // like direct messages.
+    #[cfg(feature = "persistence")]
+    pub async fn flush_room_message_batch(
+        client: ChatServiceClient<GuardedChannel>,
+        outbox: Option<Arc<DurableOutbox>>,
+        messages: Vec<WriteRoomMessageRequest>,
+    ) -> Vec<Result<(), String>> {
+        use crate::metrics::Metrics;
+
+        let count = messages.len();
+        let start = std::time::Instant::now();
+        let request = WriteRoomMessageBatchRequest { messages };
+        let results = match Self::write_room_message_batch_with_retry(client, &request, 3).await {
+            Ok(response) => {
+                Metrics::observe_db_query("grpc_write_room_message_batch", start.elapsed());
+                response
+                    .into_inner()
+                    .results
+                    .into_iter()
+                    .map(|result| {
+                        if result.success {
+                            Ok(())
+                        } else {
+                            Err(WriteFailure::Rejected(result.error_message))
+                        }
+                    })
+                    .collect()
+            }
             Err(e) => {
                 error!("gRPC call failed: {}", e);
-                Err(format!("gRPC call failed: {}", e))
+                let message = format!("gRPC call failed: {}", e);
+                let failure = if is_unavailable(&e) {
+                    WriteFailure::Unavailable(message)
+                } else {
+                    WriteFailure::Rejected(message)
+                };
+                vec![Err(failure); count]
             }
-        }
+        };
+
+        let writes = request
+            .messages
+            .into_iter()
+            .map(SpooledWrite::Room)
+            .collect();
+        Self::spool_failed(outbox, writes, results).await
     }
 
     #[cfg(feature = "persistence")]
-    pub async fn write_room_message_with_retry(
-        &self,
-        request: WriteRoomMessageRequest,
+    pub async fn write_room_message_batch_with_retry(
+        mut client: ChatServiceClient<GuardedChannel>,
+        request: &WriteRoomMessageBatchRequest,
         max_retries: u32,
-    ) -> Result<tonic::Response<WriteRoomMessageResponse>, tonic::Status>
-    {
-        let mut client = self.chat_service_client.clone();
+    ) -> Result<tonic::Response<WriteRoomMessageBatchResponse>, tonic::Status> {
         let mut attempts = 0;
         let mut last_error = None;
 
         while attempts <= max_retries {
             use tonic::Request;
 
-            match client.write_room_message(Request::new(request.clone())).await {
+            match client
+                .write_room_message_batch(Request::new(request.clone()))
+                .await
+            {
                 Ok(response) => return Ok(response),
+                // chat-service is known to be down, retrying won't help
+                Err(e) if is_circuit_open(&e) => return Err(e),
                 Err(e) => {
                     attempts += 1;
                     last_error = Some(e);
@@ -449,6 +697,9 @@ impl PersistenceService {
                             recipient_id: msg.recipient_id,
                             message_text: msg.message_text,
                             created_at: msg.created_at,
+                            edited_at: (msg.edited_at > 0).then_some(msg.edited_at),
+                            deleted_at: (msg.deleted_at > 0).then_some(msg.deleted_at),
+                            reactions: reactions(msg.reactions),
                         })
                         .collect();
 
@@ -469,4 +720,639 @@ impl PersistenceService {
             }
         }
     }
+
+    #[cfg(feature = "persistence")]
+    pub async fn handle_get_conversation_participants(
+        &self,
+        project_id: String,
+        conversation_id: String,
+    ) -> Result<Option<crate::chat::ConversationParticipants>, String> {
+        use tonic::Request;
+
+        use crate::GetConversationParticipantsRequest;
+
+        let mut client = self.chat_service_client.clone();
+        let start = std::time::Instant::now();
+
+        let request = Request::new(GetConversationParticipantsRequest {
+            project_id,
+            conversation_id: conversation_id.clone(),
+        });
+
+        match client.get_conversation_participants(request).await {
+            Ok(response) => {
+                let participants_response = response.into_inner();
+                crate::metrics::Metrics::observe_db_query(
+                    "grpc_get_conversation_participants",
+                    start.elapsed(),
+                );
+                if !participants_response.success {
+                    error!(
+                        "Failed to fetch participants for conversation {}: {}",
+                        conversation_id, participants_response.error_message
+                    );
+                    return Err(participants_response.error_message);
+                }
+
+                if participants_response.found {
+                    Ok(Some(crate::chat::ConversationParticipants {
+                        user_id_1: participants_response.user_id_1,
+                        user_id_2: participants_response.user_id_2,
+                    }))
+                } else {
+                    Ok(None)
+                }
+            }
+            Err(e) => {
+                error!("gRPC call failed: {}", e);
+                Err(format!("gRPC call failed: {}", e))
+            }
+        }
+    }
+
+    #[cfg(feature = "persistence")]
+    pub async fn handle_fetch_conversation_partners(
+        &self,
+        tenant_user_id: TenantUserId,
+    ) -> Result<Vec<String>, String> {
+        use tonic::Request;
+
+        use crate::FetchConversationPartnersRequest;
+
+        let mut client = self.chat_service_client.clone();
+        let start = std::time::Instant::now();
+
+        let request = Request::new(FetchConversationPartnersRequest {
+            tenant_user_id: Some(crate::TenantUserId {
+                project_id: tenant_user_id.project_id.clone(),
+                user_id: tenant_user_id.user_id.clone(),
+            }),
+        });
+
+        match client.fetch_conversation_partners(request).await {
+            Ok(response) => {
+                let partners_response = response.into_inner();
+                crate::metrics::Metrics::observe_db_query(
+                    "grpc_fetch_conversation_partners",
+                    start.elapsed(),
+                );
+                if partners_response.success {
+                    Ok(partners_response.user_ids)
+                } else {
+                    error!(
+                        "Failed to fetch conversation partners of {}: {}",
+                        tenant_user_id, partners_response.error_message
+                    );
+                    Err(partners_response.error_message)
+                }
+            }
+            Err(e) => {
+                error!("gRPC call failed: {}", e);
+                Err(format!("gRPC call failed: {}", e))
+            }
+        }
+    }
+
+    #[cfg(feature = "persistence")]
+    pub async fn handle_update_read_cursor(
+        &self,
+        reader: TenantUserId,
+        conversation_id: String,
+        message_id: uuid::Uuid,
+        timestamp: i64,
+    ) -> Result<bool, String> {
+        use tonic::Request;
+
+        use crate::UpdateReadCursorRequest;
+
+        let mut client = self.chat_service_client.clone();
+        let start = std::time::Instant::now();
+
+        let request = Request::new(UpdateReadCursorRequest {
+            project_id: reader.project_id.clone(),
+            conversation_id: conversation_id.clone(),
+            user_id: reader.user_id.clone(),
+            message_id: message_id.to_string(),
+            timestamp,
+        });
+
+        match client.update_read_cursor(request).await {
+            Ok(response) => {
+                let cursor_response = response.into_inner();
+                crate::metrics::Metrics::observe_db_query(
+                    "grpc_update_read_cursor",
+                    start.elapsed(),
+                );
+                if cursor_response.success {
+                    Ok(cursor_response.advanced)
+                } else {
+                    error!(
+                        "Failed to update read cursor of {} in {}: {}",
+                        reader, conversation_id, cursor_response.error_message
+                    );
+                    Err(cursor_response.error_message)
+                }
+            }
+            Err(e) => {
+                error!("gRPC call failed: {}", e);
+                Err(format!("gRPC call failed: {}", e))
+            }
+        }
+    }
+
+    #[cfg(feature = "persistence")]
+    pub async fn handle_get_read_cursors(
+        &self,
+        project_id: String,
+        conversation_id: String,
+    ) -> Result<Vec<crate::chat::ReadCursor>, String> {
+        use tonic::Request;
+
+        use crate::GetReadCursorsRequest;
+
+        let mut client = self.chat_service_client.clone();
+
+        let request = Request::new(GetReadCursorsRequest {
+            project_id,
+            conversation_id,
+        });
+
+        match client.get_read_cursors(request).await {
+            Ok(response) => {
+                let cursors_response = response.into_inner();
+                if !cursors_response.success {
+                    error!(
+                        "Failed to fetch read cursors: {}",
+                        cursors_response.error_message
+                    );
+                    return Err(cursors_response.error_message);
+                }
+
+                Ok(cursors_response
+                    .cursors
+                    .into_iter()
+                    .filter_map(|cursor| {
+                        Some(crate::chat::ReadCursor {
+                            message_id: uuid::Uuid::parse_str(&cursor.message_id).ok()?,
+                            user_id: cursor.user_id,
+                            updated_at: cursor.updated_at,
+                        })
+                    })
+                    .collect())
+            }
+            Err(e) => {
+                error!("gRPC call failed: {}", e);
+                Err(format!("gRPC call failed: {}", e))
+            }
+        }
+    }
+
+    // Replaces the content of a message `editor` sent, refused once it is
+    // older than `window`
+    #[cfg(feature = "persistence")]
+    pub async fn handle_edit_message(
+        &self,
+        editor: TenantUserId,
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+        content: String,
+        timestamp: i64,
+        window: Option<Duration>,
+    ) -> Result<(), ChangeError> {
+        use tonic::Request;
+
+        use crate::EditMessageRequest;
+
+        let mut client = self.chat_service_client.clone();
+        let start = std::time::Instant::now();
+        let (kind, target_id) = change_target(target);
+
+        let request = Request::new(EditMessageRequest {
+            project_id: editor.project_id.clone(),
+            kind,
+            target_id,
+            message_id: message_id.to_string(),
+            user_id: editor.user_id.clone(),
+            content,
+            timestamp,
+            window_ms: window_ms(window),
+        });
+
+        match client.edit_message(request).await {
+            Ok(response) => {
+                crate::metrics::Metrics::observe_db_query("grpc_edit_message", start.elapsed());
+                change_result(response.into_inner())
+            }
+            Err(e) => {
+                error!("gRPC call failed: {}", e);
+                Err(ChangeError::failed(format!("gRPC call failed: {}", e)))
+            }
+        }
+    }
+
+    // Leaves a tombstone of a message `editor` sent, refused once it is
+    // older than `window`
+    #[cfg(feature = "persistence")]
+    pub async fn handle_delete_message(
+        &self,
+        editor: TenantUserId,
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+        timestamp: i64,
+        window: Option<Duration>,
+    ) -> Result<(), ChangeError> {
+        use tonic::Request;
+
+        use crate::DeleteMessageRequest;
+
+        let mut client = self.chat_service_client.clone();
+        let start = std::time::Instant::now();
+        let (kind, target_id) = change_target(target);
+
+        let request = Request::new(DeleteMessageRequest {
+            project_id: editor.project_id.clone(),
+            kind,
+            target_id,
+            message_id: message_id.to_string(),
+            user_id: editor.user_id.clone(),
+            timestamp,
+            window_ms: window_ms(window),
+        });
+
+        match client.delete_message(request).await {
+            Ok(response) => {
+                crate::metrics::Metrics::observe_db_query("grpc_delete_message", start.elapsed());
+                change_result(response.into_inner())
+            }
+            Err(e) => {
+                error!("gRPC call failed: {}", e);
+                Err(ChangeError::failed(format!("gRPC call failed: {}", e)))
+            }
+        }
+    }
+
+    // Adds or removes the reaction of `user`, answers with all of the
+    // message's reactions after the change
+    #[cfg(feature = "persistence")]
+    #[allow(clippy::too_many_arguments)]
+    pub async fn handle_reaction(
+        &self,
+        user: TenantUserId,
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+        reaction: String,
+        added: bool,
+        timestamp: i64,
+        max_reactions: Option<u32>,
+    ) -> Result<Vec<crate::chat::Reaction>, ChangeError> {
+        use tonic::Request;
+
+        use crate::{ReactionRejection, ReactionRequest};
+
+        let mut client = self.chat_service_client.clone();
+        let start = std::time::Instant::now();
+        let (kind, target_id) = change_target(target);
+
+        let request = Request::new(ReactionRequest {
+            project_id: user.project_id.clone(),
+            kind,
+            target_id,
+            message_id: message_id.to_string(),
+            user_id: user.user_id.clone(),
+            reaction,
+            timestamp,
+            // 0 is no limit to chat-service
+            max_reactions: max_reactions.map_or(0, |max| max.clamp(1, i32::MAX as u32) as i32),
+        });
+
+        let result = if added {
+            client.add_reaction(request).await
+        } else {
+            client.remove_reaction(request).await
+        };
+
+        match result {
+            Ok(response) => {
+                crate::metrics::Metrics::observe_db_query("grpc_reaction", start.elapsed());
+                let response = response.into_inner();
+                if response.success {
+                    return Ok(reactions(response.reactions));
+                }
+                match ReactionRejection::try_from(response.rejection) {
+                    Ok(ReactionRejection::NotFound) => {
+                        Err(ChangeError::new(ErrorCode::NotFound, "Message not found"))
+                    }
+                    Ok(ReactionRejection::LimitReached) => Err(ChangeError::new(
+                        ErrorCode::LimitExceeded,
+                        "Message has too many distinct reactions",
+                    )),
+                    _ => {
+                        error!("Failed to change reaction: {}", response.error_message);
+                        Err(ChangeError::failed(response.error_message))
+                    }
+                }
+            }
+            Err(e) => {
+                error!("gRPC call failed: {}", e);
+                Err(ChangeError::failed(format!("gRPC call failed: {}", e)))
+            }
+        }
+    }
+
+    #[cfg(feature = "persistence")]
+    pub async fn handle_room_membership(
+        &self,
+        room_id: TenantRoomId,
+        user: TenantUserId,
+        joined: bool,
+    ) -> Result<(), String> {
+        use tonic::Request;
+
+        use crate::RoomMemberRequest;
+
+        let mut client = self.chat_service_client.clone();
+
+        let request = Request::new(RoomMemberRequest {
+            project_id: room_id.project_id.clone(),
+            room_id: room_id.room_id.clone(),
+            user_id: user.user_id.clone(),
+        });
+
+        let result = if joined {
+            client.add_room_member(request).await
+        } else {
+            client.remove_room_member(request).await
+        };
+
+        match result {
+            Ok(response) => {
+                let member_response = response.into_inner();
+                if member_response.success {
+                    Ok(())
+                } else {
+                    error!(
+                        "Failed to update membership of {} in {}: {}",
+                        user, room_id, member_response.error_message
+                    );
+                    Err(member_response.error_message)
+                }
+            }
+            Err(e) => {
+                error!("gRPC call failed: {}", e);
+                Err(format!("gRPC call failed: {}", e))
+            }
+        }
+    }
+
+    #[cfg(feature = "persistence")]
+    pub async fn handle_fetch_user_rooms(
+        &self,
+        tenant_user_id: TenantUserId,
+    ) -> Result<Vec<TenantRoomId>, String> {
+        use tonic::Request;
+
+        use crate::FetchUserRoomsRequest;
+
+        let mut client = self.chat_service_client.clone();
+
+        let request = Request::new(FetchUserRoomsRequest {
+            tenant_user_id: Some(crate::TenantUserId {
+                project_id: tenant_user_id.project_id.clone(),
+                user_id: tenant_user_id.user_id.clone(),
+            }),
+        });
+
+        match client.fetch_user_rooms(request).await {
+            Ok(response) => {
+                let rooms_response = response.into_inner();
+                if rooms_response.success {
+                    Ok(rooms_response
+                        .room_ids
+                        .into_iter()
+                        .map(|room_id| tenant_user_id.room(room_id))
+                        .collect())
+                } else {
+                    error!(
+                        "Failed to fetch rooms of {}: {}",
+                        tenant_user_id, rooms_response.error_message
+                    );
+                    Err(rooms_response.error_message)
+                }
+            }
+            Err(e) => {
+                error!("gRPC call failed: {}", e);
+                Err(format!("gRPC call failed: {}", e))
+            }
+        }
+    }
+
+    // Pending deliveries come back as the frames they were originally sent as,
+    // along with whether more are waiting after the last one.
+    #[cfg(feature = "persistence")]
+    pub async fn handle_fetch_pending_deliveries(
+        &self,
+        tenant_user_id: TenantUserId,
+        after_message_id: Option<uuid::Uuid>,
+        limit: i32,
+    ) -> Result<(Vec<crate::chat::ChatMessage>, bool), String> {
+        use tonic::Request;
+
+        use crate::FetchPendingDeliveriesRequest;
+        use crate::chat::ChatMessage;
+
+        let mut client = self.chat_service_client.clone();
+        let start = std::time::Instant::now();
+
+        let request = Request::new(FetchPendingDeliveriesRequest {
+            tenant_user_id: Some(crate::TenantUserId {
+                project_id: tenant_user_id.project_id.clone(),
+                user_id: tenant_user_id.user_id.clone(),
+            }),
+            limit,
+            after_message_id: after_message_id
+                .map(|id| id.to_string())
+                .unwrap_or_default(),
+        });
+
+        match client.fetch_pending_deliveries(request).await {
+            Ok(response) => {
+                let pending_response = response.into_inner();
+                crate::metrics::Metrics::observe_db_query(
+                    "grpc_fetch_pending_deliveries",
+                    start.elapsed(),
+                );
+                if !pending_response.success {
+                    error!(
+                        "Failed to fetch pending deliveries of {}: {}",
+                        tenant_user_id, pending_response.error_message
+                    );
+                    return Err(pending_response.error_message);
+                }
+
+                let messages = pending_response
+                    .deliveries
+                    .into_iter()
+                    .filter_map(|delivery| {
+                        let message_id = uuid::Uuid::parse_str(&delivery.message_id).ok()?;
+                        let from = tenant_user_id.peer(delivery.sender_id);
+                        match delivery.kind.as_str() {
+                            "dm" => Some(ChatMessage::DirectMessage {
+                                conversation_id: delivery.target_id,
+                                from,
+                                to: tenant_user_id.clone(),
+                                content: delivery.content,
+                                server_message_id: message_id,
+                                timestamp: delivery.created_at,
+                            }),
+                            "room" => Some(ChatMessage::RoomMessage {
+                                room_id: delivery.target_id,
+                                from,
+                                content: delivery.content,
+                                message_id,
+                            }),
+                            _ => None,
+                        }
+                    })
+                    .collect();
+
+                Ok((messages, pending_response.has_more))
+            }
+            Err(e) => {
+                error!("gRPC call failed: {}", e);
+                Err(format!("gRPC call failed: {}", e))
+            }
+        }
+    }
+
+    #[cfg(feature = "persistence")]
+    pub async fn handle_ack_deliveries(
+        &self,
+        tenant_user_id: TenantUserId,
+        message_ids: Vec<uuid::Uuid>,
+    ) -> Result<(), String> {
+        use tonic::Request;
+
+        use crate::AckDeliveriesRequest;
+
+        let mut client = self.chat_service_client.clone();
+
+        let request = Request::new(AckDeliveriesRequest {
+            tenant_user_id: Some(crate::TenantUserId {
+                project_id: tenant_user_id.project_id.clone(),
+                user_id: tenant_user_id.user_id.clone(),
+            }),
+            message_ids: message_ids.iter().map(|id| id.to_string()).collect(),
+        });
+
+        match client.ack_deliveries(request).await {
+            Ok(response) => {
+                let ack_response = response.into_inner();
+                if ack_response.success {
+                    Ok(())
+                } else {
+                    Err(ack_response.error_message)
+                }
+            }
+            Err(e) => {
+                error!("gRPC call failed: {}", e);
+                Err(format!("gRPC call failed: {}", e))
+            }
+        }
+    }
+}
+
+// kind and target_id as chat-service stores them
+#[cfg(feature = "persistence")]
+fn change_target(target: MessageTarget) -> (String, String) {
+    match target {
+        MessageTarget::Conversation { conversation_id } => ("dm".to_string(), conversation_id),
+        MessageTarget::Room { room_id } => ("room".to_string(), room_id),
+    }
+}
+
+// 0 is no limit to chat-service, so a zero window still refuses every change
+#[cfg(feature = "persistence")]
+fn window_ms(window: Option<Duration>) -> i64 {
+    window.map_or(0, |window| (window.as_millis() as i64).max(1))
+}
+
+#[cfg(feature = "persistence")]
+fn change_result(response: crate::MessageChangeResponse) -> Result<(), ChangeError> {
+    use crate::MessageChangeRejection;
+
+    if response.success {
+        return Ok(());
+    }
+    match MessageChangeRejection::try_from(response.rejection) {
+        Ok(MessageChangeRejection::NotFound | MessageChangeRejection::Deleted) => {
+            Err(ChangeError::new(ErrorCode::NotFound, "Message not found"))
+        }
+        Ok(MessageChangeRejection::NotSender) => Err(ChangeError::new(
+            ErrorCode::Forbidden,
+            "Only the sender may change a message",
+        )),
+        Ok(MessageChangeRejection::WindowExpired) => Err(ChangeError::new(
+            ErrorCode::EditWindowExpired,
+            "Message is too old to be changed",
+        )),
+        _ => {
+            error!("Failed to change message: {}", response.error_message);
+            Err(ChangeError::failed(response.error_message))
+        }
+    }
+}
+
+#[cfg(feature = "persistence")]
+fn reactions(reactions: Vec<crate::Reaction>) -> Vec<crate::chat::Reaction> {
+    reactions
+        .into_iter()
+        .map(|r| crate::chat::Reaction {
+            reaction: r.reaction,
+            count: r.count.max(0) as u32,
+            user_ids: r.user_ids,
+        })
+        .collect()
+}
+
+#[cfg(all(test, feature = "persistence"))]
+mod tests {
+    use super::*;
+
+    fn dm(message_id: &str) -> SpooledWrite {
+        SpooledWrite::Dm(WriteDmRequest {
+            project_id: "project".to_string(),
+            conversation_id: "conversation".to_string(),
+            sender_id: "alice".to_string(),
+            receiver_id: "bob".to_string(),
+            message: "hi".to_string(),
+            message_id: message_id.to_string(),
+            timestamp: 1,
+        })
+    }
+
+    #[tokio::test]
+    async fn only_unavailable_writes_are_spooled() {
+        let dir = std::env::temp_dir().join(format!("peroxo-spool-{}", uuid::Uuid::new_v4()));
+        let outbox = DurableOutbox::open(&dir).unwrap();
+
+        let results = PersistenceService::spool_failed(
+            Some(outbox.clone()),
+            vec![dm("1"), dm("2"), dm("3")],
+            vec![
+                Ok(()),
+                Err(WriteFailure::Rejected("Invalid UUID".to_string())),
+                Err(WriteFailure::Unavailable("no answer".to_string())),
+            ],
+        )
+        .await;
+
+        // the refused write fails, it would be refused again on replay
+        assert_eq!(
+            results,
+            vec![Ok(()), Err("Invalid UUID".to_string()), Ok(())]
+        );
+        assert_eq!(outbox.depth(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,537 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use prost::Message;
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+use super::actor::PersistenceService;
+use crate::chat_service_client::ChatServiceClient;
+use crate::circuit_breaker::{GuardedChannel, is_unavailable};
+use crate::metrics::Metrics;
+use crate::{
+    WriteDmBatchRequest, WriteDmRequest, WriteRoomMessageBatchRequest, WriteRoomMessageRequest,
+};
+
+const SEGMENT_PREFIX: &str = "segment-";
+const SEGMENT_SUFFIX: &str = ".log";
+// Records replay gave up on, kept for an operator to look at
+const DEAD_LETTER_FILE: &str = "dead-letter.log";
+// A new segment is started once the active one grows past this
+const MAX_SEGMENT_BYTES: u64 = 8 * 1024 * 1024;
+
+const KIND_DM: u8 = 1;
+const KIND_ROOM: u8 = 2;
+
+// Records sent to chat-service in one call while replaying
+const REPLAY_BATCH: usize = 50;
+const REPLAY_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const REPLAY_MAX_BACKOFF: Duration = Duration::from_secs(60);
+// A record chat-service keeps rejecting is moved to the dead letters after
+// this many tries, an unreachable chat-service is retried for as long as it
+// takes
+const MAX_REPLAY_ATTEMPTS: u32 = 5;
+
+// Why a write to chat-service failed. Only writes it couldn't be asked about
+// are spooled, one it refused would be refused again on replay.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WriteFailure {
+    Unavailable(String),
+    Rejected(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SpooledWrite {
+    Dm(WriteDmRequest),
+    Room(WriteRoomMessageRequest),
+}
+
+impl SpooledWrite {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        let (kind, payload) = match self {
+            SpooledWrite::Dm(request) => (KIND_DM, request.encode_to_vec()),
+            SpooledWrite::Room(request) => (KIND_ROOM, request.encode_to_vec()),
+        };
+        buffer.push(kind);
+        buffer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&payload);
+    }
+
+    fn decode(kind: u8, payload: &[u8]) -> Result<Self, String> {
+        match kind {
+            KIND_DM => WriteDmRequest::decode(payload)
+                .map(SpooledWrite::Dm)
+                .map_err(|e| e.to_string()),
+            KIND_ROOM => WriteRoomMessageRequest::decode(payload)
+                .map(SpooledWrite::Room)
+                .map_err(|e| e.to_string()),
+            kind => Err(format!("Unknown record kind {}", kind)),
+        }
+    }
+}
+
+struct ActiveSegment {
+    seq: u64,
+    file: File,
+    len: u64,
+}
+
+// Append-only spool for message writes chat-service couldn't take. Records
+// go to numbered segment files under `dir`; a segment is only read back once
+// it has been sealed and is deleted when all of its records were replayed.
+// Replaying is at least once, chat-service writes are idempotent per message id.
+pub struct DurableOutbox {
+    dir: PathBuf,
+    active: Mutex<ActiveSegment>,
+    depth: AtomicU64,
+    spooled: Notify,
+}
+
+fn segment_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{}{:020}{}", SEGMENT_PREFIX, seq, SEGMENT_SUFFIX))
+}
+
+fn open_segment(dir: &Path, seq: u64) -> io::Result<ActiveSegment> {
+    let file = OpenOptions::new()
+        .create_new(true)
+        .append(true)
+        .open(segment_path(dir, seq))?;
+    Ok(ActiveSegment { seq, file, len: 0 })
+}
+
+// Segment files in `dir`, oldest first
+fn list_segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let seq = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(SEGMENT_PREFIX))
+            .and_then(|name| name.strip_suffix(SEGMENT_SUFFIX))
+            .and_then(|seq| seq.parse::<u64>().ok());
+        if let Some(seq) = seq {
+            segments.push((seq, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+fn read_segment(path: &Path) -> io::Result<Vec<SpooledWrite>> {
+    Ok(parse_segment(path, &fs::read(path)?))
+}
+
+// Every complete record of a segment. A record cut short by a crash
+// mid-append ends the segment.
+fn parse_segment(path: &Path, bytes: &[u8]) -> Vec<SpooledWrite> {
+    let mut records = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        if rest.len() < 5 {
+            warn!(
+                "Ignoring a truncated record at the end of {}",
+                path.display()
+            );
+            break;
+        }
+        let kind = rest[0];
+        let len = u32::from_le_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+        let Some(payload) = rest.get(5..5 + len) else {
+            warn!(
+                "Ignoring a truncated record at the end of {}",
+                path.display()
+            );
+            break;
+        };
+        match SpooledWrite::decode(kind, payload) {
+            Ok(record) => records.push(record),
+            Err(e) => error!("Skipping a corrupt record in {}: {}", path.display(), e),
+        }
+        rest = &rest[5 + len..];
+    }
+    records
+}
+
+impl DurableOutbox {
+    // Picks up the segments a previous run left behind, new records always
+    // go to a fresh segment
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Arc<Self>> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let segments = list_segments(&dir)?;
+        let mut depth = 0;
+        for (_, path) in &segments {
+            depth += read_segment(path)?.len() as u64;
+        }
+        let next_seq = segments.last().map_or(0, |(seq, _)| seq + 1);
+        let active = open_segment(&dir, next_seq)?;
+
+        if depth > 0 {
+            info!(
+                "Outbox {} holds {} writes from a previous run",
+                dir.display(),
+                depth
+            );
+        }
+        Metrics::set_outbox_depth(depth);
+
+        let outbox = Arc::new(Self {
+            dir,
+            active: Mutex::new(active),
+            depth: AtomicU64::new(depth),
+            spooled: Notify::new(),
+        });
+        if depth > 0 {
+            outbox.spooled.notify_one();
+        }
+        Ok(outbox)
+    }
+
+    pub fn depth(&self) -> u64 {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    // Returns once the records are on disk
+    pub fn append(&self, records: &[SpooledWrite]) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        for record in records {
+            record.encode(&mut buffer);
+        }
+
+        let mut active = self.active.lock().unwrap();
+        let written = active
+            .file
+            .write_all(&buffer)
+            .and_then(|()| active.file.sync_data());
+        if let Err(e) = written {
+            // a partly written record ends the segment
+            *active = open_segment(&self.dir, active.seq + 1)?;
+            return Err(e);
+        }
+        active.len += buffer.len() as u64;
+        if active.len >= MAX_SEGMENT_BYTES {
+            *active = open_segment(&self.dir, active.seq + 1)?;
+        }
+        drop(active);
+
+        self.depth
+            .fetch_add(records.len() as u64, Ordering::Relaxed);
+        Metrics::outbox_spooled(records.len() as u64);
+        self.spooled.notify_one();
+        Ok(())
+    }
+
+    // Starts a new active segment, so everything appended so far can be replayed
+    fn seal(&self) -> io::Result<()> {
+        let mut active = self.active.lock().unwrap();
+        if active.len > 0 {
+            *active = open_segment(&self.dir, active.seq + 1)?;
+        }
+        Ok(())
+    }
+
+    fn sealed_segments(&self) -> io::Result<Vec<PathBuf>> {
+        let active_seq = self.active.lock().unwrap().seq;
+        Ok(list_segments(&self.dir)?
+            .into_iter()
+            .filter(|(seq, _)| *seq < active_seq)
+            .map(|(_, path)| path)
+            .collect())
+    }
+
+    fn replayed(&self, count: u64) {
+        self.depth.fetch_sub(count, Ordering::Relaxed);
+        Metrics::outbox_replayed(count);
+    }
+
+    // Keeps records replay gave up on in the dead-letter file, which is
+    // never replayed
+    fn dead_letter(&self, records: &[SpooledWrite]) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        for record in records {
+            record.encode(&mut buffer);
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(DEAD_LETTER_FILE))?;
+        file.write_all(&buffer)?;
+        file.sync_data()?;
+
+        Metrics::outbox_dead_lettered(records.len() as u64);
+        Ok(())
+    }
+}
+
+// Writes spooled records back to chat-service, oldest segment first
+pub async fn replay(outbox: Arc<DurableOutbox>, client: ChatServiceClient<GuardedChannel>) {
+    loop {
+        if outbox.depth() == 0 {
+            outbox.spooled.notified().await;
+        }
+
+        let sealed = {
+            let outbox = outbox.clone();
+            tokio::task::spawn_blocking(move || {
+                outbox.seal()?;
+                outbox.sealed_segments()
+            })
+            .await
+        };
+        let segments = match sealed {
+            Ok(Ok(segments)) => segments,
+            Ok(Err(e)) => {
+                error!("Failed to list outbox segments: {}", e);
+                tokio::time::sleep(REPLAY_MAX_BACKOFF).await;
+                continue;
+            }
+            Err(e) => {
+                error!("Outbox task failed: {}", e);
+                continue;
+            }
+        };
+
+        if segments.is_empty() {
+            outbox.spooled.notified().await;
+            continue;
+        }
+
+        'segments: for path in segments {
+            let records = match tokio::fs::read(&path).await {
+                Ok(bytes) => parse_segment(&path, &bytes),
+                Err(e) => {
+                    error!("Failed to read outbox segment {}: {}", path.display(), e);
+                    tokio::time::sleep(REPLAY_MAX_BACKOFF).await;
+                    break;
+                }
+            };
+            info!(
+                "Replaying {} spooled writes from {}",
+                records.len(),
+                path.display()
+            );
+            for chunk in records.chunks(REPLAY_BATCH) {
+                let given_up = replay_chunk(&client, chunk.to_vec()).await;
+                if !given_up.is_empty() {
+                    let outbox = outbox.clone();
+                    let count = given_up.len();
+                    let moved = tokio::task::spawn_blocking(move || outbox.dead_letter(&given_up))
+                        .await
+                        .map_err(|e| e.to_string())
+                        .and_then(|moved| moved.map_err(|e| e.to_string()));
+                    match moved {
+                        Ok(()) => error!("Moved {} spooled writes to the dead letters", count),
+                        Err(e) => {
+                            // the segment is kept, replaying it again beats losing it
+                            error!("Failed to move spooled writes to the dead letters: {}", e);
+                            tokio::time::sleep(REPLAY_MAX_BACKOFF).await;
+                            break 'segments;
+                        }
+                    }
+                }
+                outbox.replayed(chunk.len() as u64);
+            }
+            if let Err(e) = fs::remove_file(&path) {
+                error!("Failed to remove outbox segment {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+// Returns once every record was written or given up on, with the ones
+// given up on
+async fn replay_chunk(
+    client: &ChatServiceClient<GuardedChannel>,
+    mut records: Vec<SpooledWrite>,
+) -> Vec<SpooledWrite> {
+    let mut backoff = REPLAY_MIN_BACKOFF;
+    let mut attempts = 0;
+
+    while !records.is_empty() {
+        match write_records(client, &records).await {
+            Ok(failed) => {
+                attempts += 1;
+                if failed.is_empty() {
+                    return Vec::new();
+                }
+                if attempts >= MAX_REPLAY_ATTEMPTS {
+                    for (record, e) in &failed {
+                        error!("Giving up on spooled write {:?}: {}", record, e);
+                    }
+                    return failed.into_iter().map(|(record, _)| record).collect();
+                }
+                records = failed.into_iter().map(|(record, _)| record).collect();
+            }
+            Err(e) if is_unavailable(&e) => {
+                warn!("chat-service unreachable while replaying the outbox: {}", e);
+            }
+            // refused as a whole, counts like refusing every record
+            Err(e) => {
+                attempts += 1;
+                if attempts >= MAX_REPLAY_ATTEMPTS {
+                    error!("Giving up on {} spooled writes: {}", records.len(), e);
+                    return records;
+                }
+                warn!("chat-service refused spooled writes: {}", e);
+            }
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(REPLAY_MAX_BACKOFF);
+    }
+    Vec::new()
+}
+
+// The records chat-service rejected, with its reason
+async fn write_records(
+    client: &ChatServiceClient<GuardedChannel>,
+    records: &[SpooledWrite],
+) -> Result<Vec<(SpooledWrite, String)>, tonic::Status> {
+    let mut dms = Vec::new();
+    let mut room_messages = Vec::new();
+    for record in records {
+        match record {
+            SpooledWrite::Dm(request) => dms.push(request.clone()),
+            SpooledWrite::Room(request) => room_messages.push(request.clone()),
+        }
+    }
+
+    let mut failed = Vec::new();
+    if !dms.is_empty() {
+        let request = WriteDmBatchRequest { messages: dms };
+        let response = PersistenceService::write_dm_batch_with_retry(client.clone(), &request, 0)
+            .await?
+            .into_inner();
+        let results = response
+            .results
+            .into_iter()
+            .map(|result| (result.success, result.error_message));
+        failed.extend(unconfirmed(request.messages, results, SpooledWrite::Dm));
+    }
+    if !room_messages.is_empty() {
+        let request = WriteRoomMessageBatchRequest {
+            messages: room_messages,
+        };
+        let response =
+            PersistenceService::write_room_message_batch_with_retry(client.clone(), &request, 0)
+                .await?
+                .into_inner();
+        let results = response
+            .results
+            .into_iter()
+            .map(|result| (result.success, result.error_message));
+        failed.extend(unconfirmed(request.messages, results, SpooledWrite::Room));
+    }
+    Ok(failed)
+}
+
+// The messages chat-service didn't confirm as written, in order. One without
+// a result is not known to be written, so it fails too.
+fn unconfirmed<M>(
+    messages: Vec<M>,
+    results: impl IntoIterator<Item = (bool, String)>,
+    record: fn(M) -> SpooledWrite,
+) -> Vec<(SpooledWrite, String)> {
+    let mut results = results.into_iter();
+    messages
+        .into_iter()
+        .filter_map(|message| match results.next() {
+            Some((true, _)) => None,
+            Some((false, error)) => Some((record(message), error)),
+            None => Some((record(message), "No result for batched write".to_string())),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dm_request(message_id: &str) -> WriteDmRequest {
+        WriteDmRequest {
+            project_id: "project".to_string(),
+            conversation_id: "conversation".to_string(),
+            sender_id: "alice".to_string(),
+            receiver_id: "bob".to_string(),
+            message: "hi".to_string(),
+            message_id: message_id.to_string(),
+            timestamp: 1,
+        }
+    }
+
+    fn dm(message_id: &str) -> SpooledWrite {
+        SpooledWrite::Dm(dm_request(message_id))
+    }
+
+    #[test]
+    fn keeps_records_across_restarts() {
+        let dir = std::env::temp_dir().join(format!("peroxo-outbox-{}", uuid::Uuid::new_v4()));
+
+        let outbox = DurableOutbox::open(&dir).unwrap();
+        outbox.append(&[dm("1"), dm("2")]).unwrap();
+        outbox.seal().unwrap();
+        outbox.append(&[dm("3")]).unwrap();
+        drop(outbox);
+
+        let reopened = DurableOutbox::open(&dir).unwrap();
+        assert_eq!(reopened.depth(), 3);
+        let records: Vec<_> = reopened
+            .sealed_segments()
+            .unwrap()
+            .iter()
+            .flat_map(|path| read_segment(path).unwrap())
+            .collect();
+        assert_eq!(records, vec![dm("1"), dm("2"), dm("3")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dead_letters_are_not_replayed() {
+        let dir = std::env::temp_dir().join(format!("peroxo-outbox-{}", uuid::Uuid::new_v4()));
+
+        let outbox = DurableOutbox::open(&dir).unwrap();
+        outbox.dead_letter(&[dm("1")]).unwrap();
+        outbox.append(&[dm("2")]).unwrap();
+        outbox.seal().unwrap();
+
+        let records: Vec<_> = outbox
+            .sealed_segments()
+            .unwrap()
+            .iter()
+            .flat_map(|path| read_segment(path).unwrap())
+            .collect();
+        assert_eq!(records, vec![dm("2")]);
+        // kept on disk for an operator
+        assert_eq!(
+            read_segment(&dir.join(DEAD_LETTER_FILE)).unwrap(),
+            vec![dm("1")]
+        );
+        drop(outbox);
+        assert_eq!(DurableOutbox::open(&dir).unwrap().depth(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writes_without_a_result_stay_failed() {
+        let messages = ["1", "2", "3"].map(dm_request).to_vec();
+        // chat-service answered for two of the three
+        let results = [(true, String::new()), (false, "refused".to_string())];
+
+        let failed = unconfirmed(messages, results, SpooledWrite::Dm);
+        assert_eq!(
+            failed,
+            vec![
+                (dm("2"), "refused".to_string()),
+                (dm("3"), "No result for batched write".to_string()),
+            ]
+        );
+    }
+}
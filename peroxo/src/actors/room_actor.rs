@@ -1,5 +1,9 @@
-use crate::chat::{ChatMessage, MessageAckResponse};
-use crate::tenant::TenantUserId;
+use crate::actors::framework::Actor;
+use crate::actors::user_session::outbox::{Outbox, OutboxError};
+use crate::actors::user_session::session::ConnectionId;
+use crate::chat::{ChatMessage, MessageAckResponse, TypingTarget};
+use crate::cluster::{ClusterBus, ClusterEvent};
+use crate::tenant::{TenantRoomId, TenantUserId};
 #[cfg(any(feature = "mongo_db", feature = "persistence"))]
 use crate::{actors::persistance_actor::PersistenceService, chat::PaginatedMessagesResponse};
 
@@ -7,21 +11,26 @@ use crate::{actors::persistance_actor::PersistenceService, chat::PaginatedMessag
 use crate::chat::MessageStatus;
 
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
-use tracing::{debug, info};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::{debug, error, info};
 use uuid::Uuid;
 
 #[derive(Debug)]
 pub enum RoomMessage {
+    // `respond_to` is only set for an explicit join, devices added later
+    // on behalf of an existing member don't expect an answer
     AddMember {
         tenant_user_id: TenantUserId,
-        sender: mpsc::Sender<ChatMessage>,
-        respond_to: oneshot::Sender<Result<(), String>>,
+        connections: Vec<(ConnectionId, Outbox)>,
+        respond_to: Option<oneshot::Sender<Result<(), String>>>,
     },
+    // Removes one device, or the whole member when `connection_id` is None
     RemoveMember {
         tenant_user_id: TenantUserId,
+        connection_id: Option<ConnectionId>,
     },
     SendMessage {
         from: TenantUserId,
@@ -29,87 +38,76 @@ pub enum RoomMessage {
         message_id: Uuid,
         respond_to: Option<oneshot::Sender<MessageAckResponse>>,
     },
+    Typing {
+        from: TenantUserId,
+        typing: bool,
+    },
+    // A frame the room's actor on another node already fanned out there
+    Relay {
+        message: ChatMessage,
+        skip: Option<TenantUserId>,
+    },
     GetMembers {
         respond_to: oneshot::Sender<Vec<TenantUserId>>,
     },
     #[cfg(any(feature = "mongo_db", feature = "persistence"))]
     GetPaginatedMessages {
-        project_id: String,
         message_id: Option<Uuid>,
         respond_to: oneshot::Sender<Result<PaginatedMessagesResponse, String>>,
     },
 }
 
+// Members whose connections all closed are dropped this often
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
 pub struct RoomActor {
-    room_id: String,
-    receiver: mpsc::UnboundedReceiver<RoomMessage>,
-    members: HashMap<TenantUserId, mpsc::Sender<ChatMessage>>,
+    room_id: TenantRoomId,
+    members: HashMap<TenantUserId, HashMap<ConnectionId, Outbox>>,
+    // who the room shows as typing, kept after they left until they stop or
+    // the next cleanup
+    typing: HashSet<TenantUserId>,
+    cluster: Option<Arc<dyn ClusterBus>>,
     #[cfg(any(feature = "mongo_db", feature = "persistence"))]
     persistence: Option<Arc<PersistenceService>>,
 }
 
 impl RoomActor {
     pub fn new(
-        room_id: String,
+        room_id: TenantRoomId,
+        cluster: Option<Arc<dyn ClusterBus>>,
         #[cfg(any(feature = "mongo_db", feature = "persistence"))]
         persistence: Arc<PersistenceService>,
-    ) -> (Self, mpsc::UnboundedSender<RoomMessage>) {
-        let (sender, receiver) = mpsc::unbounded_channel();
+    ) -> Self {
+        info!("Room actor started for room: {}", room_id);
 
-        let actor = Self {
+        Self {
             room_id,
-            receiver,
             members: HashMap::new(),
+            typing: HashSet::new(),
+            cluster,
             #[cfg(any(feature = "mongo_db", feature = "persistence"))]
             persistence: Some(persistence),
-        };
-
-        (actor, sender)
-    }
-
-    pub async fn run(mut self) {
-        info!("Room actor started for room: {}", self.room_id);
-
-        let mut cleanup_interval =
-            tokio::time::interval(std::time::Duration::from_secs(60));
-
-        loop {
-            tokio::select! {
-                message = self.receiver.recv() => {
-                    match message {
-                        Some(msg) => self.handle_message(msg).await,
-                        None => break,
-                    }
-                }
-                _ = cleanup_interval.tick() => {
-                    let before = self.members.len();
-                    self.members.retain(|_, sender| !sender.is_closed());
-                    let after = self.members.len();
-                    if before != after {
-                        debug!(
-                            "Cleaned up {} stale users from {}",
-                            before - after,
-                            self.room_id
-                        );
-                    }
-                }
-            }
         }
-
-        info!("Room actor stopped for room: {}", self.room_id);
     }
+}
+
+impl Actor for RoomActor {
+    type Message = RoomMessage;
 
-    async fn handle_message(&mut self, message: RoomMessage) {
+    async fn handle(&mut self, message: RoomMessage) {
         match message {
             RoomMessage::AddMember {
                 tenant_user_id,
-                sender,
+                connections,
                 respond_to,
             } => {
-                self.handle_add_member(tenant_user_id, sender, respond_to);
+                self.handle_add_member(tenant_user_id, connections, respond_to);
             }
-            RoomMessage::RemoveMember { tenant_user_id } => {
-                self.handle_remove_member(tenant_user_id);
+            RoomMessage::RemoveMember {
+                tenant_user_id,
+                connection_id,
+            } => {
+                self.handle_remove_member(tenant_user_id, connection_id);
             }
             RoomMessage::SendMessage {
                 from,
@@ -126,6 +124,12 @@ impl RoomActor {
                     respond_to,
                 );
             }
+            RoomMessage::Typing { from, typing } => {
+                self.handle_typing(from, typing);
+            }
+            RoomMessage::Relay { message, skip } => {
+                self.fan_out(&message, skip.as_ref());
+            }
             RoomMessage::GetMembers { respond_to } => {
                 let members: Vec<TenantUserId> =
                     self.members.keys().cloned().collect();
@@ -133,34 +137,81 @@ impl RoomActor {
             }
             #[cfg(any(feature = "mongo_db", feature = "persistence"))]
             RoomMessage::GetPaginatedMessages {
-                project_id,
                 message_id,
                 respond_to,
             } => {
-                self.handle_get_paginated_messages(project_id, message_id, respond_to);
+                self.handle_get_paginated_messages(message_id, respond_to);
             }
         }
     }
 
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(CLEANUP_INTERVAL)
+    }
+
+    fn tick(&mut self) {
+        let before = self.members.len();
+        self.members.retain(|_, connections| {
+            connections.retain(|_, outbox| !outbox.is_closed());
+            !connections.is_empty()
+        });
+        let members = &self.members;
+        self.typing.retain(|user| members.contains_key(user));
+        let after = self.members.len();
+        if before != after {
+            debug!(
+                "Cleaned up {} stale users from {}",
+                before - after,
+                self.room_id
+            );
+        }
+    }
+}
+
+impl RoomActor {
     fn handle_add_member(
         &mut self,
         tenant_user_id: TenantUserId,
-        sender: mpsc::Sender<ChatMessage>,
-        respond_to: oneshot::Sender<Result<(), String>>,
+        connections: Vec<(ConnectionId, Outbox)>,
+        respond_to: Option<oneshot::Sender<Result<(), String>>>,
     ) {
-        if self.members.contains_key(&tenant_user_id) {
-            let _ = respond_to.send(Err("User already in room".to_string()));
-            return;
+        if let Some(respond_to) = respond_to {
+            if self.members.contains_key(&tenant_user_id) {
+                let _ = respond_to.send(Err("User already in room".to_string()));
+                return;
+            }
+            let _ = respond_to.send(Ok(()));
         }
 
-        self.members.insert(tenant_user_id.clone(), sender);
+        self.members
+            .entry(tenant_user_id.clone())
+            .or_default()
+            .extend(connections);
         debug!("User {} added to room {}", tenant_user_id, self.room_id);
-
-        let _ = respond_to.send(Ok(()));
     }
 
-    fn handle_remove_member(&mut self, tenant_user_id: TenantUserId) {
-        if self.members.remove(&tenant_user_id).is_some() {
+    fn handle_remove_member(
+        &mut self,
+        tenant_user_id: TenantUserId,
+        connection_id: Option<ConnectionId>,
+    ) {
+        let removed = match connection_id {
+            Some(connection_id) => {
+                let Some(connections) = self.members.get_mut(&tenant_user_id) else {
+                    return;
+                };
+                connections.remove(&connection_id);
+                if connections.is_empty() {
+                    self.members.remove(&tenant_user_id);
+                    true
+                } else {
+                    false
+                }
+            }
+            None => self.members.remove(&tenant_user_id).is_some(),
+        };
+
+        if removed {
             debug!("User {} removed from room {}", tenant_user_id, self.room_id);
         }
     }
@@ -177,23 +228,23 @@ impl RoomActor {
         #[cfg(any(feature = "mongo_db", feature = "persistence"))]
         {
             if let Some(persistence) = &self.persistence {
-                let persistence = persistence.clone();
+                // persisted under the room's tenant, not whatever the sender claims
                 let room_id = self.room_id.clone();
                 let from_clone = from.clone();
                 let content_clone = content.clone();
                 let timestamp = chrono::Utc::now().timestamp_millis();
 
                 if let Some(responder) = respond_to {
+                    // queued here, so the room keeps the order of the mailbox
+                    let write = persistence.handle_persist_room_message(
+                        room_id,
+                        from_clone,
+                        content_clone,
+                        message_id,
+                        timestamp,
+                    );
                     tokio::spawn(async move {
-                        let result = persistence
-                            .handle_persist_room_message(
-                                room_id,
-                                from_clone,
-                                content_clone,
-                                message_id,
-                                timestamp,
-                            )
-                            .await;
+                        let result = write.await;
 
                         match result {
                             Ok(()) => {
@@ -217,35 +268,89 @@ impl RoomActor {
         }
 
         let message = ChatMessage::RoomMessage {
-            room_id: self.room_id.clone(),
+            room_id: self.room_id.room_id.clone(),
             from,
             content,
             message_id,
         };
 
-        for (member_id, sender) in self.members.iter() {
-            match sender.try_send(message.clone()) {
-                Ok(_) => debug!("Message sent to member {} in {}", member_id, self.room_id),
-                Err(mpsc::error::TrySendError::Full(_)) => {
-                    debug!("Member {} queue full in {}", member_id, self.room_id);
-                }
-                Err(mpsc::error::TrySendError::Closed(_)) => {
-                    debug!("Member {} channel closed in {}", member_id, self.room_id);
+        self.fan_out(&message, None);
+        self.publish(message, None);
+    }
+
+    // Pushes `message` to every local member's devices except `skip`'s
+    fn fan_out(&self, message: &ChatMessage, skip: Option<&TenantUserId>) {
+        for (member_id, connections) in self.members.iter() {
+            if Some(member_id) == skip {
+                continue;
+            }
+            for outbox in connections.values() {
+                match outbox.push(message.clone()) {
+                    Ok(_) => debug!("Message sent to member {} in {}", member_id, self.room_id),
+                    Err(OutboxError::Full) => {
+                        debug!("Member {} queue full in {}", member_id, self.room_id);
+                    }
+                    Err(OutboxError::Closed) => {
+                        debug!("Member {} channel closed in {}", member_id, self.room_id);
+                    }
                 }
             }
         }
     }
 
+    fn handle_typing(&mut self, from: TenantUserId, typing: bool) {
+        // a stop is let through from a member that left while typing, so they
+        // don't stay "typing", anyone else has to be a member
+        let was_typing = self.typing.remove(&from);
+        let is_member = self.members.contains_key(&from);
+        if !is_member && (typing || !was_typing) {
+            return;
+        }
+        if typing {
+            self.typing.insert(from.clone());
+        }
+
+        let message = ChatMessage::UserTyping {
+            from: from.clone(),
+            target: TypingTarget::Room {
+                room_id: self.room_id.room_id.clone(),
+            },
+            typing,
+        };
+
+        self.fan_out(&message, Some(&from));
+        self.publish(message, Some(from));
+    }
+
+    // Hands `message` to the room's actors on the other nodes
+    fn publish(&self, message: ChatMessage, skip: Option<TenantUserId>) {
+        let Some(cluster) = self.cluster.clone() else {
+            return;
+        };
+        let event = ClusterEvent::Room {
+            room_id: self.room_id.clone(),
+            message,
+            skip,
+        };
+        tokio::spawn(async move {
+            if let Err(e) = cluster.broadcast(event).await {
+                error!("Failed to publish room message: {}", e);
+            }
+        });
+    }
+
     #[cfg(any(feature = "mongo_db", feature = "persistence"))]
     fn handle_get_paginated_messages(
         &self,
-        project_id: String,
         message_id: Option<Uuid>,
         respond_to: oneshot::Sender<Result<PaginatedMessagesResponse, String>>,
     ) {
         if let Some(persistence) = &self.persistence {
             let persistence = persistence.clone();
-            let room_id = self.room_id.clone();
+            let TenantRoomId {
+                project_id,
+                room_id,
+            } = self.room_id.clone();
 
             tokio::spawn(async move {
                 let result = persistence
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::chat::ChatMessage;
+
+pub const RETRANSMIT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const RETRANSMIT_AFTER: Duration = Duration::from_secs(5);
+const MAX_ATTEMPTS: u32 = 5;
+// A client this far behind on acks is treated as gone
+const MAX_IN_FLIGHT: usize = 1024;
+
+struct Unacked {
+    message: ChatMessage,
+    sent_at: Instant,
+    attempts: u32,
+}
+
+// Messages written to this connection that the client hasn't acknowledged.
+// Giving up closes the connection, whatever is left is still pending in
+// chat-service and gets replayed when the client reconnects.
+#[derive(Default)]
+pub struct InFlight {
+    messages: HashMap<Uuid, Unacked>,
+}
+
+impl InFlight {
+    pub fn track(&mut self, message: &ChatMessage, now: Instant) -> Result<(), String> {
+        let Some(message_id) = message.delivery_id() else {
+            return Ok(());
+        };
+        if self.messages.len() >= MAX_IN_FLIGHT && !self.messages.contains_key(&message_id) {
+            return Err(format!("{} deliveries awaiting ack", self.messages.len()));
+        }
+
+        self.messages.insert(
+            message_id,
+            Unacked {
+                message: message.clone(),
+                sent_at: now,
+                attempts: 1,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn ack(&mut self, message_ids: &[Uuid]) {
+        for message_id in message_ids {
+            self.messages.remove(message_id);
+        }
+    }
+
+    // Messages to write again, oldest first
+    pub fn due(&mut self, now: Instant) -> Result<Vec<ChatMessage>, String> {
+        let mut due: Vec<(Instant, ChatMessage)> = Vec::new();
+        for (message_id, unacked) in self.messages.iter_mut() {
+            if now.duration_since(unacked.sent_at) < RETRANSMIT_AFTER {
+                continue;
+            }
+            if unacked.attempts >= MAX_ATTEMPTS {
+                return Err(format!(
+                    "message {} unacknowledged after {} attempts",
+                    message_id, unacked.attempts
+                ));
+            }
+            due.push((unacked.sent_at, unacked.message.clone()));
+            unacked.sent_at = now;
+            unacked.attempts += 1;
+        }
+
+        due.sort_by_key(|(sent_at, _)| *sent_at);
+        Ok(due.into_iter().map(|(_, message)| message).collect())
+    }
+}
@@ -1,19 +1,87 @@
-use crate::actors::{message_router::RouterMessage, uuid_util::NODE_ID};
-use crate::chat::ChatMessage;
+#[cfg(feature = "persistence")]
+use crate::actors::framework::ASK_TIMEOUT;
+use crate::actors::{
+    framework::Addr, message_router::RouterMessage, user_session::session::ConnectionId,
+    uuid_util::NODE_ID,
+};
+use crate::chat::{ChatMessage, ErrorCode, MessageAckResponse, MessageTarget};
+#[cfg(feature = "persistence")]
+use crate::edits::ChangeError;
 use crate::metrics::Metrics;
 use crate::tenant::TenantUserId;
+use std::collections::HashMap;
+use tokio::sync::oneshot::error::TryRecvError;
 use tokio::sync::{mpsc, oneshot};
+use tokio::task::{JoinHandle, JoinSet};
 use tracing::{debug, error};
 use uuid::Uuid;
 
+// conversation or room of the message, its client_message_id and request_id,
+// and where its ack comes from
+pub type PendingAck = (
+    MessageTarget,
+    Uuid,
+    Option<String>,
+    oneshot::Receiver<MessageAckResponse>,
+);
+
+// Forwards acks in the order their messages were sent to the same
+// conversation or room, so a message written quickly can't be acked to the
+// client before an earlier one. A write that is stuck only holds back the
+// acks of its own conversation or room. The task ends once the sender is
+// dropped and every ack was forwarded.
+pub fn spawn_ack_pump(
+    ack_sender: mpsc::Sender<ChatMessage>,
+) -> (mpsc::UnboundedSender<PendingAck>, JoinHandle<()>) {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<PendingAck>();
+    let pump = tokio::spawn(async move {
+        // closes once the last ack queued for the target went out
+        let mut last_acks: HashMap<MessageTarget, oneshot::Receiver<()>> = HashMap::new();
+        let mut forwarding = JoinSet::new();
+        while let Some((target, client_message_id, request_id, response)) = receiver.recv().await {
+            last_acks.retain(|_, sent| matches!(sent.try_recv(), Err(TryRecvError::Empty)));
+            let (sent_sender, sent) = oneshot::channel::<()>();
+            let previous = last_acks.insert(target, sent);
+            let ack_sender = ack_sender.clone();
+            forwarding.spawn(async move {
+                let _sent = sent_sender;
+                if let Some(previous) = previous {
+                    let _ = previous.await;
+                }
+                let Ok(ack_response) = response.await else {
+                    return;
+                };
+                let ack_message = ChatMessage::MessageAck {
+                    client_message_id,
+                    message_id: ack_response.message_id,
+                    timestamp: ack_response.timestamp,
+                    status: ack_response.status,
+                    request_id,
+                };
+
+                if let Err(e) = ack_sender.send(ack_message).await {
+                    error!("Failed to send acknowledgment message: {}", e);
+                }
+            });
+            while forwarding.try_join_next().is_some() {}
+        }
+        forwarding.join_all().await;
+    });
+    (sender, pump)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_direct_message(
     conversation_id: String,
     user_token: TenantUserId,
+    connection_id: ConnectionId,
     to: TenantUserId,
     content: String,
     client_message_id: Uuid,
-    router_sender: &mpsc::UnboundedSender<RouterMessage>,
+    request_id: Option<String>,
+    router_sender: &Addr<RouterMessage>,
     ack_sender: &mpsc::Sender<ChatMessage>,
+    pending_acks: &mpsc::UnboundedSender<PendingAck>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     Metrics::websocket_message_received();
     // let user_id = user_token.user_id.parse::<i32>()?;
@@ -22,35 +90,33 @@ pub async fn handle_direct_message(
 
     let server_message_id = Uuid::now_v1(&NODE_ID);
 
+    let target = MessageTarget::Conversation {
+        conversation_id: conversation_id.clone(),
+    };
     let router_msg = RouterMessage::SendDirectMessage {
         conversation_id,
         from: user_token.clone(),
+        connection_id,
         to,
         content,
         message_id: server_message_id,
+        client_message_id,
+        request_id: request_id.clone(),
         respond_to: Some(respond_to),
     };
 
-    if router_sender.send(router_msg).is_err() {
+    if router_sender.send(router_msg).await.is_err() {
         error!("Failed to send message to router for user {}", user_token);
+        send_unavailable(ack_sender, request_id).await;
         return Err("Router communication failed".into());
     }
 
-    let ack_sender_clone = ack_sender.clone();
-    tokio::spawn(async move {
-        if let Ok(ack_response) = response.await {
-            let ack_message = ChatMessage::MessageAck {
-                client_message_id,
-                message_id: ack_response.message_id,
-                timestamp: ack_response.timestamp,
-                status: ack_response.status,
-            };
-
-            if let Err(e) = ack_sender_clone.send(ack_message).await {
-                error!("Failed to send acknowledgment message: {}", e);
-            }
-        }
-    });
+    if pending_acks
+        .send((target, client_message_id, request_id, response))
+        .is_err()
+    {
+        return Err("Ack pump stopped".into());
+    }
 
     debug!(
         "Direct message handled successfully for user {}",
@@ -59,46 +125,344 @@ pub async fn handle_direct_message(
     Ok(())
 }
 // Add to handlers module in user_session:
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_room_message(
-    user_id: TenantUserId,
-    room_id: String,
     from: TenantUserId,
+    room_id: String,
     content: String,
     client_message_id: uuid::Uuid,
-    router_sender: &mpsc::UnboundedSender<RouterMessage>,
+    request_id: Option<String>,
+    router_sender: &Addr<RouterMessage>,
     ack_sender: &mpsc::Sender<ChatMessage>,
+    pending_acks: &mpsc::UnboundedSender<PendingAck>,
 ) -> Result<(), String> {
-    if from != user_id {
-        return Err("User ID mismatch".to_string());
-    }
-
     let server_message_id = Uuid::now_v1(&NODE_ID);
 
     let (respond_to, response) = oneshot::channel();
+    let target = MessageTarget::Room {
+        room_id: room_id.clone(),
+    };
     let router_msg = RouterMessage::SendRoomMessage {
-        room_id,
+        room_id: from.room(room_id),
         from,
         content,
         message_id: server_message_id,
         respond_to: Some(respond_to),
     };
 
-    router_sender
-        .send(router_msg)
-        .map_err(|_| "Failed to send to router".to_string())?;
+    if router_sender.send(router_msg).await.is_err() {
+        send_unavailable(ack_sender, request_id).await;
+        return Err("Failed to send to router".to_string());
+    }
+
+    pending_acks
+        .send((target, client_message_id, request_id, response))
+        .map_err(|_| "Ack pump stopped".to_string())?;
+
+    Ok(())
+}
+
+// Answers with a page of the room's history, or an error when the user
+// didn't join it
+#[cfg(feature = "persistence")]
+pub async fn handle_get_room_history(
+    requester: TenantUserId,
+    room_id: String,
+    message_id: Option<Uuid>,
+    request_id: Option<String>,
+    router_sender: &Addr<RouterMessage>,
+    ack_sender: &mpsc::Sender<ChatMessage>,
+) {
+    let (respond_to, response) = oneshot::channel();
+    let router_msg = RouterMessage::GetRoomHistory {
+        requester,
+        room_id: room_id.clone(),
+        message_id,
+        respond_to,
+    };
+
+    if router_sender.send(router_msg).await.is_err() {
+        error!("Failed to send room history request to router");
+        send_unavailable(ack_sender, request_id).await;
+        return;
+    }
 
     let ack_sender = ack_sender.clone();
     tokio::spawn(async move {
-        if let Ok(ack_response) = response.await {
-            let ack_msg = ChatMessage::MessageAck {
-                client_message_id,
-                message_id: ack_response.message_id,
-                timestamp: ack_response.timestamp,
-                status: ack_response.status,
-            };
-            let _ = ack_sender.send(ack_msg).await;
+        match tokio::time::timeout(ASK_TIMEOUT, response).await {
+            Ok(Ok(Ok(page))) => {
+                let response_msg = ChatMessage::RoomHistoryResponse {
+                    room_id,
+                    messages: page.messages,
+                    has_more: page.has_more,
+                    next_cursor: page.next_cursor,
+                    request_id,
+                };
+                let _ = ack_sender.send(response_msg).await;
+            }
+            Ok(Ok(Err(e))) => {
+                debug!("Room history of {} refused: {}", room_id, e);
+                send_error(&ack_sender, ErrorCode::RequestFailed, e, request_id).await;
+            }
+            _ => {
+                error!("Room history request timeout");
+                send_unavailable(&ack_sender, request_id).await;
+            }
         }
     });
+}
 
-    Ok(())
+// Edits the message, or deletes it without `content`. The change comes back
+// as MessageEdited or MessageDeleted once chat-service stored it.
+#[cfg(feature = "persistence")]
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_message_change(
+    editor: TenantUserId,
+    connection_id: ConnectionId,
+    target: MessageTarget,
+    message_id: Uuid,
+    content: Option<String>,
+    request_id: Option<String>,
+    router_sender: &Addr<RouterMessage>,
+    ack_sender: &mpsc::Sender<ChatMessage>,
+) {
+    let (respond_to, response) = oneshot::channel();
+    let router_msg = RouterMessage::ChangeMessage {
+        editor,
+        connection_id,
+        target,
+        message_id,
+        content,
+        request_id: request_id.clone(),
+        respond_to,
+    };
+
+    ask_change(
+        router_msg,
+        response,
+        message_id,
+        request_id,
+        router_sender,
+        ack_sender,
+    )
+    .await;
+}
+
+// Adds or removes a reaction of `from`, the client gets it back as
+// ReactionAdded or ReactionRemoved once chat-service stored it
+#[cfg(feature = "persistence")]
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_reaction(
+    from: TenantUserId,
+    connection_id: ConnectionId,
+    target: MessageTarget,
+    message_id: Uuid,
+    reaction: String,
+    added: bool,
+    request_id: Option<String>,
+    router_sender: &Addr<RouterMessage>,
+    ack_sender: &mpsc::Sender<ChatMessage>,
+) {
+    let (respond_to, response) = oneshot::channel();
+    let router_msg = RouterMessage::React {
+        from,
+        connection_id,
+        target,
+        message_id,
+        reaction,
+        added,
+        request_id: request_id.clone(),
+        respond_to,
+    };
+
+    ask_change(
+        router_msg,
+        response,
+        message_id,
+        request_id,
+        router_sender,
+        ack_sender,
+    )
+    .await;
+}
+
+// Sends the change to the router and passes on its answer, without holding
+// up the frames behind it
+#[cfg(feature = "persistence")]
+async fn ask_change(
+    router_msg: RouterMessage,
+    response: oneshot::Receiver<Result<ChatMessage, ChangeError>>,
+    message_id: Uuid,
+    request_id: Option<String>,
+    router_sender: &Addr<RouterMessage>,
+    ack_sender: &mpsc::Sender<ChatMessage>,
+) {
+    if router_sender.send(router_msg).await.is_err() {
+        error!("Failed to send message change to router");
+        send_unavailable(ack_sender, request_id).await;
+        return;
+    }
+
+    let ack_sender = ack_sender.clone();
+    tokio::spawn(async move {
+        match tokio::time::timeout(ASK_TIMEOUT, response).await {
+            Ok(Ok(Ok(change))) => {
+                let _ = ack_sender.send(change).await;
+            }
+            Ok(Ok(Err(e))) => {
+                debug!("Message change of {} refused: {}", message_id, e.message);
+                send_error(&ack_sender, e.code, e.message, request_id).await;
+            }
+            _ => {
+                error!("Message change request timeout");
+                send_unavailable(&ack_sender, request_id).await;
+            }
+        }
+    });
+}
+
+pub async fn send_error(
+    ack_sender: &mpsc::Sender<ChatMessage>,
+    code: ErrorCode,
+    message: impl Into<String>,
+    request_id: Option<String>,
+) {
+    let error_msg = ChatMessage::Error {
+        code,
+        message: message.into(),
+        request_id,
+    };
+
+    if let Err(e) = ack_sender.send(error_msg).await {
+        error!("Failed to send error frame: {}", e);
+    }
+}
+
+// For a request the router didn't take, or didn't answer in time
+pub async fn send_unavailable(ack_sender: &mpsc::Sender<ChatMessage>, request_id: Option<String>) {
+    send_error(
+        ack_sender,
+        ErrorCode::Unavailable,
+        "Server is busy, try again later",
+        request_id,
+    )
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::MessageStatus;
+    use std::time::Duration;
+
+    fn answered(message_id: Uuid) -> MessageAckResponse {
+        MessageAckResponse {
+            message_id,
+            timestamp: 0,
+            status: MessageStatus::Persisted,
+        }
+    }
+
+    fn conversation(conversation_id: &str) -> MessageTarget {
+        MessageTarget::Conversation {
+            conversation_id: conversation_id.to_string(),
+        }
+    }
+
+    fn acked_id(frame: ChatMessage) -> Uuid {
+        match frame {
+            ChatMessage::MessageAck {
+                client_message_id, ..
+            } => client_message_id,
+            frame => panic!("not an ack: {:?}", frame),
+        }
+    }
+
+    #[tokio::test]
+    async fn acks_echo_request_ids_in_send_order() {
+        let (ack_sender, mut acks) = mpsc::channel(8);
+        let (pending_acks, _pump) = spawn_ack_pump(ack_sender);
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let (first_respond_to, first_response) = oneshot::channel();
+        let (second_respond_to, second_response) = oneshot::channel();
+        pending_acks
+            .send((
+                conversation("c"),
+                first,
+                Some("r1".to_string()),
+                first_response,
+            ))
+            .unwrap();
+        pending_acks
+            .send((
+                conversation("c"),
+                second,
+                Some("r2".to_string()),
+                second_response,
+            ))
+            .unwrap();
+
+        // the later message is written first
+        second_respond_to.send(answered(Uuid::new_v4())).unwrap();
+        first_respond_to.send(answered(Uuid::new_v4())).unwrap();
+
+        for (expected_id, expected_request) in [(first, "r1"), (second, "r2")] {
+            match acks.recv().await.unwrap() {
+                ChatMessage::MessageAck {
+                    client_message_id,
+                    request_id,
+                    ..
+                } => {
+                    assert_eq!(client_message_id, expected_id);
+                    assert_eq!(request_id.as_deref(), Some(expected_request));
+                }
+                frame => panic!("not an ack: {:?}", frame),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_stuck_write_only_holds_back_its_own_conversation() {
+        let (ack_sender, mut acks) = mpsc::channel(8);
+        let (pending_acks, pump) = spawn_ack_pump(ack_sender);
+        let (stuck_respond_to, stuck_response) = oneshot::channel();
+        pending_acks
+            .send((conversation("c"), Uuid::new_v4(), None, stuck_response))
+            .unwrap();
+        let (behind_respond_to, behind_response) = oneshot::channel();
+        pending_acks
+            .send((conversation("c"), Uuid::new_v4(), None, behind_response))
+            .unwrap();
+        behind_respond_to.send(answered(Uuid::new_v4())).unwrap();
+
+        // more than a channel's worth, none of them waits for the stuck one
+        let mut others = Vec::new();
+        for _ in 0..300 {
+            let (respond_to, response) = oneshot::channel();
+            let (id, room) = (Uuid::new_v4(), Uuid::new_v4().to_string());
+            let target = MessageTarget::Room { room_id: room };
+            pending_acks.send((target, id, None, response)).unwrap();
+            respond_to.send(answered(Uuid::new_v4())).unwrap();
+            others.push(id);
+        }
+
+        let mut acked = Vec::new();
+        for _ in 0..others.len() {
+            acked.push(acked_id(acks.recv().await.unwrap()));
+        }
+        acked.sort();
+        others.sort();
+        assert_eq!(acked, others);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), acks.recv())
+                .await
+                .is_err()
+        );
+
+        drop(stuck_respond_to);
+        drop(pending_acks);
+        // the ack behind the dropped write still goes out
+        acks.recv().await.unwrap();
+        pump.await.unwrap();
+    }
 }
@@ -1,2 +1,6 @@
-mod handlers;
+mod delivery;
+pub(crate) mod handlers;
+mod routes;
+pub mod outbox;
 pub mod session;
+mod typing;
@@ -0,0 +1,308 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::sync::{Notify, broadcast, mpsc};
+use tokio::time::Instant;
+
+use crate::backpressure::{BackpressureConfig, MessageClass, OverflowPolicy};
+use crate::chat::ChatMessage;
+use crate::metrics::Metrics;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OutboxError {
+    Full,
+    Closed,
+}
+
+#[derive(Clone, Debug)]
+enum LaneSender {
+    Queue(mpsc::Sender<ChatMessage>),
+    DropOldest(broadcast::Sender<ChatMessage>),
+}
+
+enum LaneReceiver {
+    Queue(mpsc::Receiver<ChatMessage>),
+    DropOldest(broadcast::Receiver<ChatMessage>),
+}
+
+fn lane(size: usize, policy: OverflowPolicy) -> (LaneSender, LaneReceiver) {
+    match policy {
+        OverflowPolicy::Queue => {
+            let (sender, receiver) = mpsc::channel(size);
+            (LaneSender::Queue(sender), LaneReceiver::Queue(receiver))
+        }
+        OverflowPolicy::DropOldest => {
+            let (sender, receiver) = broadcast::channel(size);
+            (
+                LaneSender::DropOldest(sender),
+                LaneReceiver::DropOldest(receiver),
+            )
+        }
+    }
+}
+
+// Tracks how long a connection's queues have been overflowing
+#[derive(Debug)]
+struct Overflow {
+    epoch: Instant,
+    // millis since `epoch` plus one, 0 while not overflowing
+    since: AtomicU64,
+    timeout: Duration,
+    slow_consumer: Notify,
+}
+
+impl Overflow {
+    fn record(&self) {
+        let now = self.epoch.elapsed().as_millis() as u64 + 1;
+        match self
+            .since
+            .compare_exchange(0, now, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => {}
+            Err(since) if now - since >= self.timeout.as_millis() as u64 => {
+                self.slow_consumer.notify_one();
+            }
+            Err(_) => {}
+        }
+    }
+
+    fn clear(&self) {
+        self.since.store(0, Ordering::Release);
+    }
+}
+
+// Producer side of a connection, held by the router and the rooms it joined.
+#[derive(Clone, Debug)]
+pub struct Outbox {
+    chat: LaneSender,
+    ephemeral: LaneSender,
+    overflow: Arc<Overflow>,
+}
+
+pub struct OutboxReceiver {
+    chat: LaneReceiver,
+    ephemeral: LaneReceiver,
+    overflow: Arc<Overflow>,
+}
+
+impl Outbox {
+    pub fn new(config: &BackpressureConfig) -> (Self, OutboxReceiver) {
+        let (chat, chat_receiver) = lane(config.chat_queue, config.chat_policy);
+        let (ephemeral, ephemeral_receiver) = lane(config.ephemeral_queue, config.ephemeral_policy);
+        let overflow = Arc::new(Overflow {
+            epoch: Instant::now(),
+            since: AtomicU64::new(0),
+            timeout: config.slow_consumer_timeout,
+            slow_consumer: Notify::new(),
+        });
+
+        (
+            Self {
+                chat,
+                ephemeral,
+                overflow: overflow.clone(),
+            },
+            OutboxReceiver {
+                chat: chat_receiver,
+                ephemeral: ephemeral_receiver,
+                overflow,
+            },
+        )
+    }
+
+    // Never waits, callers are actors
+    pub fn push(&self, message: ChatMessage) -> Result<(), OutboxError> {
+        let class = MessageClass::of(&message);
+        let lane = match class {
+            MessageClass::Chat => &self.chat,
+            MessageClass::Ephemeral => &self.ephemeral,
+        };
+
+        match lane {
+            LaneSender::Queue(sender) => match sender.try_send(message) {
+                Ok(()) => Ok(()),
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    Metrics::message_dropped("session", class.as_str(), 1);
+                    self.overflow.record();
+                    Err(OutboxError::Full)
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => Err(OutboxError::Closed),
+            },
+            // evictions are counted by the receiver when it notices the lag
+            LaneSender::DropOldest(sender) => sender
+                .send(message)
+                .map(|_| ())
+                .map_err(|_| OutboxError::Closed),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        match &self.chat {
+            LaneSender::Queue(sender) => sender.is_closed(),
+            LaneSender::DropOldest(sender) => sender.receiver_count() == 0,
+        }
+    }
+}
+
+impl OutboxReceiver {
+    // Chat first, then ephemeral. None once every producer is gone.
+    pub async fn recv(&mut self) -> Option<ChatMessage> {
+        loop {
+            let (message, from_chat) = tokio::select! {
+                biased;
+                message = recv_lane(&mut self.chat, MessageClass::Chat) => (message, true),
+                message = recv_lane(&mut self.ephemeral, MessageClass::Ephemeral) => (message, false),
+            };
+
+            match message {
+                LaneMessage::Message(message) => {
+                    if from_chat && lane_is_empty(&self.chat) {
+                        self.overflow.clear();
+                    }
+                    return Some(message);
+                }
+                LaneMessage::Lagged => continue,
+                LaneMessage::Closed => return None,
+            }
+        }
+    }
+
+    // Resolves once the connection has been overflowing for too long. Doesn't
+    // borrow the receiver, so it can be awaited alongside `recv`.
+    pub fn slow_consumer(&self) -> impl Future<Output = ()> + use<> {
+        let overflow = self.overflow.clone();
+        async move { overflow.slow_consumer.notified().await }
+    }
+}
+
+enum LaneMessage {
+    Message(ChatMessage),
+    Lagged,
+    Closed,
+}
+
+async fn recv_lane(lane: &mut LaneReceiver, class: MessageClass) -> LaneMessage {
+    match lane {
+        LaneReceiver::Queue(receiver) => match receiver.recv().await {
+            Some(message) => LaneMessage::Message(message),
+            None => LaneMessage::Closed,
+        },
+        LaneReceiver::DropOldest(receiver) => match receiver.recv().await {
+            Ok(message) => LaneMessage::Message(message),
+            Err(broadcast::error::RecvError::Lagged(dropped)) => {
+                Metrics::message_dropped("session", class.as_str(), dropped);
+                LaneMessage::Lagged
+            }
+            Err(broadcast::error::RecvError::Closed) => LaneMessage::Closed,
+        },
+    }
+}
+
+fn lane_is_empty(lane: &LaneReceiver) -> bool {
+    match lane {
+        LaneReceiver::Queue(receiver) => receiver.is_empty(),
+        LaneReceiver::DropOldest(receiver) => receiver.is_empty(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{ErrorCode, PresenceStatus};
+    use crate::tenant::TenantUserId;
+
+    fn chat(message: &str) -> ChatMessage {
+        ChatMessage::Error {
+            code: ErrorCode::RequestFailed,
+            message: message.to_string(),
+            request_id: None,
+        }
+    }
+
+    fn presence(user_id: &str) -> ChatMessage {
+        ChatMessage::Presence {
+            user: TenantUserId::new("p".to_string(), user_id.to_string()),
+            status: PresenceStatus::Online,
+        }
+    }
+
+    fn text(message: ChatMessage) -> String {
+        match message {
+            ChatMessage::Error { message, .. } => message,
+            ChatMessage::Presence { user, .. } => user.user_id,
+            message => panic!("unexpected frame: {:?}", message),
+        }
+    }
+
+    // Shared by every test in the binary, so only ever compared as a lower bound
+    fn dropped(class: &str) -> f64 {
+        prometheus::gather()
+            .iter()
+            .filter(|family| family.name() == "messages_dropped_total")
+            .flat_map(|family| family.get_metric())
+            .filter(|metric| {
+                metric
+                    .get_label()
+                    .iter()
+                    .any(|label| label.name() == "class" && label.value() == class)
+            })
+            .map(|metric| metric.get_counter().value())
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn chat_keeps_the_oldest_and_typing_keeps_the_newest() {
+        let config = BackpressureConfig::default()
+            .with_chat_queue(2, OverflowPolicy::Queue)
+            .with_ephemeral_queue(2, OverflowPolicy::DropOldest);
+        let (outbox, mut receiver) = Outbox::new(&config);
+        let (chat_dropped, ephemeral_dropped) = (dropped("chat"), dropped("ephemeral"));
+
+        for user_id in ["a", "b", "c"] {
+            outbox.push(presence(user_id)).unwrap();
+        }
+        outbox.push(chat("1")).unwrap();
+        outbox.push(chat("2")).unwrap();
+        assert_eq!(outbox.push(chat("3")), Err(OutboxError::Full));
+
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            received.push(text(receiver.recv().await.unwrap()));
+        }
+        // chat goes first, the oldest presence update was evicted
+        assert_eq!(received, vec!["1", "2", "b", "c"]);
+        assert!(dropped("chat") >= chat_dropped + 1.0);
+        assert!(dropped("ephemeral") >= ephemeral_dropped + 1.0);
+    }
+
+    #[tokio::test]
+    async fn only_sustained_overflow_makes_a_slow_consumer() {
+        let timeout = Duration::from_millis(50);
+        let config = BackpressureConfig::default()
+            .with_chat_queue(1, OverflowPolicy::Queue)
+            .with_slow_consumer_timeout(timeout);
+        let (outbox, mut receiver) = Outbox::new(&config);
+        let slow_consumer = receiver.slow_consumer();
+        tokio::pin!(slow_consumer);
+
+        outbox.push(chat("1")).unwrap();
+        assert_eq!(outbox.push(chat("2")), Err(OutboxError::Full));
+        tokio::time::sleep(timeout).await;
+        // caught up in time, the overflow starts over
+        receiver.recv().await.unwrap();
+        outbox.push(chat("3")).unwrap();
+        assert_eq!(outbox.push(chat("4")), Err(OutboxError::Full));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), &mut slow_consumer)
+                .await
+                .is_err()
+        );
+
+        tokio::time::sleep(timeout).await;
+        assert_eq!(outbox.push(chat("5")), Err(OutboxError::Full));
+        tokio::time::timeout(Duration::from_secs(1), slow_consumer)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{oneshot, watch};
+use tracing::{error, info};
+
+use crate::actors::framework::{ASK_TIMEOUT, Addr};
+use crate::actors::message_router::RouterMessage;
+use crate::actors::user_session::outbox::Outbox;
+use crate::actors::user_session::session::ConnectionId;
+use crate::tenant::TenantUserId;
+
+// What a session asked its router shard to remember, so it can ask again
+// when the shard is restarted without its state
+#[derive(Default)]
+pub struct Routes {
+    // asked for but not confirmed by the router yet
+    joining: HashSet<String>,
+    rooms: HashSet<String>,
+    presence: HashSet<String>,
+    conversation_partners: bool,
+}
+
+pub type SharedRoutes = Arc<Mutex<Routes>>;
+
+impl Routes {
+    pub fn joining(&mut self, room_id: &str) {
+        self.joining.insert(room_id.to_string());
+    }
+
+    // Only kept when the room wasn't left in the meantime
+    pub fn joined(&mut self, room_id: &str) {
+        if self.joining.remove(room_id) {
+            self.rooms.insert(room_id.to_string());
+        }
+    }
+
+    // Also for joins that failed
+    pub fn leave_room(&mut self, room_id: &str) {
+        self.joining.remove(room_id);
+        self.rooms.remove(room_id);
+    }
+
+    pub fn subscribe_presence(&mut self, user_ids: &[String], conversation_partners: bool) {
+        self.presence.extend(user_ids.iter().cloned());
+        self.conversation_partners |= conversation_partners;
+    }
+
+    pub fn unsubscribe_presence(&mut self, user_ids: &[String]) {
+        for user_id in user_ids {
+            self.presence.remove(user_id);
+        }
+    }
+}
+
+// Registers the connection again, with its rooms and presence
+// subscriptions, every time its router shard comes back from a crash
+pub async fn watch_restarts(
+    tenant_user_id: TenantUserId,
+    connection_id: ConnectionId,
+    outbox: Outbox,
+    routes: SharedRoutes,
+    router: Addr<RouterMessage>,
+    mut restarts: watch::Receiver<u64>,
+) {
+    while restarts.changed().await.is_ok() {
+        let registered = router
+            .ask(
+                |respond_to| RouterMessage::RegisterUser {
+                    tenant_user_id: tenant_user_id.clone(),
+                    connection_id,
+                    outbox: outbox.clone(),
+                    respond_to,
+                },
+                ASK_TIMEOUT,
+            )
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|registered| registered);
+        if let Err(e) = registered {
+            error!(
+                "Failed to register {} again after a router restart: {}",
+                tenant_user_id, e
+            );
+            continue;
+        }
+
+        let (rooms, presence, conversation_partners) = {
+            let routes = routes.lock().unwrap();
+            (
+                routes.rooms.iter().cloned().collect::<Vec<_>>(),
+                routes.presence.iter().cloned().collect::<Vec<_>>(),
+                routes.conversation_partners,
+            )
+        };
+
+        for room_id in rooms {
+            let (respond_to, _) = oneshot::channel();
+            let _ = router
+                .send(RouterMessage::JoinRoom {
+                    tenant_user_id: tenant_user_id.clone(),
+                    room_id: tenant_user_id.room(room_id),
+                    respond_to,
+                })
+                .await;
+        }
+        if !presence.is_empty() || conversation_partners {
+            let _ = router
+                .send(RouterMessage::SubscribePresence {
+                    subscriber: tenant_user_id.clone(),
+                    connection_id,
+                    targets: presence
+                        .into_iter()
+                        .map(|user_id| tenant_user_id.peer(user_id))
+                        .collect(),
+                    conversation_partners,
+                    request_id: None,
+                })
+                .await;
+        }
+
+        info!(
+            "Registered {} again after its router restarted",
+            tenant_user_id
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::actors::framework::{self, Actor};
+    use crate::backpressure::BackpressureConfig;
+
+    // Stands in for a restarted shard, reports the rooms it is asked to join
+    struct Shard {
+        joins: mpsc::UnboundedSender<String>,
+    }
+
+    impl Actor for Shard {
+        type Message = RouterMessage;
+
+        async fn handle(&mut self, message: RouterMessage) {
+            match message {
+                RouterMessage::RegisterUser { respond_to, .. } => {
+                    let _ = respond_to.send(Ok(()));
+                }
+                RouterMessage::JoinRoom { room_id, .. } => {
+                    let _ = self.joins.send(room_id.room_id);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_restarted_shard_gets_the_confirmed_rooms_back() {
+        let routes = SharedRoutes::default();
+        {
+            let mut routes = routes.lock().unwrap();
+            routes.joining("confirmed");
+            routes.joined("confirmed");
+            routes.joining("unanswered");
+            routes.joining("failed");
+            routes.leave_room("failed");
+            // confirmed only after the user left it again
+            routes.joining("left");
+            routes.leave_room("left");
+            routes.joined("left");
+        }
+
+        let (joins, mut joined) = mpsc::unbounded_channel();
+        let shard = framework::spawn(Shard { joins }, 8);
+        let (restarted, restarts) = watch::channel(0);
+        let (outbox, _frames) = Outbox::new(&BackpressureConfig::default());
+        tokio::spawn(watch_restarts(
+            TenantUserId::new("p".to_string(), "alice".to_string()),
+            ConnectionId::new_v4(),
+            outbox,
+            routes,
+            shard,
+            restarts,
+        ));
+        restarted.send(1).unwrap();
+
+        let mut rooms = Vec::new();
+        while let Ok(Some(room_id)) =
+            tokio::time::timeout(Duration::from_millis(100), joined.recv()).await
+        {
+            rooms.push(room_id);
+        }
+        assert_eq!(rooms, vec!["confirmed"]);
+    }
+}
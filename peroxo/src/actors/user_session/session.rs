@@ -1,60 +1,115 @@
-use crate::actors::{message_router::RouterMessage, user_session::handlers};
-use crate::chat::ChatMessage;
+use crate::actors::framework::{ASK_TIMEOUT, Addr};
+use crate::actors::message_router::directory::UserDirectory;
+use crate::actors::message_router::limits::TenantLimiter;
+use crate::actors::message_router::{RouterHandle, RouterMessage};
+use crate::actors::user_session::outbox::{Outbox, OutboxReceiver};
+use crate::actors::user_session::{delivery, handlers, routes, typing};
+use crate::auth::{Authenticator, Credentials, TokenExpiry};
+use crate::backpressure::BackpressureConfig;
+use crate::chat::{ChatMessage, ErrorCode};
+use crate::heartbeat::{HeartbeatConfig, Liveness};
 use crate::metrics::Metrics;
+use crate::rate_limit::{RateClass, SessionLimiter, Verdict};
+use crate::shutdown::Drain;
 use crate::tenant::TenantUserId;
-use axum::extract::ws::{Message, WebSocket};
+use crate::wire::{self, WireProtocol};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
 use futures::{SinkExt, StreamExt};
-use tokio::sync::{mpsc, oneshot};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
 use tracing::{debug, error};
 
+// Identifies one WebSocket of a user, a user can be connected from several devices.
+pub type ConnectionId = uuid::Uuid;
+
+// Sent when the client doesn't read fast enough to drain its queue
+pub const CLOSE_SLOW_CONSUMER: u16 = 4008;
+// Sent when the client stopped answering pings
+pub const CLOSE_IDLE_TIMEOUT: u16 = 4000;
+// Sent after Reconnect when the server shuts down
+pub const CLOSE_SERVICE_RESTART: u16 = 1012;
+// Sent when the token ran out without a Reauthenticate
+pub const CLOSE_TOKEN_EXPIRED: u16 = 4001;
+// Sent when a client without a token didn't authenticate with its first frame
+pub const CLOSE_UNAUTHENTICATED: u16 = 4003;
+// Sent when the client kept going past its rate limits
+pub const CLOSE_RATE_LIMITED: u16 = 4029;
+
+// How long a Close frame to a client that went quiet may take to go out
+const CLOSE_SEND_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub struct UserSession {
     tenant_user_id: TenantUserId,
+    connection_id: ConnectionId,
     socket: WebSocket,
-    router_sender: mpsc::UnboundedSender<RouterMessage>,
-    session_receiver: mpsc::Receiver<ChatMessage>,
-    session_sender: mpsc::Sender<ChatMessage>,
+    protocol: WireProtocol,
+    router_sender: Addr<RouterMessage>,
+    directory: UserDirectory,
+    outbox: Outbox,
+    session_receiver: OutboxReceiver,
+    // changes when the router shard restarted and lost this registration
+    restarts: watch::Receiver<u64>,
+    heartbeat: HeartbeatConfig,
+    drain: Drain,
+    authenticator: Authenticator,
+    expiry: TokenExpiry,
+    limiter: SessionLimiter,
+    tenant_limiter: TenantLimiter,
 }
 
 impl UserSession {
     pub async fn new(
-        tenant_user_id: TenantUserId,
+        credentials: Credentials,
         socket: WebSocket,
-        router_sender: mpsc::UnboundedSender<RouterMessage>,
+        router: &RouterHandle,
+        backpressure: &BackpressureConfig,
+        heartbeat: &HeartbeatConfig,
+        drain: &Drain,
+        authenticator: &Authenticator,
     ) -> Result<Self, String> {
-        // get a better number
-        const CHANNEL_BUFFER_SIZE: usize = 100;
-        let (session_sender, session_receiver) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+        let tenant_user_id = credentials.tenant_user_id;
+        // everything the session sends goes to its project's shard
+        let router_sender = router.shard(&tenant_user_id.project_id).clone();
+        let (outbox, session_receiver) = Outbox::new(backpressure);
+        let connection_id = uuid::Uuid::new_v4();
 
-        // Register with the message router
-        let (respond_to, response) = oneshot::channel();
-        let register_msg = RouterMessage::RegisterUser {
-            tenant_user_id: tenant_user_id.clone(),
-            sender: session_sender.clone(),
-            respond_to,
-        };
+        let restarts = router.restarts(&tenant_user_id.project_id);
 
-        if router_sender.send(register_msg).is_err() {
-            return Err("Failed to communicate with message router".to_string());
-        }
-
-        match response.await {
-            Ok(Ok(())) => {
-                debug!("User {} registered successfully", tenant_user_id);
-            }
-            Ok(Err(e)) => {
-                return Err(e);
-            }
-            Err(_) => {
-                return Err("Router response channel closed".to_string());
-            }
-        }
+        // Register with the message router
+        let registered = router_sender
+            .ask(
+                |respond_to| RouterMessage::RegisterUser {
+                    tenant_user_id: tenant_user_id.clone(),
+                    connection_id,
+                    outbox: outbox.clone(),
+                    respond_to,
+                },
+                ASK_TIMEOUT,
+            )
+            .await
+            .map_err(|e| format!("Failed to register with the message router: {}", e))?;
+        registered?;
+        debug!(
+            "User {} registered successfully on connection {}",
+            tenant_user_id, connection_id
+        );
 
         Ok(Self {
-            tenant_user_id,
+            connection_id,
+            protocol: WireProtocol::negotiated(socket.protocol()),
             socket,
             router_sender,
+            directory: router.directory.clone(),
+            outbox,
             session_receiver,
-            session_sender,
+            restarts,
+            heartbeat: heartbeat.clone(),
+            drain: drain.clone(),
+            authenticator: authenticator.clone(),
+            expiry: TokenExpiry::new(credentials.expires_at),
+            limiter: SessionLimiter::new(router.rate_limits.config(), &tenant_user_id.project_id),
+            tenant_limiter: router.rate_limits.clone(),
+            tenant_user_id,
         })
     }
 
@@ -62,22 +117,48 @@ impl UserSession {
         let (mut ws_sender, mut ws_receiver) = self.socket.split();
 
         let router_sender = self.router_sender.clone();
-        let session_sender_for_rooms = self.session_sender.clone();
+        let connection_id = self.connection_id;
         let mut session_receiver = self.session_receiver;
+        let protocol = self.protocol;
+        let heartbeat = self.heartbeat.clone();
+        let liveness = Liveness::default();
+        let expiry = self.expiry.clone();
 
         let (ack_sender, mut ack_receiver) = mpsc::channel::<ChatMessage>(100);
+        // set when draining, with the retry_after_ms of the Reconnect frame
+        let (close_sender, mut close_receiver) = oneshot::channel::<u64>();
+        // set when the client ran into its rate limits too often
+        let (kick_sender, mut kick_receiver) = oneshot::channel::<()>();
+        // AckDelivery ids from the client, for the retransmission in the send task
+        let (delivered_sender, mut delivered_receiver) = mpsc::channel::<Vec<uuid::Uuid>>(100);
         // Task to handle outgoing messages (from session to WebSocket)
         let tenant_user_id_clone = self.tenant_user_id.clone();
+        let liveness_clone = liveness.clone();
+        let expiry_clone = expiry.clone();
         let mut send_task = tokio::spawn(async move {
+            let mut in_flight = delivery::InFlight::default();
+            let mut retransmit_interval =
+                tokio::time::interval(delivery::RETRANSMIT_CHECK_INTERVAL);
+            let mut ping_interval = tokio::time::interval_at(
+                tokio::time::Instant::now() + heartbeat.ping_interval,
+                heartbeat.ping_interval,
+            );
+            let slow_consumer = session_receiver.slow_consumer();
+            tokio::pin!(slow_consumer);
+
             loop {
                 tokio::select! {
                     // Handle regular messages from session_receiver
                     message = session_receiver.recv() => {
                         match message {
                             Some(msg) => {
-                                match serde_json::to_string(&msg) {
-                                    Ok(json) => {
-                                        if ws_sender.send(Message::Text(json.into())).await.is_err() {
+                                if let Err(e) = in_flight.track(&msg, tokio::time::Instant::now()) {
+                                    debug!("Closing connection of {}: {}", tenant_user_id_clone, e);
+                                    break;
+                                }
+                                match protocol.encode(&msg) {
+                                    Ok(frame) => {
+                                        if ws_sender.send(frame).await.is_err() {
                                             debug!(
                                                 "WebSocket send failed for user {}, likely disconnected",
                                                 tenant_user_id_clone
@@ -98,9 +179,9 @@ impl UserSession {
                     ack_message = ack_receiver.recv() => {
                         match ack_message {
                             Some(msg) => {
-                                match serde_json::to_string(&msg) {
-                                    Ok(json) => {
-                                        if ws_sender.send(Message::Text(json.into())).await.is_err() {
+                                match protocol.encode(&msg) {
+                                    Ok(frame) => {
+                                        if ws_sender.send(frame).await.is_err() {
                                             debug!(
                                                 "WebSocket send failed for user {}, likely disconnected",
                                                 tenant_user_id_clone
@@ -116,31 +197,231 @@ impl UserSession {
                             None => break, // Channel closed
                         }
                     }
+                    Some(message_ids) = delivered_receiver.recv() => {
+                        in_flight.ack(&message_ids);
+                    }
+                    Ok(retry_after_ms) = &mut close_receiver => {
+                        // the acks forwarded before the drain still go out first
+                        let mut pending: Vec<_> =
+                            std::iter::from_fn(|| ack_receiver.try_recv().ok()).collect();
+                        pending.push(ChatMessage::Reconnect { retry_after_ms });
+                        for msg in pending {
+                            let Ok(frame) = protocol.encode(&msg) else {
+                                continue;
+                            };
+                            if ws_sender.send(frame).await.is_err() {
+                                break;
+                            }
+                        }
+                        let _ = ws_sender
+                            .send(Message::Close(Some(CloseFrame {
+                                code: CLOSE_SERVICE_RESTART,
+                                reason: "server restarting".into(),
+                            })))
+                            .await;
+                        break;
+                    }
+                    Ok(()) = &mut kick_receiver => {
+                        debug!("Disconnecting {} for exceeding its rate limits", tenant_user_id_clone);
+                        Metrics::rate_limit_disconnected();
+                        // the RateLimited answers queued before still go out first
+                        while let Ok(msg) = ack_receiver.try_recv() {
+                            let Ok(frame) = protocol.encode(&msg) else {
+                                continue;
+                            };
+                            if ws_sender.send(frame).await.is_err() {
+                                break;
+                            }
+                        }
+                        let close = ws_sender.send(Message::Close(Some(CloseFrame {
+                            code: CLOSE_RATE_LIMITED,
+                            reason: "rate limit exceeded".into(),
+                        })));
+                        let _ = tokio::time::timeout(CLOSE_SEND_TIMEOUT, close).await;
+                        break;
+                    }
+                    _ = &mut slow_consumer => {
+                        debug!("Disconnecting slow consumer {}", tenant_user_id_clone);
+                        Metrics::slow_consumer_disconnected();
+                        let _ = ws_sender
+                            .send(Message::Close(Some(CloseFrame {
+                                code: CLOSE_SLOW_CONSUMER,
+                                reason: "slow consumer".into(),
+                            })))
+                            .await;
+                        break;
+                    }
+                    _ = ping_interval.tick() => {
+                        // the pong, like any other frame, is noted by the recv task
+                        if ws_sender.send(Message::Ping(Default::default())).await.is_err() {
+                            break;
+                        }
+                    }
+                    // a half-open connection never errors, it just goes quiet
+                    _ = liveness_clone.idle(heartbeat.idle_timeout) => {
+                        debug!("Closing idle connection of {}", tenant_user_id_clone);
+                        Metrics::idle_connection_closed();
+                        let close = ws_sender.send(Message::Close(Some(CloseFrame {
+                            code: CLOSE_IDLE_TIMEOUT,
+                            reason: "idle timeout".into(),
+                        })));
+                        let _ = tokio::time::timeout(CLOSE_SEND_TIMEOUT, close).await;
+                        break;
+                    }
+                    _ = expiry_clone.expired() => {
+                        debug!("Closing connection of {}, its token expired", tenant_user_id_clone);
+                        Metrics::token_expired_connection_closed();
+                        let close = ws_sender.send(Message::Close(Some(CloseFrame {
+                            code: CLOSE_TOKEN_EXPIRED,
+                            reason: "token expired".into(),
+                        })));
+                        let _ = tokio::time::timeout(CLOSE_SEND_TIMEOUT, close).await;
+                        break;
+                    }
+                    _ = retransmit_interval.tick() => {
+                        let due = match in_flight.due(tokio::time::Instant::now()) {
+                            Ok(due) => due,
+                            Err(e) => {
+                                debug!("Closing connection of {}: {}", tenant_user_id_clone, e);
+                                break;
+                            }
+                        };
+                        let mut closed = false;
+                        for msg in due {
+                            let Ok(frame) = protocol.encode(&msg) else {
+                                continue;
+                            };
+                            if ws_sender.send(frame).await.is_err() {
+                                closed = true;
+                                break;
+                            }
+                        }
+                        if closed {
+                            break;
+                        }
+                    }
                 }
             }
         });
 
+        // Typing indicators expire on their own, so they are tracked outside the recv task
+        let (typing_sender, typing_receiver) = mpsc::channel(typing::TYPING_CHANNEL_SIZE);
+        tokio::spawn(typing::run(
+            self.tenant_user_id.clone(),
+            typing_receiver,
+            router_sender.clone(),
+        ));
+
+        let (pending_acks, ack_pump) = handlers::spawn_ack_pump(ack_sender.clone());
+        // keeps the ack channel open while draining, when the recv task and
+        // the pump are gone but the send task still has to say goodbye
+        let _ack_sender = ack_sender.clone();
+
+        let routes = routes::SharedRoutes::default();
+        let reregister_task = tokio::spawn(routes::watch_restarts(
+            self.tenant_user_id.clone(),
+            connection_id,
+            self.outbox.clone(),
+            routes.clone(),
+            router_sender.clone(),
+            self.restarts.clone(),
+        ));
+
         // Task to handle incoming messages (from WebSocket to router)
         let tenant_user_id_clone = self.tenant_user_id.clone();
         let router_sender_clone = router_sender.clone();
+        let directory = self.directory.clone();
+        let authenticator = self.authenticator.clone();
+        let mut limiter = self.limiter;
+        let tenant_limiter = self.tenant_limiter.clone();
+        let mut kick_sender = Some(kick_sender);
 
         let mut recv_task = tokio::spawn(async move {
-            while let Some(Ok(Message::Text(text))) = ws_receiver.next().await {
-                match serde_json::from_str::<ChatMessage>(&text) {
-                    Ok(ChatMessage::SendDirectMessage {
+            while let Some(frame) = ws_receiver.next().await {
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        debug!("WebSocket of {} failed: {}", tenant_user_id_clone, e);
+                        break;
+                    }
+                };
+                liveness.seen();
+                match &frame {
+                    Message::Text(_) | Message::Binary(_) => {}
+                    // pings are answered by the socket itself
+                    Message::Ping(_) | Message::Pong(_) => continue,
+                    Message::Close(close) => {
+                        match close {
+                            Some(close) => debug!(
+                                "{} closed the connection: {} {}",
+                                tenant_user_id_clone, close.code, close.reason
+                            ),
+                            None => debug!("{} closed the connection", tenant_user_id_clone),
+                        }
+                        // reading on sends the reply and ends the stream
+                        continue;
+                    }
+                }
+                // the connection is being closed, nothing more is taken from it
+                if kick_sender.is_none() {
+                    continue;
+                }
+                let (message, request_id) = match wire::decode(&frame) {
+                    Ok(request) => (request.message, request.request_id),
+                    Err(e) => {
+                        debug!("Refused frame from {}: {}", tenant_user_id_clone, e.message);
+                        handlers::send_error(&ack_sender, e.code, e.message, e.request_id).await;
+                        continue;
+                    }
+                };
+                if let Some(class) = RateClass::of(&message) {
+                    let now = tokio::time::Instant::now();
+                    // the connection's own limits first, a noisy client
+                    // doesn't use up its project's
+                    let limited = match limiter.check(class, now) {
+                        Verdict::Allowed => tenant_limiter
+                            .take(&tenant_user_id_clone.project_id, class, now)
+                            .err()
+                            .map(|retry_after| (retry_after, "tenant")),
+                        Verdict::Limited(retry_after) => Some((retry_after, "session")),
+                        Verdict::Disconnect => {
+                            Metrics::rate_limited(class.as_str(), "session");
+                            if let Some(kick_sender) = kick_sender.take() {
+                                let _ = kick_sender.send(());
+                            }
+                            continue;
+                        }
+                    };
+                    if let Some((retry_after, scope)) = limited {
+                        Metrics::rate_limited(class.as_str(), scope);
+                        let rate_limited = ChatMessage::RateLimited {
+                            retry_after_ms: retry_after.as_millis().max(1) as u64,
+                            request_id,
+                        };
+                        let _ = ack_sender.send(rate_limited).await;
+                        continue;
+                    }
+                }
+                match message {
+                    ChatMessage::SendDirectMessage {
                         conversation_id,
                         to,
                         content,
                         client_message_id,
-                    }) => {
+                    } => {
+                        // recipients are always resolved inside the authenticated project
+                        let to = tenant_user_id_clone.peer(to);
                         if let Err(e) = handlers::handle_direct_message(
                             conversation_id,
                             tenant_user_id_clone.clone(),
+                            connection_id,
                             to,
                             content,
                             client_message_id,
+                            request_id,
                             &router_sender_clone,
                             &ack_sender,
+                            &pending_acks,
                         )
                         .await
                         {
@@ -148,129 +429,462 @@ impl UserSession {
                         }
                     }
                     #[cfg(feature = "persistence")]
-                    Ok(ChatMessage::GetPaginatedMessages {
-                        project_id,
+                    ChatMessage::GetPaginatedMessages {
                         message_id,
                         conversation_id,
-                    }) => {
+                    } => {
                         let (respond_to, response) = oneshot::channel();
                         let router_msg = RouterMessage::GetPaginatedMessages {
-                            project_id,
+                            requester: tenant_user_id_clone.clone(),
                             message_id,
-                            conversation_id,
+                            conversation_id: conversation_id.clone(),
                             respond_to,
                         };
 
-                        if router_sender_clone.send(router_msg).is_err() {
+                        if router_sender_clone.send(router_msg).await.is_err() {
                             error!("Failed to send chat history request to router");
+                            handlers::send_unavailable(&ack_sender, request_id).await;
                         } else {
                             let ack_sender_clone = ack_sender.clone();
                             tokio::spawn(async move {
-                                match response.await {
-                                    Ok(Ok(paginated_response)) => {
+                                match tokio::time::timeout(ASK_TIMEOUT, response).await {
+                                    Ok(Ok(Ok(paginated_response))) => {
                                         let response_msg = ChatMessage::ChatHistoryResponse {
+                                            conversation_id,
                                             messages: paginated_response.messages,
                                             has_more: paginated_response.has_more,
                                             next_cursor: paginated_response.next_cursor.and_then(
                                                 |cursor| uuid::Uuid::parse_str(&cursor).ok(),
                                             ),
+                                            request_id,
                                         };
                                         let _ = ack_sender_clone.send(response_msg).await;
                                     }
-                                    Ok(Err(e)) => {
+                                    Ok(Ok(Err(e))) => {
                                         error!("Failed to get chat history: {}", e);
+                                        handlers::send_error(
+                                            &ack_sender_clone,
+                                            ErrorCode::RequestFailed,
+                                            e,
+                                            request_id,
+                                        )
+                                        .await;
                                     }
-                                    Err(_) => {
+                                    _ => {
                                         error!("Chat history request timeout");
+                                        handlers::send_unavailable(&ack_sender_clone, request_id)
+                                            .await;
                                     }
                                 }
                             });
                         }
                     }
 
-                    Ok(ChatMessage::RoomMessage {
+                    ChatMessage::SendRoomMessage {
                         room_id,
-                        from,
                         content,
-                        message_id,
-                    }) => {
+                        client_message_id,
+                    } => {
                         if let Err(e) = handlers::handle_room_message(
                             tenant_user_id_clone.clone(),
                             room_id,
-                            from,
                             content,
-                            message_id,
+                            client_message_id,
+                            request_id,
                             &router_sender_clone,
                             &ack_sender,
+                            &pending_acks,
                         )
                         .await
                         {
                             error!("Failed to handle room message: {}", e);
                         }
                     }
-                    Ok(ChatMessage::JoinRoom { room_id }) => {
-                        let (respond_to, _) = oneshot::channel();
+                    ChatMessage::JoinRoom { room_id } => {
+                        routes.lock().unwrap().joining(&room_id);
+                        let (respond_to, response) = oneshot::channel();
                         let router_msg = RouterMessage::JoinRoom {
                             tenant_user_id: tenant_user_id_clone.clone(),
-                            room_id,
-                            sender: session_sender_for_rooms.clone(),
+                            room_id: tenant_user_id_clone.room(room_id.clone()),
                             respond_to,
                         };
 
-                        if router_sender_clone.send(router_msg).is_err() {
+                        if router_sender_clone.send(router_msg).await.is_err() {
                             error!("Failed to send join room request to router");
+                            routes.lock().unwrap().leave_room(&room_id);
+                            handlers::send_unavailable(&ack_sender, request_id).await;
+                        } else {
+                            let ack_sender_clone = ack_sender.clone();
+                            let routes = routes.clone();
+                            tokio::spawn(async move {
+                                let result = tokio::time::timeout(ASK_TIMEOUT, response).await;
+                                // a restarted shard only gets the rooms it confirmed
+                                if let Ok(Ok(Ok(()))) = result {
+                                    routes.lock().unwrap().joined(&room_id);
+                                } else {
+                                    routes.lock().unwrap().leave_room(&room_id);
+                                }
+                                match result {
+                                    Ok(Ok(Ok(()))) => {}
+                                    Ok(Ok(Err(e))) => {
+                                        handlers::send_error(
+                                            &ack_sender_clone,
+                                            ErrorCode::RequestFailed,
+                                            e,
+                                            request_id,
+                                        )
+                                        .await;
+                                    }
+                                    _ => {
+                                        handlers::send_unavailable(&ack_sender_clone, request_id)
+                                            .await
+                                    }
+                                }
+                            });
+                        }
+                    }
+                    ChatMessage::LeaveRoom { room_id } => {
+                        routes.lock().unwrap().leave_room(&room_id);
+                        let router_msg = RouterMessage::LeaveRoom {
+                            tenant_user_id: tenant_user_id_clone.clone(),
+                            room_id: tenant_user_id_clone.room(room_id),
+                        };
+
+                        if router_sender_clone.send(router_msg).await.is_err() {
+                            error!("Failed to send leave room request to router");
+                            handlers::send_unavailable(&ack_sender, request_id).await;
+                        }
+                    }
+                    #[cfg(feature = "persistence")]
+                    ChatMessage::GetRoomHistory {
+                        room_id,
+                        message_id,
+                    } => {
+                        handlers::handle_get_room_history(
+                            tenant_user_id_clone.clone(),
+                            room_id,
+                            message_id,
+                            request_id,
+                            &router_sender_clone,
+                            &ack_sender,
+                        )
+                        .await;
+                    }
+
+                    ChatMessage::AckDelivery { message_ids } => {
+                        let _ = delivered_sender.send(message_ids.clone()).await;
+                        let router_msg = RouterMessage::AckDelivery {
+                            tenant_user_id: tenant_user_id_clone.clone(),
+                            connection_id,
+                            message_ids,
+                        };
+
+                        if router_sender_clone.send(router_msg).await.is_err() {
+                            error!("Failed to send delivery ack to router");
+                            handlers::send_unavailable(&ack_sender, request_id).await;
                         }
                     }
+                    ChatMessage::TypingStarted { target } => {
+                        // dropped when the session floods faster than it is drained
+                        let _ = typing_sender.try_send(typing::TypingEvent::Started(target));
+                    }
+                    ChatMessage::TypingStopped { target } => {
+                        let _ = typing_sender.try_send(typing::TypingEvent::Stopped(target));
+                    }
+                    ChatMessage::SubscribePresence {
+                        user_ids,
+                        conversation_partners,
+                    } => {
+                        routes
+                            .lock()
+                            .unwrap()
+                            .subscribe_presence(&user_ids, conversation_partners);
+                        let router_msg = RouterMessage::SubscribePresence {
+                            subscriber: tenant_user_id_clone.clone(),
+                            connection_id,
+                            targets: user_ids
+                                .into_iter()
+                                .map(|user_id| tenant_user_id_clone.peer(user_id))
+                                .collect(),
+                            conversation_partners,
+                            request_id: request_id.clone(),
+                        };
+
+                        if router_sender_clone.send(router_msg).await.is_err() {
+                            error!("Failed to send presence subscription to router");
+                            handlers::send_unavailable(&ack_sender, request_id).await;
+                        }
+                    }
+                    ChatMessage::UnsubscribePresence { user_ids } => {
+                        routes.lock().unwrap().unsubscribe_presence(&user_ids);
+                        let router_msg = RouterMessage::UnsubscribePresence {
+                            subscriber: tenant_user_id_clone.clone(),
+                            targets: user_ids
+                                .into_iter()
+                                .map(|user_id| tenant_user_id_clone.peer(user_id))
+                                .collect(),
+                        };
+
+                        if router_sender_clone.send(router_msg).await.is_err() {
+                            error!("Failed to send presence unsubscription to router");
+                            handlers::send_unavailable(&ack_sender, request_id).await;
+                        }
+                    }
+                    // answered from the directory, the router isn't involved
+                    ChatMessage::GetPresence { user_ids } => {
+                        let targets = user_ids
+                            .into_iter()
+                            .map(|user_id| tenant_user_id_clone.peer(user_id))
+                            .collect();
+                        let users = directory.presence_of(&tenant_user_id_clone, targets);
+                        let _ = ack_sender
+                            .send(ChatMessage::PresenceSnapshot { users, request_id })
+                            .await;
+                    }
+                    ChatMessage::GetOnlineUsers {} => {
+                        let user_ids = directory
+                            .online_users(&tenant_user_id_clone.project_id)
+                            .into_iter()
+                            .map(|user| user.user_id)
+                            .collect();
+                        let _ = ack_sender
+                            .send(ChatMessage::OnlineUsers {
+                                user_ids,
+                                request_id,
+                            })
+                            .await;
+                    }
 
                     #[cfg(feature = "persistence")]
-                    Ok(ChatMessage::SyncMessages {
-                        project_id,
+                    ChatMessage::SyncMessages {
                         conversation_id,
                         message_id,
-                    }) => {
+                    } => {
                         let (respond_to, response) = oneshot::channel();
                         let router_msg = RouterMessage::SyncMessages {
-                            project_id,
+                            requester: tenant_user_id_clone.clone(),
                             conversation_id,
                             message_id,
                             respond_to,
                         };
 
-                        if router_sender_clone.send(router_msg).is_err() {
+                        if router_sender_clone.send(router_msg).await.is_err() {
                             error!("Failed to send sync messages request to router");
+                            handlers::send_unavailable(&ack_sender, request_id).await;
                         } else {
                             let ack_sender_clone = ack_sender.clone();
                             tokio::spawn(async move {
-                                match response.await {
-                                    Ok(Ok(messages)) => {
-                                        let response_msg =
-                                            ChatMessage::SyncMessagesResponse { messages };
+                                match tokio::time::timeout(ASK_TIMEOUT, response).await {
+                                    Ok(Ok(Ok(messages))) => {
+                                        let response_msg = ChatMessage::SyncMessagesResponse {
+                                            messages,
+                                            request_id,
+                                        };
                                         let _ = ack_sender_clone.send(response_msg).await;
                                     }
-                                    Ok(Err(e)) => {
+                                    Ok(Ok(Err(e))) => {
                                         error!("Failed to sync messages: {}", e);
+                                        handlers::send_error(
+                                            &ack_sender_clone,
+                                            ErrorCode::RequestFailed,
+                                            e,
+                                            request_id,
+                                        )
+                                        .await;
                                     }
-                                    Err(_) => {
+                                    _ => {
                                         error!("Sync messages request timeout");
+                                        handlers::send_unavailable(&ack_sender_clone, request_id)
+                                            .await;
                                     }
                                 }
                             });
                         }
                     }
-                    Ok(_) => {
-                        // Ignore other message types from clients for now
+                    #[cfg(feature = "persistence")]
+                    ChatMessage::MarkRead {
+                        conversation_id,
+                        up_to_message_id,
+                    } => {
+                        let router_msg = RouterMessage::MarkRead {
+                            reader: tenant_user_id_clone.clone(),
+                            connection_id,
+                            conversation_id,
+                            up_to_message_id,
+                        };
+
+                        if router_sender_clone.send(router_msg).await.is_err() {
+                            error!("Failed to send mark read request to router");
+                            handlers::send_unavailable(&ack_sender, request_id).await;
+                        }
                     }
-                    Err(e) => {
-                        error!(
-                            "Failed to parse message from {}: {}",
-                            tenant_user_id_clone, e
-                        );
+                    #[cfg(feature = "persistence")]
+                    ChatMessage::GetReadCursors { conversation_id } => {
+                        let (respond_to, response) = oneshot::channel();
+                        let router_msg = RouterMessage::GetReadCursors {
+                            requester: tenant_user_id_clone.clone(),
+                            conversation_id: conversation_id.clone(),
+                            respond_to,
+                        };
+
+                        if router_sender_clone.send(router_msg).await.is_err() {
+                            error!("Failed to send read cursors request to router");
+                            handlers::send_unavailable(&ack_sender, request_id).await;
+                        } else {
+                            let ack_sender_clone = ack_sender.clone();
+                            tokio::spawn(async move {
+                                match tokio::time::timeout(ASK_TIMEOUT, response).await {
+                                    Ok(Ok(Ok(cursors))) => {
+                                        let response_msg = ChatMessage::ReadCursors {
+                                            conversation_id,
+                                            cursors,
+                                            request_id,
+                                        };
+                                        let _ = ack_sender_clone.send(response_msg).await;
+                                    }
+                                    Ok(Ok(Err(e))) => {
+                                        error!("Failed to get read cursors: {}", e);
+                                        handlers::send_error(
+                                            &ack_sender_clone,
+                                            ErrorCode::RequestFailed,
+                                            e,
+                                            request_id,
+                                        )
+                                        .await;
+                                    }
+                                    _ => {
+                                        error!("Read cursors request timeout");
+                                        handlers::send_unavailable(&ack_sender_clone, request_id)
+                                            .await;
+                                    }
+                                }
+                            });
+                        }
+                    }
+                    #[cfg(feature = "persistence")]
+                    ChatMessage::EditMessage {
+                        target,
+                        message_id,
+                        content,
+                    } => {
+                        handlers::handle_message_change(
+                            tenant_user_id_clone.clone(),
+                            connection_id,
+                            target,
+                            message_id,
+                            Some(content),
+                            request_id,
+                            &router_sender_clone,
+                            &ack_sender,
+                        )
+                        .await;
+                    }
+                    #[cfg(feature = "persistence")]
+                    ChatMessage::DeleteMessage { target, message_id } => {
+                        handlers::handle_message_change(
+                            tenant_user_id_clone.clone(),
+                            connection_id,
+                            target,
+                            message_id,
+                            None,
+                            request_id,
+                            &router_sender_clone,
+                            &ack_sender,
+                        )
+                        .await;
+                    }
+                    #[cfg(feature = "persistence")]
+                    ChatMessage::AddReaction {
+                        target,
+                        message_id,
+                        reaction,
+                    } => {
+                        handlers::handle_reaction(
+                            tenant_user_id_clone.clone(),
+                            connection_id,
+                            target,
+                            message_id,
+                            reaction,
+                            true,
+                            request_id,
+                            &router_sender_clone,
+                            &ack_sender,
+                        )
+                        .await;
+                    }
+                    #[cfg(feature = "persistence")]
+                    ChatMessage::RemoveReaction {
+                        target,
+                        message_id,
+                        reaction,
+                    } => {
+                        handlers::handle_reaction(
+                            tenant_user_id_clone.clone(),
+                            connection_id,
+                            target,
+                            message_id,
+                            reaction,
+                            false,
+                            request_id,
+                            &router_sender_clone,
+                            &ack_sender,
+                        )
+                        .await;
+                    }
+                    // an Authenticate after the first frame is taken as a Reauthenticate
+                    ChatMessage::Authenticate { token } | ChatMessage::Reauthenticate { token } => {
+                        let authenticator = authenticator.clone();
+                        let expiry = expiry.clone();
+                        let tenant_user_id = tenant_user_id_clone.clone();
+                        let ack_sender_clone = ack_sender.clone();
+                        tokio::spawn(async move {
+                            match authenticator.verify(token).await {
+                                // a token of someone else would switch users mid-connection
+                                Ok(credentials) if credentials.tenant_user_id == tenant_user_id => {
+                                    expiry.renew(credentials.expires_at);
+                                    let response_msg = ChatMessage::Authenticated {
+                                        expires_at: credentials.expires_at,
+                                        request_id,
+                                    };
+                                    let _ = ack_sender_clone.send(response_msg).await;
+                                }
+                                Ok(_) => {
+                                    handlers::send_error(
+                                        &ack_sender_clone,
+                                        ErrorCode::Unauthorized,
+                                        "Token belongs to another user",
+                                        request_id,
+                                    )
+                                    .await;
+                                }
+                                Err(e) => {
+                                    handlers::send_error(
+                                        &ack_sender_clone,
+                                        e.code(),
+                                        e.to_string(),
+                                        request_id,
+                                    )
+                                    .await;
+                                }
+                            }
+                        });
+                    }
+                    // server-only types are refused by `wire::decode` already
+                    _ => {
+                        handlers::send_error(
+                            &ack_sender,
+                            ErrorCode::UnsupportedMessageType,
+                            "Not a message clients can send",
+                            request_id,
+                        )
+                        .await;
                     }
                 }
             }
         });
 
         // Wait for either task to complete
+        let drain = self.drain.clone();
         tokio::select! {
             _ = &mut send_task => {
                 debug!("Send task completed for user {}", self.tenant_user_id);
@@ -280,13 +894,27 @@ impl UserSession {
                 debug!("Receive task completed for user {}", self.tenant_user_id);
                 send_task.abort();
             }
+            _ = drain.started() => {
+                debug!("Draining session of {}", self.tenant_user_id);
+                // nothing new is read, what the client already sent is still acked
+                recv_task.abort();
+                let deadline = tokio::time::Instant::now() + drain.config.drain_timeout;
+                let _ = tokio::time::timeout_at(deadline, ack_pump).await;
+                let _ = close_sender.send(drain.config.retry_after_ms(connection_id.as_u128()));
+                if tokio::time::timeout_at(deadline, &mut send_task).await.is_err() {
+                    send_task.abort();
+                }
+            }
         }
 
+        reregister_task.abort();
+
         // Unregister from router
         let unregister_msg = RouterMessage::UnregisterUser {
             tenant_user_id: self.tenant_user_id.clone(),
+            connection_id: self.connection_id,
         };
-        let _ = router_sender.send(unregister_msg);
+        let _ = router_sender.send(unregister_msg).await;
 
         Metrics::websocket_disconnected();
 
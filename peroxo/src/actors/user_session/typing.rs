@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::actors::framework::Addr;
+use crate::actors::message_router::RouterMessage;
+use crate::chat::TypingTarget;
+use crate::metrics::Metrics;
+use crate::tenant::TenantUserId;
+
+// Typing stops on its own when the client hasn't repeated TypingStarted for this long
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// Token bucket for relayed TypingStarted events, a burst of 5 then one per second
+const TYPING_BURST: u32 = 5;
+const TYPING_REFILL: Duration = Duration::from_secs(1);
+const MAX_ACTIVE_TARGETS: usize = 32;
+
+pub const TYPING_CHANNEL_SIZE: usize = 16;
+
+pub enum TypingEvent {
+    Started(TypingTarget),
+    Stopped(TypingTarget),
+}
+
+struct TypingState {
+    // target -> when typing expires unless refreshed
+    active: HashMap<TypingTarget, Instant>,
+    tokens: u32,
+    last_refill: Instant,
+}
+
+impl TypingState {
+    fn new() -> Self {
+        Self {
+            active: HashMap::new(),
+            tokens: TYPING_BURST,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Returns true when peers have to be told, refreshes of an active
+    // target only push its deadline.
+    fn start(&mut self, target: TypingTarget, now: Instant) -> bool {
+        if let Some(deadline) = self.active.get_mut(&target) {
+            *deadline = now + TYPING_TIMEOUT;
+            return false;
+        }
+
+        self.refill(now);
+        if self.tokens == 0 || self.active.len() >= MAX_ACTIVE_TARGETS {
+            return false;
+        }
+        self.tokens -= 1;
+        self.active.insert(target, now + TYPING_TIMEOUT);
+        true
+    }
+
+    fn stop(&mut self, target: &TypingTarget) -> bool {
+        self.active.remove(target).is_some()
+    }
+
+    fn expired(&mut self, now: Instant) -> Vec<TypingTarget> {
+        let expired: Vec<TypingTarget> = self
+            .active
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(target, _)| target.clone())
+            .collect();
+        for target in &expired {
+            self.active.remove(target);
+        }
+        expired
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let refills =
+            (now.duration_since(self.last_refill).as_millis() / TYPING_REFILL.as_millis()) as u32;
+        if refills > 0 {
+            self.tokens = (self.tokens + refills).min(TYPING_BURST);
+            self.last_refill += TYPING_REFILL * refills;
+        }
+    }
+}
+
+// Runs alongside a session, ends once the session drops its event sender
+// and then stops whatever is still active.
+pub async fn run(
+    tenant_user_id: TenantUserId,
+    mut events: mpsc::Receiver<TypingEvent>,
+    router_sender: Addr<RouterMessage>,
+) {
+    let mut state = TypingState::new();
+    let mut expiry_interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+
+    // typing is not worth waiting on a busy router for
+    let relay = |target: TypingTarget, typing: bool| {
+        let message = RouterMessage::Typing {
+            from: tenant_user_id.clone(),
+            target,
+            typing,
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) = router_sender.try_send(message) {
+            Metrics::message_dropped("router", "ephemeral", 1);
+        }
+    };
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                match event {
+                    Some(TypingEvent::Started(target)) => {
+                        if state.start(target.clone(), Instant::now()) {
+                            relay(target, true);
+                        } else if !state.active.contains_key(&target) {
+                            debug!("Typing from {} throttled", tenant_user_id);
+                        }
+                    }
+                    Some(TypingEvent::Stopped(target)) => {
+                        if state.stop(&target) {
+                            relay(target, false);
+                        }
+                    }
+                    None => break,
+                }
+            }
+            _ = expiry_interval.tick() => {
+                for target in state.expired(Instant::now()) {
+                    relay(target, false);
+                }
+            }
+        }
+    }
+
+    for (target, _) in state.active.drain() {
+        relay(target, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(room_id: &str) -> TypingTarget {
+        TypingTarget::Room {
+            room_id: room_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn refreshes_only_push_the_deadline() {
+        let mut state = TypingState::new();
+        let start = Instant::now();
+
+        assert!(state.start(room("a"), start));
+        assert!(!state.start(room("a"), start + Duration::from_secs(5)));
+        assert_eq!(state.tokens, TYPING_BURST - 1);
+
+        // still typing past the first deadline because of the refresh
+        assert!(state.expired(start + TYPING_TIMEOUT).is_empty());
+        assert_eq!(
+            state.expired(start + Duration::from_secs(5) + TYPING_TIMEOUT),
+            vec![room("a")]
+        );
+        assert!(!state.stop(&room("a")));
+    }
+
+    #[test]
+    fn new_targets_are_throttled_after_a_burst() {
+        let mut state = TypingState::new();
+        let start = Instant::now();
+
+        for i in 0..TYPING_BURST {
+            assert!(state.start(room(&i.to_string()), start));
+            assert!(state.stop(&room(&i.to_string())));
+        }
+        assert!(!state.start(room("late"), start));
+        assert!(!state.active.contains_key(&room("late")));
+
+        assert!(state.start(room("late"), start + TYPING_REFILL));
+        assert!(!state.start(room("later"), start + TYPING_REFILL));
+    }
+}
@@ -0,0 +1,224 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::http::{HeaderMap, StatusCode, header::SEC_WEBSOCKET_PROTOCOL};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::VerifyUserTokenRequest;
+use crate::auth_service_client::AuthServiceClient;
+use crate::chat::ErrorCode;
+use crate::circuit_breaker::GuardedChannel;
+use crate::tenant::TenantUserId;
+
+// A Sec-WebSocket-Protocol entry carrying the token, offered next to one of
+// the wire protocols, e.g. "peroxo.json.v1, peroxo.token.<token>". It is
+// never picked as the connection's protocol.
+pub const TOKEN_PROTOCOL_PREFIX: &str = "peroxo.token.";
+
+#[derive(Clone, Debug)]
+pub struct AuthConfig {
+    // How long a client that sent no token with the upgrade has to send its
+    // Authenticate frame
+    pub auth_timeout: Duration,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            auth_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn with_auth_timeout(mut self, timeout: Duration) -> Self {
+        self.auth_timeout = timeout;
+        self
+    }
+}
+
+// Who a token belongs to and when it runs out, in unix seconds
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub tenant_user_id: TenantUserId,
+    pub expires_at: u64,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    // unknown or expired token
+    InvalidToken,
+    // auth-service couldn't be asked or gave no answer
+    Unavailable(String),
+}
+
+impl AuthError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AuthError::InvalidToken => StatusCode::UNAUTHORIZED,
+            AuthError::Unavailable(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AuthError::InvalidToken => ErrorCode::Unauthorized,
+            AuthError::Unavailable(_) => ErrorCode::Unavailable,
+        }
+    }
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::InvalidToken => write!(f, "Invalid token"),
+            AuthError::Unavailable(e) => write!(f, "Auth service error: {}", e),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Authenticator {
+    pub config: AuthConfig,
+    client: AuthServiceClient<GuardedChannel>,
+}
+
+impl Authenticator {
+    pub fn new(client: AuthServiceClient<GuardedChannel>, config: AuthConfig) -> Self {
+        Self { config, client }
+    }
+
+    pub async fn verify(&self, token: String) -> Result<Credentials, AuthError> {
+        let mut client = self.client.clone();
+
+        let req = tonic::Request::new(VerifyUserTokenRequest { token });
+
+        let resp = client
+            .verify_user_token(req)
+            .await
+            .map_err(|e| AuthError::Unavailable(e.to_string()))?
+            .into_inner();
+
+        if !resp.found {
+            return Err(AuthError::InvalidToken);
+        }
+
+        match resp.user_token {
+            Some(user_token) => Ok(Credentials {
+                tenant_user_id: TenantUserId::from_token(&user_token)
+                    .map_err(|_| AuthError::InvalidToken)?,
+                expires_at: user_token.expires_at,
+            }),
+            None => Err(AuthError::Unavailable(
+                "found=true but no user_token data".to_string(),
+            )),
+        }
+    }
+}
+
+// The token offered as a Sec-WebSocket-Protocol entry, if any
+pub fn token_from_protocols(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|protocol| protocol.trim().strip_prefix(TOKEN_PROTOCOL_PREFIX))
+        .filter(|token| !token.is_empty())
+        .map(str::to_string)
+}
+
+// When the token of a connection runs out, moved on by Reauthenticate.
+// Shared between the task reading the socket and the one writing it.
+#[derive(Clone)]
+pub struct TokenExpiry {
+    expires_at: Arc<watch::Sender<u64>>,
+}
+
+impl TokenExpiry {
+    pub fn new(expires_at: u64) -> Self {
+        Self {
+            expires_at: Arc::new(watch::Sender::new(expires_at)),
+        }
+    }
+
+    // Replaces the expiry with the one of the token the client just sent,
+    // even an earlier one
+    pub fn renew(&self, expires_at: u64) {
+        self.expires_at.send_replace(expires_at);
+    }
+
+    // Resolves once the current token expired, a renew while waiting moves
+    // the deadline either way
+    pub async fn expired(&self) {
+        let mut expires_at = self.expires_at.subscribe();
+        loop {
+            let deadline = Duration::from_secs(*expires_at.borrow_and_update());
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            if now >= deadline {
+                return;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep_until(Instant::now() + (deadline - now)) => {}
+                _ = expires_at.changed() => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn token_is_read_from_the_protocols() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(token_from_protocols(&headers), None);
+
+        headers.insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("peroxo.json.v1, peroxo.token.abc123"),
+        );
+        assert_eq!(token_from_protocols(&headers).as_deref(), Some("abc123"));
+
+        headers.insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("peroxo.json.v1, peroxo.token."),
+        );
+        assert_eq!(token_from_protocols(&headers), None);
+    }
+
+    #[tokio::test]
+    async fn renewed_tokens_expire_later() {
+        let expiry = TokenExpiry::new(now() - 1);
+        tokio::time::timeout(Duration::from_millis(50), expiry.expired())
+            .await
+            .expect("a token from the past has expired");
+
+        expiry.renew(now() + 60);
+        let expired = expiry.expired();
+        tokio::pin!(expired);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut expired)
+                .await
+                .is_err()
+        );
+
+        // an earlier token counts right away, not once the later one ran out
+        expiry.renew(now() - 1);
+        tokio::time::timeout(Duration::from_millis(50), expired)
+            .await
+            .expect("renewed with a token from the past");
+    }
+}
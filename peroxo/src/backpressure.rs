@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use crate::chat::ChatMessage;
+
+// What happens to a frame when the connection's queue for its class is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // Evict the oldest queued frame, only the latest state matters
+    DropOldest,
+    // Queue up to the limit and drop the new frame past it. A connection
+    // that stays full for `slow_consumer_timeout` is disconnected.
+    Queue,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageClass {
+    // messages, acks and replies to the client's own requests
+    Chat,
+    // typing, presence and read receipts
+    Ephemeral,
+}
+
+impl MessageClass {
+    pub fn of(message: &ChatMessage) -> Self {
+        match message {
+            ChatMessage::Presence { .. } | ChatMessage::UserTyping { .. } => {
+                MessageClass::Ephemeral
+            }
+            #[cfg(feature = "persistence")]
+            ChatMessage::ReadReceipt { .. } => MessageClass::Ephemeral,
+            _ => MessageClass::Chat,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageClass::Chat => "chat",
+            MessageClass::Ephemeral => "ephemeral",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BackpressureConfig {
+    // Sessions wait for room in the router's mailbox, which pushes back on
+    // the client's socket instead of dropping its requests
+    pub router_mailbox: usize,
+    pub room_mailbox: usize,
+    pub chat_queue: usize,
+    pub chat_policy: OverflowPolicy,
+    pub ephemeral_queue: usize,
+    pub ephemeral_policy: OverflowPolicy,
+    pub slow_consumer_timeout: Duration,
+}
+
+impl Default for BackpressureConfig {
+    fn default() -> Self {
+        Self {
+            router_mailbox: 10_000,
+            room_mailbox: 1_000,
+            chat_queue: 256,
+            chat_policy: OverflowPolicy::Queue,
+            ephemeral_queue: 32,
+            ephemeral_policy: OverflowPolicy::DropOldest,
+            slow_consumer_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl BackpressureConfig {
+    pub fn with_router_mailbox(mut self, size: usize) -> Self {
+        self.router_mailbox = size;
+        self
+    }
+
+    pub fn with_room_mailbox(mut self, size: usize) -> Self {
+        self.room_mailbox = size;
+        self
+    }
+
+    pub fn with_chat_queue(mut self, size: usize, policy: OverflowPolicy) -> Self {
+        self.chat_queue = size;
+        self.chat_policy = policy;
+        self
+    }
+
+    pub fn with_ephemeral_queue(mut self, size: usize, policy: OverflowPolicy) -> Self {
+        self.ephemeral_queue = size;
+        self.ephemeral_policy = policy;
+        self
+    }
+
+    pub fn with_slow_consumer_timeout(mut self, timeout: Duration) -> Self {
+        self.slow_consumer_timeout = timeout;
+        self
+    }
+}
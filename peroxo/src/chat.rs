@@ -3,38 +3,75 @@ use uuid::Uuid;
 
 use crate::tenant::TenantUserId;
 
+// Frames are objects named by their "type", e.g.
+// {"type": "JoinRoom", "v": 1, "room_id": "lobby"}. The "v" is added and
+// checked by the wire layer, like the optional "request_id" a client may
+// put on any request. The responses and errors a request causes carry its
+// request_id back.
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
 pub enum ChatMessage {
     // client to server (naming from client POV, can be improved)
     SendDirectMessage {
         conversation_id: String,
-        to: TenantUserId,
+        // user_id of the recipient, always resolved within the sender's project
+        to: String,
         content: String,
         client_message_id: uuid::Uuid,
         // message_id: Option<uuid::Uuid>,
     },
-    // server to client
+    // server to client, also echoed to the sender's other devices
     DirectMessage {
+        conversation_id: String,
         from: TenantUserId,
+        to: TenantUserId,
         content: String,
         server_message_id: uuid::Uuid,
         timestamp: i64,
     },
-    // Presence update (user online/offline)
+    // Presence update (user online/offline), pushed to subscribers
     Presence {
         user: TenantUserId,
         status: PresenceStatus,
     },
+    // client to server, user_ids are resolved within the caller's project.
+    // Answered with a PresenceSnapshot of the subscribed users.
+    SubscribePresence {
+        user_ids: Vec<String>,
+        // also subscribe to everyone the caller has a direct conversation with
+        #[serde(default)]
+        conversation_partners: bool,
+    },
+    UnsubscribePresence {
+        user_ids: Vec<String>,
+    },
+    // client to server, one-off lookup without subscribing
+    GetPresence {
+        user_ids: Vec<String>,
+    },
+    PresenceSnapshot {
+        users: Vec<UserPresence>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+    // client to server, online users of the caller's project
+    GetOnlineUsers {},
+    OnlineUsers {
+        user_ids: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
 
     MessageAck {
         client_message_id: uuid::Uuid,
         message_id: uuid::Uuid,
         timestamp: i64,
         status: MessageStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
     },
 
     GetPaginatedMessages {
-        project_id: String,
         // paginataion cursor
         message_id: Option<uuid::Uuid>,
         // feat: add limit for pagination
@@ -42,10 +79,20 @@ pub enum ChatMessage {
         conversation_id: String,
     },
     ChatHistoryResponse {
+        conversation_id: String,
         messages: Vec<ResponseDirectMessage>,
         has_more: bool,
         next_cursor: Option<uuid::Uuid>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
     },
+    // client to server
+    SendRoomMessage {
+        room_id: String,
+        content: String,
+        client_message_id: uuid::Uuid,
+    },
+    // server to client
     RoomMessage {
         room_id: String,
         from: TenantUserId,
@@ -59,19 +106,251 @@ pub enum ChatMessage {
     LeaveRoom {
         room_id: String,
     },
+    // client to server, a page of a joined room's history before
+    // `message_id`, newest first
+    #[cfg(feature = "persistence")]
+    GetRoomHistory {
+        room_id: String,
+        message_id: Option<uuid::Uuid>,
+    },
+    #[cfg(feature = "persistence")]
+    RoomHistoryResponse {
+        room_id: String,
+        messages: Vec<ResponseRoomMessage>,
+        has_more: bool,
+        next_cursor: Option<uuid::Uuid>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
 
     #[cfg(feature = "persistence")]
     SyncMessages {
-        project_id: String,
         conversation_id: String,
         message_id: uuid::Uuid,
     },
     #[cfg(feature = "persistence")]
     SyncMessagesResponse {
         messages: Vec<ResponseDirectMessage>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+
+    // client to server, moves the caller's read cursor forward
+    #[cfg(feature = "persistence")]
+    MarkRead {
+        conversation_id: String,
+        up_to_message_id: uuid::Uuid,
+    },
+    // server to client, sent to the other participant and the reader's other
+    // devices. Cursors only move forward, so clients keep the newest one.
+    #[cfg(feature = "persistence")]
+    ReadReceipt {
+        conversation_id: String,
+        reader: TenantUserId,
+        up_to_message_id: uuid::Uuid,
+        timestamp: i64,
+    },
+    #[cfg(feature = "persistence")]
+    GetReadCursors {
+        conversation_id: String,
+    },
+    #[cfg(feature = "persistence")]
+    ReadCursors {
+        conversation_id: String,
+        cursors: Vec<ReadCursor>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+
+    // client to server, only the sender may change a message and only for a
+    // while after sending it, how long depends on the project. Answered with
+    // MessageEdited or MessageDeleted, which everyone in the conversation or
+    // room gets as well.
+    #[cfg(feature = "persistence")]
+    EditMessage {
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+        content: String,
+    },
+    #[cfg(feature = "persistence")]
+    DeleteMessage {
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+    },
+    // server to client, replaces the content of a message already shown
+    #[cfg(feature = "persistence")]
+    MessageEdited {
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+        from: TenantUserId,
+        content: String,
+        edited_at: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+    // server to client, history keeps the message as a tombstone without content
+    #[cfg(feature = "persistence")]
+    MessageDeleted {
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+        from: TenantUserId,
+        deleted_at: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+
+    // client to server, a user reacts at most once with each reaction. A
+    // message may only have so many distinct reactions, how many depends on
+    // the project. Answered with ReactionAdded or ReactionRemoved, which
+    // everyone in the conversation or room gets as well.
+    #[cfg(feature = "persistence")]
+    AddReaction {
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+        reaction: String,
+    },
+    #[cfg(feature = "persistence")]
+    RemoveReaction {
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+        reaction: String,
+    },
+    // server to client, `reactions` are all of the message's after the change
+    #[cfg(feature = "persistence")]
+    ReactionAdded {
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+        from: TenantUserId,
+        reaction: String,
+        reactions: Vec<Reaction>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+    #[cfg(feature = "persistence")]
+    ReactionRemoved {
+        target: MessageTarget,
+        message_id: uuid::Uuid,
+        from: TenantUserId,
+        reaction: String,
+        reactions: Vec<Reaction>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+
+    // client to server, repeated while the user keeps typing. The server
+    // stops the indicator on its own when they go quiet or disconnect.
+    TypingStarted {
+        target: TypingTarget,
+    },
+    TypingStopped {
+        target: TypingTarget,
+    },
+    // server to client, never persisted
+    UserTyping {
+        from: TenantUserId,
+        target: TypingTarget,
+        typing: bool,
+    },
+
+    // client to server, acknowledges DirectMessage and RoomMessage frames by
+    // their message id. Unacknowledged ones are retransmitted and replayed on
+    // the next connection, so clients should drop duplicates by id.
+    AckDelivery {
+        message_ids: Vec<uuid::Uuid>,
+    },
+
+    // client to server, authenticates a connection opened without a token.
+    // Has to be its first frame, answered with Authenticated.
+    Authenticate {
+        token: String,
+    },
+    // client to server, swaps in a fresh token before the current one
+    // expires. Answered with Authenticated, or with an Error that leaves the
+    // connection on its current token.
+    Reauthenticate {
+        token: String,
+    },
+    // server to client, first frame of every connection and the answer to
+    // Authenticate and Reauthenticate. The connection is closed with 4001 at
+    // `expires_at`, in unix seconds, unless it is reauthenticated before.
+    Authenticated {
+        expires_at: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+
+    // server to client, the server is shutting down and closes the
+    // connection right after. Reconnect after `retry_after_ms`, another
+    // instance will take the connection.
+    Reconnect {
+        retry_after_ms: u64,
+    },
+
+    // server to client, answers a request refused for coming too fast,
+    // instead of its usual reply. Retry after `retry_after_ms` at the
+    // earliest, a connection that keeps going is closed with 4029.
+    RateLimited {
+        retry_after_ms: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+
+    // server to client, sent when a client request is rejected or fails
+    Error {
+        code: ErrorCode,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
     },
 }
 
+impl ChatMessage {
+    // Id the client acknowledges with AckDelivery, for frames that are
+    // delivered at least once
+    pub fn delivery_id(&self) -> Option<Uuid> {
+        match self {
+            ChatMessage::DirectMessage {
+                server_message_id, ..
+            } => Some(*server_message_id),
+            ChatMessage::RoomMessage { message_id, .. } => Some(*message_id),
+            _ => None,
+        }
+    }
+}
+
+// Stable codes clients can branch on, the message is for humans only.
+// Codes are only ever added, never renamed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // the request targets a user or resource outside the caller's project
+    CrossTenant,
+    // a known message type with missing or malformed fields
+    InvalidMessage,
+    // the request would exceed a limit, e.g. presence subscriptions per user
+    // or distinct reactions per message
+    LimitExceeded,
+    // the frame doesn't decode to an object with a "type"
+    InvalidFrame,
+    // the frame's "v" is not a protocol version this server speaks
+    UnsupportedVersion,
+    // the "type" is unknown, or names a message only the server sends
+    UnsupportedMessageType,
+    // the server couldn't take or answer the request in time, it may be
+    // retried later
+    Unavailable,
+    // the request was taken but failed, the message says why
+    RequestFailed,
+    // the token is missing, invalid or expired, or belongs to another user
+    Unauthorized,
+    // the message doesn't exist, or was deleted
+    NotFound,
+    // the caller may not do this, e.g. change someone else's message
+    Forbidden,
+    // the message is too old to be changed
+    EditWindowExpired,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum MessageStatus {
     Delivered,
@@ -92,6 +371,26 @@ pub enum PresenceStatus {
     Offline,
 }
 
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TypingTarget {
+    // `to` is resolved within the sender's project, like SendDirectMessage
+    Conversation { conversation_id: String, to: String },
+    Room { room_id: String },
+}
+
+// Where a changed or reacted to message was sent
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MessageTarget {
+    Conversation { conversation_id: String },
+    Room { room_id: String },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserPresence {
+    pub user_id: String,
+    pub status: PresenceStatus,
+}
+
 // Rename Struct to something more appropriate
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ResponseDirectMessage {
@@ -101,6 +400,45 @@ pub struct ResponseDirectMessage {
     pub recipient_id: String,
     pub message_text: String,
     pub created_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<i64>,
+    // set for a deleted message, its text is empty then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<Reaction>,
+}
+
+// A room message in history, the room's counterpart of ResponseDirectMessage
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ResponseRoomMessage {
+    pub room_id: String,
+    pub message_id: Uuid,
+    pub sender_id: String,
+    pub content: String,
+    pub created_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<i64>,
+    // set for a deleted message, its content is empty then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<Reaction>,
+}
+
+// Everyone who reacted to a message with `reaction`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Reaction {
+    pub reaction: String,
+    pub count: u32,
+    pub user_ids: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReadCursor {
+    pub user_id: String,
+    pub message_id: Uuid,
+    pub updated_at: i64,
 }
 
 #[derive(Clone, Debug)]
@@ -109,3 +447,28 @@ pub struct PaginatedMessagesResponse {
     pub next_cursor: Option<String>,
     pub has_more: bool,
 }
+
+#[derive(Clone, Debug)]
+pub struct PaginatedRoomMessagesResponse {
+    pub messages: Vec<ResponseRoomMessage>,
+    pub next_cursor: Option<Uuid>,
+    pub has_more: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConversationParticipants {
+    pub user_id_1: String,
+    pub user_id_2: String,
+}
+
+impl ConversationParticipants {
+    pub fn peer_of(&self, user_id: &str) -> Option<&str> {
+        if self.user_id_1 == user_id {
+            Some(&self.user_id_2)
+        } else if self.user_id_2 == user_id {
+            Some(&self.user_id_1)
+        } else {
+            None
+        }
+    }
+}
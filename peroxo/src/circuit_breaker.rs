@@ -0,0 +1,347 @@
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use tokio::time::Instant;
+use tonic::body::Body;
+use tonic::codegen::http::{self, HeaderValue};
+use tonic::codegen::{Service, StdError};
+use tonic::transport::Channel;
+use tracing::{info, warn};
+
+use crate::metrics::Metrics;
+
+const CIRCUIT_OPEN: &str = "circuit open";
+
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerConfig {
+    // Consecutive failed calls that open the circuit
+    pub failure_threshold: u32,
+    // How long calls fail fast before a single probe is let through
+    pub open_for: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_for: Duration::from_secs(10),
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    pub fn with_failure_threshold(mut self, failures: u32) -> Self {
+        self.failure_threshold = failures;
+        self
+    }
+
+    pub fn with_open_for(mut self, duration: Duration) -> Self {
+        self.open_for = duration;
+        self
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // the probe is in flight, everything else still fails fast
+    HalfOpen,
+}
+
+// Shared by every clone of a client. While open, calls are answered with
+// `Unavailable` right away instead of waiting on a service that is down.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    service: &'static str,
+    config: CircuitBreakerConfig,
+    state: Arc<Mutex<State>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(service: &'static str, config: CircuitBreakerConfig) -> Self {
+        Metrics::set_circuit_open(service, false);
+        Self {
+            service,
+            config,
+            state: Arc::new(Mutex::new(State::Closed { failures: 0 })),
+        }
+    }
+
+    // Whether a call may go out now
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if Instant::now() >= until => {
+                *state = State::HalfOpen;
+                true
+            }
+            State::Open { .. } | State::HalfOpen => false,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        !matches!(*self.state.lock().unwrap(), State::Closed { .. })
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, State::Closed { .. }) {
+            info!("{} is reachable again, closing its circuit", self.service);
+            Metrics::set_circuit_open(self.service, false);
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let open = match *state {
+            State::Closed { failures } => failures + 1 >= self.config.failure_threshold,
+            State::HalfOpen => true,
+            State::Open { .. } => false,
+        };
+        if open {
+            self.open(&mut state);
+        } else if let State::Closed { failures } = &mut *state {
+            *failures += 1;
+        }
+    }
+
+    // A probe that never finished, it is tried again after another wait
+    fn record_abandoned(&self) {
+        let mut state = self.state.lock().unwrap();
+        if *state == State::HalfOpen {
+            self.open(&mut state);
+        }
+    }
+
+    // Opens the circuit without waiting for calls to fail, e.g. when the
+    // health checks report no serving endpoint
+    pub fn trip(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.open(&mut state);
+    }
+
+    fn open(&self, state: &mut State) {
+        if matches!(*state, State::Closed { .. }) {
+            warn!(
+                "{} is failing, opening its circuit for {:?}",
+                self.service, self.config.open_for
+            );
+            Metrics::set_circuit_open(self.service, true);
+        }
+        *state = State::Open {
+            until: Instant::now() + self.config.open_for,
+        };
+    }
+}
+
+// Whether a call was refused by an open circuit rather than tried
+pub fn is_circuit_open(status: &tonic::Status) -> bool {
+    status.code() == tonic::Code::Unavailable && status.message() == CIRCUIT_OPEN
+}
+
+// Whether a call got no answer, the service was unreachable, too slow or
+// its circuit open. The same call may well succeed later.
+pub fn is_unavailable(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::Unavailable | tonic::Code::DeadlineExceeded
+    )
+}
+
+// A channel that bounds every call by a deadline and goes through the
+// breaker. Transport errors, timeouts and `Unavailable` answers count as
+// failures, any other answer means the service is up.
+#[derive(Clone)]
+pub struct GuardedChannel {
+    inner: Channel,
+    breaker: CircuitBreaker,
+    deadline: Duration,
+}
+
+impl GuardedChannel {
+    pub fn new(inner: Channel, breaker: CircuitBreaker, deadline: Duration) -> Self {
+        Self {
+            inner,
+            breaker,
+            deadline,
+        }
+    }
+}
+
+impl Service<http::Request<Body>> for GuardedChannel {
+    type Response = http::Response<Body>;
+    type Error = StdError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    // Readiness is awaited inside the call so it falls under the deadline,
+    // an empty balancer would otherwise stall the caller forever
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut request: http::Request<Body>) -> Self::Future {
+        let breaker = self.breaker.clone();
+        if !breaker.allow() {
+            return futures::future::ready(Err(tonic::Status::unavailable(CIRCUIT_OPEN).into()))
+                .boxed();
+        }
+
+        // a deadline set on the request wins over the client's default
+        let deadline = request
+            .headers()
+            .get("grpc-timeout")
+            .and_then(parse_grpc_timeout)
+            .unwrap_or(self.deadline);
+        if let Ok(value) = HeaderValue::from_str(&format!("{}m", deadline.as_millis())) {
+            request.headers_mut().insert("grpc-timeout", value);
+        }
+
+        let (service, method) = grpc_path(request.uri().path());
+        let mut inner = self.inner.clone();
+        async move {
+            let start = std::time::Instant::now();
+            let mut outcome = Outcome::new(breaker);
+            let call = async {
+                futures::future::poll_fn(|cx| inner.poll_ready(cx)).await?;
+                inner.call(request).await
+            };
+
+            let (result, status) = match tokio::time::timeout(deadline, call).await {
+                Ok(Ok(response)) => {
+                    let status = grpc_status(&response);
+                    (Ok(response), status)
+                }
+                // surfaced as Unavailable as well, so callers can tell them
+                // from answers the same way the breaker does
+                Ok(Err(e)) => {
+                    let mut status = tonic::Status::unavailable(e.to_string());
+                    status.set_source(Arc::new(e));
+                    (Err(status.into()), tonic::Code::Unavailable)
+                }
+                Err(_) => (
+                    Err(tonic::Status::deadline_exceeded(format!(
+                        "no answer within {:?}",
+                        deadline
+                    ))
+                    .into()),
+                    tonic::Code::DeadlineExceeded,
+                ),
+            };
+
+            outcome.finish(!matches!(
+                status,
+                tonic::Code::Unavailable | tonic::Code::DeadlineExceeded
+            ));
+            Metrics::grpc_request_completed(
+                &service,
+                &method,
+                &format!("{:?}", status),
+                start.elapsed(),
+            );
+            result
+        }
+        .boxed()
+    }
+}
+
+// Reports the result of a call to the breaker, or that it was dropped
+// before it had one
+struct Outcome {
+    breaker: CircuitBreaker,
+    finished: bool,
+}
+
+impl Outcome {
+    fn new(breaker: CircuitBreaker) -> Self {
+        Self {
+            breaker,
+            finished: false,
+        }
+    }
+
+    fn finish(&mut self, success: bool) {
+        self.finished = true;
+        if success {
+            self.breaker.record_success();
+        } else {
+            self.breaker.record_failure();
+        }
+    }
+}
+
+impl Drop for Outcome {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.breaker.record_abandoned();
+        }
+    }
+}
+
+// The status of a trailers-only answer is in its headers. Streamed answers
+// carry it in the trailers and count as a success once they start.
+fn grpc_status(response: &http::Response<Body>) -> tonic::Code {
+    response
+        .headers()
+        .get("grpc-status")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i32>().ok())
+        .map_or(tonic::Code::Ok, tonic::Code::from_i32)
+}
+
+// "/chat_service.ChatService/WriteDmBatch" -> ("chat_service.ChatService", "WriteDmBatch")
+fn grpc_path(path: &str) -> (String, String) {
+    let mut parts = path.trim_start_matches('/').splitn(2, '/');
+    let service = parts.next().unwrap_or_default().to_string();
+    let method = parts.next().unwrap_or_default().to_string();
+    (service, method)
+}
+
+fn parse_grpc_timeout(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?;
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    let amount: u64 = amount.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 3600)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn opens_after_failures_and_probes_once() {
+        let breaker = CircuitBreaker::new(
+            "test",
+            CircuitBreakerConfig::default()
+                .with_failure_threshold(2)
+                .with_open_for(Duration::from_millis(20)),
+        );
+
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert!(!breaker.allow());
+
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        // one probe goes through, the calls next to it still fail fast
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+
+        breaker.record_success();
+        assert!(breaker.allow());
+        assert!(!breaker.is_open());
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{debug, error};
+
+use crate::actors::message_router::{RouterHandle, RouterMessage};
+use crate::actors::user_session::session::ConnectionId;
+use crate::chat::{ChatMessage, PresenceStatus};
+use crate::tenant::{TenantRoomId, TenantUserId};
+
+pub mod memory;
+#[cfg(feature = "redis_bus")]
+pub mod redis_bus;
+
+pub use memory::{MemoryBus, MemoryCluster};
+#[cfg(feature = "redis_bus")]
+pub use redis_bus::RedisBus;
+
+pub type NodeId = String;
+
+// Inbound envelopes buffered per node before the bus pushes back
+pub const CLUSTER_CHANNEL_SIZE: usize = 4096;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClusterEnvelope {
+    pub origin: NodeId,
+    pub event: ClusterEvent,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ClusterEvent {
+    // A frame for the devices of `user` connected to the receiving node,
+    // or a single one of them when `connection_id` is set
+    Deliver {
+        user: TenantUserId,
+        connection_id: Option<ConnectionId>,
+        message: ChatMessage,
+        receipt: Option<Box<DeliveryReceipt>>,
+    },
+    // Fan-out of a room frame to the members connected to the receiving node
+    Room {
+        room_id: TenantRoomId,
+        message: ChatMessage,
+        skip: Option<TenantUserId>,
+    },
+    // `user` got their first or lost their last connection on the origin node
+    Presence {
+        user: TenantUserId,
+        status: PresenceStatus,
+    },
+    // A node came up and wants to know who is connected elsewhere
+    NodeStarted,
+}
+
+impl ClusterEvent {
+    pub fn project_id(&self) -> Option<&str> {
+        match self {
+            ClusterEvent::Deliver { user, .. } | ClusterEvent::Presence { user, .. } => {
+                Some(&user.project_id)
+            }
+            ClusterEvent::Room { room_id, .. } => Some(&room_id.project_id),
+            ClusterEvent::NodeStarted => None,
+        }
+    }
+}
+
+// Sent back to the origin node once a forwarded message reached a device
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeliveryReceipt {
+    pub node: NodeId,
+    pub user: TenantUserId,
+    pub connection_id: ConnectionId,
+    pub ack: ChatMessage,
+}
+
+// Links the routers of several peroxo processes. Implementations deliver
+// `send_to_node` and `broadcast` events to the receiver returned by
+// `subscribe` on the target nodes, and keep track of which nodes a user is
+// connected to. A broadcast is not delivered back to its origin.
+pub trait ClusterBus: Send + Sync {
+    fn node_id(&self) -> &str;
+
+    // Called once when the router starts
+    fn subscribe(&self) -> BoxFuture<'_, Result<mpsc::Receiver<ClusterEnvelope>, String>>;
+
+    fn send_to_node<'a>(
+        &'a self,
+        node: &'a str,
+        event: ClusterEvent,
+    ) -> BoxFuture<'a, Result<(), String>>;
+
+    fn broadcast(&self, event: ClusterEvent) -> BoxFuture<'_, Result<(), String>>;
+
+    // Records that `user` is connected to this node
+    fn register_user<'a>(&'a self, user: &'a TenantUserId) -> BoxFuture<'a, Result<(), String>>;
+
+    fn unregister_user<'a>(&'a self, user: &'a TenantUserId) -> BoxFuture<'a, Result<(), String>>;
+
+    // Every node `user` is connected to, this one included
+    fn locate_user<'a>(
+        &'a self,
+        user: &'a TenantUserId,
+    ) -> BoxFuture<'a, Result<Vec<NodeId>, String>>;
+}
+
+// Feeds events from other nodes into the router shard of their project
+pub async fn forward_to_router(
+    mut receiver: mpsc::Receiver<ClusterEnvelope>,
+    router: RouterHandle,
+) {
+    while let Some(envelope) = receiver.recv().await {
+        debug!(
+            "Cluster event from {}: {:?}",
+            envelope.origin, envelope.event
+        );
+        let shard = match envelope.event.project_id() {
+            Some(project_id) => router.shard(project_id),
+            None => router.first_shard(),
+        };
+        if shard
+            .send(RouterMessage::Cluster {
+                origin: envelope.origin,
+                event: envelope.event,
+            })
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    error!("Cluster bus subscription ended");
+}
+
+// Keeps the registry and the other nodes' view of who is online in step with
+// the local connections. Updates run in order, so a quick reconnect can't be
+// overtaken by the disconnect before it.
+pub async fn announce_presence(
+    cluster: Arc<dyn ClusterBus>,
+    mut announcements: mpsc::Receiver<(TenantUserId, PresenceStatus)>,
+) {
+    while let Some((user, status)) = announcements.recv().await {
+        let registered = match status {
+            PresenceStatus::Online => cluster.register_user(&user).await,
+            PresenceStatus::Offline => cluster.unregister_user(&user).await,
+        };
+        if let Err(e) = registered {
+            error!("Failed to update the location of {}: {}", user, e);
+        }
+        if let Err(e) = cluster
+            .broadcast(ClusterEvent::Presence { user, status })
+            .await
+        {
+            error!("Failed to announce presence: {}", e);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use tokio::sync::mpsc;
+
+use super::{CLUSTER_CHANNEL_SIZE, ClusterBus, ClusterEnvelope, ClusterEvent, NodeId};
+use crate::tenant::TenantUserId;
+
+#[derive(Default)]
+struct Hub {
+    nodes: HashMap<NodeId, mpsc::Sender<ClusterEnvelope>>,
+    locations: HashMap<TenantUserId, HashSet<NodeId>>,
+}
+
+// Several nodes within one process, for tests and local experiments
+#[derive(Clone, Default)]
+pub struct MemoryCluster {
+    hub: Arc<Mutex<Hub>>,
+}
+
+impl MemoryCluster {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn node(&self, node_id: impl Into<NodeId>) -> MemoryBus {
+        MemoryBus {
+            node_id: node_id.into(),
+            hub: self.hub.clone(),
+        }
+    }
+}
+
+pub struct MemoryBus {
+    node_id: NodeId,
+    hub: Arc<Mutex<Hub>>,
+}
+
+impl MemoryBus {
+    fn envelope(&self, event: ClusterEvent) -> ClusterEnvelope {
+        ClusterEnvelope {
+            origin: self.node_id.clone(),
+            event,
+        }
+    }
+}
+
+impl ClusterBus for MemoryBus {
+    fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    fn subscribe(&self) -> BoxFuture<'_, Result<mpsc::Receiver<ClusterEnvelope>, String>> {
+        let (sender, receiver) = mpsc::channel(CLUSTER_CHANNEL_SIZE);
+        self.hub
+            .lock()
+            .unwrap()
+            .nodes
+            .insert(self.node_id.clone(), sender);
+        async move { Ok(receiver) }.boxed()
+    }
+
+    fn send_to_node<'a>(
+        &'a self,
+        node: &'a str,
+        event: ClusterEvent,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        let sender = self.hub.lock().unwrap().nodes.get(node).cloned();
+        let envelope = self.envelope(event);
+        async move {
+            let sender = sender.ok_or_else(|| format!("Unknown node {}", node))?;
+            sender
+                .send(envelope)
+                .await
+                .map_err(|_| format!("Node {} stopped", node))
+        }
+        .boxed()
+    }
+
+    fn broadcast(&self, event: ClusterEvent) -> BoxFuture<'_, Result<(), String>> {
+        let senders: Vec<_> = self
+            .hub
+            .lock()
+            .unwrap()
+            .nodes
+            .iter()
+            .filter(|(node, _)| **node != self.node_id)
+            .map(|(_, sender)| sender.clone())
+            .collect();
+        let envelope = self.envelope(event);
+        async move {
+            for sender in senders {
+                // a stopped node just misses the event
+                let _ = sender.send(envelope.clone()).await;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn register_user<'a>(&'a self, user: &'a TenantUserId) -> BoxFuture<'a, Result<(), String>> {
+        self.hub
+            .lock()
+            .unwrap()
+            .locations
+            .entry(user.clone())
+            .or_default()
+            .insert(self.node_id.clone());
+        async { Ok(()) }.boxed()
+    }
+
+    fn unregister_user<'a>(&'a self, user: &'a TenantUserId) -> BoxFuture<'a, Result<(), String>> {
+        let mut hub = self.hub.lock().unwrap();
+        if let Some(nodes) = hub.locations.get_mut(user) {
+            nodes.remove(&self.node_id);
+            if nodes.is_empty() {
+                hub.locations.remove(user);
+            }
+        }
+        async { Ok(()) }.boxed()
+    }
+
+    fn locate_user<'a>(
+        &'a self,
+        user: &'a TenantUserId,
+    ) -> BoxFuture<'a, Result<Vec<NodeId>, String>> {
+        let nodes = self
+            .hub
+            .lock()
+            .unwrap()
+            .locations
+            .get(user)
+            .map(|nodes| nodes.iter().cloned().collect())
+            .unwrap_or_default();
+        async { Ok(nodes) }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::PresenceStatus;
+
+    fn user(user_id: &str) -> TenantUserId {
+        TenantUserId {
+            project_id: "project".to_string(),
+            user_id: user_id.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_events_between_nodes() {
+        let cluster = MemoryCluster::new();
+        let a = cluster.node("a");
+        let b = cluster.node("b");
+        let mut a_receiver = a.subscribe().await.unwrap();
+        let mut b_receiver = b.subscribe().await.unwrap();
+
+        a.broadcast(ClusterEvent::NodeStarted).await.unwrap();
+        let envelope = b_receiver.recv().await.unwrap();
+        assert_eq!(envelope.origin, "a");
+        assert!(matches!(envelope.event, ClusterEvent::NodeStarted));
+        // broadcasts don't come back to the sender
+        assert!(a_receiver.try_recv().is_err());
+
+        b.send_to_node(
+            "a",
+            ClusterEvent::Presence {
+                user: user("bob"),
+                status: PresenceStatus::Online,
+            },
+        )
+        .await
+        .unwrap();
+        let envelope = a_receiver.recv().await.unwrap();
+        assert_eq!(envelope.origin, "b");
+        assert!(matches!(envelope.event, ClusterEvent::Presence { .. }));
+    }
+
+    #[tokio::test]
+    async fn tracks_user_locations() {
+        let cluster = MemoryCluster::new();
+        let a = cluster.node("a");
+        let b = cluster.node("b");
+        let alice = user("alice");
+
+        a.register_user(&alice).await.unwrap();
+        b.register_user(&alice).await.unwrap();
+        let mut nodes = a.locate_user(&alice).await.unwrap();
+        nodes.sort();
+        assert_eq!(nodes, vec!["a".to_string(), "b".to_string()]);
+
+        a.unregister_user(&alice).await.unwrap();
+        assert_eq!(b.locate_user(&alice).await.unwrap(), vec!["b".to_string()]);
+        b.unregister_user(&alice).await.unwrap();
+        assert!(a.locate_user(&alice).await.unwrap().is_empty());
+    }
+}
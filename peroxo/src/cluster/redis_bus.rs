@@ -0,0 +1,192 @@
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use redis::AsyncCommands;
+use redis::aio::MultiplexedConnection;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use super::{CLUSTER_CHANNEL_SIZE, ClusterBus, ClusterEnvelope, ClusterEvent, NodeId};
+use crate::tenant::TenantUserId;
+
+const BROADCAST_CHANNEL: &str = "peroxo:broadcast";
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+// A node that dies leaves its entries behind, they go away with the key
+// unless the user reconnects somewhere in the meantime
+const LOCATION_TTL_SECS: i64 = 24 * 60 * 60;
+
+fn node_channel(node_id: &str) -> String {
+    format!("peroxo:node:{}", node_id)
+}
+
+fn location_key(user: &TenantUserId) -> String {
+    format!("peroxo:user:{}:{}", user.project_id, user.user_id)
+}
+
+// Redis pub/sub for events and a set per user for the location registry
+pub struct RedisBus {
+    node_id: NodeId,
+    client: redis::Client,
+    connection: MultiplexedConnection,
+}
+
+impl RedisBus {
+    pub async fn connect(url: &str, node_id: impl Into<NodeId>) -> Result<Self, String> {
+        let client = redis::Client::open(url).map_err(|e| e.to_string())?;
+        let connection = client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            node_id: node_id.into(),
+            client,
+            connection,
+        })
+    }
+
+    async fn publish(&self, channel: &str, event: ClusterEvent) -> Result<(), String> {
+        let envelope = ClusterEnvelope {
+            origin: self.node_id.clone(),
+            event,
+        };
+        let payload = serde_json::to_string(&envelope).map_err(|e| e.to_string())?;
+        let mut connection = self.connection.clone();
+        connection
+            .publish::<_, _, ()>(channel, payload)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+async fn open_pubsub(
+    client: &redis::Client,
+    node_id: &str,
+) -> redis::RedisResult<redis::aio::PubSub> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(node_channel(node_id)).await?;
+    pubsub.subscribe(BROADCAST_CHANNEL).await?;
+    Ok(pubsub)
+}
+
+// Pumps pub/sub messages into `sender`, resubscribing when the connection drops
+async fn pump(
+    client: redis::Client,
+    node_id: NodeId,
+    mut pubsub: redis::aio::PubSub,
+    sender: mpsc::Sender<ClusterEnvelope>,
+) {
+    loop {
+        let mut messages = pubsub.into_on_message();
+        while let Some(message) = messages.next().await {
+            let envelope = match message
+                .get_payload::<String>()
+                .map_err(|e| e.to_string())
+                .and_then(|payload| {
+                    serde_json::from_str::<ClusterEnvelope>(&payload).map_err(|e| e.to_string())
+                }) {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    warn!("Ignoring malformed cluster message: {}", e);
+                    continue;
+                }
+            };
+            if envelope.origin == node_id {
+                continue;
+            }
+            if sender.send(envelope).await.is_err() {
+                return;
+            }
+        }
+
+        error!("Redis subscription of node {} dropped", node_id);
+        pubsub = loop {
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+            if sender.is_closed() {
+                return;
+            }
+            match open_pubsub(&client, &node_id).await {
+                Ok(pubsub) => break pubsub,
+                Err(e) => error!("Failed to resubscribe node {}: {}", node_id, e),
+            }
+        };
+        info!("Redis subscription of node {} restored", node_id);
+    }
+}
+
+impl ClusterBus for RedisBus {
+    fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    fn subscribe(&self) -> BoxFuture<'_, Result<mpsc::Receiver<ClusterEnvelope>, String>> {
+        async move {
+            let pubsub = open_pubsub(&self.client, &self.node_id)
+                .await
+                .map_err(|e| e.to_string())?;
+            let (sender, receiver) = mpsc::channel(CLUSTER_CHANNEL_SIZE);
+            tokio::spawn(pump(
+                self.client.clone(),
+                self.node_id.clone(),
+                pubsub,
+                sender,
+            ));
+            Ok(receiver)
+        }
+        .boxed()
+    }
+
+    fn send_to_node<'a>(
+        &'a self,
+        node: &'a str,
+        event: ClusterEvent,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        async move { self.publish(&node_channel(node), event).await }.boxed()
+    }
+
+    fn broadcast(&self, event: ClusterEvent) -> BoxFuture<'_, Result<(), String>> {
+        async move { self.publish(BROADCAST_CHANNEL, event).await }.boxed()
+    }
+
+    fn register_user<'a>(&'a self, user: &'a TenantUserId) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            let key = location_key(user);
+            let mut connection = self.connection.clone();
+            redis::pipe()
+                .sadd(&key, &self.node_id)
+                .ignore()
+                .expire(&key, LOCATION_TTL_SECS)
+                .ignore()
+                .query_async::<()>(&mut connection)
+                .await
+                .map_err(|e| e.to_string())
+        }
+        .boxed()
+    }
+
+    fn unregister_user<'a>(&'a self, user: &'a TenantUserId) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            let mut connection = self.connection.clone();
+            connection
+                .srem::<_, _, ()>(location_key(user), &self.node_id)
+                .await
+                .map_err(|e| e.to_string())
+        }
+        .boxed()
+    }
+
+    fn locate_user<'a>(
+        &'a self,
+        user: &'a TenantUserId,
+    ) -> BoxFuture<'a, Result<Vec<NodeId>, String>> {
+        async move {
+            let mut connection = self.connection.clone();
+            connection
+                .smembers::<_, Vec<NodeId>>(location_key(user))
+                .await
+                .map_err(|e| e.to_string())
+        }
+        .boxed()
+    }
+}
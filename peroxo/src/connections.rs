@@ -1,19 +1,179 @@
-use tonic::transport::Channel;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tonic::transport::channel::Change;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::{HealthCheckRequest, health_client::HealthClient};
+use tracing::{info, warn};
 
 use crate::chat_service_client::ChatServiceClient;
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, GuardedChannel};
+
+// Name chat-service reports its health under
+const CHAT_SERVICE_HEALTH_NAME: &str = "chat_service.ChatService";
+
+#[derive(Clone, Debug)]
+pub struct GrpcClientConfig {
+    pub connect_timeout: Duration,
+    // Deadline of a call unless the request sets its own
+    pub request_timeout: Duration,
+    pub breaker: CircuitBreakerConfig,
+    // How often every endpoint of a service with a health service is checked
+    pub health_check_interval: Duration,
+}
+
+impl Default for GrpcClientConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(3),
+            request_timeout: Duration::from_secs(5),
+            breaker: CircuitBreakerConfig::default(),
+            health_check_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+impl GrpcClientConfig {
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    pub fn with_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.breaker = config;
+        self
+    }
+
+    pub fn with_health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check_interval = interval;
+        self
+    }
+}
+
+// `addrs` is one address or several separated by commas, calls are balanced
+// over them
+fn endpoints(
+    addrs: &str,
+    config: &GrpcClientConfig,
+) -> Result<Vec<Endpoint>, Box<dyn std::error::Error>> {
+    let endpoints = addrs
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(|addr| {
+            Ok(Endpoint::from_shared(addr.to_string())?
+                .connect_timeout(config.connect_timeout)
+                .tcp_keepalive(Some(Duration::from_secs(30))))
+        })
+        .collect::<Result<Vec<_>, tonic::transport::Error>>()?;
+    if endpoints.is_empty() {
+        return Err(format!("no endpoint in {:?}", addrs).into());
+    }
+    Ok(endpoints)
+}
 
+// Connects lazily, an endpoint that is down doesn't stop the start and is
+// taken out of rotation by the health checks until it serves again
 pub async fn connect_chat_service_client(
     chat_service_addr: String,
-) -> Result<ChatServiceClient<Channel>, Box<dyn std::error::Error>> {
-    let client = ChatServiceClient::connect(chat_service_addr).await?;
-    Ok(client)
+    config: &GrpcClientConfig,
+) -> Result<ChatServiceClient<GuardedChannel>, Box<dyn std::error::Error>> {
+    let endpoints = endpoints(&chat_service_addr, config)?;
+    let (channel, changes) = Channel::balance_channel(endpoints.len());
+    for endpoint in &endpoints {
+        changes
+            .send(Change::Insert(endpoint.uri().clone(), endpoint.clone()))
+            .await?;
+    }
+
+    let breaker = CircuitBreaker::new("chat-service", config.breaker.clone());
+    tokio::spawn(watch_health(
+        CHAT_SERVICE_HEALTH_NAME,
+        endpoints,
+        changes,
+        breaker.clone(),
+        config.clone(),
+    ));
+
+    let channel = GuardedChannel::new(channel, breaker, config.request_timeout);
+    Ok(ChatServiceClient::new(channel))
 }
 
 pub async fn connect_auth_service_client(
     auth_service_addr: String,
-) -> Result<crate::auth_service_client::AuthServiceClient<Channel>, Box<dyn std::error::Error>> {
-    let client = crate::auth_service_client::AuthServiceClient::connect(auth_service_addr).await?;
-    Ok(client)
+    config: &GrpcClientConfig,
+) -> Result<crate::auth_service_client::AuthServiceClient<GuardedChannel>, Box<dyn std::error::Error>>
+{
+    let channel = Channel::balance_list(endpoints(&auth_service_addr, config)?.into_iter());
+    let breaker = CircuitBreaker::new("auth-service", config.breaker.clone());
+    let channel = GuardedChannel::new(channel, breaker, config.request_timeout);
+    Ok(crate::auth_service_client::AuthServiceClient::new(channel))
+}
+
+// Keeps the endpoints that report `service` as serving in the balancer, and
+// opens the circuit while none of them does
+async fn watch_health(
+    service: &'static str,
+    endpoints: Vec<Endpoint>,
+    changes: mpsc::Sender<Change<Uri, Endpoint>>,
+    breaker: CircuitBreaker,
+    config: GrpcClientConfig,
+) {
+    let checks: Vec<_> = endpoints
+        .iter()
+        .map(|endpoint| HealthClient::new(endpoint.connect_lazy()))
+        .collect();
+    let mut serving = vec![true; endpoints.len()];
+    let mut interval = tokio::time::interval(config.health_check_interval);
+
+    loop {
+        interval.tick().await;
+        for (i, endpoint) in endpoints.iter().enumerate() {
+            let now_serving = is_serving(checks[i].clone(), service, config.request_timeout).await;
+            if now_serving == serving[i] {
+                continue;
+            }
+            serving[i] = now_serving;
+
+            let uri = endpoint.uri().clone();
+            let change = if now_serving {
+                info!("{} at {} is serving again", service, uri);
+                Change::Insert(uri, endpoint.clone())
+            } else {
+                warn!(
+                    "{} at {} is not serving, taking it out of rotation",
+                    service, uri
+                );
+                Change::Remove(uri)
+            };
+            // the client is gone
+            if changes.send(change).await.is_err() {
+                return;
+            }
+        }
+
+        if !serving.contains(&true) {
+            breaker.trip();
+        }
+    }
+}
+
+async fn is_serving(mut client: HealthClient<Channel>, service: &str, timeout: Duration) -> bool {
+    let request = HealthCheckRequest {
+        service: service.to_string(),
+    };
+    match tokio::time::timeout(timeout, client.check(request)).await {
+        Ok(Ok(response)) => response.into_inner().status == ServingStatus::Serving as i32,
+        // an instance without a health service is judged by its calls alone
+        Ok(Err(status)) => status.code() == tonic::Code::Unimplemented,
+        Err(_) => false,
+    }
 }
 
 #[cfg(feature = "mongo_db")]
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::chat::ErrorCode;
+
+#[derive(Clone, Debug)]
+pub struct EditConfig {
+    // How long after sending it a message may be edited or deleted by its
+    // sender, None for no limit
+    pub window: Option<Duration>,
+    // by project id, for tenants that need a longer or shorter window
+    pub tenants: HashMap<String, Option<Duration>>,
+}
+
+impl Default for EditConfig {
+    fn default() -> Self {
+        Self {
+            window: Some(Duration::from_secs(15 * 60)),
+            tenants: HashMap::new(),
+        }
+    }
+}
+
+impl EditConfig {
+    pub fn with_window(mut self, window: Option<Duration>) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn with_tenant(mut self, project_id: impl Into<String>, window: Option<Duration>) -> Self {
+        self.tenants.insert(project_id.into(), window);
+        self
+    }
+
+    pub fn window_for(&self, project_id: &str) -> Option<Duration> {
+        self.tenants.get(project_id).copied().unwrap_or(self.window)
+    }
+}
+
+// Why an edit, delete or reaction didn't happen
+#[derive(Clone, Debug)]
+pub struct ChangeError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ChangeError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn failed(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::RequestFailed, message)
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+#[derive(Clone, Debug)]
+pub struct HeartbeatConfig {
+    // How often every connection is pinged
+    pub ping_interval: Duration,
+    // A connection that sent nothing for this long, pongs included, is
+    // considered dead and closed. Keep it above the ping interval.
+    pub idle_timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(20),
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    pub fn with_ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+}
+
+// When a connection last heard from its client, any frame counts. Shared
+// between the task reading the socket and the one writing it.
+#[derive(Clone)]
+pub struct Liveness {
+    last_seen: Arc<Mutex<Instant>>,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Self {
+            last_seen: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
+
+impl Liveness {
+    pub fn seen(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    // Resolves once nothing was heard for `timeout`
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let deadline = *self.last_seen.lock().unwrap() + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn any_frame_keeps_a_connection_alive() {
+        let timeout = Duration::from_millis(300);
+        let start = Instant::now();
+        let liveness = Liveness::default();
+        let idle = liveness.idle(timeout);
+        tokio::pin!(idle);
+        assert!(futures::poll!(&mut idle).is_pending());
+
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            liveness.seen();
+        }
+        // the first deadline passed, the last frame moved it on
+        tokio::time::sleep_until(start + timeout).await;
+        assert!(futures::poll!(&mut idle).is_pending());
+
+        // quiet from here on
+        idle.await;
+        assert_eq!(Instant::now(), start + Duration::from_millis(150) + timeout);
+    }
+}
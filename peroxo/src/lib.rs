@@ -1,7 +1,7 @@
 use axum::{
     Router,
     extract::{Query, State, WebSocketUpgrade},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     middleware,
     response::IntoResponse,
     routing::{any, get},
@@ -19,67 +19,55 @@ tonic::include_proto!("auth_service");
 tonic::include_proto!("chat_service");
 
 pub mod actors;
+pub mod auth;
+pub mod backpressure;
 pub mod chat;
+pub mod circuit_breaker;
+pub mod cluster;
 pub mod connections;
+#[cfg(feature = "persistence")]
+pub mod edits;
 mod handlers;
+pub mod heartbeat;
 pub mod metrics;
 #[cfg(feature = "mongo_db")]
 pub mod mongo_db;
+pub mod rate_limit;
+#[cfg(feature = "persistence")]
+pub mod reactions;
 pub mod socket;
+pub mod shutdown;
 pub mod state;
 pub mod tenant;
-
-async fn verify_token(
-    state: &Arc<PerOxoState>,
-    token: String,
-) -> Result<UserToken, (StatusCode, String)> {
-    let mut client = state.auth_client.clone();
-
-    let req = tonic::Request::new(VerifyUserTokenRequest { token });
-
-    let resp = match client.verify_user_token(req).await {
-        Ok(r) => r.into_inner(),
-        Err(e) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Auth service error: {}", e),
-            ));
-        }
-    };
-
-    if !resp.found {
-        return Err((StatusCode::UNAUTHORIZED, "Invalid token".to_string()));
-    }
-
-    match resp.user_token {
-        Some(user_token) => Ok(user_token),
-        None => Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Auth service returned found=true but no user_token data".to_string(),
-        )),
-    }
-}
+// the fake chat-service has no mongo_db stand-in
+#[cfg(all(test, feature = "persistence", not(feature = "mongo_db")))]
+mod testing;
+pub mod wire;
 
 async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<PerOxoState>>,
+    headers: HeaderMap,
     Query(params): Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
-    let token = match params.get("token") {
-        Some(token) => token.clone(),
-        None => return (StatusCode::BAD_REQUEST, "Missing token").into_response(),
-    };
+    // clients go to another instance while this one drains
+    if state.drain.is_draining() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server is shutting down").into_response();
+    }
 
-    let user_token = match verify_token(&state, token.clone()).await {
-        Ok(id) => id,
-        Err(err) => return err.into_response(),
+    // ?token= still works but ends up in access logs. Without any token the
+    // client has to send Authenticate as its first frame.
+    let token = auth::token_from_protocols(&headers).or_else(|| params.get("token").cloned());
+    let credentials = match token {
+        Some(token) => match state.authenticator.verify(token).await {
+            Ok(credentials) => Some(credentials),
+            Err(e) => return (e.status(), e.to_string()).into_response(),
+        },
+        None => None,
     };
 
-    let tenant_user_id = match tenant::TenantUserId::from_token(&user_token) {
-        Ok(id) => id,
-        Err(_) => return (StatusCode::UNAUTHORIZED, "Invalid tenant token").into_response(),
-    };
-    ws.on_upgrade(move |socket| dm_socket(socket, tenant_user_id, state))
+    ws.protocols(wire::SUPPORTED_PROTOCOLS)
+        .on_upgrade(move |socket| dm_socket(socket, credentials, state))
 }
 
 pub fn peroxo_route(state: Arc<PerOxoState>) -> Router {
@@ -1,4 +1,4 @@
-use per_oxo::{peroxo_route, state::PerOxoStateBuilder};
+use per_oxo::{peroxo_route, shutdown, state::PerOxoStateBuilder};
 use std::{net::SocketAddr, sync::Arc};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -17,13 +17,35 @@ async fn main() {
     let chat_service_addr = std::env::var("CHAT_SERVICE_ADDR").unwrap();
     let auth_service_addr = std::env::var("AUTH_SERVICE_ADDR").unwrap();
 
-    let state = match PerOxoStateBuilder::new()
+    let builder = PerOxoStateBuilder::new()
         // .with_mongo_config(state)
         .with_persistence_connection_url(chat_service_addr)
-        .with_auth_url(auth_service_addr)
-        .build()
-        .await
-    {
+        .with_auth_url(auth_service_addr);
+
+    // writes chat-service can't take are kept here until it is back
+    let builder = match std::env::var("PER_OXO_OUTBOX_DIR") {
+        Ok(dir) => builder.with_outbox_dir(dir),
+        Err(_) => builder,
+    };
+
+    // peroxo instances sharing a Redis form one cluster
+    #[cfg(feature = "redis_bus")]
+    let builder = match std::env::var("REDIS_CLUSTER_URL") {
+        Ok(url) => {
+            let node_id = std::env::var("PER_OXO_NODE_ID")
+                .unwrap_or_else(|_| uuid::Uuid::new_v4().to_string());
+            match per_oxo::cluster::RedisBus::connect(&url, node_id).await {
+                Ok(bus) => builder.with_cluster_bus(Arc::new(bus)),
+                Err(e) => {
+                    tracing::error!("Failed to connect to the cluster bus: {}", e);
+                    return;
+                }
+            }
+        }
+        Err(_) => builder,
+    };
+
+    let state = match builder.build().await {
         Ok(state) => state,
         Err(e) => {
             tracing::error!("Failed to build PerOxoState: {:?}", e);
@@ -31,17 +53,25 @@ async fn main() {
         }
     };
 
-    let app = peroxo_route(Arc::new(state));
+    let state = Arc::new(state);
+    let app = peroxo_route(state.clone());
 
     let addr = std::env::var("PER_OXO_SERVICE_ADDR").unwrap();
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
 
     tracing::info!("Per-OXO service listening on {}", addr);
 
+    // the listener stays open while draining so /metrics keeps answering,
+    // new WebSocket upgrades are refused
     axum::serve(
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
+    .with_graceful_shutdown(async move {
+        shutdown::signal().await;
+        state.shutdown().await;
+        tracing::info!("Per-OXO service drained");
+    })
     .await
     .unwrap();
 }
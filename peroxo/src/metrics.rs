@@ -1,8 +1,9 @@
 use axum::{extract::Request, http::StatusCode, middleware::Next, response::Response};
 
 use prometheus::{
-    CounterVec, Encoder, Gauge, HistogramVec, TextEncoder, histogram_opts, opts,
-    register_counter_vec, register_gauge, register_histogram_vec,
+    Counter, CounterVec, Encoder, Gauge, GaugeVec, HistogramVec, TextEncoder, histogram_opts, opts,
+    register_counter, register_counter_vec, register_gauge, register_gauge_vec,
+    register_histogram_vec,
 };
 
 use std::sync::LazyLock;
@@ -94,6 +95,87 @@ static DB_QUERY_DURATION_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
     .unwrap()
 });
 
+static MESSAGES_DROPPED_TOTAL: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        opts!(
+            "messages_dropped_total",
+            "Messages dropped because a queue or mailbox was full"
+        ),
+        &["queue", "class"]
+    )
+    .unwrap()
+});
+
+static SLOW_CONSUMER_DISCONNECTS_TOTAL: LazyLock<Counter> = LazyLock::new(|| {
+    register_counter!(opts!(
+        "slow_consumer_disconnects_total",
+        "Connections closed for not keeping up with their queue"
+    ))
+    .unwrap()
+});
+
+static IDLE_DISCONNECTS_TOTAL: LazyLock<Counter> = LazyLock::new(|| {
+    register_counter!(opts!(
+        "idle_disconnects_total",
+        "Connections closed for not answering pings"
+    ))
+    .unwrap()
+});
+
+static TOKEN_EXPIRED_DISCONNECTS_TOTAL: LazyLock<Counter> = LazyLock::new(|| {
+    register_counter!(opts!(
+        "token_expired_disconnects_total",
+        "Connections closed because their token expired"
+    ))
+    .unwrap()
+});
+
+static RATE_LIMITED_TOTAL: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        opts!(
+            "rate_limited_total",
+            "Client requests refused by a connection or tenant rate limit"
+        ),
+        &["class", "scope"]
+    )
+    .unwrap()
+});
+
+static RATE_LIMIT_DISCONNECTS_TOTAL: LazyLock<Counter> = LazyLock::new(|| {
+    register_counter!(opts!(
+        "rate_limit_disconnects_total",
+        "Connections closed for running into their rate limits too often"
+    ))
+    .unwrap()
+});
+
+static PERSISTENCE_OUTBOX_DEPTH: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(opts!(
+        "persistence_outbox_depth",
+        "Message writes spooled to the local outbox, waiting to be replayed"
+    ))
+    .unwrap()
+});
+
+static PERSISTENCE_OUTBOX_DEAD_LETTERS_TOTAL: LazyLock<Counter> = LazyLock::new(|| {
+    register_counter!(opts!(
+        "persistence_outbox_dead_letters_total",
+        "Spooled message writes replay gave up on, to be alerted on"
+    ))
+    .unwrap()
+});
+
+static GRPC_CIRCUIT_OPEN: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        opts!(
+            "grpc_circuit_open",
+            "1 while calls to the service fail fast, 0 while they go through"
+        ),
+        &["service"]
+    )
+    .unwrap()
+});
+
 pub async fn metrics_middleware(req: Request, next: Next) -> Response {
     let start = Instant::now();
     let method = req.method().to_string();
@@ -184,6 +266,57 @@ impl Metrics {
             .observe(duration.as_secs_f64());
     }
 
+    // --- Backpressure ---
+    pub fn message_dropped(queue: &str, class: &str, count: u64) {
+        MESSAGES_DROPPED_TOTAL
+            .with_label_values(&[queue, class])
+            .inc_by(count as f64);
+    }
+
+    pub fn slow_consumer_disconnected() {
+        SLOW_CONSUMER_DISCONNECTS_TOTAL.inc();
+    }
+
+    pub fn idle_connection_closed() {
+        IDLE_DISCONNECTS_TOTAL.inc();
+    }
+
+    pub fn token_expired_connection_closed() {
+        TOKEN_EXPIRED_DISCONNECTS_TOTAL.inc();
+    }
+
+    pub fn rate_limited(class: &str, scope: &str) {
+        RATE_LIMITED_TOTAL.with_label_values(&[class, scope]).inc();
+    }
+
+    pub fn rate_limit_disconnected() {
+        RATE_LIMIT_DISCONNECTS_TOTAL.inc();
+    }
+
+    // --- Persistence outbox ---
+    pub fn set_outbox_depth(depth: u64) {
+        PERSISTENCE_OUTBOX_DEPTH.set(depth as f64);
+    }
+
+    pub fn outbox_spooled(count: u64) {
+        PERSISTENCE_OUTBOX_DEPTH.add(count as f64);
+    }
+
+    pub fn outbox_replayed(count: u64) {
+        PERSISTENCE_OUTBOX_DEPTH.sub(count as f64);
+    }
+
+    pub fn outbox_dead_lettered(count: u64) {
+        PERSISTENCE_OUTBOX_DEAD_LETTERS_TOTAL.inc_by(count as f64);
+    }
+
+    // --- gRPC clients ---
+    pub fn set_circuit_open(service: &str, open: bool) {
+        GRPC_CIRCUIT_OPEN
+            .with_label_values(&[service])
+            .set(if open { 1.0 } else { 0.0 });
+    }
+
     pub fn observe_db_query(operation: &str, duration: std::time::Duration) {
         DB_QUERY_DURATION_SECONDS
             .with_label_values(&[operation])
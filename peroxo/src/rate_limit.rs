@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::chat::ChatMessage;
+#[cfg(feature = "persistence")]
+use crate::chat::MessageTarget;
+
+// A token bucket, `burst` requests at once and `per_second` on average
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, per_second: f64) -> Self {
+        Self { burst, per_second }
+    }
+}
+
+// The requests that are limited, each has its own bucket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateClass {
+    DirectMessage,
+    RoomMessage,
+    JoinRoom,
+}
+
+impl RateClass {
+    pub fn of(message: &ChatMessage) -> Option<Self> {
+        match message {
+            ChatMessage::SendDirectMessage { .. } => Some(RateClass::DirectMessage),
+            ChatMessage::SendRoomMessage { .. } => Some(RateClass::RoomMessage),
+            ChatMessage::JoinRoom { .. } => Some(RateClass::JoinRoom),
+            // changes and reactions count like the messages they are about
+            #[cfg(feature = "persistence")]
+            ChatMessage::EditMessage { target, .. }
+            | ChatMessage::DeleteMessage { target, .. }
+            | ChatMessage::AddReaction { target, .. }
+            | ChatMessage::RemoveReaction { target, .. } => Some(match target {
+                MessageTarget::Conversation { .. } => RateClass::DirectMessage,
+                MessageTarget::Room { .. } => RateClass::RoomMessage,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RateClass::DirectMessage => "direct_message",
+            RateClass::RoomMessage => "room_message",
+            RateClass::JoinRoom => "join_room",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimits {
+    pub direct_message: RateLimit,
+    pub room_message: RateLimit,
+    pub join_room: RateLimit,
+}
+
+impl RateLimits {
+    pub fn with_direct_message(mut self, limit: RateLimit) -> Self {
+        self.direct_message = limit;
+        self
+    }
+
+    pub fn with_room_message(mut self, limit: RateLimit) -> Self {
+        self.room_message = limit;
+        self
+    }
+
+    pub fn with_join_room(mut self, limit: RateLimit) -> Self {
+        self.join_room = limit;
+        self
+    }
+
+    fn of(&self, class: RateClass) -> RateLimit {
+        match class {
+            RateClass::DirectMessage => self.direct_message,
+            RateClass::RoomMessage => self.room_message,
+            RateClass::JoinRoom => self.join_room,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TenantRateLimits {
+    // each connection on its own
+    pub session: RateLimits,
+    // every connection of the project on this node together
+    pub tenant: RateLimits,
+}
+
+impl Default for TenantRateLimits {
+    fn default() -> Self {
+        Self {
+            session: RateLimits {
+                direct_message: RateLimit::new(20, 10.0),
+                room_message: RateLimit::new(20, 10.0),
+                join_room: RateLimit::new(10, 1.0),
+            },
+            tenant: RateLimits {
+                direct_message: RateLimit::new(2_000, 1_000.0),
+                room_message: RateLimit::new(2_000, 1_000.0),
+                join_room: RateLimit::new(200, 50.0),
+            },
+        }
+    }
+}
+
+impl TenantRateLimits {
+    pub fn with_session(mut self, limits: RateLimits) -> Self {
+        self.session = limits;
+        self
+    }
+
+    pub fn with_tenant(mut self, limits: RateLimits) -> Self {
+        self.tenant = limits;
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    pub default: TenantRateLimits,
+    // by project id, for tenants that need more or less than the default
+    pub tenants: HashMap<String, TenantRateLimits>,
+    // A connection refused by its own limits this many times within
+    // `strike_window` is disconnected. Refusals by the tenant's limits
+    // don't count, they may be caused by other connections.
+    pub max_strikes: u32,
+    pub strike_window: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            default: TenantRateLimits::default(),
+            tenants: HashMap::new(),
+            max_strikes: 20,
+            strike_window: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn with_default(mut self, limits: TenantRateLimits) -> Self {
+        self.default = limits;
+        self
+    }
+
+    pub fn with_tenant(mut self, project_id: impl Into<String>, limits: TenantRateLimits) -> Self {
+        self.tenants.insert(project_id.into(), limits);
+        self
+    }
+
+    pub fn with_max_strikes(mut self, strikes: u32, window: Duration) -> Self {
+        self.max_strikes = strikes;
+        self.strike_window = window;
+        self
+    }
+
+    pub fn for_project(&self, project_id: &str) -> &TenantRateLimits {
+        self.tenants.get(project_id).unwrap_or(&self.default)
+    }
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: now,
+        }
+    }
+
+    // Takes a token, or says how long until the next one
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let per_second = self.limit.per_second.max(0.001);
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * per_second).min(self.limit.burst as f64);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / per_second))
+        }
+    }
+}
+
+// One bucket per class
+pub struct Buckets {
+    direct_message: TokenBucket,
+    room_message: TokenBucket,
+    join_room: TokenBucket,
+}
+
+impl Buckets {
+    pub fn new(limits: &RateLimits, now: Instant) -> Self {
+        Self {
+            direct_message: TokenBucket::new(limits.of(RateClass::DirectMessage), now),
+            room_message: TokenBucket::new(limits.of(RateClass::RoomMessage), now),
+            join_room: TokenBucket::new(limits.of(RateClass::JoinRoom), now),
+        }
+    }
+
+    pub fn take(&mut self, class: RateClass, now: Instant) -> Result<(), Duration> {
+        match class {
+            RateClass::DirectMessage => self.direct_message.take(now),
+            RateClass::RoomMessage => self.room_message.take(now),
+            RateClass::JoinRoom => self.join_room.take(now),
+        }
+    }
+}
+
+pub enum Verdict {
+    Allowed,
+    // refused, the client may retry after this long
+    Limited(Duration),
+    // refused once too often, the connection is closed
+    Disconnect,
+}
+
+// The limits of one connection and how often it ran into them
+pub struct SessionLimiter {
+    buckets: Buckets,
+    max_strikes: u32,
+    strike_window: Duration,
+    strikes: u32,
+    first_strike: Instant,
+}
+
+impl SessionLimiter {
+    pub fn new(config: &RateLimitConfig, project_id: &str) -> Self {
+        let now = Instant::now();
+        Self {
+            buckets: Buckets::new(&config.for_project(project_id).session, now),
+            max_strikes: config.max_strikes,
+            strike_window: config.strike_window,
+            strikes: 0,
+            first_strike: now,
+        }
+    }
+
+    pub fn check(&mut self, class: RateClass, now: Instant) -> Verdict {
+        let Err(retry_after) = self.buckets.take(class, now) else {
+            return Verdict::Allowed;
+        };
+
+        if now.duration_since(self.first_strike) > self.strike_window {
+            self.strikes = 0;
+            self.first_strike = now;
+        }
+        self.strikes += 1;
+        if self.strikes > self.max_strikes {
+            Verdict::Disconnect
+        } else {
+            Verdict::Limited(retry_after)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let start = Instant::now();
+        let limits = RateLimits {
+            direct_message: RateLimit::new(2, 1.0),
+            room_message: RateLimit::new(1, 1.0),
+            join_room: RateLimit::new(1, 1.0),
+        };
+        let mut buckets = Buckets::new(&limits, start);
+
+        assert!(buckets.take(RateClass::DirectMessage, start).is_ok());
+        assert!(buckets.take(RateClass::DirectMessage, start).is_ok());
+        let retry_after = buckets.take(RateClass::DirectMessage, start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+        // classes don't share tokens
+        assert!(buckets.take(RateClass::RoomMessage, start).is_ok());
+
+        let later = start + Duration::from_millis(500);
+        let retry_after = buckets.take(RateClass::DirectMessage, later).unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(500));
+        assert!(
+            buckets
+                .take(RateClass::DirectMessage, start + Duration::from_secs(1))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn repeat_offenders_are_disconnected() {
+        let config = RateLimitConfig::default()
+            .with_tenant(
+                "noisy",
+                TenantRateLimits::default().with_session(RateLimits {
+                    direct_message: RateLimit::new(1, 1.0),
+                    room_message: RateLimit::new(1, 1.0),
+                    join_room: RateLimit::new(1, 1.0),
+                }),
+            )
+            .with_max_strikes(2, Duration::from_secs(10));
+        let mut limiter = SessionLimiter::new(&config, "noisy");
+        let now = Instant::now();
+
+        assert!(matches!(
+            limiter.check(RateClass::JoinRoom, now),
+            Verdict::Allowed
+        ));
+        assert!(matches!(
+            limiter.check(RateClass::JoinRoom, now),
+            Verdict::Limited(_)
+        ));
+        assert!(matches!(
+            limiter.check(RateClass::JoinRoom, now),
+            Verdict::Limited(_)
+        ));
+        assert!(matches!(
+            limiter.check(RateClass::JoinRoom, now),
+            Verdict::Disconnect
+        ));
+
+        // strikes are forgotten once the window is over
+        let mut limiter = SessionLimiter::new(&config, "noisy");
+        assert!(matches!(
+            limiter.check(RateClass::JoinRoom, now),
+            Verdict::Allowed
+        ));
+        for window in 1..=5 {
+            let at = now + Duration::from_secs(11 * window);
+            assert!(matches!(
+                limiter.check(RateClass::JoinRoom, at),
+                Verdict::Allowed
+            ));
+            assert!(matches!(
+                limiter.check(RateClass::JoinRoom, at),
+                Verdict::Limited(_)
+            ));
+        }
+    }
+}
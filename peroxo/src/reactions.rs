@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+// Longest reaction taken, in bytes. Emoji sequences like flags or families
+// fit, as do short codes like ":thumbsup:".
+pub const MAX_REACTION_BYTES: usize = 64;
+
+#[derive(Clone, Debug)]
+pub struct ReactionConfig {
+    // How many distinct reactions a message may have, None for no limit.
+    // Adding one the message already has is always allowed. The check runs
+    // before and after the write, a message may show one too many while
+    // concurrent adds settle.
+    pub max_reactions: Option<u32>,
+    // by project id, for tenants that need more or fewer
+    pub tenants: HashMap<String, Option<u32>>,
+}
+
+impl Default for ReactionConfig {
+    fn default() -> Self {
+        Self {
+            max_reactions: Some(20),
+            tenants: HashMap::new(),
+        }
+    }
+}
+
+impl ReactionConfig {
+    pub fn with_max_reactions(mut self, max_reactions: Option<u32>) -> Self {
+        self.max_reactions = max_reactions;
+        self
+    }
+
+    pub fn with_tenant(
+        mut self,
+        project_id: impl Into<String>,
+        max_reactions: Option<u32>,
+    ) -> Self {
+        self.tenants.insert(project_id.into(), max_reactions);
+        self
+    }
+
+    pub fn max_reactions_for(&self, project_id: &str) -> Option<u32> {
+        self.tenants
+            .get(project_id)
+            .copied()
+            .unwrap_or(self.max_reactions)
+    }
+}
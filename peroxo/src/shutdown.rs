@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tracing::info;
+
+#[derive(Clone, Debug)]
+pub struct ShutdownConfig {
+    // How long sessions and the writes they started get to finish once a
+    // shutdown begins, keep it below the orchestrator's grace period
+    pub drain_timeout: Duration,
+    // Clients are told to reconnect after this long, spread over up to
+    // twice it so they don't all come back at once
+    pub retry_after: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout: Duration::from_secs(25),
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    // Somewhere in [retry_after, 2 * retry_after), picked by `seed`
+    pub fn retry_after_ms(&self, seed: u128) -> u64 {
+        let retry_after = self.retry_after.as_millis().max(1);
+        (retry_after + seed % retry_after) as u64
+    }
+}
+
+// Tells sessions the server is going away and counts the ones still open
+#[derive(Clone)]
+pub struct Drain {
+    pub config: ShutdownConfig,
+    draining: watch::Sender<bool>,
+    sessions: watch::Sender<usize>,
+}
+
+impl Drain {
+    pub fn new(config: ShutdownConfig) -> Self {
+        Self {
+            config,
+            draining: watch::Sender::new(false),
+            sessions: watch::Sender::new(0),
+        }
+    }
+
+    pub fn start(&self) {
+        self.draining.send_replace(true);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    // Resolves once the drain started
+    pub async fn started(&self) {
+        let _ = self
+            .draining
+            .subscribe()
+            .wait_for(|draining| *draining)
+            .await;
+    }
+
+    // Counts a session as open until the guard is dropped
+    pub fn session(&self) -> SessionGuard {
+        self.sessions.send_modify(|sessions| *sessions += 1);
+        SessionGuard {
+            sessions: self.sessions.clone(),
+        }
+    }
+
+    pub async fn sessions_closed(&self) {
+        let _ = self
+            .sessions
+            .subscribe()
+            .wait_for(|sessions| *sessions == 0)
+            .await;
+    }
+
+    pub fn open_sessions(&self) -> usize {
+        *self.sessions.borrow()
+    }
+}
+
+pub struct SessionGuard {
+    sessions: watch::Sender<usize>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.send_modify(|sessions| *sessions -= 1);
+    }
+}
+
+// Resolves on SIGTERM, or Ctrl-C when run by hand
+pub async fn signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received Ctrl-C, shutting down"),
+        _ = terminate => info!("Received SIGTERM, shutting down"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_waits_for_every_open_session() {
+        let drain = Drain::new(ShutdownConfig::default());
+        let first = drain.session();
+        let second = drain.session();
+        assert!(!drain.is_draining());
+
+        drain.start();
+        assert!(drain.is_draining());
+        tokio::time::timeout(Duration::from_secs(1), drain.started())
+            .await
+            .unwrap();
+
+        drop(first);
+        assert_eq!(drain.open_sessions(), 1);
+        let closed = tokio::time::timeout(Duration::from_millis(50), drain.sessions_closed()).await;
+        assert!(closed.is_err());
+
+        drop(second);
+        tokio::time::timeout(Duration::from_secs(1), drain.sessions_closed())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn reconnects_are_spread_over_twice_the_delay() {
+        let config = ShutdownConfig::default().with_retry_after(Duration::from_millis(100));
+        assert_eq!(config.retry_after_ms(0), 100);
+        assert_eq!(config.retry_after_ms(99), 199);
+        assert_eq!(config.retry_after_ms(100), 100);
+    }
+}
@@ -1,10 +1,101 @@
-use crate::{state::PerOxoState, tenant::TenantUserId};
-use axum::extract::ws::WebSocket;
+use crate::{
+    actors::user_session::session::CLOSE_UNAUTHENTICATED,
+    auth::{Authenticator, Credentials},
+    chat::{ChatMessage, ErrorCode},
+    state::PerOxoState,
+    wire::{self, FrameError, WireProtocol},
+};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
 use std::sync::Arc;
+use tracing::debug;
+
+pub async fn dm_socket(
+    mut socket: WebSocket,
+    credentials: Option<Credentials>,
+    state: Arc<PerOxoState>,
+) {
+    let protocol = WireProtocol::negotiated(socket.protocol());
+
+    let (credentials, request_id) = match credentials {
+        Some(credentials) => (credentials, None),
+        None => match first_frame_credentials(&mut socket, &state.authenticator).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => {
+                debug!("Refused unauthenticated connection: {}", e.message);
+                let error = ChatMessage::Error {
+                    code: e.code,
+                    message: e.message,
+                    request_id: e.request_id,
+                };
+                if let Ok(frame) = protocol.encode(&error) {
+                    let _ = socket.send(frame).await;
+                }
+                let _ = socket
+                    .send(Message::Close(Some(CloseFrame {
+                        code: CLOSE_UNAUTHENTICATED,
+                        reason: "authentication failed".into(),
+                    })))
+                    .await;
+                return;
+            }
+        },
+    };
+
+    // tells every client when to reauthenticate, whichever way it sent its token
+    let authenticated = ChatMessage::Authenticated {
+        expires_at: credentials.expires_at,
+        request_id,
+    };
+    let Ok(frame) = protocol.encode(&authenticated) else {
+        return;
+    };
+    if socket.send(frame).await.is_err() {
+        return;
+    }
 
-pub async fn dm_socket(socket: WebSocket, tenant_user_id: TenantUserId, state: Arc<PerOxoState>) {
     state
         .connection_manager
-        .handle_connection(socket, tenant_user_id)
+        .handle_connection(socket, credentials)
         .await;
 }
+
+// Waits for the Authenticate frame of a client that sent no token with the
+// upgrade, anything else before it fails the connection
+async fn first_frame_credentials(
+    socket: &mut WebSocket,
+    authenticator: &Authenticator,
+) -> Result<(Credentials, Option<String>), FrameError> {
+    let timeout = authenticator.config.auth_timeout;
+    let frame = tokio::time::timeout(timeout, async {
+        loop {
+            match socket.recv().await {
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_))) => return None,
+                Some(Ok(frame)) => return Some(frame),
+                _ => return None,
+            }
+        }
+    })
+    .await
+    .map_err(|_| {
+        FrameError::new(
+            ErrorCode::Unauthorized,
+            format!("No Authenticate frame within {:?}", timeout),
+            None,
+        )
+    })?
+    .ok_or_else(|| FrameError::new(ErrorCode::Unauthorized, "Connection closed", None))?;
+
+    let request = wire::decode(&frame)?;
+    let ChatMessage::Authenticate { token } = request.message else {
+        return Err(FrameError::new(
+            ErrorCode::Unauthorized,
+            "Authenticate has to be the first frame",
+            request.request_id,
+        ));
+    };
+    match authenticator.verify(token).await {
+        Ok(credentials) => Ok((credentials, request.request_id)),
+        Err(e) => Err(FrameError::new(e.code(), e.to_string(), request.request_id)),
+    }
+}
@@ -1,34 +1,66 @@
 use crate::actors::{
     connection_manager::ConnectionManager,
-    message_router::{MessageRouter, RouterMessage},
+    message_router::{MessageRouter, RouterHandle},
 };
+use crate::auth::{AuthConfig, Authenticator};
+use crate::backpressure::BackpressureConfig;
+use crate::circuit_breaker::GuardedChannel;
+use crate::cluster::{self, ClusterBus, ClusterEvent};
+use crate::connections::GrpcClientConfig;
+use crate::heartbeat::HeartbeatConfig;
+use crate::rate_limit::RateLimitConfig;
+use crate::shutdown::{Drain, ShutdownConfig};
 
 #[cfg(feature = "persistence")]
 #[cfg(any(feature = "mongo_db", feature = "persistence"))]
 use crate::actors::persistance_actor::PersistenceService;
+#[cfg(any(feature = "mongo_db", feature = "persistence"))]
+use crate::actors::persistance_actor::WriteBatchConfig;
+#[cfg(feature = "persistence")]
+use crate::actors::persistance_actor::DurableOutbox;
 #[cfg(feature = "persistence")]
 use crate::chat_service_client::ChatServiceClient;
+#[cfg(feature = "persistence")]
+use crate::edits::EditConfig;
 #[cfg(feature = "mongo_db")]
 use crate::mongo_db::config::MongoDbConfig;
+#[cfg(feature = "persistence")]
+use crate::reactions::ReactionConfig;
 
-use std::sync::Arc;
-use tokio::sync::mpsc;
 #[cfg(feature = "persistence")]
-use tonic::transport::Channel;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::warn;
 pub struct PerOxoState {
     pub connection_manager: Arc<ConnectionManager>,
-    pub router_sender: mpsc::UnboundedSender<RouterMessage>,
-    pub auth_client: crate::auth_service_client::AuthServiceClient<Channel>,
+    pub router: RouterHandle,
+    pub auth_client: crate::auth_service_client::AuthServiceClient<GuardedChannel>,
+    pub authenticator: Authenticator,
     #[cfg(feature = "persistence")]
-    pub chat_client: ChatServiceClient<Channel>,
+    pub chat_client: ChatServiceClient<GuardedChannel>,
+    pub drain: Drain,
+    #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+    persistence: Arc<PersistenceService>,
 }
 
 impl PerOxoState {
+    #[allow(clippy::too_many_arguments)]
     async fn new(
-        #[cfg(feature = "persistence")] chat_service_client: ChatServiceClient<Channel>,
+        #[cfg(feature = "persistence")] chat_service_client: ChatServiceClient<GuardedChannel>,
+        #[cfg(any(feature = "mongo_db", feature = "persistence"))] write_batch: WriteBatchConfig,
+        #[cfg(feature = "persistence")] outbox: Option<Arc<DurableOutbox>>,
         #[cfg(feature = "mongo_db")] mango_db_client: mongodb::Client,
         #[cfg(feature = "mongo_db")] mongo_config: MongoDbConfig,
-        auth_client: crate::auth_service_client::AuthServiceClient<Channel>,
+        auth_client: crate::auth_service_client::AuthServiceClient<GuardedChannel>,
+        auth: AuthConfig,
+        backpressure: BackpressureConfig,
+        rate_limits: RateLimitConfig,
+        #[cfg(feature = "persistence")] edits: EditConfig,
+        #[cfg(feature = "persistence")] reactions: ReactionConfig,
+        heartbeat: HeartbeatConfig,
+        shutdown: ShutdownConfig,
+        cluster: Option<Arc<dyn ClusterBus>>,
+        router_shards: usize,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         #[cfg(feature = "persistence")]
         let chat_service_client_clone = chat_service_client.clone();
@@ -37,36 +69,107 @@ impl PerOxoState {
         let persistence = Arc::new(PersistenceService::new(
             #[cfg(feature = "persistence")]
             chat_service_client_clone,
+            #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+            write_batch,
+            #[cfg(feature = "persistence")]
+            outbox,
             #[cfg(feature = "mongo_db")]
             mango_db_client,
             #[cfg(feature = "mongo_db")]
             mongo_config,
         ));
 
-        let (router, router_sender) = MessageRouter::new(
+        let router = MessageRouter::spawn_shards(
+            router_shards,
+            backpressure.clone(),
+            rate_limits,
+            #[cfg(feature = "persistence")]
+            edits,
+            #[cfg(feature = "persistence")]
+            reactions,
+            cluster.clone(),
             #[cfg(any(feature = "mongo_db", feature = "persistence"))]
-            persistence,
+            persistence.clone(),
         );
-        let connection_manager = Arc::new(ConnectionManager::new(router_sender.clone()));
+        let drain = Drain::new(shutdown);
+        let authenticator = Authenticator::new(auth_client.clone(), auth);
+        let connection_manager = Arc::new(ConnectionManager::new(
+            router.clone(),
+            backpressure,
+            heartbeat,
+            drain.clone(),
+            authenticator.clone(),
+        ));
 
-        tokio::spawn(router.run());
+        if let Some(cluster) = cluster {
+            let receiver = cluster.subscribe().await?;
+            tokio::spawn(cluster::forward_to_router(receiver, router.clone()));
+            // the other nodes answer with the users connected to them
+            cluster.broadcast(ClusterEvent::NodeStarted).await?;
+        }
 
         Ok(Self {
             connection_manager,
-            router_sender,
+            router,
             auth_client,
+            authenticator,
             #[cfg(feature = "persistence")]
             chat_client: chat_service_client,
+            drain,
+            #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+            persistence,
         })
     }
+
+    // Stops taking connections, has every session send its pending acks and
+    // tell its client to reconnect, then waits for the message writes still
+    // queued. Gives up on whatever is left after the drain timeout.
+    pub async fn shutdown(&self) {
+        let deadline = tokio::time::Instant::now() + self.drain.config.drain_timeout;
+        self.drain.start();
+
+        if tokio::time::timeout_at(deadline, self.drain.sessions_closed())
+            .await
+            .is_err()
+        {
+            warn!(
+                "{} sessions still open after the drain timeout",
+                self.drain.open_sessions()
+            );
+        }
+
+        #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+        if tokio::time::timeout_at(deadline, self.persistence.flush())
+            .await
+            .is_err()
+        {
+            warn!("Message writes still queued after the drain timeout");
+        }
+    }
 }
 
 pub struct PerOxoStateBuilder {
     #[cfg(feature = "persistence")]
     connection_url: Option<String>,
+    #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+    write_batch: WriteBatchConfig,
+    #[cfg(feature = "persistence")]
+    outbox_dir: Option<PathBuf>,
     #[cfg(feature = "mongo_db")]
     mongo_config: Option<MongoDbConfig>,
     auth_url: Option<String>,
+    auth: AuthConfig,
+    grpc_client: GrpcClientConfig,
+    backpressure: BackpressureConfig,
+    rate_limits: RateLimitConfig,
+    #[cfg(feature = "persistence")]
+    edits: EditConfig,
+    #[cfg(feature = "persistence")]
+    reactions: ReactionConfig,
+    heartbeat: HeartbeatConfig,
+    shutdown: ShutdownConfig,
+    cluster: Option<Arc<dyn ClusterBus>>,
+    router_shards: usize,
 }
 
 impl Default for PerOxoStateBuilder {
@@ -80,18 +183,53 @@ impl PerOxoStateBuilder {
         Self {
             #[cfg(feature = "persistence")]
             connection_url: None,
+            #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+            write_batch: WriteBatchConfig::default(),
+            #[cfg(feature = "persistence")]
+            outbox_dir: None,
             #[cfg(feature = "mongo_db")]
             mongo_config: None,
             auth_url: None,
+            auth: AuthConfig::default(),
+            grpc_client: GrpcClientConfig::default(),
+            backpressure: BackpressureConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+            #[cfg(feature = "persistence")]
+            edits: EditConfig::default(),
+            #[cfg(feature = "persistence")]
+            reactions: ReactionConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            cluster: None,
+            router_shards: std::thread::available_parallelism().map_or(1, |n| n.get()),
         }
     }
 
+    // One chat-service address, or several separated by commas
     #[cfg(feature = "persistence")]
     pub fn with_persistence_connection_url(mut self, url: impl Into<String>) -> Self {
         self.connection_url = Some(url.into());
         self
     }
 
+    // How many message writes are grouped into one call to the chat service,
+    // how long the first of them may wait for the others, and over how many
+    // ordered lanes conversations and rooms are spread
+    #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+    pub fn with_write_batch(mut self, config: WriteBatchConfig) -> Self {
+        self.write_batch = config;
+        self
+    }
+
+    // Message writes chat-service can't take are spooled to segment files in
+    // `dir` and replayed once it is back, also after a restart. Without it
+    // they are acked as failed.
+    #[cfg(feature = "persistence")]
+    pub fn with_outbox_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.outbox_dir = Some(dir.into());
+        self
+    }
+
     #[cfg(feature = "mongo_db")]
     pub fn with_mongo_config(mut self, config: MongoDbConfig) -> Self {
         self.mongo_config = Some(config);
@@ -109,15 +247,91 @@ impl PerOxoStateBuilder {
         self
     }
 
+    // How long a client that connected without a token gets to authenticate
+    pub fn with_auth(mut self, config: AuthConfig) -> Self {
+        self.auth = config;
+        self
+    }
+
+    // Deadlines, circuit breaking and health checks of the chat-service and
+    // auth-service clients. While chat-service's circuit is open messages are
+    // still delivered live, their writes are spooled to the outbox or acked as
+    // failed right away.
+    pub fn with_grpc_client(mut self, config: GrpcClientConfig) -> Self {
+        self.grpc_client = config;
+        self
+    }
+
+    // Mailbox and queue sizes, and what happens when they overflow
+    pub fn with_backpressure(mut self, config: BackpressureConfig) -> Self {
+        self.backpressure = config;
+        self
+    }
+
+    // How fast connections, and all connections of a project together, may
+    // send messages and join rooms, and per-project overrides
+    pub fn with_rate_limits(mut self, config: RateLimitConfig) -> Self {
+        self.rate_limits = config;
+        self
+    }
+
+    // How long senders may edit and delete their messages, and per-project
+    // overrides
+    #[cfg(feature = "persistence")]
+    pub fn with_edits(mut self, config: EditConfig) -> Self {
+        self.edits = config;
+        self
+    }
+
+    // How many distinct reactions a message may have, and per-project
+    // overrides
+    #[cfg(feature = "persistence")]
+    pub fn with_reactions(mut self, config: ReactionConfig) -> Self {
+        self.reactions = config;
+        self
+    }
+
+    // How often connections are pinged and how long a silent one is kept
+    // before it is closed and its user reported offline
+    pub fn with_heartbeat(mut self, config: HeartbeatConfig) -> Self {
+        self.heartbeat = config;
+        self
+    }
+
+    // How long `PerOxoState::shutdown` waits for sessions and writes, and
+    // when clients are told to come back
+    pub fn with_shutdown(mut self, config: ShutdownConfig) -> Self {
+        self.shutdown = config;
+        self
+    }
+
+    // Projects are spread over this many router actors, defaults to one per core
+    pub fn with_router_shards(mut self, shards: usize) -> Self {
+        self.router_shards = shards;
+        self
+    }
+
+    // Shares users, rooms and presence with other peroxo instances
+    pub fn with_cluster_bus(mut self, cluster: Arc<dyn ClusterBus>) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
     pub async fn build(self) -> Result<PerOxoState, Box<dyn std::error::Error>> {
         #[cfg(feature = "persistence")]
         let chat_service_client = if let Some(url) = self.connection_url {
             use crate::connections::connect_chat_service_client;
-            connect_chat_service_client(url).await?
+            connect_chat_service_client(url, &self.grpc_client).await?
         } else {
             return Err("connection_url required when persistence is enabled".into());
         };
 
+        #[cfg(feature = "persistence")]
+        let outbox = match self.outbox_dir {
+            Some(dir) => Some(DurableOutbox::open(dir)?),
+            None => None,
+        };
+
         #[cfg(feature = "mongo_db")]
         let (mango_db_client, mongo_config) = if let Some(config) = self.mongo_config {
             use crate::connections::connect_mongo_db_client;
@@ -129,7 +343,7 @@ impl PerOxoStateBuilder {
 
         let auth_service_client = if let Some(url) = self.auth_url {
             use crate::connections::connect_auth_service_client;
-            connect_auth_service_client(url).await?
+            connect_auth_service_client(url, &self.grpc_client).await?
         } else {
             return Err("auth_url is required".into());
         };
@@ -137,11 +351,26 @@ impl PerOxoStateBuilder {
         PerOxoState::new(
             #[cfg(feature = "persistence")]
             chat_service_client,
+            #[cfg(any(feature = "mongo_db", feature = "persistence"))]
+            self.write_batch,
+            #[cfg(feature = "persistence")]
+            outbox,
             #[cfg(feature = "mongo_db")]
             mango_db_client,
             #[cfg(feature = "mongo_db")]
             mongo_config,
             auth_service_client,
+            self.auth,
+            self.backpressure,
+            self.rate_limits,
+            #[cfg(feature = "persistence")]
+            self.edits,
+            #[cfg(feature = "persistence")]
+            self.reactions,
+            self.heartbeat,
+            self.shutdown,
+            self.cluster,
+            self.router_shards,
         )
         .await
     }
@@ -22,6 +22,26 @@ impl TenantUserId {
             user_id: token.user_id.clone(),
         })
     }
+
+    // Resolve a client-supplied user_id inside this user's project.
+    pub fn peer(&self, user_id: String) -> Self {
+        Self {
+            project_id: self.project_id.clone(),
+            user_id,
+        }
+    }
+
+    pub fn same_tenant(&self, other: &TenantUserId) -> bool {
+        self.project_id == other.project_id
+    }
+
+    // Resolve a client-supplied room_id inside this user's project.
+    pub fn room(&self, room_id: String) -> TenantRoomId {
+        TenantRoomId {
+            project_id: self.project_id.clone(),
+            room_id,
+        }
+    }
 }
 
 impl std::fmt::Display for TenantUserId {
@@ -29,3 +49,29 @@ impl std::fmt::Display for TenantUserId {
         write!(f, "{}:{}", self.project_id, self.user_id)
     }
 }
+
+// Rooms are namespaced per project, two tenants can both own a "general" room.
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TenantRoomId {
+    pub project_id: String,
+    pub room_id: String,
+}
+
+impl TenantRoomId {
+    pub fn new(project_id: String, room_id: String) -> Self {
+        Self {
+            project_id,
+            room_id,
+        }
+    }
+
+    pub fn same_tenant(&self, user: &TenantUserId) -> bool {
+        self.project_id == user.project_id
+    }
+}
+
+impl std::fmt::Display for TenantRoomId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.project_id, self.room_id)
+    }
+}
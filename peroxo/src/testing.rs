@@ -0,0 +1,623 @@
+// A chat-service stand-in for tests. It keeps just enough state in memory
+// for the router and sessions to be driven end to end, every rpc it has no
+// use for answers Unimplemented.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use tonic::transport::{Channel, Server};
+use tonic::{Request, Response, Status};
+
+use crate::actors::message_router::{MessageRouter, RouterHandle, RouterMessage};
+use crate::actors::persistance_actor::{PersistenceService, WriteBatchConfig};
+use crate::actors::user_session::outbox::{Outbox, OutboxReceiver};
+use crate::actors::user_session::session::ConnectionId;
+use crate::backpressure::BackpressureConfig;
+use crate::chat::ChatMessage;
+use crate::chat_service_client::ChatServiceClient;
+use crate::chat_service_server::{ChatService, ChatServiceServer};
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, GuardedChannel};
+use crate::edits::EditConfig;
+use crate::rate_limit::RateLimitConfig;
+use crate::reactions::ReactionConfig;
+use crate::tenant::TenantUserId;
+use crate::*;
+
+// How long a test waits for a frame before giving up on it
+const FRAME_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+pub struct FakeState {
+    // (project_id, conversation_id) -> its two users
+    pub conversations: HashMap<(String, String), (String, String)>,
+    // (project_id, user_id) -> persisted rooms
+    pub rooms: HashMap<(String, String), Vec<String>>,
+    // (project_id, user_id) -> not yet acked deliveries, oldest first
+    pub pending: HashMap<(String, String), Vec<PendingDelivery>>,
+    // (project_id, conversation_id) -> user_id -> message_id
+    pub read_cursors: HashMap<(String, String), BTreeMap<String, String>>,
+    // message_id -> the message edits and deletes are checked against
+    pub messages: HashMap<String, FakeMessage>,
+    // message_id -> reaction -> users
+    pub reactions: HashMap<String, BTreeMap<String, Vec<String>>>,
+    // AddRoomMember and RemoveRoomMember answer with an error while set
+    pub fail_room_membership: bool,
+    // how often each rpc was called
+    pub calls: HashMap<&'static str, usize>,
+}
+
+pub struct FakeMessage {
+    pub sender_id: String,
+    pub sent_at: i64,
+    pub content: String,
+    pub deleted: bool,
+}
+
+#[derive(Clone, Default)]
+pub struct FakeChatService {
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl FakeChatService {
+    pub fn with_conversation(
+        self,
+        project_id: &str,
+        conversation_id: &str,
+        user_id_1: &str,
+        user_id_2: &str,
+    ) -> Self {
+        self.state().conversations.insert(
+            (project_id.to_string(), conversation_id.to_string()),
+            (user_id_1.to_string(), user_id_2.to_string()),
+        );
+        self
+    }
+
+    pub fn with_message(self, message_id: uuid::Uuid, sender_id: &str, sent_at: i64) -> Self {
+        self.state().messages.insert(
+            message_id.to_string(),
+            FakeMessage {
+                sender_id: sender_id.to_string(),
+                sent_at,
+                content: "hi".to_string(),
+                deleted: false,
+            },
+        );
+        self
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, FakeState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn calls(&self, method: &str) -> usize {
+        self.state().calls.get(method).copied().unwrap_or(0)
+    }
+
+    // Counts a call of `method` and hands out the state to answer it
+    fn call(&self, method: &'static str) -> MutexGuard<'_, FakeState> {
+        self.record(method);
+        self.state()
+    }
+
+    fn record(&self, method: &'static str) {
+        *self.state().calls.entry(method).or_default() += 1;
+    }
+
+    // Serves the fake on a free local port, the client goes through the
+    // same breaker and deadline as the real one
+    pub async fn persistence(&self) -> Arc<PersistenceService> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::builder()
+            .add_service(ChatServiceServer::new(self.clone()))
+            .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener));
+        tokio::spawn(server);
+
+        let channel = Channel::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect_lazy();
+        let channel = GuardedChannel::new(
+            channel,
+            CircuitBreaker::new("chat_service", CircuitBreakerConfig::default()),
+            Duration::from_secs(5),
+        );
+        Arc::new(PersistenceService::new(
+            ChatServiceClient::new(channel),
+            WriteBatchConfig::default(),
+            None,
+        ))
+    }
+
+    pub async fn router(&self) -> RouterHandle {
+        self.router_with(ReactionConfig::default()).await
+    }
+
+    pub async fn router_with(&self, reactions: ReactionConfig) -> RouterHandle {
+        self.spawn_router(1, reactions).await
+    }
+
+    pub async fn sharded_router(&self, shard_count: usize) -> RouterHandle {
+        self.spawn_router(shard_count, ReactionConfig::default())
+            .await
+    }
+
+    async fn spawn_router(&self, shard_count: usize, reactions: ReactionConfig) -> RouterHandle {
+        MessageRouter::spawn_shards(
+            shard_count,
+            BackpressureConfig::default(),
+            RateLimitConfig::default(),
+            EditConfig::default(),
+            reactions,
+            None,
+            self.persistence().await,
+        )
+    }
+}
+
+pub fn user(project_id: &str, user_id: &str) -> TenantUserId {
+    TenantUserId::new(project_id.to_string(), user_id.to_string())
+}
+
+// Registers a new device of `user`, its frames arrive on the receiver
+pub async fn connect(router: &RouterHandle, user: &TenantUserId) -> (ConnectionId, OutboxReceiver) {
+    let (outbox, receiver) = Outbox::new(&BackpressureConfig::default());
+    let connection_id = ConnectionId::new_v4();
+    let (respond_to, response) = tokio::sync::oneshot::channel();
+    router
+        .shard(&user.project_id)
+        .send(RouterMessage::RegisterUser {
+            tenant_user_id: user.clone(),
+            connection_id,
+            outbox,
+            respond_to,
+        })
+        .await
+        .unwrap();
+    response.await.unwrap().unwrap();
+    (connection_id, receiver)
+}
+
+// The device goes away, the user's other devices stay
+pub async fn disconnect(router: &RouterHandle, user: &TenantUserId, connection_id: ConnectionId) {
+    router
+        .shard(&user.project_id)
+        .send(RouterMessage::UnregisterUser {
+            tenant_user_id: user.clone(),
+            connection_id,
+        })
+        .await
+        .unwrap();
+}
+
+pub async fn next_frame(receiver: &mut OutboxReceiver) -> ChatMessage {
+    tokio::time::timeout(FRAME_TIMEOUT, receiver.recv())
+        .await
+        .expect("no frame arrived")
+        .expect("connection closed")
+}
+
+// Fails if anything arrives for a while
+pub async fn assert_silent(receiver: &mut OutboxReceiver) {
+    if let Ok(Some(frame)) = tokio::time::timeout(Duration::from_millis(100), receiver.recv()).await
+    {
+        panic!("unexpected frame: {:?}", frame);
+    }
+}
+
+#[tonic::async_trait]
+impl ChatService for FakeChatService {
+    async fn write_dm(
+        &self,
+        _request: Request<WriteDmRequest>,
+    ) -> Result<Response<WriteDmResponse>, Status> {
+        Err(Status::unimplemented("not faked"))
+    }
+
+    async fn write_dm_batch(
+        &self,
+        request: Request<WriteDmBatchRequest>,
+    ) -> Result<Response<WriteDmBatchResponse>, Status> {
+        self.record("WriteDMBatch");
+        let results = request
+            .into_inner()
+            .messages
+            .iter()
+            .map(|_| WriteDmResponse {
+                success: true,
+                error_message: String::new(),
+            })
+            .collect();
+        Ok(Response::new(WriteDmBatchResponse { results }))
+    }
+
+    async fn fetch_user_conversations(
+        &self,
+        _request: Request<FetchUserConversationsRequest>,
+    ) -> Result<Response<FetchUserConversationsResponse>, Status> {
+        Err(Status::unimplemented("not faked"))
+    }
+
+    async fn fetch_conversation_history(
+        &self,
+        _request: Request<FetchConversationHistoryRequest>,
+    ) -> Result<Response<FetchConversationHistoryResponse>, Status> {
+        Err(Status::unimplemented("not faked"))
+    }
+
+    async fn get_paginated_messages(
+        &self,
+        _request: Request<GetPaginatedMessagesRequest>,
+    ) -> Result<Response<GetPaginatedMessagesResponse>, Status> {
+        Err(Status::unimplemented("not faked"))
+    }
+
+    async fn write_room_message(
+        &self,
+        _request: Request<WriteRoomMessageRequest>,
+    ) -> Result<Response<WriteRoomMessageResponse>, Status> {
+        Err(Status::unimplemented("not faked"))
+    }
+
+    async fn write_room_message_batch(
+        &self,
+        request: Request<WriteRoomMessageBatchRequest>,
+    ) -> Result<Response<WriteRoomMessageBatchResponse>, Status> {
+        self.record("WriteRoomMessageBatch");
+        let results = request
+            .into_inner()
+            .messages
+            .iter()
+            .map(|_| WriteRoomMessageResponse {
+                success: true,
+                error_message: String::new(),
+            })
+            .collect();
+        Ok(Response::new(WriteRoomMessageBatchResponse { results }))
+    }
+
+    async fn get_paginated_room_messages(
+        &self,
+        _request: Request<GetPaginatedRoomMessagesRequest>,
+    ) -> Result<Response<GetPaginatedRoomMessagesResponse>, Status> {
+        Err(Status::unimplemented("not faked"))
+    }
+
+    async fn sync_messages(
+        &self,
+        _request: Request<SyncMessagesRequest>,
+    ) -> Result<Response<SyncMessagesResponse>, Status> {
+        Err(Status::unimplemented("not faked"))
+    }
+
+    async fn get_sert_conversation(
+        &self,
+        _request: Request<GetSertConversationRequest>,
+    ) -> Result<Response<GetSertConversationResponse>, Status> {
+        Err(Status::unimplemented("not faked"))
+    }
+
+    async fn get_conversation_participants(
+        &self,
+        request: Request<GetConversationParticipantsRequest>,
+    ) -> Result<Response<GetConversationParticipantsResponse>, Status> {
+        let request = request.into_inner();
+        let state = self.call("GetConversationParticipants");
+        let participants = state
+            .conversations
+            .get(&(request.project_id, request.conversation_id))
+            .cloned();
+        Ok(Response::new(GetConversationParticipantsResponse {
+            success: true,
+            error_message: String::new(),
+            found: participants.is_some(),
+            user_id_1: participants.clone().unwrap_or_default().0,
+            user_id_2: participants.unwrap_or_default().1,
+        }))
+    }
+
+    async fn fetch_conversation_partners(
+        &self,
+        request: Request<FetchConversationPartnersRequest>,
+    ) -> Result<Response<FetchConversationPartnersResponse>, Status> {
+        let user = request.into_inner().tenant_user_id.unwrap_or_default();
+        let state = self.call("FetchConversationPartners");
+        let user_ids = state
+            .conversations
+            .iter()
+            .filter(|((project_id, _), _)| *project_id == user.project_id)
+            .filter_map(|(_, (user_id_1, user_id_2))| {
+                if *user_id_1 == user.user_id {
+                    Some(user_id_2.clone())
+                } else if *user_id_2 == user.user_id {
+                    Some(user_id_1.clone())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        Ok(Response::new(FetchConversationPartnersResponse {
+            success: true,
+            error_message: String::new(),
+            user_ids,
+        }))
+    }
+
+    async fn update_read_cursor(
+        &self,
+        request: Request<UpdateReadCursorRequest>,
+    ) -> Result<Response<UpdateReadCursorResponse>, Status> {
+        let request = request.into_inner();
+        let mut state = self.call("UpdateReadCursor");
+        let cursors = state
+            .read_cursors
+            .entry((request.project_id, request.conversation_id))
+            .or_default();
+        // v1 uuids of one test compare like their timestamps
+        let advanced = cursors
+            .get(&request.user_id)
+            .is_none_or(|current| *current < request.message_id);
+        if advanced {
+            cursors.insert(request.user_id, request.message_id);
+        }
+        Ok(Response::new(UpdateReadCursorResponse {
+            success: true,
+            error_message: String::new(),
+            advanced,
+        }))
+    }
+
+    async fn get_read_cursors(
+        &self,
+        request: Request<GetReadCursorsRequest>,
+    ) -> Result<Response<GetReadCursorsResponse>, Status> {
+        let request = request.into_inner();
+        let state = self.call("GetReadCursors");
+        let cursors = state
+            .read_cursors
+            .get(&(request.project_id, request.conversation_id))
+            .map(|cursors| {
+                cursors
+                    .iter()
+                    .map(|(user_id, message_id)| crate::ReadCursor {
+                        user_id: user_id.clone(),
+                        message_id: message_id.clone(),
+                        updated_at: 0,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Response::new(GetReadCursorsResponse {
+            success: true,
+            error_message: String::new(),
+            cursors,
+        }))
+    }
+
+    async fn add_room_member(
+        &self,
+        request: Request<RoomMemberRequest>,
+    ) -> Result<Response<RoomMemberResponse>, Status> {
+        let request = request.into_inner();
+        let mut state = self.call("AddRoomMember");
+        if state.fail_room_membership {
+            return Ok(Response::new(member_failed()));
+        }
+        let rooms = state
+            .rooms
+            .entry((request.project_id, request.user_id))
+            .or_default();
+        if !rooms.contains(&request.room_id) {
+            rooms.push(request.room_id);
+        }
+        Ok(Response::new(RoomMemberResponse {
+            success: true,
+            error_message: String::new(),
+        }))
+    }
+
+    async fn remove_room_member(
+        &self,
+        request: Request<RoomMemberRequest>,
+    ) -> Result<Response<RoomMemberResponse>, Status> {
+        let request = request.into_inner();
+        let mut state = self.call("RemoveRoomMember");
+        if state.fail_room_membership {
+            return Ok(Response::new(member_failed()));
+        }
+        if let Some(rooms) = state.rooms.get_mut(&(request.project_id, request.user_id)) {
+            rooms.retain(|room_id| *room_id != request.room_id);
+        }
+        Ok(Response::new(RoomMemberResponse {
+            success: true,
+            error_message: String::new(),
+        }))
+    }
+
+    async fn fetch_user_rooms(
+        &self,
+        request: Request<FetchUserRoomsRequest>,
+    ) -> Result<Response<FetchUserRoomsResponse>, Status> {
+        let user = request.into_inner().tenant_user_id.unwrap_or_default();
+        let state = self.call("FetchUserRooms");
+        let room_ids = state
+            .rooms
+            .get(&(user.project_id, user.user_id))
+            .cloned()
+            .unwrap_or_default();
+        Ok(Response::new(FetchUserRoomsResponse {
+            success: true,
+            error_message: String::new(),
+            room_ids,
+        }))
+    }
+
+    async fn fetch_pending_deliveries(
+        &self,
+        request: Request<FetchPendingDeliveriesRequest>,
+    ) -> Result<Response<FetchPendingDeliveriesResponse>, Status> {
+        let request = request.into_inner();
+        let user = request.tenant_user_id.unwrap_or_default();
+        let state = self.call("FetchPendingDeliveries");
+        let pending = state
+            .pending
+            .get(&(user.project_id, user.user_id))
+            .cloned()
+            .unwrap_or_default();
+        let start = pending
+            .iter()
+            .position(|delivery| delivery.message_id == request.after_message_id)
+            .map_or(0, |position| position + 1);
+        let rest = &pending[start..];
+        let limit = (request.limit.max(0) as usize).min(rest.len());
+        Ok(Response::new(FetchPendingDeliveriesResponse {
+            success: true,
+            error_message: String::new(),
+            deliveries: rest[..limit].to_vec(),
+            has_more: rest.len() > limit,
+        }))
+    }
+
+    async fn ack_deliveries(
+        &self,
+        request: Request<AckDeliveriesRequest>,
+    ) -> Result<Response<AckDeliveriesResponse>, Status> {
+        let request = request.into_inner();
+        let user = request.tenant_user_id.unwrap_or_default();
+        let mut state = self.call("AckDeliveries");
+        if let Some(pending) = state.pending.get_mut(&(user.project_id, user.user_id)) {
+            pending.retain(|delivery| !request.message_ids.contains(&delivery.message_id));
+        }
+        Ok(Response::new(AckDeliveriesResponse {
+            success: true,
+            error_message: String::new(),
+        }))
+    }
+
+    async fn edit_message(
+        &self,
+        request: Request<EditMessageRequest>,
+    ) -> Result<Response<MessageChangeResponse>, Status> {
+        let request = request.into_inner();
+        let mut state = self.call("EditMessage");
+        let response = change_message(
+            &mut state,
+            &request.message_id,
+            &request.user_id,
+            request.timestamp,
+            request.window_ms,
+            |message| message.content = request.content.clone(),
+        );
+        Ok(Response::new(response))
+    }
+
+    async fn delete_message(
+        &self,
+        request: Request<DeleteMessageRequest>,
+    ) -> Result<Response<MessageChangeResponse>, Status> {
+        let request = request.into_inner();
+        let mut state = self.call("DeleteMessage");
+        let response = change_message(
+            &mut state,
+            &request.message_id,
+            &request.user_id,
+            request.timestamp,
+            request.window_ms,
+            |message| message.deleted = true,
+        );
+        Ok(Response::new(response))
+    }
+
+    async fn add_reaction(
+        &self,
+        request: Request<ReactionRequest>,
+    ) -> Result<Response<ReactionResponse>, Status> {
+        let request = request.into_inner();
+        let mut state = self.call("AddReaction");
+        let reactions = state.reactions.entry(request.message_id).or_default();
+        if request.max_reactions > 0
+            && !reactions.contains_key(&request.reaction)
+            && reactions.len() >= request.max_reactions as usize
+        {
+            return Ok(Response::new(ReactionResponse {
+                success: false,
+                error_message: String::new(),
+                rejection: crate::ReactionRejection::LimitReached as i32,
+                reactions: Vec::new(),
+            }));
+        }
+        let users = reactions.entry(request.reaction).or_default();
+        if !users.contains(&request.user_id) {
+            users.push(request.user_id);
+        }
+        Ok(Response::new(reaction_response(reactions)))
+    }
+
+    async fn remove_reaction(
+        &self,
+        request: Request<ReactionRequest>,
+    ) -> Result<Response<ReactionResponse>, Status> {
+        let request = request.into_inner();
+        let mut state = self.call("RemoveReaction");
+        let reactions = state.reactions.entry(request.message_id).or_default();
+        if let Some(users) = reactions.get_mut(&request.reaction) {
+            users.retain(|user_id| *user_id != request.user_id);
+            if users.is_empty() {
+                reactions.remove(&request.reaction);
+            }
+        }
+        Ok(Response::new(reaction_response(reactions)))
+    }
+}
+
+fn member_failed() -> RoomMemberResponse {
+    RoomMemberResponse {
+        success: false,
+        error_message: "membership not stored".to_string(),
+    }
+}
+
+// Applies `change` if the user may still make it, refused the way
+// chat-service refuses it otherwise
+fn change_message(
+    state: &mut FakeState,
+    message_id: &str,
+    user_id: &str,
+    timestamp: i64,
+    window_ms: i64,
+    change: impl FnOnce(&mut FakeMessage),
+) -> MessageChangeResponse {
+    let rejection = match state.messages.get_mut(message_id) {
+        None => MessageChangeRejection::NotFound,
+        Some(message) if message.deleted => MessageChangeRejection::Deleted,
+        Some(message) if message.sender_id != user_id => MessageChangeRejection::NotSender,
+        Some(message) if window_ms > 0 && timestamp - message.sent_at > window_ms => {
+            MessageChangeRejection::WindowExpired
+        }
+        Some(message) => {
+            change(message);
+            MessageChangeRejection::NotRejected
+        }
+    };
+    MessageChangeResponse {
+        success: rejection == MessageChangeRejection::NotRejected,
+        error_message: String::new(),
+        rejection: rejection as i32,
+    }
+}
+
+fn reaction_response(reactions: &BTreeMap<String, Vec<String>>) -> ReactionResponse {
+    ReactionResponse {
+        success: true,
+        error_message: String::new(),
+        rejection: crate::ReactionRejection::NotRejected as i32,
+        reactions: reactions
+            .iter()
+            .map(|(reaction, user_ids)| crate::Reaction {
+                reaction: reaction.clone(),
+                count: user_ids.len() as i32,
+                user_ids: user_ids.clone(),
+            })
+            .collect(),
+    }
+}
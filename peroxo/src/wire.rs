@@ -0,0 +1,275 @@
+use axum::extract::ws::Message;
+use axum::http::HeaderValue;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::chat::{ChatMessage, ErrorCode};
+
+// Version of the frame layout, carried as "v" in every frame. Frames
+// without one are read as the current version.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Longest request_id a client may set, it is echoed back as is
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// Sec-WebSocket-Protocol names. JSON text frames are used when the client
+// asks for neither.
+pub const JSON_PROTOCOL: &str = "peroxo.json.v1";
+pub const MSGPACK_PROTOCOL: &str = "peroxo.msgpack.v1";
+
+// In order of preference, the first one the client offers is picked
+pub const SUPPORTED_PROTOCOLS: [&str; 2] = [MSGPACK_PROTOCOL, JSON_PROTOCOL];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WireProtocol {
+    // ChatMessage as JSON in text frames
+    #[default]
+    Json,
+    // ChatMessage as MessagePack in binary frames, field names kept so both
+    // encodings share one schema
+    MessagePack,
+}
+
+impl WireProtocol {
+    // The protocol picked during the upgrade, if any
+    pub fn negotiated(protocol: Option<&HeaderValue>) -> Self {
+        match protocol.and_then(|value| value.to_str().ok()) {
+            Some(MSGPACK_PROTOCOL) => WireProtocol::MessagePack,
+            _ => WireProtocol::Json,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WireProtocol::Json => JSON_PROTOCOL,
+            WireProtocol::MessagePack => MSGPACK_PROTOCOL,
+        }
+    }
+
+    pub fn encode(&self, message: &ChatMessage) -> Result<Message, String> {
+        let envelope = Envelope {
+            message,
+            v: PROTOCOL_VERSION,
+        };
+        match self {
+            WireProtocol::Json => serde_json::to_string(&envelope)
+                .map(|json| Message::Text(json.into()))
+                .map_err(|e| e.to_string()),
+            WireProtocol::MessagePack => rmp_serde::to_vec_named(&envelope)
+                .map(|bytes| Message::Binary(bytes.into()))
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(flatten)]
+    message: &'a ChatMessage,
+    v: u32,
+}
+
+// Read on its own first, so a frame of a newer version or an unknown type
+// is told apart from a malformed one
+#[derive(Deserialize)]
+struct Header {
+    #[serde(rename = "type")]
+    kind: String,
+    v: Option<u32>,
+    request_id: Option<String>,
+}
+
+// A frame from the client and the id its answers are tagged with
+#[derive(Debug)]
+pub struct Request {
+    pub message: ChatMessage,
+    pub request_id: Option<String>,
+}
+
+// Why a frame from the client was refused, answered with an Error frame
+#[derive(Debug)]
+pub struct FrameError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub request_id: Option<String>,
+}
+
+impl FrameError {
+    pub fn new(code: ErrorCode, message: impl Into<String>, request_id: Option<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            request_id,
+        }
+    }
+}
+
+// Frames are decoded by their type, so a client may send either encoding
+// whatever it negotiated for the frames it receives
+pub fn decode(frame: &Message) -> Result<Request, FrameError> {
+    let header: Header =
+        parse(frame).map_err(|e| FrameError::new(ErrorCode::InvalidFrame, e, None))?;
+    if header
+        .request_id
+        .as_ref()
+        .is_some_and(|id| id.len() > MAX_REQUEST_ID_LEN)
+    {
+        return Err(FrameError::new(
+            ErrorCode::InvalidFrame,
+            format!("request_id is longer than {} bytes", MAX_REQUEST_ID_LEN),
+            None,
+        ));
+    }
+    let request_id = header.request_id;
+    if let Some(v) = header.v.filter(|v| *v != PROTOCOL_VERSION) {
+        return Err(FrameError::new(
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "Protocol version {} is not supported, this server speaks version {}",
+                v, PROTOCOL_VERSION
+            ),
+            request_id,
+        ));
+    }
+    if !CLIENT_MESSAGE_TYPES.contains(&header.kind.as_str()) {
+        return Err(FrameError::new(
+            ErrorCode::UnsupportedMessageType,
+            format!("{} is not a message clients can send", header.kind),
+            request_id,
+        ));
+    }
+    match parse(frame) {
+        Ok(message) => Ok(Request {
+            message,
+            request_id,
+        }),
+        Err(e) => Err(FrameError::new(ErrorCode::InvalidMessage, e, request_id)),
+    }
+}
+
+fn parse<T: DeserializeOwned>(frame: &Message) -> Result<T, String> {
+    match frame {
+        Message::Text(text) => serde_json::from_str(text).map_err(|e| e.to_string()),
+        Message::Binary(bytes) => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+        _ => Err("Not a data frame".to_string()),
+    }
+}
+
+// The ChatMessage types a client may send, the rest only go out
+const CLIENT_MESSAGE_TYPES: &[&str] = &[
+    "SendDirectMessage",
+    "SubscribePresence",
+    "UnsubscribePresence",
+    "GetPresence",
+    "GetOnlineUsers",
+    #[cfg(feature = "persistence")]
+    "GetPaginatedMessages",
+    "SendRoomMessage",
+    "JoinRoom",
+    "LeaveRoom",
+    #[cfg(feature = "persistence")]
+    "GetRoomHistory",
+    #[cfg(feature = "persistence")]
+    "SyncMessages",
+    #[cfg(feature = "persistence")]
+    "MarkRead",
+    #[cfg(feature = "persistence")]
+    "GetReadCursors",
+    #[cfg(feature = "persistence")]
+    "EditMessage",
+    #[cfg(feature = "persistence")]
+    "DeleteMessage",
+    #[cfg(feature = "persistence")]
+    "AddReaction",
+    #[cfg(feature = "persistence")]
+    "RemoveReaction",
+    "TypingStarted",
+    "TypingStopped",
+    "AckDelivery",
+    "Authenticate",
+    "Reauthenticate",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::MessageStatus;
+
+    #[test]
+    fn message_pack_round_trips() {
+        let message = ChatMessage::MessageAck {
+            client_message_id: uuid::Uuid::new_v4(),
+            message_id: uuid::Uuid::new_v4(),
+            timestamp: 1_700_000_000_000,
+            status: MessageStatus::Failed("Room is overloaded".to_string()),
+            request_id: Some("send-1".to_string()),
+        };
+
+        let frame = WireProtocol::MessagePack.encode(&message).unwrap();
+        assert!(matches!(frame, Message::Binary(_)));
+        // MessageAck only goes out, so it is read back without the checks
+        let decoded: ChatMessage = parse(&frame).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", message));
+    }
+
+    #[test]
+    fn frames_are_tagged_and_versioned() {
+        let frame = WireProtocol::Json
+            .encode(&ChatMessage::JoinRoom {
+                room_id: "lobby".to_string(),
+            })
+            .unwrap();
+        let Message::Text(text) = &frame else {
+            panic!("expected a text frame");
+        };
+        let json: serde_json::Value = serde_json::from_str(text).unwrap();
+        assert_eq!(json["type"], "JoinRoom");
+        assert_eq!(json["v"], PROTOCOL_VERSION);
+        assert!(decode(&frame).is_ok());
+
+        let request = decode(&Message::Text(
+            r#"{"type":"GetOnlineUsers","v":1,"request_id":"r1"}"#.into(),
+        ))
+        .unwrap();
+        assert_eq!(request.request_id.as_deref(), Some("r1"));
+
+        let code = |text: &str| decode(&Message::Text(text.into())).unwrap_err().code;
+        assert_eq!(code("not json"), ErrorCode::InvalidFrame);
+        assert_eq!(
+            code(r#"{"type":"JoinRoom","v":2,"room_id":"lobby"}"#),
+            ErrorCode::UnsupportedVersion
+        );
+        assert_eq!(
+            code(r#"{"type":"MessageAck","v":1}"#),
+            ErrorCode::UnsupportedMessageType
+        );
+        let error = decode(&Message::Text(
+            r#"{"type":"JoinRoom","v":1,"request_id":"r2"}"#.into(),
+        ))
+        .unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidMessage);
+        assert_eq!(error.request_id.as_deref(), Some("r2"));
+
+        // too long to be echoed back
+        let request_id = "r".repeat(MAX_REQUEST_ID_LEN + 1);
+        let error = decode(&Message::Text(
+            format!(
+                r#"{{"type":"GetOnlineUsers","v":1,"request_id":"{}"}}"#,
+                request_id
+            )
+            .into(),
+        ))
+        .unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidFrame);
+        assert_eq!(error.request_id, None);
+    }
+
+    #[test]
+    fn falls_back_to_json() {
+        assert_eq!(WireProtocol::negotiated(None), WireProtocol::Json);
+        assert_eq!(
+            WireProtocol::negotiated(Some(&HeaderValue::from_static(MSGPACK_PROTOCOL))),
+            WireProtocol::MessagePack
+        );
+    }
+}